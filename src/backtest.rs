@@ -0,0 +1,117 @@
+//! Evaluates delay predictions against what happened later in a recording.
+//!
+//! For every record that reports a delay, a predictor only sees the records of the vehicle up to
+//! that point and predicts the delay of the same train a horizon later. The prediction is
+//! compared to the delay reported by the first record at or after the horizon.
+
+use std::fmt::Display;
+
+use crate::records::{Record, Trains};
+
+/// Predicts the delay of a train from the records observed so far.
+pub trait DelayPredictor {
+    fn name(&self) -> String;
+
+    /// Delay in seconds `horizon` seconds after the last record of `history`, which are the
+    /// records of one vehicle ordered by time.
+    fn predict(&self, history: &[Record], horizon: f64) -> Option<f64>;
+}
+
+/// Assumes the current delay stays the same, the baseline every other predictor has to beat.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PersistencePredictor;
+
+impl DelayPredictor for PersistencePredictor {
+    fn name(&self) -> String {
+        "persistence".to_string()
+    }
+
+    fn predict(&self, history: &[Record], _horizon: f64) -> Option<f64> {
+        history.last()?.delay
+    }
+}
+
+/// Errors of the predictions for one horizon.
+#[derive(Debug, Clone)]
+pub struct BacktestResult {
+    pub predictor: String,
+    /// Horizon in seconds.
+    pub horizon: f64,
+    pub predictions: usize,
+    /// Mean absolute error in seconds.
+    pub mean_absolute_error: f64,
+    /// Root mean squared error in seconds.
+    pub root_mean_squared_error: f64,
+    /// Mean of the predicted minus the actual delays in seconds, positive if too pessimistic.
+    pub bias: f64,
+}
+
+impl Display for BacktestResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} +{:.0}s: {} predictions, MAE {:.1}s, RMSE {:.1}s, bias {:+.1}s",
+            self.predictor,
+            self.horizon,
+            self.predictions,
+            self.mean_absolute_error,
+            self.root_mean_squared_error,
+            self.bias
+        )
+    }
+}
+
+/// Records at or after the horizon further apart than this many seconds are not used, as the
+/// vehicle was not observed in between.
+pub const MAX_TARGET_DISTANCE: f64 = 60.0;
+
+/// Backtests `predictor` on every vehicle for each of the `horizons` in seconds.
+pub fn backtest(
+    trains: &Trains,
+    predictor: &dyn DelayPredictor,
+    horizons: &[f64],
+) -> Vec<BacktestResult> {
+    horizons
+        .iter()
+        .map(|&horizon| {
+            let (mut predictions, mut absolute, mut squared, mut bias) = (0, 0.0, 0.0, 0.0);
+            for vehicle in trains.values() {
+                let records = &vehicle.records;
+                for (index, record) in records.iter().enumerate() {
+                    if record.delay.is_none() {
+                        continue;
+                    }
+                    let target_time = record.timestamp + horizon * 1000.0;
+                    let target = records[index..].partition_point(|r| r.timestamp < target_time);
+                    let Some(target) = records.get(index + target) else {
+                        break;
+                    };
+                    if target.train_number != record.train_number
+                        || (target.timestamp - target_time) / 1000.0 > MAX_TARGET_DISTANCE
+                    {
+                        continue;
+                    }
+                    let (Some(actual), Some(predicted)) =
+                        (target.delay, predictor.predict(&records[..=index], horizon))
+                    else {
+                        continue;
+                    };
+                    let error = predicted - actual;
+                    predictions += 1;
+                    absolute += error.abs();
+                    squared += error * error;
+                    bias += error;
+                }
+            }
+            let n = predictions.max(1) as f64;
+            BacktestResult {
+                predictor: predictor.name(),
+                horizon,
+                predictions,
+                mean_absolute_error: absolute / n,
+                root_mean_squared_error: (squared / n).sqrt(),
+                bias: bias / n,
+            }
+        })
+        .collect()
+}
@@ -1,669 +1,549 @@
-#![feature(type_name_of_val)]
-
-use geojson::GeoJson;
-use serde_json::Value;
-use serde_json::{self, Map};
-use std::collections::HashMap;
-use std::error::Error;
-use std::fmt::Display;
 use std::fs::File;
-use std::hash::Hash;
-use std::io::{BufRead, BufReader};
-use std::ops::{Deref, DerefMut};
-use std::string::String;
-
-use std::any::type_name_of_val;
-use std::time::Duration;
-use std::{thread, usize};
-
-use macroquad::prelude::*;
-
-use scraper::response_messages::{Content, ResponseMessage};
-
-#[derive(Debug)]
-enum AnalysisError {
-    MissingProperty(String),
-    IncorrectType(String, String),
-    IncorrectValueType(String, String, String),
-    MissingItems(usize, usize),
-}
-
-impl Display for AnalysisError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            AnalysisError::MissingProperty(property_name) => {
-                write!(f, "missing property: '{property_name}'")
-            }
-            AnalysisError::IncorrectType(expected_type_name, actual_type_name) => write!(
-                f,
-                "expected value to be of type '{expected_type_name}', but found it to be of type '{actual_type_name}'!"
-            ),
-            AnalysisError::IncorrectValueType(
-                expected_type_name,
-                actual_value,
-                actual_type_name,
-            ) => write!(
-                f,
-                "expected value ({expected_type_name}) to be of type '{actual_value}', but found it to be of type '{actual_type_name}'!"
-            ),
-            AnalysisError::MissingItems(expected_amount, acutal_amount) => write!(
-                f,
-                "Expected {expected_amount} items, but found {acutal_amount} instead!"
-            ),
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use scraper::backtest::{backtest, PersistencePredictor};
+use scraper::circulation::{CirculationConfig, Circulations};
+use scraper::gtfs::{write_matches_csv, Timetable};
+use scraper::headways::{HeadwayConfig, Headways};
+use scraper::heatmap::Heatmap;
+use scraper::predictions::{self, LivePredictions, PredictionConfig};
+use scraper::propagation::DelayPropagation;
+use scraper::recording::{parse_time, Recording, RecordingFilter};
+use scraper::records::{local_time, Record};
+use scraper::render::{Replay, ReplayConfig};
+use scraper::report::{MessageStatistics, StatisticsReport};
+use scraper::segments::SegmentStatistics;
+use scraper::tracker::{self, TrackerConfig};
+use scraper::viewer::{Viewer, ViewerConfig};
+
+#[derive(Parser, Debug)]
+#[command(about = "Analyzes and visualizes recordings of the S-Bahn Munich")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Args, Debug)]
+struct InputArgs {
+    /// Recordings as JSONL, directories converted to Parquet or SQLite databases
+    #[arg(default_value = "./s-bahn-munich-live-map.jsonl")]
+    inputs: Vec<PathBuf>,
+    /// Start of the time range, as RFC 3339 or local time like '2023-11-07 17:30'
+    #[arg(long, value_parser = parse_time)]
+    from: Option<f64>,
+    /// End of the time range, as RFC 3339 or local time like '2023-11-07 18:30'
+    #[arg(long, value_parser = parse_time)]
+    to: Option<f64>,
+    /// Only analyze this line, can be given multiple times
+    #[arg(long = "line")]
+    lines: Vec<String>,
+}
+
+impl InputArgs {
+    fn filter(&self) -> RecordingFilter {
+        RecordingFilter {
+            from: self.from,
+            to: self.to,
+            lines: self.lines.clone(),
         }
     }
-}
-
-impl std::error::Error for AnalysisError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
-    }
-}
-
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
-struct Coordinate {
-    latitude: f64,
-    longitude: f64,
-}
-
-impl TryFrom<serde_json::Value> for Coordinate {
-    type Error = AnalysisError;
-
-    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
-        match value {
-            Value::Array(mut array) => {
-                if array.len() != 2 {
-                    return Err(AnalysisError::MissingItems(3, array.len()));
-                }
-                Ok(Self {
-                    latitude: array
-                        .pop()
-                        .filter(|v| matches!(v, Value::Number(_)))
-                        .expect("'latitude' must exist")
-                        .as_f64()
-                        .expect("'latitude' must exist"),
-                    longitude: array
-                        .pop()
-                        .filter(|v| matches!(v, Value::Number(_)))
-                        .expect("'longitude' must exist")
-                        .as_f64()
-                        .expect("'longitude' must exist"),
-                })
-            }
-            _ => Err(AnalysisError::IncorrectType(
-                "Value::Array".to_string(),
-                "Any other type".to_string(),
-            )),
-        }
-    }
-}
-
-#[allow(dead_code)]
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
-struct Line {
-    color: String,
-    id: i64,
-    name: String,
-    stroke: String,
-    text_color: String,
-}
-
-impl TryFrom<Value> for Line {
-    type Error = AnalysisError;
-
-    fn try_from(value: Value) -> Result<Self, Self::Error> {
-        match &value.as_object() {
-            Some(object) => Ok(Self {
-                color: object.extract("color")?,
-                id: object.extract("id")?,
-                name: object.extract("name")?,
-                stroke: object.extract("stroke")?,
-                text_color: object.extract("text_color")?,
-            }),
-            None => Err(AnalysisError::IncorrectType(
-                "Value::Object".to_string(),
-                type_name_of_val(&value).to_string(),
-            )),
-        }
-    }
-}
-
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
-struct Train {
-    delay: Option<String>,
-    has_journey: bool,
-    has_realtime: bool,
-    has_realtime_journey: bool,
-    line: Option<Line>,
-    operator_provides_realtime_journey: String,
-    original_line: Option<String>,
-    original_rake: Option<String>,
-    original_train_number: i32,
-    position_correction: i32,
-    rake: Option<String>,
-    raw_coordinates: Option<Coordinate>,
-    // raw_time
-    ride_state: Option<String>,
-    // route_identfier: Option<String>,
-    state: Option<String>, // TODO: replace with enum when all states are known
-    tenant: String,        // Most probably always `sbm`
-    // time_intervals
-    // time_since_update
-    // timestamp
-    train_id: String,
-    train_number: Option<i64>,
-    transmitting_vehicle: Option<String>, // Might be a unique id
-    // type: String, // Can only be rail
-    vehicle_number: Option<String>, // Might be a unique id
-}
-
-trait Extractor<T> {
-    type Error;
-    fn extract(&self, field_name: &str) -> Result<T, Self::Error>;
-}
 
-impl Extractor<String> for &Map<String, Value> {
-    type Error = AnalysisError;
-    fn extract(&self, field_name: &str) -> Result<String, Self::Error> {
-        match self.get(field_name) {
-            Some(property) => match property {
-                Value::String(value) => Ok(value.to_string()),
-                _ => {
-                    println!("ERR TYP: {field_name}");
-                    Err(AnalysisError::IncorrectValueType(
-                        "String".to_string(),
-                        format!("{property:?}"),
-                        type_name_of_val(property).to_string(),
-                    ))
-                }
-            },
-            None => Err(AnalysisError::MissingProperty(field_name.to_string())),
-        }
+    fn load(&self) -> Result<Recording, String> {
+        Recording::load(&self.inputs, &self.filter())
+            .map_err(|err| format!("unable to load the recordings: {err}"))
     }
 }
 
-impl Extractor<Option<String>> for &Map<String, Value> {
-    type Error = AnalysisError;
-    fn extract(&self, field_name: &str) -> Result<Option<String>, Self::Error> {
-        match self.get(field_name) {
-            Some(property) => match property {
-                Value::String(value) => Ok(Some(value.to_string())),
-                Value::Null => Ok(None),
-                _ => Err(AnalysisError::IncorrectValueType(
-                    "String".to_string(),
-                    format!("{property:?}"),
-                    type_name_of_val(&property).to_string(),
-                )),
-            },
-            None => Ok(None),
-        }
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ReportFormat {
+    Markdown,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Writes a statistics report of the recordings
+    Stats {
+        #[command(flatten)]
+        input: InputArgs,
+        /// Format of the report
+        #[arg(long, value_enum, default_value_t = ReportFormat::Markdown)]
+        format: ReportFormat,
+        /// File the report is written to instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Replays the recordings on a map, in which the vehicles can be inspected
+    View {
+        #[command(flatten)]
+        input: InputArgs,
+        /// Seconds of the recording replayed per second
+        #[arg(long, default_value_t = 60.0)]
+        speed: f64,
+    },
+    /// Writes the segment statistics, headways, incidents, circulations, delay propagation
+    /// and schedule delays as CSV, and the heatmaps of the network as GeoJSON
+    Export {
+        #[command(flatten)]
+        input: InputArgs,
+        /// Directory the files are written to
+        #[arg(long, default_value = ".")]
+        output: PathBuf,
+        /// Directory of a static GTFS timetable, `./gtfs` is used if it exists
+        #[arg(long, env = "GTFS_PATH")]
+        gtfs: Option<PathBuf>,
+        /// Learns the delay propagation from the records outside of incidents only
+        #[arg(long)]
+        exclude_incidents: bool,
+    },
+    /// Reports the messages that cannot be parsed and fails if there are any
+    Validate(InputArgs),
+    /// Evaluates delay predictions against the delays reported later
+    Backtest {
+        #[command(flatten)]
+        input: InputArgs,
+        /// Horizons of the predictions in seconds
+        #[arg(long, value_delimiter = ',', default_value = "60,300,900")]
+        horizons: Vec<f64>,
+        /// Evaluates the records outside of incidents only
+        #[arg(long)]
+        exclude_incidents: bool,
+    },
+    /// Evaluates the positions predicted by the tracker against the positions reported later
+    Track {
+        #[command(flatten)]
+        input: InputArgs,
+        /// Horizons of the predictions in seconds
+        #[arg(long, value_delimiter = ',', default_value = "30,60,120,300")]
+        horizons: Vec<f64>,
+    },
+    /// Prints the next departures from a station as predicted at a time of the recordings
+    Board {
+        #[command(flatten)]
+        input: InputArgs,
+        /// Name of the station
+        #[arg(long)]
+        station: String,
+        /// Time of the prediction, as RFC 3339 or local time like '2023-11-07 17:30'
+        #[arg(long, value_parser = parse_time)]
+        at: f64,
+        /// Number of departures
+        #[arg(long, default_value_t = predictions::DEFAULT_DEPARTURES)]
+        limit: usize,
+    },
+    /// Draws the network and the trains without a window into a PNG, an animated GIF or PNG
+    /// frames
+    Render {
+        #[command(flatten)]
+        input: InputArgs,
+        /// A '.png' for a single image at '--at', a '.gif' for an animation or otherwise a
+        /// directory for the frames, e.g. for ffmpeg
+        #[arg(long)]
+        output: PathBuf,
+        /// Time of the single image, as RFC 3339 or local time like '2023-11-07 17:30'
+        #[arg(long, value_parser = parse_time)]
+        at: Option<f64>,
+        /// Width of the images in pixels
+        #[arg(long, default_value_t = 1280)]
+        width: u32,
+        /// Height of the images in pixels
+        #[arg(long, default_value_t = 720)]
+        height: u32,
+        /// Seconds of the recording per second of the animation
+        #[arg(long, default_value_t = 60.0)]
+        speed: f64,
+        /// Frames per second of the animation
+        #[arg(long, default_value_t = 10)]
+        fps: u32,
+        /// Leaves out the time in the top left corner
+        #[arg(long)]
+        no_timestamp: bool,
+    },
+}
+
+/// Writes a file of the export, the error names the file.
+fn write_file(
+    path: &Path,
+    name: &str,
+    write: impl FnOnce(&mut BufWriter<File>) -> std::io::Result<()>,
+) -> Result<(), String> {
+    let file =
+        File::create(path).map_err(|err| format!("unable to create {}: {err}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    write(&mut writer)
+        .and_then(|()| writer.flush())
+        .map_err(|err| format!("unable to write {name} to {}: {err}", path.display()))
+}
+
+fn stats(input: &InputArgs, format: ReportFormat, output: Option<&Path>) -> Result<(), String> {
+    let mut messages = MessageStatistics::new();
+    let recording = Recording::load_with(&input.inputs, &input.filter(), |m| messages.insert(m))
+        .map_err(|err| format!("unable to load the recordings: {err}"))?;
+    let report = StatisticsReport::new(&recording, &messages);
+
+    let write = |writer: &mut dyn Write| match format {
+        ReportFormat::Markdown => report.write_markdown(writer),
+        ReportFormat::Json => report
+            .write_json(&mut *writer)
+            .and_then(|()| writeln!(writer)),
+    };
+    let result = match output {
+        Some(path) => File::create(path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            writer.flush()
+        }),
+        None => write(&mut std::io::stdout().lock()),
+    };
+    result.map_err(|err| format!("unable to write the report: {err}"))
+}
+
+fn export(
+    input: &InputArgs,
+    output: &Path,
+    gtfs: Option<&Path>,
+    exclude_incidents: bool,
+) -> Result<(), String> {
+    let recording = input.load()?;
+    if !recording.errors.is_empty() {
+        eprintln!("{}", recording.errors);
     }
-}
-
-impl Extractor<i64> for &Map<String, Value> {
-    type Error = AnalysisError;
-    fn extract(&self, field_name: &str) -> Result<i64, Self::Error> {
-        match self.get(field_name) {
-            Some(property) => match property {
-                Value::Number(value) => Ok(value.as_i64().expect("must be integer")),
-                _ => {
-                    println!("ERR TYP: {field_name}");
-                    Err(AnalysisError::IncorrectValueType(
-                        "i64".to_string(),
-                        format!("{property:?}"),
-                        type_name_of_val(property).to_string(),
-                    ))
-                }
-            },
-            None => Err(AnalysisError::MissingProperty(field_name.to_string())),
-        }
+    std::fs::create_dir_all(output)
+        .map_err(|err| format!("unable to create {}: {err}", output.display()))?;
+    let mut errors = Vec::new();
+
+    let segments = SegmentStatistics::from_trains(&recording.trains, &recording.stations);
+    errors.extend(
+        write_file(
+            &output.join("segment-statistics.csv"),
+            "segment statistics",
+            |writer| segments.write_csv(writer),
+        )
+        .err(),
+    );
+
+    let headways = Headways::from_trains(
+        &recording.trains,
+        &recording.stations,
+        HeadwayConfig::default(),
+    );
+    errors.extend(
+        write_file(&output.join("headways.csv"), "headways", |writer| {
+            headways.write_csv(writer)
+        })
+        .err(),
+    );
+
+    errors.extend(
+        write_file(&output.join("incidents.csv"), "incidents", |writer| {
+            recording
+                .incidents
+                .write_csv(&recording.trains, &recording.stations, writer)
+        })
+        .err(),
+    );
+
+    let circulations = Circulations::from_trains(
+        &recording.trains,
+        &recording.stations,
+        CirculationConfig::default(),
+    );
+    errors.extend(
+        write_file(&output.join("circulations.csv"), "circulations", |writer| {
+            circulations.write_csv(writer)
+        })
+        .err(),
+    );
+    errors.extend(
+        write_file(&output.join("rake-changes.csv"), "rake changes", |writer| {
+            circulations.write_rake_changes_csv(writer)
+        })
+        .err(),
+    );
+
+    let propagation = if exclude_incidents {
+        let (_, regular) = recording.incidents.split(&recording.trains);
+        DelayPropagation::from_trains(&regular, &recording.stations)
+    } else {
+        DelayPropagation::from_trains(&recording.trains, &recording.stations)
+    };
+    errors.extend(
+        write_file(
+            &output.join("delay-propagation.csv"),
+            "delay propagation",
+            |writer| propagation.write_csv(writer),
+        )
+        .err(),
+    );
+
+    let heatmap = Heatmap::from_trains(&recording.trains, &recording.stations);
+    errors.extend(
+        write_file(&output.join("heatmap.geojson"), "heatmap", |writer| {
+            heatmap.write_geojson(writer)
+        })
+        .err(),
+    );
+
+    let gtfs = gtfs.map(Path::to_path_buf).or_else(|| {
+        let default = PathBuf::from("./gtfs");
+        default.is_dir().then_some(default)
+    });
+    if let Some(gtfs) = gtfs {
+        let timetable =
+            Timetable::load(&gtfs).map_err(|err| format!("unable to load the timetable: {err}"))?;
+        let matches = timetable.match_trains(&recording.trains, &recording.stations);
+        let deviations: Vec<f64> = matches
+            .iter()
+            .filter_map(|m| m.reported_deviation())
+            .collect();
+        println!(
+            "timetable: {} trips, {} trains matched, mean deviation of the reported delays: {:.0}s",
+            timetable.len(),
+            matches.len(),
+            deviations.iter().sum::<f64>() / deviations.len().max(1) as f64
+        );
+        errors.extend(
+            write_file(
+                &output.join("schedule-delays.csv"),
+                "schedule delays",
+                |writer| write_matches_csv(&matches, writer),
+            )
+            .err(),
+        );
     }
-}
-
-impl Extractor<Option<i64>> for &Map<String, Value> {
-    type Error = AnalysisError;
-    fn extract(&self, field_name: &str) -> Result<Option<i64>, Self::Error> {
-        match self.get(field_name) {
-            Some(property) => match property {
-                Value::Number(value) => Ok(value.as_i64()),
-                Value::Null => Ok(None),
-                _ => Err(AnalysisError::IncorrectValueType(
-                    "Option<i64>".to_string(),
-                    format!("{property:?}"),
-                    type_name_of_val(property).to_string(),
-                )),
-            },
-            None => Ok(None),
-        }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
     }
 }
 
-impl TryFrom<Content> for Train {
-    type Error = AnalysisError;
-
-    fn try_from(value: Content) -> Result<Self, Self::Error> {
-        match value {
-            Content::TrajectorySchematic(raw_train) => match raw_train {
-                GeoJson::Feature(feature) => match &feature.properties {
-                    Some(properties) => Ok(Self {
-                        delay: properties.extract("delay")?,
-                        has_journey: properties.get("has_journey").map_or(false, |v| match v {
-                            Value::Bool(b) => *b,
-                            _ => false,
-                        }),
-                        has_realtime: properties.get("has_realtime").map_or(false, |v| match v {
-                            Value::Bool(b) => *b,
-                            _ => false,
-                        }),
-                        has_realtime_journey: properties.get("has_realtime_journey").map_or(
-                            false,
-                            |v| match v {
-                                Value::Bool(b) => *b,
-                                _ => false,
-                            },
-                        ),
-                        line: properties
-                            .get("line")
-                            .filter(|l| !l.is_null())
-                            .map(|l| l.to_owned().try_into())
-                            .transpose()?,
-                        operator_provides_realtime_journey: properties
-                            .extract("operator_provides_realtime_journey")?,
-                        original_line: properties.extract("original_line")?,
-                        original_rake: properties.extract("original_rake")?,
-                        original_train_number: 0,
-                        position_correction: 0,
-                        rake: properties.extract("rake")?,
-                        raw_coordinates: properties
-                            .get("raw_coordinates")
-                            .map(|x| x.clone().try_into().unwrap()),
-                        ride_state: properties.extract("ride_state")?,
-                        // route_identfier: properties.extract("route_identfier")?,
-                        state: properties.extract("state")?,
-                        tenant: properties.extract("tenant")?,
-                        train_id: properties.extract("train_id")?,
-                        train_number: properties.extract("train_number")?,
-                        transmitting_vehicle: properties.extract("transmitting_vehicle")?,
-                        vehicle_number: properties.extract("vehicle_number")?,
-                    }),
-                    None => Err(AnalysisError::IncorrectType(
-                        "Properties".to_string(),
-                        type_name_of_val(&feature.properties).to_string(),
-                    )),
-                },
-                _ => Err(AnalysisError::IncorrectType(
-                    "Feature".to_string(),
-                    type_name_of_val(&raw_train).to_string(),
-                )),
-            },
-            _ => Err(AnalysisError::IncorrectType(
-                "TrajectorySchematic".to_string(),
-                type_name_of_val(&value).to_string(),
-            )),
-        }
+fn validate(input: &InputArgs) -> Result<(), String> {
+    let recording = input.load()?;
+    let records: usize = recording.trains.values().map(|v| v.records.len()).sum();
+    println!(
+        "{records} records of {} vehicles, {} stations, {} incidents",
+        recording.trains.len(),
+        recording.stations.len(),
+        recording.incidents.len()
+    );
+    if recording.errors.is_empty() {
+        println!("no errors");
+        Ok(())
+    } else {
+        Err(recording.errors.to_string())
     }
 }
 
-#[derive(Debug)]
-struct Counter<T>(HashMap<T, usize>);
-
-impl<T> Counter<T> {
-    fn new() -> Self {
-        Self(HashMap::new())
-    }
-
-    fn insert(&mut self, k: T) -> Option<usize>
-    where
-        T: Eq + Hash,
-    {
-        match self.0.get_mut(&k) {
-            Some(v) => {
-                *v += 1;
-                Some(*v)
-            }
-            None => self.0.insert(k, 1),
-        }
+fn run_backtest(
+    input: &InputArgs,
+    horizons: &[f64],
+    exclude_incidents: bool,
+) -> Result<(), String> {
+    let recording = input.load()?;
+    let trains = if exclude_incidents {
+        let (_, regular) = recording.incidents.split(&recording.trains);
+        regular
+    } else {
+        recording.trains
+    };
+    for result in backtest(&trains, &PersistencePredictor, horizons) {
+        println!("{result}");
     }
+    Ok(())
 }
 
-impl<T> Deref for Counter<T> {
-    type Target = HashMap<T, usize>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
+fn track(input: &InputArgs, horizons: &[f64]) -> Result<(), String> {
+    let recording = input.load()?;
+    for result in tracker::evaluate(&recording.trains, &TrackerConfig::default(), horizons) {
+        println!("{result}");
     }
-}
-
-#[derive(Debug)]
-enum ColorConversionError {
-    WrongBeginning,
-    ParseInt(std::num::ParseIntError),
-}
-
-impl Display for ColorConversionError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ColorConversionError::WrongBeginning => write!(f, "does not start with '#'"),
-            ColorConversionError::ParseInt(err) => write!(f, "{err:?}"),
-        }
+    Ok(())
+}
+
+fn board(input: &InputArgs, station: &str, at: f64, limit: usize) -> Result<(), String> {
+    let recording = input.load()?;
+    let Some(station) = recording
+        .stations
+        .iter()
+        .find(|s| s.name.eq_ignore_ascii_case(station))
+        .map(|s| s.name.clone())
+    else {
+        return Err(format!("unknown station '{station}'"));
+    };
+
+    // The routes and travel times are learned from the records before the time of the
+    // prediction only, as they would be live, and the vehicles are replayed up to it.
+    let config = PredictionConfig::default();
+    let oldest = at - config.history * 1000.0;
+    let mut live = LivePredictions::new(config);
+    let (past, _) = recording.trains.partition(|record| record.timestamp < at);
+    live.learn(&past, &recording.stations);
+    let mut records: Vec<&Record> = recording
+        .trains
+        .values()
+        .flat_map(|vehicle| &vehicle.records)
+        .filter(|record| (oldest..=at).contains(&record.timestamp))
+        .collect();
+    records.sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+    for record in records {
+        live.insert_record(record.clone());
     }
-}
-
-impl Error for ColorConversionError {}
-
-impl From<std::num::ParseIntError> for ColorConversionError {
-    fn from(value: std::num::ParseIntError) -> Self {
-        ColorConversionError::ParseInt(value)
+    live.advance(at);
+
+    println!(
+        "Departures from {station} at {}",
+        local_time(at).format("%Y-%m-%d %H:%M:%S")
+    );
+    let departures = live.departures(&station, limit);
+    if departures.is_empty() {
+        println!("no departures predicted");
     }
-}
-
-fn try_color_from_string(s: String) -> Result<Color, ColorConversionError> {
-    if !s.starts_with('#') {
-        return Err(ColorConversionError::WrongBeginning);
-    }
-
-    let r: u8 = u8::from_str_radix(&s[1..3], 16)?;
-    let g: u8 = u8::from_str_radix(&s[3..5], 16)?;
-    let b: u8 = u8::from_str_radix(&s[5..7], 16)?;
-
-    Ok(Color {
-        r: f32::from(r) / 255.0,
-        g: f32::from(g) / 255.0,
-        b: f32::from(b) / 255.0,
-        a: 1.0,
-    })
-}
-
-fn map(value: f32, a_min: f32, a_max: f32, b_min: f32, b_max: f32) -> f32 {
-    (value - a_min) / (a_max - a_min) * (b_max - b_min) + b_min
-}
-
-enum State {
-    Driving,
-    Boarding,
-}
-
-impl From<String> for State {
-    fn from(value: String) -> Self {
-        if value == "DRIVING" {
-            Self::Driving
-        } else {
-            Self::Boarding
-        }
-    }
-}
-
-#[allow(unused)]
-struct Record {
-    timestamp: String,
-    position: Coordinate,
-    line: String,
-    line_color: Color,
-    state: State,
-    vehicle_number: String,
-    train_number: i64,
-}
-
-impl Record {
-    fn render(&self) {
-        draw_circle(
-            map(
-                self.position.longitude as f32,
-                11.0,
-                12.0,
-                0.0,
-                screen_width(),
-            ),
-            map(
-                self.position.latitude as f32,
-                47.5,
-                48.5,
-                screen_height(),
-                0.0,
-            ),
-            5.0,
-            self.line_color,
+    for departure in departures {
+        let delay = departure
+            .delay
+            .map(|delay| format!("{:+.0} min", delay / 60.0))
+            .unwrap_or_default();
+        println!(
+            "{}  {:<4} {:>6}  {:<30} {:>8}  {}{}",
+            local_time(departure.departure).format("%H:%M"),
+            departure.line,
+            departure.train_number,
+            departure.destination,
+            delay,
+            departure.confidence,
+            if departure.boarding { ", boarding" } else { "" },
         );
     }
-}
-
-impl TryFrom<ResponseMessage> for Record {
-    type Error = AnalysisError;
-
-    fn try_from(value: ResponseMessage) -> Result<Self, Self::Error> {
-        match value.content {
-            Content::TrajectorySchematic(trajectory) => match trajectory {
-                GeoJson::Feature(feature) => match feature.properties {
-                    Some(properties) => Ok(Self {
-                        timestamp: value.timestamp.to_string(),
-                        position: properties
-                            .get("raw_coordinates")
-                            .map(std::borrow::ToOwned::to_owned)
-                            .ok_or(AnalysisError::MissingProperty(
-                                "raw_coordinates".to_string(),
-                            ))?
-                            .try_into()?,
-                        line: properties
-                            .get("line")
-                            .map(std::borrow::ToOwned::to_owned)
-                            .ok_or(AnalysisError::MissingProperty("line".to_string()))?
-                            .as_object()
-                            .ok_or(AnalysisError::IncorrectType(
-                                "object".to_string(),
-                                "unknown".to_string(),
-                            ))?
-                            .get("name")
-                            .ok_or(AnalysisError::MissingProperty("name".to_string()))?
-                            .as_str()
-                            .ok_or(AnalysisError::IncorrectType(
-                                "string".to_string(),
-                                "unknown".to_string(),
-                            ))?
-                            .to_string(),
-                        line_color: try_color_from_string(
-                            properties
-                                .get("line")
-                                .map(std::borrow::ToOwned::to_owned)
-                                .ok_or(AnalysisError::MissingProperty("line".to_string()))?
-                                .as_object()
-                                .ok_or(AnalysisError::IncorrectType(
-                                    "object".to_string(),
-                                    "unknown".to_string(),
-                                ))?
-                                .get("color")
-                                .ok_or(AnalysisError::MissingProperty("color".to_string()))?
-                                .as_str()
-                                .ok_or(AnalysisError::IncorrectType(
-                                    "string".to_string(),
-                                    "unknown".to_string(),
-                                ))?
-                                .to_string(),
-                        )
-                        .map_err(|cce| {
-                            AnalysisError::IncorrectType("Color".to_string(), cce.to_string())
-                        })?,
-                        state: properties
-                            .get("state")
-                            .map(std::borrow::ToOwned::to_owned)
-                            .ok_or(AnalysisError::MissingProperty("state".to_string()))?
-                            .as_str()
-                            .ok_or(AnalysisError::MissingProperty("state".to_string()))?
-                            .to_string()
-                            .into(),
-                        vehicle_number: properties
-                            .get("vehicle_number")
-                            .map(std::borrow::ToOwned::to_owned)
-                            .ok_or(AnalysisError::MissingProperty("vehicle_number".to_string()))?
-                            .as_str()
-                            .ok_or(AnalysisError::MissingProperty("vehicle_number".to_string()))?
-                            .to_string(),
-                        train_number: properties
-                            .get("train_number")
-                            .map(std::borrow::ToOwned::to_owned)
-                            .ok_or(AnalysisError::MissingProperty("train_number".to_string()))?
-                            .as_i64()
-                            .ok_or(AnalysisError::IncorrectType(
-                                "i64".to_string(),
-                                "unknown".to_string(),
-                            ))?,
-                    }),
-                    None => Err(AnalysisError::MissingProperty("properties".to_string())),
-                },
-                _ => Err(AnalysisError::IncorrectType(
-                    "GeoJson::Feature".to_string(),
-                    type_name_of_val(&trajectory).to_string(),
-                )),
-            },
-            Content::DeletedVehiclesSchematic(_) => todo!(),
-            _ => Err(AnalysisError::IncorrectType(
-                "Content::TrajectorySchematic".to_string(),
-                type_name_of_val(&value.content).to_string(),
-            )),
-        }
-    }
-}
-
-struct Vehicle {
-    number: String,
-    records: Vec<Record>,
-}
-
-impl Vehicle {
-    fn from_record(r: Record) -> Self {
-        Self {
-            number: r.vehicle_number.clone(),
-            records: vec![r],
-        }
-    }
-
-    fn update(&mut self, r: Record) {
-        self.records.push(r);
-    }
-
-    fn render(&self, idx: usize) {
-        if let Some(record) = self.records.get(idx) {
-            record.render();
-        }
-    }
-}
-
-struct Trains(HashMap<String, Vehicle>);
-
-impl Trains {
-    fn new() -> Self {
-        Self(HashMap::new())
-    }
-
-    fn insert(&mut self, r: Record) {
-        match self.get_mut(&r.vehicle_number) {
-            Some(v) => v.update(r),
-            None => {
-                self.0
-                    .insert(r.vehicle_number.clone(), Vehicle::from_record(r));
-            }
-        }
-    }
-
-    fn render(&self, idx: usize) {
-        for t in self.0.values() {
-            t.render(idx);
-        }
-    }
-}
-
-impl Deref for Trains {
-    type Target = HashMap<String, Vehicle>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl DerefMut for Trains {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
-}
-
-#[macroquad::main("BasicShapes")]
-async fn main() {
-    let reader =
-        BufReader::new(File::open("./s-bahn-munich-live-map.jsonl").expect("Cannot open file.txt"));
-    let mut trains: usize = 0;
-    let mut delays: Counter<Option<String>> = Counter::new();
-    let mut states: Counter<Option<String>> = Counter::new();
-    let mut ride_states: Counter<Option<String>> = Counter::new();
-    let mut original_lines: Counter<Option<String>> = Counter::new();
-    let mut lines: Counter<Option<Line>> = Counter::new();
-    let mut persistent_trains = Trains::new();
-
-    for line in reader.lines() {
-        match line {
-            Ok(line) => {
-                if !line.is_empty() {
-                    match serde_json::from_str::<ResponseMessage>(&line) {
-                        Ok(m) => {
-                            match m.content {
-                                Content::TrajectorySchematic(_) => {
-                                    match <Content as TryInto<Train>>::try_into(m.content.clone()) {
-                                        Ok(train) => {
-                                            trains += 1;
-                                            delays.insert(train.delay.clone());
-                                            states.insert(train.state.clone());
-                                            ride_states.insert(train.ride_state.clone());
-                                            original_lines.insert(train.original_line.clone());
-                                            lines.insert(train.line.clone());
-                                            // persistent_trains.insert(train.clone());
-                                        }
-                                        Err(err) => {
-                                            eprintln!("should be train: {err}\n\t{line:#?}");
-                                        }
-                                    };
-                                    match <ResponseMessage as TryInto<Record>>::try_into(m.clone()) {
-                                        Ok(record) => {
-                                            persistent_trains.insert(record);
-                                        }
-                                        Err(_err) => {
-                                            // eprintln!("should be record: {}\n\t{:#?}", err, line)
-                                        }
-                                    }
-                                    // break;
-                                }
-                                // Content::SbmNewsTicker(news) => {
-                                //     println!("{:#?}", news);
-                                // },
-                                Content::StationSchematic(_station) => {}
-                                _ => {}
-                            }
-                        }
-                        Err(err) => eprintln!("{err}, unable to parse: '{line}'"),
-                    }
-                }
-            }
-            Err(err) => eprintln!("ERROR: {err}"),
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn render(
+    input: &InputArgs,
+    output: &Path,
+    at: Option<f64>,
+    width: u32,
+    height: u32,
+    speed: f64,
+    fps: u32,
+    no_timestamp: bool,
+) -> Result<(), String> {
+    let recording = input.load()?;
+    let fps = fps.max(1);
+    let replay = Replay::new(
+        &recording.trains,
+        &recording.stations,
+        ReplayConfig {
+            width,
+            height,
+            step: speed / f64::from(fps),
+            frame_duration: 1000 / fps,
+            timestamp: !no_timestamp,
+            ..ReplayConfig::default()
+        },
+    );
+    let to_error = |err| format!("unable to write {}: {err}", output.display());
+    let extension = output
+        .extension()
+        .map(|extension| extension.to_ascii_lowercase());
+    if extension
+        .as_ref()
+        .is_some_and(|extension| extension == "png")
+    {
+        let Some(at) = at else {
+            return Err("a single image needs the time '--at'".to_string());
         };
+        return replay.render(at).save_png(output).map_err(to_error);
     }
-    println!("trains: {trains}");
-    println!("delays: {delays:#?}");
-    println!("states: {states:#?}");
-    println!("ride_states: {ride_states:#?}");
-    println!("original_line: {original_lines:#?}");
-    println!("line: {lines:#?}");
-
-    // Render
-    let mut i = 0;
-    loop {
-        clear_background(Color::from_hex(0x009E_9E9E));
 
-        persistent_trains.render(i);
-
-        next_frame().await;
-        thread::sleep(Duration::from_millis(20));
-        i += 1;
-        if i >= 995 {
-            i = 0;
+    let Some((from, to)) = replay.time_range() else {
+        return Err("no trains to render".to_string());
+    };
+    let frames = if extension.is_some_and(|extension| extension == "gif") {
+        let file = File::create(output).map_err(|err| to_error(err.into()))?;
+        replay
+            .write_gif(BufWriter::new(file), from, to)
+            .map_err(to_error)?
+    } else {
+        let frames = replay.write_frames(output, from, to).map_err(to_error)?;
+        println!(
+            "ffmpeg -framerate {fps} -i {}/frame-%05d.png -pix_fmt yuv420p replay.mp4",
+            output.display()
+        );
+        frames
+    };
+    println!("rendered {frames} frames into {}", output.display());
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match &cli.command {
+        Command::Stats {
+            input,
+            format,
+            output,
+        } => stats(input, *format, output.as_deref()),
+        Command::View { input, speed } => input.load().map(|recording| {
+            let viewer = Viewer::new(
+                recording.trains,
+                &recording.stations,
+                ViewerConfig {
+                    speed: *speed,
+                    ..ViewerConfig::default()
+                },
+            );
+            macroquad::Window::new("analysis", viewer.run());
+        }),
+        Command::Export {
+            input,
+            output,
+            gtfs,
+            exclude_incidents,
+        } => export(input, output, gtfs.as_deref(), *exclude_incidents),
+        Command::Validate(input) => validate(input),
+        Command::Backtest {
+            input,
+            horizons,
+            exclude_incidents,
+        } => run_backtest(input, horizons, *exclude_incidents),
+        Command::Track { input, horizons } => track(input, horizons),
+        Command::Board {
+            input,
+            station,
+            at,
+            limit,
+        } => board(input, station, *at, *limit),
+        Command::Render {
+            input,
+            output,
+            at,
+            width,
+            height,
+            speed,
+            fps,
+            no_timestamp,
+        } => render(
+            input,
+            output,
+            *at,
+            *width,
+            *height,
+            *speed,
+            *fps,
+            *no_timestamp,
+        ),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
         }
     }
 }
@@ -0,0 +1,22 @@
+use scraper::columnar;
+
+/// Converts a JSONL recording into Parquet tables, by default the recording of the scraper into
+/// the directory `s-bahn-munich-live-map`.
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let input = args
+        .next()
+        .unwrap_or("s-bahn-munich-live-map.jsonl".to_string());
+    let output = args.next().unwrap_or("s-bahn-munich-live-map".to_string());
+
+    match columnar::convert(&input, &output) {
+        Ok(summary) => println!(
+            "converted {input} into {output}: {} positions, {} stations, {} news, {} skipped",
+            summary.positions, summary.stations, summary.news, summary.skipped
+        ),
+        Err(err) => {
+            eprintln!("unable to convert {input}: {err}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use clap::Parser;
+use dotenvy::dotenv;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+
+use scraper::dashboard::Dashboard;
+use scraper::source::{self, Source};
+
+/// Interval in which the dashboard is drawn again.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Parser, Debug)]
+#[command(about = "Shows the live feed of the S-Bahn Munich in the terminal")]
+struct Cli {
+    /// Replays this recording instead of connecting to the websocket
+    #[arg(long, conflicts_with = "follow")]
+    recording: Option<PathBuf>,
+    /// Speed of the replay, e.g. 2 replays twice as fast
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+    /// Follows the recording the scraper is writing instead of connecting to the websocket
+    #[arg(long)]
+    follow: Option<PathBuf>,
+    /// Starts with the map instead of the list of trains
+    #[arg(long)]
+    map: bool,
+}
+
+fn main() -> ExitCode {
+    let _ = dotenv();
+    let cli = Cli::parse();
+    let source = match (cli.recording, cli.follow) {
+        (Some(path), _) => Source::Recording {
+            path,
+            speed: Some(cli.speed),
+        },
+        (None, Some(path)) => Source::Follow(path),
+        (None, None) => match std::env::var("API_KEY") {
+            Ok(api_key) => Source::Live(source::url(&api_key)),
+            Err(_) => {
+                eprintln!("expects an API key, a recording or a recording to follow");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    let mut dashboard = Dashboard::new();
+    if cli.map {
+        dashboard.toggle_map();
+    }
+    let dashboard = Arc::new(Mutex::new(dashboard));
+    let feed = Arc::clone(&dashboard);
+    // The thread ends with the process, a replay may end before.
+    thread::spawn(move || {
+        source.for_each(|message| {
+            feed.lock()
+                .expect("dashboard is not poisoned")
+                .update(message);
+            true
+        })
+    });
+
+    let mut terminal = ratatui::init();
+    let result = loop {
+        let drawn = terminal.draw(|frame| {
+            dashboard
+                .lock()
+                .expect("dashboard is not poisoned")
+                .render(frame)
+        });
+        if let Err(err) = drawn {
+            break Err(err);
+        }
+        match event::poll(REFRESH_INTERVAL).and_then(|ready| ready.then(event::read).transpose()) {
+            Ok(Some(Event::Key(key))) if key.kind == KeyEventKind::Press => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => break Ok(()),
+                KeyCode::Char('m') => dashboard
+                    .lock()
+                    .expect("dashboard is not poisoned")
+                    .toggle_map(),
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(err) => break Err(err),
+        }
+    };
+    ratatui::restore();
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("unable to draw the dashboard: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
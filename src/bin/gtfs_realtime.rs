@@ -0,0 +1,52 @@
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use dotenvy::dotenv;
+
+use scraper::gtfs_realtime::{self, GtfsRealtimeFeed};
+use scraper::source::{self, Source};
+
+/// Serves the vehicles as GTFS-Realtime feeds, either live or replayed from the recording given
+/// as the first argument, optionally followed by the speed of the replay.
+fn main() -> ExitCode {
+    let _ = dotenv();
+    let mut args = std::env::args().skip(1);
+    let source = match args.next() {
+        Some(path) => Source::Recording {
+            path: path.into(),
+            speed: Some(
+                args.next()
+                    .map(|speed| speed.parse().expect("speed must be a number"))
+                    .unwrap_or(1.0),
+            ),
+        },
+        None => Source::Live(source::url(
+            &std::env::var("API_KEY").expect("expects an API key"),
+        )),
+    };
+    let address = std::env::var("GTFS_RT_ADDRESS").unwrap_or("127.0.0.1:8080".to_string());
+
+    // Binds before spawning, so an address in use stops the server instead of a thread.
+    let server = match tiny_http::Server::http(&address) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("unable to serve on {address}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let feed = Arc::new(Mutex::new(GtfsRealtimeFeed::new()));
+    let server_feed = Arc::clone(&feed);
+    println!("Serving on http://{address}/vehicle-positions and http://{address}/trip-updates");
+    thread::spawn(move || gtfs_realtime::serve(server_feed, server));
+
+    if let Err(err) = source.for_each(|message| {
+        feed.lock().expect("feed is not poisoned").update(message);
+        true
+    }) {
+        eprintln!("unable to read the messages: {err}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
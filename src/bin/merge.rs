@@ -0,0 +1,60 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use scraper::merge::{self, MergeConfig};
+use scraper::records::local_time;
+use scraper::time_index::TimeIndex;
+
+fn print_gaps(gaps: &[std::ops::Range<f64>]) {
+    for gap in gaps {
+        println!(
+            "\t{} - {} ({:.0}s)",
+            local_time(gap.start).to_rfc3339(),
+            local_time(gap.end).to_rfc3339(),
+            (gap.end - gap.start) / 1000.0
+        );
+    }
+}
+
+/// Merges the recordings given after the output path into one deduplicated, time-ordered
+/// recording and indexes it.
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [output, inputs @ ..] = args.as_slice() else {
+        eprintln!("usage: merge <output> <input>...");
+        std::process::exit(2);
+    };
+    if inputs.is_empty() {
+        eprintln!("usage: merge <output> <input>...");
+        std::process::exit(2);
+    }
+    if inputs.contains(output) {
+        eprintln!("the output must not be one of the inputs");
+        std::process::exit(2);
+    }
+
+    let writer = BufWriter::new(File::create(output).expect("needs access to the output"));
+    let report = merge::merge(inputs, &MergeConfig::default(), writer)
+        .expect("should be able to merge the recordings");
+    for input in &report.inputs {
+        println!(
+            "{}: {} messages, {} invalid lines, {} gaps",
+            input.path.display(),
+            input.messages,
+            input.invalid,
+            input.gaps.len()
+        );
+    }
+    println!(
+        "{output}: {} messages, {} duplicates, {} late, {} gaps in all inputs",
+        report.messages,
+        report.duplicates,
+        report.late,
+        report.gaps.len()
+    );
+    print_gaps(&report.gaps);
+
+    if let Err(err) = TimeIndex::open(output) {
+        eprintln!("unable to index {output}: {err}");
+    }
+}
@@ -0,0 +1,97 @@
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use clap::Parser;
+use dotenvy::dotenv;
+
+use scraper::predictions::{self, LivePredictions, PredictionConfig};
+use scraper::recording::{Recording, RecordingFilter};
+use scraper::source::{self, Source};
+
+#[derive(Parser, Debug)]
+#[command(about = "Serves predicted positions and arrivals of the S-Bahn Munich")]
+struct Cli {
+    /// Replays this recording instead of connecting to the websocket
+    #[arg(long)]
+    recording: Option<PathBuf>,
+    /// Speed of the replay, e.g. 2 replays twice as fast
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+    /// Recordings to learn the routes, travel times and delay propagation from
+    #[arg(long)]
+    history: Vec<PathBuf>,
+    /// Address of the HTTP API
+    #[arg(long, env = "PREDICTIONS_ADDRESS", default_value = "127.0.0.1:8090")]
+    address: String,
+    /// Address of the websocket
+    #[arg(long, env = "PREDICTIONS_WS_ADDRESS", default_value = "127.0.0.1:8091")]
+    websocket_address: String,
+}
+
+fn main() -> ExitCode {
+    let _ = dotenv();
+    let cli = Cli::parse();
+    let source = match cli.recording {
+        Some(path) => Source::Recording {
+            path,
+            speed: Some(cli.speed),
+        },
+        None => match std::env::var("API_KEY") {
+            Ok(api_key) => Source::Live(source::url(&api_key)),
+            Err(_) => {
+                eprintln!("expects an API key or a recording");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    let mut live = LivePredictions::new(PredictionConfig::default());
+    if !cli.history.is_empty() {
+        match Recording::load(&cli.history, &RecordingFilter::default()) {
+            Ok(history) => live.learn(&history.trains, &history.stations),
+            Err(err) => {
+                eprintln!("unable to load the history: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let live = Arc::new(Mutex::new(live));
+
+    // Binds before spawning, so an address in use stops the server instead of a thread.
+    let server = match tiny_http::Server::http(&cli.address) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("unable to serve on {}: {err}", cli.address);
+            return ExitCode::FAILURE;
+        }
+    };
+    let listener = match TcpListener::bind(&cli.websocket_address) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("unable to serve on {}: {err}", cli.websocket_address);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!(
+        "Serving on http://{}/vehicles, http://{}/arrivals, http://{}/departures and ws://{}",
+        cli.address, cli.address, cli.address, cli.websocket_address
+    );
+    let http = Arc::clone(&live);
+    thread::spawn(move || predictions::serve_http(http, server));
+    let websocket = Arc::clone(&live);
+    thread::spawn(move || predictions::serve_websocket(websocket, listener));
+
+    if let Err(err) = source.for_each(|message| {
+        live.lock()
+            .expect("predictions are not poisoned")
+            .update(message);
+        true
+    }) {
+        eprintln!("unable to read the messages: {err}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
@@ -1,57 +1,82 @@
 use std::fs;
 use std::io::prelude::*;
-use std::time::{Duration, SystemTime};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use dotenvy::dotenv;
 
+use scraper::database::Database;
+use scraper::response_messages::ResponseMessage;
+use scraper::source;
+use scraper::time_index::{self, TimeIndexWriter};
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Valid time")
+        .as_millis() as f64
+}
+
+/// Records the messages as raw JSONL into `JSONL_PATH`, which is disabled if set to an empty
+/// value, together with its time index, and parsed into the SQLite database at `SQLITE_PATH` if
+/// it is set.
 fn main() {
     let _ = dotenv();
     let api_key = std::env::var("API_KEY").expect("expects an API key");
-    let url: url::Url = format!("wss://api.geops.io/realtime-ws/v1/?key={api_key}").parse().unwrap();
+    let url = source::url(&api_key);
 
-    let mut out_file = fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .read(false)
-        .open("s-bahn-munich-live-map.jsonl")
-        .expect("needs access to write the contents to the file");
+    let jsonl_path =
+        std::env::var("JSONL_PATH").unwrap_or("s-bahn-munich-live-map.jsonl".to_string());
+    let mut out_file = (!jsonl_path.is_empty()).then(|| {
+        let index = TimeIndexWriter::open(&jsonl_path).expect("needs access to the time index");
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(false)
+            .open(&jsonl_path)
+            .expect("needs access to write the contents to the file");
+        let offset = file.metadata().map_or(0, |m| m.len());
+        (file, index, offset)
+    });
+    let mut database = std::env::var("SQLITE_PATH")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .map(|path| Database::open(path).expect("needs access to the database"));
 
     println!("URL: {url}");
 
     loop {
-        let (mut socket, _) = tungstenite::connect(url.clone()).expect("should be able to connect");
-        let _ = socket.send("BBOX 1152072 6048052 1433666 6205578 5 tenant=sbm".into());
-        let _ = socket.send("BUFFER 100 100".into());
-        let _ = socket.send("GET extra_geoms".into());
-        let _ = socket.send("SUB extra_geoms".into());
-        let _ = socket.send("GET healthcheck".into());
-        let _ = socket.send("SUB healthcheck".into());
-        let _ = socket.send("GET sbm_newsticker".into());
-        let _ = socket.send("SUB sbm_newsticker".into());
-        let _ = socket.send("GET station_schematic".into());
-        let _ = socket.send("SUB station_schematic".into());
-        let _ = socket.send("GET deleted_vehicles_schematic".into());
-        let _ = socket.send("SUB deleted_vehicles_schematic".into());
-        let _ = socket.send("GET trajectory_schematic".into());
-        let _ = socket.send("SUB trajectory_schematic".into());
-        let _ = socket.send("GET station".into());
-        let _ = socket.send("SUB station".into());
-        let _ = socket.send("GET deleted_vehicles".into());
-        let _ = socket.send("SUB deleted_vehicles".into());
-        let _ = socket.send("GET trajectory".into());
-        let _ = socket.send("SUB trajectory".into());
-
-        let _ = socket.send("PING".into());
+        let mut socket = source::connect(&url).expect("should be able to connect");
         let mut last_ping = SystemTime::now();
+        // The database is secondary to the JSONL recording, which keeps running if it fails.
+        if let Some(database) = &mut database {
+            if let Err(err) = database.start_session(now()) {
+                eprintln!("unable to start a session in the database: {err}");
+            }
+        }
 
         loop {
             match socket.read() {
                 Ok(msg) => {
                     match msg {
                         tungstenite::Message::Text(text) => {
-                            writeln!(out_file, "{text}")
-                                .expect("writing message to file without error");
+                            if let Some((out_file, index, offset)) = &mut out_file {
+                                if let Some(timestamp) = time_index::timestamp(&text) {
+                                    index
+                                        .record(timestamp, *offset)
+                                        .expect("writing the time index without error");
+                                }
+                                writeln!(out_file, "{text}")
+                                    .expect("writing message to file without error");
+                                *offset += text.len() as u64 + 1;
+                            }
+                            if let Some(database) = &mut database {
+                                if let Ok(message) = serde_json::from_str::<ResponseMessage>(&text)
+                                {
+                                    if let Err(err) = database.insert(message) {
+                                        eprintln!("unable to write to the database: {err}");
+                                    }
+                                }
+                            }
                         }
                         // tungstenite::Message::Binary(bin) => todo!(),
                         tungstenite::Message::Close(_) => break,
@@ -61,7 +86,7 @@ fn main() {
                         _ => {}
                     }
                     // println!("Received: {}", msg);
-                    if last_ping.elapsed().expect("Valid time") >= Duration::from_secs(10) {
+                    if last_ping.elapsed().expect("Valid time") >= source::PING_INTERVAL {
                         let _ = socket.send("PING".into());
                         last_ping = SystemTime::now();
                     }
@@ -72,5 +97,11 @@ fn main() {
                 }
             }
         }
+
+        if let Some(database) = &mut database {
+            if let Err(err) = database.end_session(now()) {
+                eprintln!("unable to end the session in the database: {err}");
+            }
+        }
     }
 }
@@ -0,0 +1,448 @@
+//! Follows the physical units through the trips they run over a day.
+//!
+//! The feed keys a vehicle by the number of its leading unit, and a unit runs many train numbers
+//! over a day. Coupled units share a trip, which the `rake` of a trajectory lists. The records of
+//! a vehicle are split into trips whenever the train number changes, every unit of the rake is
+//! assigned the trip, and the trips of a unit within a service day form its circulation.
+//!
+//! Consecutive trips of a unit are turnarounds. A late inbound trip delays the outbound one as
+//! soon as the delay exceeds the slack of the layover, which [`propagate`] estimates. Changes of
+//! the rake while a vehicle is observed are reported as coupling and uncoupling. The feed also
+//! sends the `original_rake` a trip is planned to run with, a change into it completes the
+//! planned formation while any other change is an unplanned coupling or uncoupling, e.g. to
+//! replace a broken unit.
+
+use chrono::{Duration, NaiveDate};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::io::{self, Write};
+
+use crate::records::{local_time, Record, State, Trains};
+use crate::segments::SegmentStatistics;
+use crate::stations::Stations;
+
+/// Splits a rake into the numbers of its units in the format of `vehicle_number`, e.g.
+/// `423 002`. Units given as 12 digit UIC numbers like `948004230024` are decoded into their
+/// series and serial number, anything else is kept as it is.
+pub fn decode_rake(rake: &str) -> Vec<String> {
+    rake.split([';', ','])
+        .map(str::trim)
+        .filter(|unit| !unit.is_empty())
+        .map(|unit| {
+            let digits: String = unit.chars().filter(char::is_ascii_digit).collect();
+            if digits.len() == 12 && unit.chars().all(|c| c.is_ascii_digit() || c == ' ') {
+                let series = digits[4..8].trim_start_matches('0');
+                format!("{series} {}", &digits[8..11])
+            } else {
+                unit.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Units of a record, the vehicle itself if it sent no rake.
+fn units(record: &Record) -> Vec<String> {
+    match record.rake.as_deref().map(decode_rake) {
+        Some(units) if !units.is_empty() => units,
+        _ => vec![record.vehicle_number.clone()],
+    }
+}
+
+/// Whether a record runs with the units its trip is planned with, in any order. Unknown without
+/// an `original_rake`.
+fn is_planned_formation(record: &Record) -> Option<bool> {
+    let mut planned = decode_rake(record.original_rake.as_deref()?);
+    if planned.is_empty() {
+        return None;
+    }
+    let mut units = units(record);
+    planned.sort();
+    units.sort();
+    Some(planned == units)
+}
+
+/// Delay in seconds of an outbound trip caused by an inbound trip `inbound_delay` seconds late,
+/// if the layover is scheduled to take `scheduled_layover` seconds and the unit needs at least
+/// `min_turnaround` seconds to turn.
+pub fn propagate(inbound_delay: f64, scheduled_layover: f64, min_turnaround: f64) -> f64 {
+    (inbound_delay - (scheduled_layover - min_turnaround).max(0.0)).max(0.0)
+}
+
+#[derive(Debug, Clone)]
+pub struct CirculationConfig {
+    /// Trips of a unit further apart than this many seconds are not a turnaround, e.g. the unit
+    /// was parked in between.
+    pub max_layover: f64,
+    /// Minimum time in seconds a unit needs between arriving and departing again.
+    pub min_turnaround: f64,
+    /// Hour of the local time at which a service day starts, trips before belong to the day
+    /// before.
+    pub service_day_start: u32,
+}
+
+impl Default for CirculationConfig {
+    fn default() -> Self {
+        Self {
+            max_layover: 60.0 * 60.0,
+            min_turnaround: 3.0 * 60.0,
+            service_day_start: 3,
+        }
+    }
+}
+
+/// The records of a vehicle under one train number.
+#[derive(Debug, Clone)]
+pub struct Trip {
+    pub train_number: i64,
+    pub train_id: String,
+    pub line: String,
+    pub vehicle_number: String,
+    /// Units that were part of the rake at any time of the trip, in the order they appeared.
+    pub units: Vec<String>,
+    /// First and last record in milliseconds since the unix epoch.
+    pub start: f64,
+    pub end: f64,
+    /// First record driving, or the start if the trip never drove.
+    pub departure: f64,
+    pub departure_delay: Option<f64>,
+    /// First record after driving for the last time, or the end.
+    pub arrival: f64,
+    pub arrival_delay: Option<f64>,
+}
+
+impl Trip {
+    fn from_records(records: &[Record]) -> Self {
+        let first = &records[0];
+        let last = &records[records.len() - 1];
+        let mut units: Vec<String> = Vec::new();
+        for record in records {
+            for unit in self::units(record) {
+                if !units.contains(&unit) {
+                    units.push(unit);
+                }
+            }
+        }
+        let departure = records
+            .iter()
+            .find(|r| r.state == State::Driving)
+            .unwrap_or(first);
+        let arrival = records
+            .iter()
+            .rposition(|r| r.state == State::Driving)
+            .and_then(|index| records.get(index + 1))
+            .unwrap_or(last);
+        Self {
+            train_number: first.train_number,
+            train_id: first.train_id.clone(),
+            line: first.line.clone(),
+            vehicle_number: first.vehicle_number.clone(),
+            units,
+            start: first.timestamp,
+            end: last.timestamp,
+            departure: departure.timestamp,
+            departure_delay: departure.delay,
+            arrival: arrival.timestamp,
+            arrival_delay: arrival.delay,
+        }
+    }
+}
+
+/// The trips of a unit within a service day, ordered by time.
+#[derive(Debug, Clone)]
+pub struct Circulation {
+    pub unit: String,
+    pub day: NaiveDate,
+    pub trips: Vec<Trip>,
+}
+
+/// Two consecutive trips of a unit.
+#[derive(Debug, Clone, Copy)]
+pub struct Turnaround<'a> {
+    pub unit: &'a str,
+    pub day: NaiveDate,
+    pub inbound: &'a Trip,
+    pub outbound: &'a Trip,
+}
+
+impl Turnaround<'_> {
+    /// Observed time in seconds between arriving and departing.
+    pub fn layover(&self) -> f64 {
+        (self.outbound.departure - self.inbound.arrival) / 1000.0
+    }
+
+    /// Time in seconds between the scheduled arrival and departure, derived from the delays.
+    pub fn scheduled_layover(&self) -> Option<f64> {
+        Some(self.layover() - self.outbound.departure_delay? + self.inbound.arrival_delay?)
+    }
+
+    /// Delay in seconds the outbound trip is expected to depart with because of the inbound one.
+    pub fn propagated_delay(&self, min_turnaround: f64) -> Option<f64> {
+        Some(propagate(
+            self.inbound.arrival_delay?,
+            self.scheduled_layover()?,
+            min_turnaround,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RakeChangeKind {
+    Coupling,
+    Uncoupling,
+}
+
+impl Display for RakeChangeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RakeChangeKind::Coupling => write!(f, "coupling"),
+            RakeChangeKind::Uncoupling => write!(f, "uncoupling"),
+        }
+    }
+}
+
+/// Units joining or leaving the rake of a vehicle.
+#[derive(Debug, Clone)]
+pub struct RakeChange {
+    /// First record with the new rake.
+    pub timestamp: f64,
+    pub vehicle_number: String,
+    pub train_number: i64,
+    pub kind: RakeChangeKind,
+    pub units: Vec<String>,
+    /// Whether the new rake is the planned formation of the trip, none if the feed sent no
+    /// planned rake.
+    pub planned: Option<bool>,
+    /// Station the vehicle was at, if any.
+    pub station: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Circulations {
+    config: CirculationConfig,
+    circulations: BTreeMap<(String, NaiveDate), Circulation>,
+    rake_changes: Vec<RakeChange>,
+}
+
+impl Circulations {
+    pub fn from_trains(trains: &Trains, stations: &Stations, config: CirculationConfig) -> Self {
+        let mut circulations: BTreeMap<(String, NaiveDate), Circulation> = BTreeMap::new();
+        let mut rake_changes = Vec::new();
+        let day_start = Duration::hours(i64::from(config.service_day_start));
+
+        for vehicle in trains.values() {
+            let records = &vehicle.records;
+            for trip in records.chunk_by(|a, b| a.train_number == b.train_number) {
+                let trip = Trip::from_records(trip);
+                let day = (local_time(trip.start) - day_start).date_naive();
+                for unit in &trip.units {
+                    circulations
+                        .entry((unit.clone(), day))
+                        .or_insert_with(|| Circulation {
+                            unit: unit.clone(),
+                            day,
+                            trips: Vec::new(),
+                        })
+                        .trips
+                        .push(trip.clone());
+                }
+            }
+
+            for pair in records.windows(2) {
+                let (previous, record) = (&pair[0], &pair[1]);
+                if previous.rake.is_none() || record.rake.is_none() || previous.rake == record.rake
+                {
+                    continue;
+                }
+                let (before, after) = (units(previous), units(record));
+                let station = stations
+                    .nearest(&record.position, SegmentStatistics::MAX_STATION_DISTANCE)
+                    .map(|s| s.name.clone());
+                let planned = is_planned_formation(record);
+                let changes = [
+                    (RakeChangeKind::Coupling, &after, &before),
+                    (RakeChangeKind::Uncoupling, &before, &after),
+                ];
+                for (kind, from, without) in changes {
+                    let units: Vec<String> = from
+                        .iter()
+                        .filter(|unit| !without.contains(unit))
+                        .cloned()
+                        .collect();
+                    if !units.is_empty() {
+                        rake_changes.push(RakeChange {
+                            timestamp: record.timestamp,
+                            vehicle_number: record.vehicle_number.clone(),
+                            train_number: record.train_number,
+                            kind,
+                            units,
+                            planned,
+                            station: station.clone(),
+                        });
+                    }
+                }
+            }
+        }
+
+        for circulation in circulations.values_mut() {
+            circulation
+                .trips
+                .sort_by(|a, b| a.start.total_cmp(&b.start));
+        }
+        rake_changes.sort_by(|a, b| {
+            a.timestamp
+                .total_cmp(&b.timestamp)
+                .then(a.vehicle_number.cmp(&b.vehicle_number))
+        });
+
+        Self {
+            config,
+            circulations,
+            rake_changes,
+        }
+    }
+
+    pub fn config(&self) -> &CirculationConfig {
+        &self.config
+    }
+
+    /// The circulation of `unit` on the service `day`.
+    pub fn get(&self, unit: &str, day: NaiveDate) -> Option<&Circulation> {
+        self.circulations.get(&(unit.to_string(), day))
+    }
+
+    /// All circulations ordered by unit and day.
+    pub fn iter(&self) -> impl Iterator<Item = &Circulation> {
+        self.circulations.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.circulations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.circulations.is_empty()
+    }
+
+    /// Couplings and uncouplings ordered by time.
+    pub fn rake_changes(&self) -> &[RakeChange] {
+        &self.rake_changes
+    }
+
+    /// Consecutive trips of every unit at most [`CirculationConfig::max_layover`] apart.
+    pub fn turnarounds(&self) -> Vec<Turnaround<'_>> {
+        self.iter()
+            .flat_map(|circulation| {
+                circulation.trips.windows(2).map(|pair| Turnaround {
+                    unit: &circulation.unit,
+                    day: circulation.day,
+                    inbound: &pair[0],
+                    outbound: &pair[1],
+                })
+            })
+            .filter(|t| {
+                t.inbound.train_number != t.outbound.train_number
+                    && t.layover() <= self.config.max_layover
+            })
+            .collect()
+    }
+
+    /// Train numbers that were observed to follow `inbound` with the same unit, most frequent
+    /// first, e.g. to find the departure a late arrival is going to delay.
+    pub fn outbound_trains(&self, inbound: i64) -> Vec<(i64, usize)> {
+        let mut counts: HashMap<i64, usize> = HashMap::new();
+        for turnaround in self.turnarounds() {
+            if turnaround.inbound.train_number == inbound {
+                *counts.entry(turnaround.outbound.train_number).or_default() += 1;
+            }
+        }
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by_key(|&(train_number, count)| (std::cmp::Reverse(count), train_number));
+        counts
+    }
+
+    /// Writes one row per turnaround, the times are given in local time and the delays and
+    /// layovers in seconds.
+    pub fn write_csv<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(
+            "unit,day,inbound_train_number,inbound_line,arrival,arrival_delay,\
+             outbound_train_number,outbound_line,departure,departure_delay,layover,\
+             scheduled_layover,propagated_delay"
+                .split(','),
+        )?;
+        let optional = |value: Option<f64>| value.map_or(String::new(), |v| format!("{v:.0}"));
+        for turnaround in self.turnarounds() {
+            writer.write_record([
+                turnaround.unit.to_string(),
+                turnaround.day.to_string(),
+                turnaround.inbound.train_number.to_string(),
+                turnaround.inbound.line.clone(),
+                local_time(turnaround.inbound.arrival).to_rfc3339(),
+                optional(turnaround.inbound.arrival_delay),
+                turnaround.outbound.train_number.to_string(),
+                turnaround.outbound.line.clone(),
+                local_time(turnaround.outbound.departure).to_rfc3339(),
+                optional(turnaround.outbound.departure_delay),
+                format!("{:.0}", turnaround.layover()),
+                optional(turnaround.scheduled_layover()),
+                optional(turnaround.propagated_delay(self.config.min_turnaround)),
+            ])?;
+        }
+        writer.flush()
+    }
+
+    /// Writes one row per coupling or uncoupling, the units are separated by semicolons and
+    /// `planned` is empty if the planned formation is unknown.
+    pub fn write_rake_changes_csv<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(
+            "time,vehicle_number,train_number,kind,units,planned,station".split(','),
+        )?;
+        for change in &self.rake_changes {
+            writer.write_record([
+                local_time(change.timestamp).to_rfc3339(),
+                change.vehicle_number.clone(),
+                change.train_number.to_string(),
+                change.kind.to_string(),
+                change.units.join(";"),
+                change
+                    .planned
+                    .map_or(String::new(), |planned| planned.to_string()),
+                change.station.clone().unwrap_or_default(),
+            ])?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_uic_numbers() {
+        assert_eq!(decode_rake("948004230014"), ["423 001"]);
+        assert_eq!(
+            decode_rake("948004230014;948004230022"),
+            ["423 001", "423 002"]
+        );
+        assert_eq!(decode_rake("9480 0423 001-4"), ["9480 0423 001-4"]);
+        assert_eq!(decode_rake("9480 0423 0014"), ["423 001"]);
+    }
+
+    #[test]
+    fn keeps_other_units() {
+        assert_eq!(decode_rake(" 423 001 , 420 400;"), ["423 001", "420 400"]);
+        assert_eq!(decode_rake("94800423001"), ["94800423001"]);
+        assert!(decode_rake("").is_empty());
+        assert!(decode_rake(" ; ,").is_empty());
+    }
+
+    #[test]
+    fn propagates_delay_beyond_the_slack() {
+        // 10 minutes layover of which 4 are needed to turn.
+        assert_eq!(propagate(300.0, 600.0, 240.0), 0.0);
+        assert_eq!(propagate(500.0, 600.0, 240.0), 140.0);
+        assert_eq!(propagate(500.0, 120.0, 240.0), 500.0);
+    }
+}
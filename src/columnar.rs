@@ -0,0 +1,617 @@
+//! Converts recordings into a columnar layout of Parquet files and reads them back.
+//!
+//! Every kind of message gets its own table with typed columns: `positions.parquet` holds one
+//! row per parsed [`Record`], `stations.parquet` one row per [`Station`] and `news.parquet` one
+//! row per message of the news ticker. An empty news ticker is kept as a row without a title, as
+//! it closes the incidents of the previous one. The recordings are chronological, so the
+//! statistics of each row group allow to skip everything outside of a time range.
+
+use arrow::array::{
+    Array, ArrayRef, BooleanArray, Float64Array, Float64Builder, Int64Array, Int64Builder,
+    ListArray, ListBuilder, StringArray, StringBuilder,
+};
+use arrow::compute::filter_record_batch;
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::{ArrowWriter, ProjectionMask};
+use parquet::basic::Compression;
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::statistics::Statistics;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::records::{
+    color_to_string, try_color_from_string, Coordinate, Record, RideState, State,
+};
+use crate::response_messages::{Content, NewsTickerMessage, ResponseMessage, SbmNewsTicker};
+use crate::stations::{Station, Stations};
+
+#[derive(Debug)]
+pub enum ColumnarError {
+    Io(io::Error),
+    Arrow(ArrowError),
+    Parquet(ParquetError),
+    /// A column is missing or does not have the expected type.
+    InvalidColumn(String),
+}
+
+impl Display for ColumnarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColumnarError::Io(err) => write!(f, "io error: {err}"),
+            ColumnarError::Arrow(err) => write!(f, "arrow error: {err}"),
+            ColumnarError::Parquet(err) => write!(f, "parquet error: {err}"),
+            ColumnarError::InvalidColumn(column) => write!(f, "invalid column: '{column}'"),
+        }
+    }
+}
+
+impl std::error::Error for ColumnarError {}
+
+impl From<io::Error> for ColumnarError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<ArrowError> for ColumnarError {
+    fn from(value: ArrowError) -> Self {
+        Self::Arrow(value)
+    }
+}
+
+impl From<ParquetError> for ColumnarError {
+    fn from(value: ParquetError) -> Self {
+        Self::Parquet(value)
+    }
+}
+
+/// The tables of a converted recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Table {
+    Positions,
+    Stations,
+    News,
+}
+
+impl Table {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Table::Positions => "positions.parquet",
+            Table::Stations => "stations.parquet",
+            Table::News => "news.parquet",
+        }
+    }
+
+    /// Every table starts with the timestamp of the message in milliseconds.
+    pub fn schema(&self) -> SchemaRef {
+        let timestamp = Field::new("timestamp", DataType::Float64, false);
+        let fields = match self {
+            Table::Positions => vec![
+                timestamp,
+                Field::new("train_id", DataType::Utf8, false),
+                Field::new("train_number", DataType::Int64, false),
+                Field::new("vehicle_number", DataType::Utf8, false),
+                Field::new("line", DataType::Utf8, false),
+                Field::new("line_color", DataType::Utf8, false),
+                Field::new("state", DataType::Utf8, false),
+                Field::new("ride_state", DataType::Utf8, true),
+                Field::new("delay", DataType::Float64, true),
+                Field::new("latitude", DataType::Float64, false),
+                Field::new("longitude", DataType::Float64, false),
+                Field::new("rake", DataType::Utf8, true),
+                Field::new("original_rake", DataType::Utf8, true),
+                Field::new("line_stroke", DataType::Utf8, true),
+                Field::new("line_text_color", DataType::Utf8, true),
+            ],
+            Table::Stations => vec![
+                timestamp,
+                Field::new("name", DataType::Utf8, false),
+                Field::new("latitude", DataType::Float64, false),
+                Field::new("longitude", DataType::Float64, false),
+            ],
+            Table::News => vec![
+                timestamp,
+                Field::new("title", DataType::Utf8, true),
+                Field::new(
+                    "lines",
+                    DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+                    true,
+                ),
+                Field::new("content", DataType::Utf8, true),
+                Field::new("updated", DataType::Utf8, true),
+            ],
+        };
+        Arc::new(Schema::new(fields))
+    }
+}
+
+/// Collects the rows of the positions table until they are written as a batch.
+#[derive(Debug, Default)]
+struct PositionColumns {
+    timestamp: Float64Builder,
+    train_id: StringBuilder,
+    train_number: Int64Builder,
+    vehicle_number: StringBuilder,
+    line: StringBuilder,
+    line_color: StringBuilder,
+    state: StringBuilder,
+    ride_state: StringBuilder,
+    delay: Float64Builder,
+    latitude: Float64Builder,
+    longitude: Float64Builder,
+    rake: StringBuilder,
+    original_rake: StringBuilder,
+    line_stroke: StringBuilder,
+    line_text_color: StringBuilder,
+}
+
+impl PositionColumns {
+    fn append(&mut self, record: &Record) {
+        self.timestamp.append_value(record.timestamp);
+        self.train_id.append_value(&record.train_id);
+        self.train_number.append_value(record.train_number);
+        self.vehicle_number.append_value(&record.vehicle_number);
+        self.line.append_value(&record.line);
+        self.line_color
+            .append_value(color_to_string(record.line_color));
+        self.state.append_value(record.state.to_string());
+        self.ride_state
+            .append_option(record.ride_state.as_ref().map(ToString::to_string));
+        self.delay.append_option(record.delay);
+        self.latitude.append_value(record.position.latitude);
+        self.longitude.append_value(record.position.longitude);
+        self.rake.append_option(record.rake.as_deref());
+        self.original_rake
+            .append_option(record.original_rake.as_deref());
+        self.line_stroke
+            .append_option(record.line_stroke.map(color_to_string));
+        self.line_text_color
+            .append_option(record.line_text_color.map(color_to_string));
+    }
+
+    fn finish(&mut self) -> Vec<ArrayRef> {
+        vec![
+            Arc::new(self.timestamp.finish()),
+            Arc::new(self.train_id.finish()),
+            Arc::new(self.train_number.finish()),
+            Arc::new(self.vehicle_number.finish()),
+            Arc::new(self.line.finish()),
+            Arc::new(self.line_color.finish()),
+            Arc::new(self.state.finish()),
+            Arc::new(self.ride_state.finish()),
+            Arc::new(self.delay.finish()),
+            Arc::new(self.latitude.finish()),
+            Arc::new(self.longitude.finish()),
+            Arc::new(self.rake.finish()),
+            Arc::new(self.original_rake.finish()),
+            Arc::new(self.line_stroke.finish()),
+            Arc::new(self.line_text_color.finish()),
+        ]
+    }
+}
+
+#[derive(Debug, Default)]
+struct StationColumns {
+    timestamp: Float64Builder,
+    name: StringBuilder,
+    latitude: Float64Builder,
+    longitude: Float64Builder,
+}
+
+impl StationColumns {
+    fn append(&mut self, timestamp: f64, station: &Station) {
+        self.timestamp.append_value(timestamp);
+        self.name.append_value(&station.name);
+        self.latitude.append_value(station.position.latitude);
+        self.longitude.append_value(station.position.longitude);
+    }
+
+    fn finish(&mut self) -> Vec<ArrayRef> {
+        vec![
+            Arc::new(self.timestamp.finish()),
+            Arc::new(self.name.finish()),
+            Arc::new(self.latitude.finish()),
+            Arc::new(self.longitude.finish()),
+        ]
+    }
+}
+
+#[derive(Debug, Default)]
+struct NewsColumns {
+    timestamp: Float64Builder,
+    title: StringBuilder,
+    lines: ListBuilder<StringBuilder>,
+    content: StringBuilder,
+    updated: StringBuilder,
+}
+
+impl NewsColumns {
+    fn append(&mut self, timestamp: f64, ticker: &SbmNewsTicker) {
+        if ticker.messages.is_empty() {
+            self.timestamp.append_value(timestamp);
+            self.title.append_null();
+            self.lines.append_null();
+            self.content.append_null();
+            self.updated.append_null();
+        }
+        for message in &ticker.messages {
+            self.timestamp.append_value(timestamp);
+            self.title.append_value(&message.title);
+            self.lines
+                .append_value(message.lines.iter().map(|l| Some(l.as_str())));
+            self.content.append_value(&message.content);
+            self.updated.append_value(&message.updated);
+        }
+    }
+
+    fn finish(&mut self) -> Vec<ArrayRef> {
+        vec![
+            Arc::new(self.timestamp.finish()),
+            Arc::new(self.title.finish()),
+            Arc::new(self.lines.finish()),
+            Arc::new(self.content.finish()),
+            Arc::new(self.updated.finish()),
+        ]
+    }
+}
+
+/// Number of rows and skipped messages of a conversion.
+#[derive(Debug, Clone, Default)]
+pub struct ConversionSummary {
+    pub positions: usize,
+    pub stations: usize,
+    pub news: usize,
+    /// Lines that are no valid message, or trajectories and stations that cannot be parsed.
+    pub skipped: usize,
+}
+
+/// Writes messages into the tables of a directory.
+pub struct ColumnarWriter {
+    positions: ArrowWriter<File>,
+    stations: ArrowWriter<File>,
+    news: ArrowWriter<File>,
+    position_columns: PositionColumns,
+    station_columns: StationColumns,
+    news_columns: NewsColumns,
+    pending: usize,
+    summary: ConversionSummary,
+}
+
+impl ColumnarWriter {
+    /// Rows that are collected before they are handed to the writers.
+    pub const BATCH_SIZE: usize = 8192;
+    /// Rows per row group, the unit that is skipped when scanning a time range.
+    pub const ROW_GROUP_SIZE: usize = 128 * 1024;
+
+    /// Creates the directory and its tables, existing tables are overwritten.
+    pub fn create(dir: impl AsRef<Path>) -> Result<Self, ColumnarError> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(Self::ROW_GROUP_SIZE)
+            .build();
+        let writer = |table: Table| -> Result<ArrowWriter<File>, ColumnarError> {
+            Ok(ArrowWriter::try_new(
+                File::create(dir.join(table.file_name()))?,
+                table.schema(),
+                Some(properties.clone()),
+            )?)
+        };
+        Ok(Self {
+            positions: writer(Table::Positions)?,
+            stations: writer(Table::Stations)?,
+            news: writer(Table::News)?,
+            position_columns: PositionColumns::default(),
+            station_columns: StationColumns::default(),
+            news_columns: NewsColumns::default(),
+            pending: 0,
+            summary: ConversionSummary::default(),
+        })
+    }
+
+    /// Appends a message to its table, messages of other sources are ignored.
+    pub fn write(&mut self, message: ResponseMessage) -> Result<(), ColumnarError> {
+        let timestamp = message.timestamp;
+        match message.content {
+            Content::TrajectorySchematic(_) => match Record::try_from(message) {
+                Ok(record) => {
+                    self.position_columns.append(&record);
+                    self.summary.positions += 1;
+                }
+                Err(_) => self.summary.skipped += 1,
+            },
+            Content::Station(_) => match Station::try_from(message.content) {
+                Ok(station) => {
+                    self.station_columns.append(timestamp, &station);
+                    self.summary.stations += 1;
+                }
+                Err(_) => self.summary.skipped += 1,
+            },
+            Content::SbmNewsTicker(ticker) => {
+                self.news_columns.append(timestamp, &ticker);
+                self.summary.news += ticker.messages.len().max(1);
+            }
+            _ => return Ok(()),
+        }
+        self.pending += 1;
+        if self.pending >= Self::BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), ColumnarError> {
+        let columns = [
+            (Table::Positions, self.position_columns.finish()),
+            (Table::Stations, self.station_columns.finish()),
+            (Table::News, self.news_columns.finish()),
+        ];
+        for (table, columns) in columns {
+            let batch = RecordBatch::try_new(table.schema(), columns)?;
+            if batch.num_rows() == 0 {
+                continue;
+            }
+            match table {
+                Table::Positions => self.positions.write(&batch)?,
+                Table::Stations => self.stations.write(&batch)?,
+                Table::News => self.news.write(&batch)?,
+            }
+        }
+        self.pending = 0;
+        Ok(())
+    }
+
+    /// Writes the remaining rows and the footers of the tables.
+    pub fn finish(mut self) -> Result<ConversionSummary, ColumnarError> {
+        self.flush()?;
+        self.positions.close()?;
+        self.stations.close()?;
+        self.news.close()?;
+        Ok(self.summary)
+    }
+}
+
+/// Converts a JSONL recording of the scraper into the tables of `output`.
+pub fn convert(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+) -> Result<ConversionSummary, ColumnarError> {
+    let reader = BufReader::new(File::open(input)?);
+    let mut writer = ColumnarWriter::create(output)?;
+    let mut skipped = 0;
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<ResponseMessage>(&line) {
+            Ok(message) => writer.write(message)?,
+            Err(_) => skipped += 1,
+        }
+    }
+    let mut summary = writer.finish()?;
+    summary.skipped += skipped;
+    Ok(summary)
+}
+
+fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> Result<&'a T, ColumnarError> {
+    batch
+        .column_by_name(name)
+        .and_then(|c| c.as_any().downcast_ref::<T>())
+        .ok_or_else(|| ColumnarError::InvalidColumn(name.to_string()))
+}
+
+fn optional_string(array: &StringArray, row: usize) -> Option<String> {
+    array.is_valid(row).then(|| array.value(row).to_string())
+}
+
+/// A recording converted by [`convert`].
+#[derive(Debug, Clone)]
+pub struct ColumnarRecording {
+    dir: PathBuf,
+}
+
+impl ColumnarRecording {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, ColumnarError> {
+        let dir = dir.as_ref().to_path_buf();
+        for table in [Table::Positions, Table::Stations, Table::News] {
+            let path = dir.join(table.file_name());
+            if !path.is_file() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("missing table {}", path.display()),
+                )
+                .into());
+            }
+        }
+        Ok(Self { dir })
+    }
+
+    /// Reads the given columns, or all if `None`, of the rows within `range`, or all if `None`.
+    /// Only the row groups overlapping the range are decoded.
+    pub fn scan(
+        &self,
+        table: Table,
+        columns: Option<&[&str]>,
+        range: Option<Range<f64>>,
+    ) -> Result<Vec<RecordBatch>, ColumnarError> {
+        let file = File::open(self.dir.join(table.file_name()))?;
+        let mut builder = ParquetRecordBatchReaderBuilder::try_new(file)?;
+
+        if let Some(range) = &range {
+            let row_groups = builder
+                .metadata()
+                .row_groups()
+                .iter()
+                .enumerate()
+                .filter(|(_, group)| match group.column(0).statistics() {
+                    Some(Statistics::Double(statistics)) => {
+                        statistics.min_opt().is_none_or(|min| *min < range.end)
+                            && statistics.max_opt().is_none_or(|max| *max >= range.start)
+                    }
+                    _ => true,
+                })
+                .map(|(index, _)| index)
+                .collect();
+            builder = builder.with_row_groups(row_groups);
+        }
+        if let Some(columns) = columns {
+            // The timestamp is always needed to filter the rows.
+            let names: Vec<&str> = std::iter::once("timestamp")
+                .chain(columns.iter().copied().filter(|c| *c != "timestamp"))
+                .collect();
+            let schema = builder.schema().clone();
+            let indices = names
+                .iter()
+                .map(|name| {
+                    schema
+                        .index_of(name)
+                        .map_err(|_| ColumnarError::InvalidColumn(name.to_string()))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let mask = ProjectionMask::roots(builder.parquet_schema(), indices);
+            builder = builder.with_projection(mask);
+        }
+
+        let mut batches = Vec::new();
+        for batch in builder.build()? {
+            let batch = batch?;
+            let batch = match &range {
+                Some(range) => {
+                    let timestamps: &Float64Array = column(&batch, "timestamp")?;
+                    let selection: BooleanArray = timestamps
+                        .iter()
+                        .map(|t| t.map(|t| range.contains(&t)))
+                        .collect();
+                    filter_record_batch(&batch, &selection)?
+                }
+                None => batch,
+            };
+            if batch.num_rows() > 0 {
+                batches.push(batch);
+            }
+        }
+        Ok(batches)
+    }
+
+    /// Reads the records within `range` in the order they were received.
+    pub fn records(&self, range: Option<Range<f64>>) -> Result<Vec<Record>, ColumnarError> {
+        let mut records = Vec::new();
+        for batch in self.scan(Table::Positions, None, range)? {
+            let timestamp: &Float64Array = column(&batch, "timestamp")?;
+            let train_id: &StringArray = column(&batch, "train_id")?;
+            let train_number: &Int64Array = column(&batch, "train_number")?;
+            let vehicle_number: &StringArray = column(&batch, "vehicle_number")?;
+            let line: &StringArray = column(&batch, "line")?;
+            let line_color: &StringArray = column(&batch, "line_color")?;
+            let state: &StringArray = column(&batch, "state")?;
+            let ride_state: &StringArray = column(&batch, "ride_state")?;
+            let delay: &Float64Array = column(&batch, "delay")?;
+            let latitude: &Float64Array = column(&batch, "latitude")?;
+            let longitude: &Float64Array = column(&batch, "longitude")?;
+            let rake: &StringArray = column(&batch, "rake")?;
+            let original_rake: &StringArray = column(&batch, "original_rake")?;
+            let line_stroke: &StringArray = column(&batch, "line_stroke")?;
+            let line_text_color: &StringArray = column(&batch, "line_text_color")?;
+            // Only used for drawing, so an invalid one is left out.
+            let optional_color = |array: &StringArray, row| {
+                optional_string(array, row).and_then(|color| try_color_from_string(color).ok())
+            };
+            for row in 0..batch.num_rows() {
+                records.push(Record {
+                    timestamp: timestamp.value(row),
+                    position: Coordinate {
+                        latitude: latitude.value(row),
+                        longitude: longitude.value(row),
+                    },
+                    line: line.value(row).to_string(),
+                    line_color: try_color_from_string(line_color.value(row).to_string())
+                        .map_err(|_| ColumnarError::InvalidColumn("line_color".to_string()))?,
+                    state: State::from(state.value(row).to_string()),
+                    ride_state: optional_string(ride_state, row).map(RideState::from),
+                    delay: delay.is_valid(row).then(|| delay.value(row)),
+                    train_id: train_id.value(row).to_string(),
+                    vehicle_number: vehicle_number.value(row).to_string(),
+                    train_number: train_number.value(row),
+                    rake: optional_string(rake, row),
+                    original_rake: optional_string(original_rake, row),
+                    line_stroke: optional_color(line_stroke, row),
+                    line_text_color: optional_color(line_text_color, row),
+                });
+            }
+        }
+        Ok(records)
+    }
+
+    /// Reads all stations, later rows replace earlier ones.
+    pub fn stations(&self) -> Result<Stations, ColumnarError> {
+        let mut stations = Stations::new();
+        for batch in self.scan(Table::Stations, None, None)? {
+            let name: &StringArray = column(&batch, "name")?;
+            let latitude: &Float64Array = column(&batch, "latitude")?;
+            let longitude: &Float64Array = column(&batch, "longitude")?;
+            for row in 0..batch.num_rows() {
+                stations.insert(Station {
+                    name: name.value(row).to_string(),
+                    position: Coordinate {
+                        latitude: latitude.value(row),
+                        longitude: longitude.value(row),
+                    },
+                });
+            }
+        }
+        Ok(stations)
+    }
+
+    /// Reassembles the news tickers within `range` with the timestamp they were received at.
+    pub fn news(
+        &self,
+        range: Option<Range<f64>>,
+    ) -> Result<Vec<(f64, SbmNewsTicker)>, ColumnarError> {
+        let mut tickers: Vec<(f64, SbmNewsTicker)> = Vec::new();
+        for batch in self.scan(Table::News, None, range)? {
+            let timestamp: &Float64Array = column(&batch, "timestamp")?;
+            let title: &StringArray = column(&batch, "title")?;
+            let lines: &ListArray = column(&batch, "lines")?;
+            let content: &StringArray = column(&batch, "content")?;
+            let updated: &StringArray = column(&batch, "updated")?;
+            for row in 0..batch.num_rows() {
+                let timestamp = timestamp.value(row);
+                if tickers.last().is_none_or(|(t, _)| *t != timestamp) {
+                    tickers.push((
+                        timestamp,
+                        SbmNewsTicker {
+                            incident_program: None,
+                            messages: Vec::new(),
+                        },
+                    ));
+                }
+                let Some(title) = optional_string(title, row) else {
+                    continue;
+                };
+                let line_values = lines.value(row);
+                let line_values = line_values
+                    .as_any()
+                    .downcast_ref::<StringArray>()
+                    .ok_or_else(|| ColumnarError::InvalidColumn("lines".to_string()))?;
+                let (_, ticker) = tickers.last_mut().expect("a ticker was pushed");
+                ticker.messages.push(NewsTickerMessage {
+                    title,
+                    lines: line_values.iter().flatten().map(str::to_string).collect(),
+                    content: optional_string(content, row).unwrap_or_default(),
+                    updated: optional_string(updated, row).unwrap_or_default(),
+                });
+            }
+        }
+        Ok(tickers)
+    }
+}
@@ -0,0 +1,499 @@
+//! State and rendering of the terminal dashboard, an alternative to the window of `analysis view`
+//! that also works over SSH. It shows the lines and their active trains with the delays, the
+//! disruptions of the news ticker and the health of the feed.
+//!
+//! The map is schematic: every train is drawn at the position of the station it is closest to on
+//! the schematic map sent by the `station_schematic` source. Without schematic stations the
+//! geographic positions are drawn instead.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use macroquad::color::Color;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{self, Style, Stylize};
+use ratatui::symbols::Marker;
+use ratatui::text::{Line, Span};
+use ratatui::widgets::canvas::{Canvas, Points};
+use ratatui::widgets::{Block, Cell, Paragraph, Row, Table, Wrap};
+use ratatui::Frame;
+
+use crate::incidents::Incidents;
+use crate::records::{local_time, Record};
+use crate::response_messages::{Content, ResponseMessage, WebSocket};
+use crate::segments::SegmentStatistics;
+use crate::stations::{Station, Stations};
+
+/// Trains at least this many seconds late count as delayed.
+pub const DELAYED: f64 = 5.0 * 60.0;
+
+/// The feed is considered stale if no message has been received for this long.
+pub const STALE_AFTER: Duration = Duration::from_secs(30);
+
+/// Messages received and the health reported by the feed itself.
+#[derive(Debug, Default)]
+pub struct FeedHealth {
+    messages: usize,
+    by_source: BTreeMap<&'static str, usize>,
+    /// When the messages of the last minute were received, to compute the rate.
+    recent: VecDeque<Instant>,
+    /// Health of the services of the `healthcheck` source, keyed by service and tenant.
+    services: BTreeMap<String, bool>,
+    /// Latest status of the `websocket` source.
+    status: Option<String>,
+}
+
+impl FeedHealth {
+    pub fn insert(&mut self, message: &ResponseMessage) {
+        let now = Instant::now();
+        self.messages += 1;
+        *self.by_source.entry(message.content.source()).or_default() += 1;
+        self.recent.push_back(now);
+        while self
+            .recent
+            .front()
+            .is_some_and(|received| now.duration_since(*received) > Duration::from_secs(60))
+        {
+            self.recent.pop_front();
+        }
+        match &message.content {
+            Content::Healthcheck(check) => {
+                let service = match &check.tenant {
+                    Some(tenant) => format!("{} ({tenant})", check.service),
+                    None => check.service.clone(),
+                };
+                self.services.insert(service, check.healthy);
+            }
+            Content::Websocket(WebSocket::Status { status }) => {
+                self.status = Some(status.clone());
+            }
+            _ => {}
+        }
+    }
+
+    pub fn messages(&self) -> usize {
+        self.messages
+    }
+
+    /// Messages received within the last minute.
+    pub fn rate(&self) -> usize {
+        self.recent
+            .iter()
+            .filter(|received| received.elapsed() <= Duration::from_secs(60))
+            .count()
+    }
+
+    /// Time since the latest message was received.
+    pub fn since_last(&self) -> Option<Duration> {
+        self.recent.back().map(Instant::elapsed)
+    }
+
+    /// Whether messages are arriving and no service reports to be unhealthy.
+    pub fn is_healthy(&self) -> bool {
+        self.since_last()
+            .is_some_and(|since_last| since_last < STALE_AFTER)
+            && self.services.values().all(|&healthy| healthy)
+    }
+}
+
+/// Active trains and delays of a line.
+#[derive(Debug, Clone)]
+pub struct LineSummary {
+    pub line: String,
+    pub color: Color,
+    pub trains: usize,
+    /// Delays in seconds of the trains reporting one.
+    pub mean_delay: Option<f64>,
+    pub max_delay: Option<f64>,
+    /// Trains that are at least [`DELAYED`] late.
+    pub delayed: usize,
+    /// Active disruptions of the news ticker affecting the line.
+    pub disruptions: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Dashboard {
+    /// Latest record of every active vehicle.
+    vehicles: HashMap<String, Record>,
+    stations: Stations,
+    schematic: Stations,
+    incidents: Incidents,
+    health: FeedHealth,
+    timestamp: f64,
+    show_map: bool,
+}
+
+impl Dashboard {
+    /// Vehicles without an update for this many seconds are left out.
+    pub const MAX_AGE: f64 = 5.0 * 60.0;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Timestamp of the latest message in milliseconds.
+    pub fn timestamp(&self) -> f64 {
+        self.timestamp
+    }
+
+    pub fn health(&self) -> &FeedHealth {
+        &self.health
+    }
+
+    /// Switches between the list of trains and the map.
+    pub fn toggle_map(&mut self) {
+        self.show_map = !self.show_map;
+    }
+
+    pub fn update(&mut self, message: ResponseMessage) {
+        self.health.insert(&message);
+        self.timestamp = self.timestamp.max(message.timestamp);
+        match message.content {
+            Content::TrajectorySchematic(_) => {
+                if let Ok(record) = Record::try_from(message) {
+                    self.vehicles.insert(record.vehicle_number.clone(), record);
+                }
+            }
+            Content::Station(_) => {
+                if let Ok(station) = Station::try_from(message.content) {
+                    self.stations.insert(station);
+                }
+            }
+            Content::StationSchematic(_) => {
+                if let Ok(station) = Station::try_from(message.content) {
+                    self.schematic.insert(station);
+                }
+            }
+            Content::SbmNewsTicker(ref ticker) => {
+                self.incidents.insert(ticker, message.timestamp);
+            }
+            Content::DeletedVehiclesSchematic(Some(ref train_id))
+            | Content::DeletedVehicles(Some(ref train_id)) => {
+                self.vehicles
+                    .retain(|_, record| &record.train_id != train_id);
+            }
+            _ => {}
+        }
+        // The news ticker is only sent when it changes.
+        self.incidents.extend(self.timestamp);
+        let max_age = Self::MAX_AGE * 1000.0;
+        let timestamp = self.timestamp;
+        self.vehicles
+            .retain(|_, record| timestamp - record.timestamp <= max_age);
+    }
+
+    /// Latest records of the active trains, the most delayed first.
+    pub fn trains(&self) -> Vec<&Record> {
+        let mut trains: Vec<&Record> = self.vehicles.values().collect();
+        trains.sort_by(|a, b| {
+            b.delay
+                .unwrap_or(f64::NEG_INFINITY)
+                .total_cmp(&a.delay.unwrap_or(f64::NEG_INFINITY))
+                .then_with(|| a.line.cmp(&b.line))
+                .then_with(|| a.train_number.cmp(&b.train_number))
+        });
+        trains
+    }
+
+    /// Summaries of the lines with active trains, ordered by line.
+    pub fn lines(&self) -> Vec<LineSummary> {
+        let mut lines: BTreeMap<&str, Vec<&Record>> = BTreeMap::new();
+        for record in self.vehicles.values() {
+            lines.entry(&record.line).or_default().push(record);
+        }
+        lines
+            .into_iter()
+            .map(|(line, records)| {
+                let delays: Vec<f64> = records.iter().filter_map(|r| r.delay).collect();
+                LineSummary {
+                    line: line.to_string(),
+                    color: records[0].line_color,
+                    trains: records.len(),
+                    mean_delay: (!delays.is_empty())
+                        .then(|| delays.iter().sum::<f64>() / delays.len() as f64),
+                    max_delay: delays.iter().copied().reduce(f64::max),
+                    delayed: delays.iter().filter(|&&delay| delay >= DELAYED).count(),
+                    disruptions: self
+                        .incidents
+                        .in_effect(self.timestamp)
+                        .filter(|i| !i.lines.is_empty() && i.affects(line))
+                        .count(),
+                }
+            })
+            .collect()
+    }
+
+    pub fn render(&self, frame: &mut Frame) {
+        let [header, body] =
+            Layout::vertical([Constraint::Length(3), Constraint::Min(0)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(body);
+        let lines = self.lines();
+        let [lines_area, trains_area] = Layout::vertical([
+            Constraint::Length(lines.len() as u16 + 3),
+            Constraint::Min(0),
+        ])
+        .areas(left);
+        let health = self.health_lines();
+        let [news_area, health_area] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(health.len() as u16 + 2),
+        ])
+        .areas(right);
+
+        self.render_header(frame, header);
+        render_lines(frame, lines_area, &lines);
+        if self.show_map {
+            self.render_map(frame, trains_area);
+        } else {
+            self.render_trains(frame, trains_area);
+        }
+        self.render_news(frame, news_area);
+        frame.render_widget(
+            Paragraph::new(health).block(Block::bordered().title("Feed")),
+            health_area,
+        );
+    }
+
+    fn render_header(&self, frame: &mut Frame, area: Rect) {
+        let time = if self.timestamp > 0.0 {
+            local_time(self.timestamp)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        } else {
+            "waiting for messages".to_string()
+        };
+        let status = if self.health.is_healthy() {
+            Span::styled("healthy", style::Color::Green)
+        } else {
+            Span::styled("unhealthy", style::Color::Red)
+        };
+        let view = if self.show_map { "trains" } else { "map" };
+        let line = Line::from(vec![
+            "S-Bahn Munich".bold(),
+            format!("  {time}  active trains: {}  feed ", self.vehicles.len()).into(),
+            status,
+            format!("  [m] {view}  [q] quit").dark_gray(),
+        ]);
+        frame.render_widget(Paragraph::new(line).block(Block::bordered()), area);
+    }
+
+    fn render_trains(&self, frame: &mut Frame, area: Rect) {
+        let rows = self.trains().into_iter().map(|record| {
+            let station = self
+                .stations
+                .nearest(&record.position, SegmentStatistics::MAX_STATION_DISTANCE)
+                .map(|station| station.name.clone())
+                .unwrap_or_default();
+            Row::new(vec![
+                Cell::from(Span::styled(
+                    record.line.clone(),
+                    terminal_color(record.line_color),
+                )),
+                Cell::from(record.train_number.to_string()),
+                Cell::from(record.vehicle_number.clone()),
+                Cell::from(record.state.to_string()),
+                delay_cell(record.delay),
+                Cell::from(station),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(5),
+                Constraint::Length(7),
+                Constraint::Length(16),
+                Constraint::Length(10),
+                Constraint::Length(8),
+                Constraint::Min(10),
+            ],
+        )
+        .header(Row::new(["Line", "Train", "Vehicle", "State", "Delay", "Station"]).bold())
+        .block(Block::bordered().title("Trains"));
+        frame.render_widget(table, area);
+    }
+
+    fn render_map(&self, frame: &mut Frame, area: Rect) {
+        let schematic = !self.schematic.is_empty();
+        let stations: Vec<(f64, f64)> = if schematic {
+            &self.schematic
+        } else {
+            &self.stations
+        }
+        .iter()
+        .map(|station| (station.position.longitude, station.position.latitude))
+        .collect();
+        let trains: Vec<(f64, f64, &Record)> = self
+            .vehicles
+            .values()
+            .filter_map(|record| {
+                let position = if schematic {
+                    let nearest = self.stations.nearest(&record.position, f64::INFINITY)?;
+                    &self.schematic.get(&nearest.name)?.position
+                } else {
+                    &record.position
+                };
+                Some((position.longitude, position.latitude, record))
+            })
+            .collect();
+
+        let points = || {
+            stations
+                .iter()
+                .copied()
+                .chain(trains.iter().map(|&(x, y, _)| (x, y)))
+        };
+        let bounds = |values: Vec<f64>| {
+            let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            if min.is_finite() && max.is_finite() {
+                let margin = ((max - min) * 0.05).max(1e-6);
+                [min - margin, max + margin]
+            } else {
+                [0.0, 1.0]
+            }
+        };
+        let title = if schematic { "Map (schematic)" } else { "Map" };
+        let canvas = Canvas::default()
+            .block(Block::bordered().title(title))
+            .marker(Marker::Braille)
+            .x_bounds(bounds(points().map(|(x, _)| x).collect()))
+            .y_bounds(bounds(points().map(|(_, y)| y).collect()))
+            .paint(|ctx| {
+                ctx.draw(&Points {
+                    coords: &stations,
+                    color: style::Color::DarkGray,
+                });
+                ctx.layer();
+                for &(x, y, record) in &trains {
+                    ctx.print(
+                        x,
+                        y,
+                        Span::styled(record.line.clone(), terminal_color(record.line_color)),
+                    );
+                }
+            });
+        frame.render_widget(canvas, area);
+    }
+
+    fn render_news(&self, frame: &mut Frame, area: Rect) {
+        let mut text: Vec<Line> = Vec::new();
+        for disruption in self.incidents.in_effect(self.timestamp) {
+            let mut heading = vec![Span::from(disruption.lines.join(", ")).bold()];
+            if !disruption.kinds.is_empty() {
+                let kinds: Vec<String> = disruption.kinds.iter().map(|k| k.to_string()).collect();
+                heading.push(format!(" ({})", kinds.join(", ")).yellow());
+            }
+            text.push(Line::from(heading));
+            text.push(Line::from(disruption.title.clone()));
+            text.push(Line::from(""));
+        }
+        if text.is_empty() {
+            text.push(Line::from("no disruptions").dark_gray());
+        }
+        frame.render_widget(
+            Paragraph::new(text)
+                .wrap(Wrap { trim: true })
+                .block(Block::bordered().title("News")),
+            area,
+        );
+    }
+
+    fn health_lines(&self) -> Vec<Line<'static>> {
+        let health = &self.health;
+        let last = match health.since_last() {
+            Some(since_last) if since_last >= STALE_AFTER => {
+                format!("{} s ago", since_last.as_secs()).red()
+            }
+            Some(since_last) => format!("{} s ago", since_last.as_secs()).into(),
+            None => "never".red(),
+        };
+        let mut lines = vec![
+            Line::from(vec!["last message ".into(), last]),
+            Line::from(format!(
+                "messages     {} ({}/min)",
+                health.messages(),
+                health.rate()
+            )),
+        ];
+        if let Some(status) = &health.status {
+            lines.push(Line::from(format!("websocket    {status}")));
+        }
+        for (service, &healthy) in &health.services {
+            let state = if healthy {
+                "healthy".green()
+            } else {
+                "unhealthy".red()
+            };
+            lines.push(Line::from(vec![format!("{service} ").into(), state]));
+        }
+        for (source, count) in &health.by_source {
+            lines.push(Line::from(format!("{source:<27} {count}")).dark_gray());
+        }
+        lines
+    }
+}
+
+fn render_lines(frame: &mut Frame, area: Rect, lines: &[LineSummary]) {
+    let rows = lines.iter().map(|line| {
+        let disruptions = if line.disruptions > 0 {
+            Cell::from(line.disruptions.to_string().yellow())
+        } else {
+            Cell::from("")
+        };
+        Row::new(vec![
+            Cell::from(Span::styled(line.line.clone(), terminal_color(line.color))),
+            Cell::from(line.trains.to_string()),
+            delay_cell(line.mean_delay),
+            delay_cell(line.max_delay),
+            Cell::from(line.delayed.to_string()),
+            disruptions,
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(5),
+            Constraint::Length(7),
+            Constraint::Length(11),
+            Constraint::Length(10),
+            Constraint::Length(8),
+            Constraint::Length(11),
+        ],
+    )
+    .header(
+        Row::new([
+            "Line",
+            "Trains",
+            "Mean delay",
+            "Max delay",
+            "Delayed",
+            "Disruptions",
+        ])
+        .bold(),
+    )
+    .block(Block::bordered().title("Lines"));
+    frame.render_widget(table, area);
+}
+
+/// Delay in seconds in whole minutes, highlighted if the train is late.
+fn delay_cell(delay: Option<f64>) -> Cell<'static> {
+    let Some(delay) = delay else {
+        return Cell::from("");
+    };
+    let text = format!("{:+.0} min", delay / 60.0);
+    let style = if delay >= DELAYED {
+        Style::new().red()
+    } else if delay >= 2.0 * 60.0 {
+        Style::new().yellow()
+    } else {
+        Style::new()
+    };
+    Cell::from(Span::styled(text, style))
+}
+
+fn terminal_color(color: Color) -> style::Color {
+    let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+    style::Color::Rgb(channel(color.r), channel(color.g), channel(color.b))
+}
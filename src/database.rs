@@ -0,0 +1,543 @@
+//! Stores the parsed messages in an SQLite database, so recordings can be queried with SQL.
+//!
+//! The tables are normalized: `lines` and `vehicles` hold what rarely changes, `positions`
+//! references them with every update of a vehicle, and `stations` keeps the latest position of
+//! every station. Messages of the news ticker are stored one row per message in `news`, with the
+//! lines joined by commas. Every connection of the scraper is a row in `sessions`, which allows
+//! to find the gaps of a recording.
+//!
+//! A row of `vehicles` is a train id together with the vehicle, train number and line it was
+//! seen with. When one of them changes, a new row is added, so earlier positions keep the train
+//! number and line they were recorded with.
+
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::records::{
+    color_to_string, try_color_from_string, Coordinate, Record, RideState, State,
+};
+use crate::response_messages::{Content, NewsTickerMessage, ResponseMessage, SbmNewsTicker};
+use crate::stations::{Station, Stations};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    started REAL NOT NULL,
+    ended REAL
+);
+CREATE TABLE IF NOT EXISTS lines (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    color TEXT NOT NULL,
+    stroke TEXT,
+    text_color TEXT
+);
+CREATE TABLE IF NOT EXISTS vehicles (
+    id INTEGER PRIMARY KEY,
+    train_id TEXT NOT NULL,
+    vehicle_number TEXT NOT NULL,
+    train_number INTEGER NOT NULL,
+    line_id INTEGER NOT NULL REFERENCES lines(id),
+    UNIQUE(train_id, vehicle_number, train_number, line_id)
+);
+CREATE TABLE IF NOT EXISTS positions (
+    id INTEGER PRIMARY KEY,
+    session_id INTEGER REFERENCES sessions(id),
+    vehicle_id INTEGER NOT NULL REFERENCES vehicles(id),
+    timestamp REAL NOT NULL,
+    latitude REAL NOT NULL,
+    longitude REAL NOT NULL,
+    state TEXT NOT NULL,
+    ride_state TEXT,
+    delay REAL,
+    rake TEXT,
+    original_rake TEXT
+);
+CREATE INDEX IF NOT EXISTS positions_timestamp ON positions(timestamp);
+CREATE INDEX IF NOT EXISTS positions_vehicle ON positions(vehicle_id, timestamp);
+CREATE TABLE IF NOT EXISTS stations (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    latitude REAL NOT NULL,
+    longitude REAL NOT NULL,
+    updated REAL NOT NULL
+);
+CREATE TABLE IF NOT EXISTS news (
+    id INTEGER PRIMARY KEY,
+    session_id INTEGER REFERENCES sessions(id),
+    timestamp REAL NOT NULL,
+    title TEXT,
+    lines TEXT,
+    content TEXT,
+    updated TEXT
+);
+CREATE INDEX IF NOT EXISTS news_timestamp ON news(timestamp);
+";
+
+pub struct Database {
+    connection: Connection,
+    session: Option<i64>,
+    /// Ids of the rows in `lines` by name.
+    lines: HashMap<String, i64>,
+    /// Ids of the rows in `vehicles` by train id, vehicle number, train number and line id.
+    vehicles: HashMap<(String, String, i64, i64), i64>,
+    /// Messages written since the current transaction was started.
+    pending: usize,
+}
+
+impl Database {
+    /// Messages that are written in one transaction.
+    pub const BATCH_SIZE: usize = 1000;
+
+    /// Opens or creates the database at `path` and creates the missing tables.
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection,
+            session: None,
+            lines: HashMap::new(),
+            vehicles: HashMap::new(),
+            pending: 0,
+        })
+    }
+
+    /// The underlying connection, e.g. for ad-hoc queries.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Starts a new session at `timestamp` in milliseconds, ending the current one.
+    pub fn start_session(&mut self, timestamp: f64) -> rusqlite::Result<i64> {
+        self.end_session(timestamp)?;
+        self.connection
+            .execute("INSERT INTO sessions (started) VALUES (?1)", [timestamp])?;
+        let session = self.connection.last_insert_rowid();
+        self.session = Some(session);
+        Ok(session)
+    }
+
+    /// Ends the current session, if any, and writes the pending messages.
+    pub fn end_session(&mut self, timestamp: f64) -> rusqlite::Result<()> {
+        self.flush()?;
+        if let Some(session) = self.session.take() {
+            self.connection.execute(
+                "UPDATE sessions SET ended = ?1 WHERE id = ?2",
+                params![timestamp, session],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Commits the messages written since the last commit.
+    pub fn flush(&mut self) -> rusqlite::Result<()> {
+        if self.pending > 0 {
+            self.connection.execute_batch("COMMIT")?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    fn line_id(&mut self, record: &Record) -> rusqlite::Result<i64> {
+        if let Some(&id) = self.lines.get(&record.line) {
+            return Ok(id);
+        }
+        // Lines stored without the outline and text colors get them once they are known.
+        self.connection.execute(
+            "INSERT INTO lines (name, color, stroke, text_color) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(name) DO UPDATE SET
+                 stroke = coalesce(stroke, excluded.stroke),
+                 text_color = coalesce(text_color, excluded.text_color)",
+            params![
+                record.line,
+                color_to_string(record.line_color),
+                record.line_stroke.map(color_to_string),
+                record.line_text_color.map(color_to_string),
+            ],
+        )?;
+        let id = self.connection.query_row(
+            "SELECT id FROM lines WHERE name = ?1",
+            [&record.line],
+            |row| row.get(0),
+        )?;
+        self.lines.insert(record.line.clone(), id);
+        Ok(id)
+    }
+
+    fn vehicle_id(&mut self, record: &Record) -> rusqlite::Result<i64> {
+        let line_id = self.line_id(record)?;
+        let key = (
+            record.train_id.clone(),
+            record.vehicle_number.clone(),
+            record.train_number,
+            line_id,
+        );
+        if let Some(&id) = self.vehicles.get(&key) {
+            return Ok(id);
+        }
+        self.connection.execute(
+            "INSERT INTO vehicles (train_id, vehicle_number, train_number, line_id)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT DO NOTHING",
+            params![key.0, key.1, key.2, key.3],
+        )?;
+        let id = self.connection.query_row(
+            "SELECT id FROM vehicles
+             WHERE train_id = ?1 AND vehicle_number = ?2 AND train_number = ?3 AND line_id = ?4",
+            params![key.0, key.1, key.2, key.3],
+            |row| row.get(0),
+        )?;
+        self.vehicles.insert(key, id);
+        Ok(id)
+    }
+
+    /// Writes a message into its tables and returns whether it was stored. Messages of other
+    /// sources, or that cannot be parsed, are skipped. If writing fails, only this message is
+    /// undone and the following ones can still be written.
+    pub fn insert(&mut self, message: ResponseMessage) -> rusqlite::Result<bool> {
+        if self.pending == 0 {
+            self.connection.execute_batch("BEGIN")?;
+        }
+        self.pending += 1;
+        self.connection.execute_batch("SAVEPOINT message")?;
+        match self.write(message) {
+            Ok(stored) => {
+                self.connection.execute_batch("RELEASE message")?;
+                if self.pending >= Self::BATCH_SIZE {
+                    self.flush()?;
+                }
+                Ok(stored)
+            }
+            Err(err) => {
+                let _ = self
+                    .connection
+                    .execute_batch("ROLLBACK TO message; RELEASE message");
+                // The cached ids may refer to rows that were rolled back.
+                self.lines.clear();
+                self.vehicles.clear();
+                Err(err)
+            }
+        }
+    }
+
+    fn write(&mut self, message: ResponseMessage) -> rusqlite::Result<bool> {
+        let timestamp = message.timestamp;
+        let stored = match message.content {
+            Content::TrajectorySchematic(_) => match Record::try_from(message) {
+                Ok(record) => {
+                    let vehicle_id = self.vehicle_id(&record)?;
+                    self.connection
+                        .prepare_cached(
+                            "INSERT INTO positions
+                             (session_id, vehicle_id, timestamp, latitude, longitude, state, ride_state, delay, rake, original_rake)
+                             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                        )?
+                        .execute(params![
+                            self.session,
+                            vehicle_id,
+                            record.timestamp,
+                            record.position.latitude,
+                            record.position.longitude,
+                            record.state.to_string(),
+                            record.ride_state.as_ref().map(ToString::to_string),
+                            record.delay,
+                            record.rake,
+                            record.original_rake,
+                        ])?;
+                    true
+                }
+                Err(_) => false,
+            },
+            Content::Station(_) => match Station::try_from(message.content) {
+                Ok(station) => {
+                    self.connection.execute(
+                        "INSERT INTO stations (name, latitude, longitude, updated)
+                         VALUES (?1, ?2, ?3, ?4)
+                         ON CONFLICT(name) DO UPDATE SET
+                            latitude = excluded.latitude,
+                            longitude = excluded.longitude,
+                            updated = excluded.updated",
+                        params![
+                            station.name,
+                            station.position.latitude,
+                            station.position.longitude,
+                            timestamp
+                        ],
+                    )?;
+                    true
+                }
+                Err(_) => false,
+            },
+            Content::SbmNewsTicker(ticker) => {
+                let mut statement = self.connection.prepare_cached(
+                    "INSERT INTO news (session_id, timestamp, title, lines, content, updated)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                )?;
+                // An empty news ticker is kept as a row without a title, as it closes the
+                // messages of the previous one.
+                if ticker.messages.is_empty() {
+                    statement.execute(params![
+                        self.session,
+                        timestamp,
+                        None::<String>,
+                        None::<String>,
+                        None::<String>,
+                        None::<String>
+                    ])?;
+                }
+                for message in &ticker.messages {
+                    statement.execute(params![
+                        self.session,
+                        timestamp,
+                        message.title,
+                        message.lines.join(","),
+                        message.content,
+                        message.updated
+                    ])?;
+                }
+                true
+            }
+            _ => false,
+        };
+        Ok(stored)
+    }
+
+    /// Reads the records within `range`, optionally only of one train, ordered by time.
+    pub fn records(
+        &self,
+        train_id: Option<&str>,
+        range: Range<f64>,
+    ) -> rusqlite::Result<Vec<Record>> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT p.timestamp, p.latitude, p.longitude, l.name, l.color, p.state, p.ride_state,
+                    p.delay, v.train_id, v.vehicle_number, v.train_number, p.rake, l.stroke,
+                    l.text_color, p.original_rake
+             FROM positions p
+             JOIN vehicles v ON v.id = p.vehicle_id
+             JOIN lines l ON l.id = v.line_id
+             WHERE p.timestamp >= ?1 AND p.timestamp < ?2
+               AND (?3 IS NULL OR v.train_id = ?3)
+             ORDER BY p.timestamp",
+        )?;
+        let records = statement
+            .query_map(params![range.start, range.end, train_id], |row| {
+                let color: String = row.get(4)?;
+                Ok(Record {
+                    timestamp: row.get(0)?,
+                    position: Coordinate {
+                        latitude: row.get(1)?,
+                        longitude: row.get(2)?,
+                    },
+                    line: row.get(3)?,
+                    line_color: try_color_from_string(color).map_err(|_| {
+                        rusqlite::Error::InvalidColumnType(
+                            4,
+                            "color".to_string(),
+                            rusqlite::types::Type::Text,
+                        )
+                    })?,
+                    state: State::from(row.get::<_, String>(5)?),
+                    ride_state: row.get::<_, Option<String>>(6)?.map(RideState::from),
+                    delay: row.get(7)?,
+                    train_id: row.get(8)?,
+                    vehicle_number: row.get(9)?,
+                    train_number: row.get(10)?,
+                    rake: row.get(11)?,
+                    original_rake: row.get(14)?,
+                    line_stroke: row
+                        .get::<_, Option<String>>(12)?
+                        .and_then(|color| try_color_from_string(color).ok()),
+                    line_text_color: row
+                        .get::<_, Option<String>>(13)?
+                        .and_then(|color| try_color_from_string(color).ok()),
+                })
+            })?
+            .collect();
+        records
+    }
+
+    pub fn stations(&self) -> rusqlite::Result<Stations> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT name, latitude, longitude FROM stations")?;
+        let mut stations = Stations::new();
+        for station in statement.query_map([], |row| {
+            Ok(Station {
+                name: row.get(0)?,
+                position: Coordinate {
+                    latitude: row.get(1)?,
+                    longitude: row.get(2)?,
+                },
+            })
+        })? {
+            stations.insert(station?);
+        }
+        Ok(stations)
+    }
+
+    /// Reassembles the news tickers within `range` with the timestamp they were received at.
+    pub fn news(&self, range: Range<f64>) -> rusqlite::Result<Vec<(f64, SbmNewsTicker)>> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT timestamp, title, lines, content, updated FROM news
+             WHERE timestamp >= ?1 AND timestamp < ?2
+             ORDER BY timestamp, id",
+        )?;
+        let mut rows = statement.query(params![range.start, range.end])?;
+        let mut tickers: Vec<(f64, SbmNewsTicker)> = Vec::new();
+        while let Some(row) = rows.next()? {
+            let timestamp: f64 = row.get(0)?;
+            if tickers.last().is_none_or(|(t, _)| *t != timestamp) {
+                tickers.push((
+                    timestamp,
+                    SbmNewsTicker {
+                        incident_program: None,
+                        messages: Vec::new(),
+                    },
+                ));
+            }
+            let Some(title) = row.get::<_, Option<String>>(1)? else {
+                continue;
+            };
+            let lines: Option<String> = row.get(2)?;
+            let (_, ticker) = tickers.last_mut().expect("a ticker was pushed");
+            ticker.messages.push(NewsTickerMessage {
+                title,
+                lines: lines
+                    .filter(|l| !l.is_empty())
+                    .map(|l| l.split(',').map(str::to_string).collect())
+                    .unwrap_or_default(),
+                content: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                updated: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+            });
+        }
+        Ok(tickers)
+    }
+
+    /// Time range covered by the positions, `None` if there are none.
+    pub fn time_range(&self) -> rusqlite::Result<Option<Range<f64>>> {
+        self.connection.query_row(
+            "SELECT MIN(timestamp), MAX(timestamp) FROM positions",
+            [],
+            |row| {
+                Ok(
+                    match (row.get::<_, Option<f64>>(0)?, row.get::<_, Option<f64>>(1)?) {
+                        (Some(start), Some(end)) => Some(start..end),
+                        _ => None,
+                    },
+                )
+            },
+        )
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn trajectory(
+        train_id: &str,
+        train_number: i64,
+        delay: i64,
+        timestamp: f64,
+    ) -> ResponseMessage {
+        serde_json::from_value(json!({
+            "source": "trajectory_schematic",
+            "content": {"type": "Feature", "geometry": null, "properties": {
+                "train_id": train_id,
+                "train_number": train_number,
+                "vehicle_number": "423 001",
+                "line": {"name": "S1", "color": "#16bae7", "stroke": "#ffffff", "text_color": "#000000"},
+                "state": "BOARDING",
+                "ride_state": "REALTIME",
+                "delay": delay,
+                "raw_coordinates": [11.5, 48.1],
+                "rake": "948004230014;948004230022",
+                "original_rake": "948004230014",
+            }},
+            "timestamp": timestamp,
+            "client_reference": null,
+        }))
+        .expect("valid message")
+    }
+
+    #[test]
+    fn reads_the_records_written() {
+        let mut database = Database::open(":memory:").expect("database");
+        database.start_session(1000.0).unwrap();
+        assert!(database
+            .insert(trajectory("sbm_1", 6401, 60000, 1000.0))
+            .unwrap());
+        assert!(database
+            .insert(trajectory("sbm_1", 6402, -3000, 2000.0))
+            .unwrap());
+        assert!(database
+            .insert(trajectory("sbm_2", 6403, 0, 3000.0))
+            .unwrap());
+        database.end_session(3000.0).unwrap();
+
+        let records = database.records(None, 0.0..f64::MAX).unwrap();
+        assert_eq!(records.len(), 3);
+        let record = &records[0];
+        assert_eq!(record.train_id, "sbm_1");
+        assert_eq!(record.train_number, 6401);
+        assert_eq!(record.line, "S1");
+        assert_eq!(
+            record.line_style().text_color,
+            try_color_from_string("#000000".to_string()).unwrap()
+        );
+        assert_eq!(record.state, State::Boarding);
+        assert_eq!(record.ride_state, Some(RideState::Realtime));
+        assert_eq!(record.delay, Some(60.0));
+        assert_eq!(record.position.latitude, 48.1);
+        assert_eq!(record.rake.as_deref(), Some("948004230014;948004230022"));
+        assert_eq!(record.original_rake.as_deref(), Some("948004230014"));
+        // The earlier position keeps the train number it was recorded with.
+        let train = database.records(Some("sbm_1"), 0.0..f64::MAX).unwrap();
+        let numbers: Vec<i64> = train.iter().map(|r| r.train_number).collect();
+        assert_eq!(numbers, [6401, 6402]);
+        assert_eq!(database.time_range().unwrap(), Some(1000.0..3000.0));
+    }
+
+    #[test]
+    fn undoes_only_the_failed_message() {
+        let mut database = Database::open(":memory:").expect("database");
+        database
+            .connection()
+            .execute_batch(
+                "CREATE TRIGGER reject BEFORE INSERT ON positions WHEN NEW.delay < 0
+                 BEGIN SELECT RAISE(ABORT, 'negative delay'); END;",
+            )
+            .unwrap();
+        assert!(database
+            .insert(trajectory("sbm_1", 6401, 0, 1000.0))
+            .is_ok());
+        // Fails after the vehicle of the new train was added, which is undone as well.
+        assert!(database
+            .insert(trajectory("sbm_2", 6402, -1000, 2000.0))
+            .is_err());
+        assert!(database
+            .insert(trajectory("sbm_2", 6402, 1000, 3000.0))
+            .is_ok());
+        database.flush().unwrap();
+
+        let records = database.records(None, 0.0..f64::MAX).unwrap();
+        let timestamps: Vec<f64> = records.iter().map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, [1000.0, 3000.0]);
+        let vehicles: i64 = database
+            .connection()
+            .query_row("SELECT COUNT(*) FROM vehicles", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(vehicles, 2);
+    }
+}
@@ -0,0 +1,725 @@
+//! Loads a static GTFS timetable and matches the observed trains to their scheduled trips, so
+//! delays can be derived independently of the `delay` reported by the websocket.
+//!
+//! A train is matched to the trips of the route named like its line that run on the day of the
+//! observation (or the day before, for trips past midnight). Trips whose short name is a train
+//! number must carry the number of the observed train. Trips without one are matched by time:
+//! the observed stops are aligned with the scheduled stops in order and the trip that is closest
+//! to the observed departures wins.
+
+use chrono::{Datelike, Duration, NaiveDate, TimeZone};
+use chrono_tz::Europe::Berlin;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::records::{local_time, Coordinate, Trains};
+use crate::segments::{SegmentStatistics, Stop};
+use crate::stations::Stations;
+
+#[derive(Debug)]
+pub enum GtfsError {
+    Csv {
+        file: &'static str,
+        error: csv::Error,
+    },
+    InvalidValue {
+        file: &'static str,
+        field: &'static str,
+        value: String,
+    },
+}
+
+impl Display for GtfsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GtfsError::Csv { file, error } => write!(f, "unable to read '{file}': {error}"),
+            GtfsError::InvalidValue { file, field, value } => {
+                write!(f, "invalid value of '{field}' in '{file}': '{value}'")
+            }
+        }
+    }
+}
+
+impl std::error::Error for GtfsError {}
+
+#[derive(Debug, Deserialize)]
+struct RouteRow {
+    route_id: String,
+    route_short_name: Option<String>,
+    route_long_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TripRow {
+    route_id: String,
+    service_id: String,
+    trip_id: String,
+    trip_headsign: Option<String>,
+    trip_short_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StopRow {
+    stop_id: String,
+    stop_name: Option<String>,
+    stop_lat: Option<f64>,
+    stop_lon: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StopTimeRow {
+    trip_id: String,
+    arrival_time: Option<String>,
+    departure_time: Option<String>,
+    stop_id: String,
+    stop_sequence: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct CalendarRow {
+    service_id: String,
+    monday: u8,
+    tuesday: u8,
+    wednesday: u8,
+    thursday: u8,
+    friday: u8,
+    saturday: u8,
+    sunday: u8,
+    start_date: String,
+    end_date: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CalendarDateRow {
+    service_id: String,
+    date: String,
+    exception_type: u8,
+}
+
+/// Reads all rows of a file of the feed, `None` if the file does not exist.
+fn read_rows<T: for<'de> Deserialize<'de>>(
+    dir: &Path,
+    file: &'static str,
+) -> Result<Option<Vec<T>>, GtfsError> {
+    let path = dir.join(file);
+    if !path.exists() {
+        return Ok(None);
+    }
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .and_then(|mut reader| reader.deserialize().collect())
+        .map(Some)
+        .map_err(|error| GtfsError::Csv { file, error })
+}
+
+fn parse_date(
+    file: &'static str,
+    field: &'static str,
+    value: &str,
+) -> Result<NaiveDate, GtfsError> {
+    NaiveDate::parse_from_str(value, "%Y%m%d").map_err(|_| GtfsError::InvalidValue {
+        file,
+        field,
+        value: value.to_string(),
+    })
+}
+
+/// Parses a time of the form `H:MM:SS` into seconds, hours past 24 are allowed for trips that
+/// continue after midnight.
+fn parse_time(field: &'static str, value: &str) -> Result<u32, GtfsError> {
+    let invalid = || GtfsError::InvalidValue {
+        file: "stop_times.txt",
+        field,
+        value: value.to_string(),
+    };
+    let mut parts = value.split(':').map(|p| p.parse::<u32>());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(h)), Some(Ok(m)), Some(Ok(s)), None) if m < 60 && s < 60 => {
+            Ok(h * 3600 + m * 60 + s)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Days on which the trips of a service run.
+#[derive(Debug, Clone, Default)]
+pub struct Service {
+    /// Regular days of the week, starting with monday.
+    pub weekdays: [bool; 7],
+    pub start: Option<NaiveDate>,
+    pub end: Option<NaiveDate>,
+    pub added: HashSet<NaiveDate>,
+    pub removed: HashSet<NaiveDate>,
+}
+
+impl Service {
+    pub fn runs_on(&self, date: NaiveDate) -> bool {
+        if self.added.contains(&date) {
+            return true;
+        }
+        if self.removed.contains(&date) {
+            return false;
+        }
+        match (self.start, self.end) {
+            (Some(start), Some(end)) => {
+                start <= date
+                    && date <= end
+                    && self.weekdays[date.weekday().num_days_from_monday() as usize]
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduledStop {
+    pub stop_id: String,
+    pub name: String,
+    pub position: Option<Coordinate>,
+    /// Seconds after the start of the service day.
+    pub arrival: u32,
+    /// Seconds after the start of the service day.
+    pub departure: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trip {
+    pub id: String,
+    /// Short name of the route, e.g. the name of the line.
+    pub route: String,
+    pub service_id: String,
+    pub headsign: Option<String>,
+    /// Train number, if the short name of the trip is one.
+    pub train_number: Option<i64>,
+    /// Stops ordered by their sequence.
+    pub stops: Vec<ScheduledStop>,
+}
+
+/// Observed and scheduled times of a train at a station.
+#[derive(Debug, Clone)]
+pub struct StopDelay {
+    pub station: String,
+    /// All times are in milliseconds since the unix epoch.
+    pub scheduled_arrival: f64,
+    pub arrival: f64,
+    pub scheduled_departure: f64,
+    pub departure: f64,
+    /// Delay in seconds reported by the feed when the train departed.
+    pub reported_delay: Option<f64>,
+}
+
+impl StopDelay {
+    /// Delay of the arrival in seconds.
+    pub fn arrival_delay(&self) -> f64 {
+        (self.arrival - self.scheduled_arrival) / 1000.0
+    }
+
+    /// Delay of the departure in seconds.
+    pub fn departure_delay(&self) -> f64 {
+        (self.departure - self.scheduled_departure) / 1000.0
+    }
+}
+
+/// An observed train matched to a scheduled trip.
+#[derive(Debug, Clone)]
+pub struct TripMatch<'a> {
+    pub trip: &'a Trip,
+    pub service_date: NaiveDate,
+    pub line: String,
+    pub train_number: i64,
+    pub stops: Vec<StopDelay>,
+}
+
+impl TripMatch<'_> {
+    /// Mean delay of the departures in seconds.
+    pub fn mean_delay(&self) -> f64 {
+        self.stops
+            .iter()
+            .map(StopDelay::departure_delay)
+            .sum::<f64>()
+            / self.stops.len() as f64
+    }
+
+    /// Mean difference in seconds between the delays reported by the feed and the derived ones,
+    /// `None` if the feed reported no delays.
+    pub fn reported_deviation(&self) -> Option<f64> {
+        let deviations: Vec<f64> = self
+            .stops
+            .iter()
+            .filter_map(|s| Some(s.reported_delay? - s.departure_delay()))
+            .collect();
+        (!deviations.is_empty()).then(|| deviations.iter().sum::<f64>() / deviations.len() as f64)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Timetable {
+    trips: Vec<Trip>,
+    services: HashMap<String, Service>,
+    /// Indices of the trips per lowercase route name.
+    routes: HashMap<String, Vec<usize>>,
+}
+
+impl Timetable {
+    /// Observed departures earlier than this many seconds before the schedule are no match.
+    pub const MAX_EARLINESS: f64 = 5.0 * 60.0;
+    /// Observed departures later than this many seconds after the schedule are no match.
+    pub const MAX_DELAY: f64 = 2.0 * 60.0 * 60.0;
+
+    /// Loads the feed from a directory containing the extracted `.txt` files.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self, GtfsError> {
+        let dir = dir.as_ref();
+        let required = |file: &'static str| GtfsError::Csv {
+            file,
+            error: io::Error::from(io::ErrorKind::NotFound).into(),
+        };
+
+        let routes: HashMap<String, String> = read_rows::<RouteRow>(dir, "routes.txt")?
+            .ok_or_else(|| required("routes.txt"))?
+            .into_iter()
+            .map(|r| {
+                let name = r.route_short_name.or(r.route_long_name).unwrap_or_default();
+                (r.route_id, name)
+            })
+            .collect();
+
+        let stops: HashMap<String, StopRow> = read_rows::<StopRow>(dir, "stops.txt")?
+            .ok_or_else(|| required("stops.txt"))?
+            .into_iter()
+            .map(|s| (s.stop_id.clone(), s))
+            .collect();
+
+        let mut services: HashMap<String, Service> = HashMap::new();
+        for row in read_rows::<CalendarRow>(dir, "calendar.txt")?.unwrap_or_default() {
+            let service = services.entry(row.service_id).or_default();
+            service.weekdays = [
+                row.monday,
+                row.tuesday,
+                row.wednesday,
+                row.thursday,
+                row.friday,
+                row.saturday,
+                row.sunday,
+            ]
+            .map(|d| d == 1);
+            service.start = Some(parse_date("calendar.txt", "start_date", &row.start_date)?);
+            service.end = Some(parse_date("calendar.txt", "end_date", &row.end_date)?);
+        }
+        for row in read_rows::<CalendarDateRow>(dir, "calendar_dates.txt")?.unwrap_or_default() {
+            let date = parse_date("calendar_dates.txt", "date", &row.date)?;
+            let service = services.entry(row.service_id).or_default();
+            match row.exception_type {
+                1 => service.added.insert(date),
+                _ => service.removed.insert(date),
+            };
+        }
+
+        let mut stop_times: HashMap<String, Vec<StopTimeRow>> = HashMap::new();
+        for row in read_rows::<StopTimeRow>(dir, "stop_times.txt")?
+            .ok_or_else(|| required("stop_times.txt"))?
+        {
+            stop_times.entry(row.trip_id.clone()).or_default().push(row);
+        }
+
+        let mut trips = Vec::new();
+        for row in read_rows::<TripRow>(dir, "trips.txt")?.ok_or_else(|| required("trips.txt"))? {
+            let Some(mut times) = stop_times.remove(&row.trip_id) else {
+                continue;
+            };
+            times.sort_by_key(|t| t.stop_sequence);
+            let mut scheduled = Vec::with_capacity(times.len());
+            for time in times {
+                // Intermediate stops may omit their times, they cannot be used for matching.
+                let (arrival, departure) = match (&time.arrival_time, &time.departure_time) {
+                    (Some(arrival), Some(departure)) => (
+                        parse_time("arrival_time", arrival)?,
+                        parse_time("departure_time", departure)?,
+                    ),
+                    (Some(time), None) | (None, Some(time)) => {
+                        let time = parse_time("departure_time", time)?;
+                        (time, time)
+                    }
+                    (None, None) => continue,
+                };
+                let stop = stops.get(&time.stop_id);
+                scheduled.push(ScheduledStop {
+                    name: stop
+                        .and_then(|s| s.stop_name.clone())
+                        .unwrap_or_else(|| time.stop_id.clone()),
+                    position: stop.and_then(|s| {
+                        Some(Coordinate {
+                            latitude: s.stop_lat?,
+                            longitude: s.stop_lon?,
+                        })
+                    }),
+                    stop_id: time.stop_id,
+                    arrival,
+                    departure,
+                });
+            }
+            trips.push(Trip {
+                route: routes.get(&row.route_id).cloned().unwrap_or_default(),
+                train_number: row.trip_short_name.as_deref().and_then(|n| n.parse().ok()),
+                id: row.trip_id,
+                service_id: row.service_id,
+                headsign: row.trip_headsign,
+                stops: scheduled,
+            });
+        }
+
+        let mut route_trips: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, trip) in trips.iter().enumerate() {
+            route_trips
+                .entry(trip.route.to_lowercase())
+                .or_default()
+                .push(index);
+        }
+
+        Ok(Self {
+            trips,
+            services,
+            routes: route_trips,
+        })
+    }
+
+    pub fn trips(&self) -> impl Iterator<Item = &Trip> {
+        self.trips.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.trips.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trips.is_empty()
+    }
+
+    pub fn runs_on(&self, trip: &Trip, date: NaiveDate) -> bool {
+        self.services
+            .get(&trip.service_id)
+            .is_some_and(|s| s.runs_on(date))
+    }
+
+    /// Converts seconds after the start of a service day into milliseconds since the unix epoch.
+    /// The service day starts twelve hours before noon, which differs from midnight when the
+    /// clocks change.
+    fn scheduled_time(date: NaiveDate, seconds: u32) -> Option<f64> {
+        let noon = Berlin
+            .from_local_datetime(&date.and_hms_opt(12, 0, 0)?)
+            .single()?;
+        let time = noon - Duration::hours(12) + Duration::seconds(i64::from(seconds));
+        Some(time.timestamp_millis() as f64)
+    }
+
+    /// Aligns the observed stops with the stops of a trip on a service day, stops are matched
+    /// in order by their distance to the station.
+    fn align(
+        trip: &Trip,
+        date: NaiveDate,
+        stops: &[Stop],
+        stations: &Stations,
+    ) -> Option<Vec<StopDelay>> {
+        let mut delays = Vec::new();
+        let mut next = 0;
+        for stop in stops {
+            let Some(station) = stations.get(stop.station) else {
+                continue;
+            };
+            let Some(offset) = trip.stops[next..].iter().position(|s| {
+                s.position.as_ref().is_some_and(|p| {
+                    p.distance(&station.position) <= SegmentStatistics::MAX_STATION_DISTANCE
+                }) || s.name.eq_ignore_ascii_case(stop.station)
+            }) else {
+                continue;
+            };
+            let scheduled = &trip.stops[next + offset];
+            next += offset + 1;
+            let delay = StopDelay {
+                station: stop.station.to_string(),
+                scheduled_arrival: Self::scheduled_time(date, scheduled.arrival)?,
+                arrival: stop.arrival.timestamp,
+                scheduled_departure: Self::scheduled_time(date, scheduled.departure)?,
+                departure: stop.departure.timestamp,
+                reported_delay: stop.departure.delay,
+            };
+            if delay.departure_delay() < -Self::MAX_EARLINESS
+                || delay.departure_delay() > Self::MAX_DELAY
+            {
+                return None;
+            }
+            delays.push(delay);
+        }
+        Some(delays)
+    }
+
+    /// Matches the stops of a single train, i.e. with the same line and train number, to the
+    /// best fitting trip.
+    pub fn match_stops(&self, stops: &[Stop], stations: &Stations) -> Option<TripMatch<'_>> {
+        let first = stops.first()?.arrival;
+        let date = first.local_time().date_naive();
+        let candidates = self.routes.get(&first.line.to_lowercase())?;
+
+        let mut best: Option<(bool, TripMatch)> = None;
+        for &index in candidates {
+            let trip = &self.trips[index];
+            let numbered = match trip.train_number {
+                Some(number) if number != first.train_number => continue,
+                Some(_) => true,
+                None => false,
+            };
+            for service_date in [date, date.pred_opt()?] {
+                if !self.runs_on(trip, service_date) {
+                    continue;
+                }
+                let Some(delays) = Self::align(trip, service_date, stops, stations) else {
+                    continue;
+                };
+                // Without a train number a single stop could match any trip passing it.
+                if delays.is_empty() || (!numbered && delays.len() < stops.len().min(2)) {
+                    continue;
+                }
+                let candidate = TripMatch {
+                    trip,
+                    service_date,
+                    line: first.line.clone(),
+                    train_number: first.train_number,
+                    stops: delays,
+                };
+                let better = match &best {
+                    None => true,
+                    Some((best_numbered, best)) => {
+                        (
+                            numbered,
+                            candidate.stops.len(),
+                            -candidate.mean_delay().abs(),
+                        ) > (*best_numbered, best.stops.len(), -best.mean_delay().abs())
+                    }
+                };
+                if better {
+                    best = Some((numbered, candidate));
+                }
+            }
+        }
+        best.map(|(_, m)| m)
+    }
+
+    /// Matches every train of every vehicle, trains that match no trip are left out.
+    pub fn match_trains<'a>(&'a self, trains: &Trains, stations: &Stations) -> Vec<TripMatch<'a>> {
+        let mut matches: Vec<TripMatch> = trains
+            .values()
+            .flat_map(|vehicle| {
+                let stops = SegmentStatistics::stops(&vehicle.records, stations);
+                stops
+                    .chunk_by(|a, b| {
+                        a.departure.train_number == b.arrival.train_number
+                            && a.departure.line == b.arrival.line
+                    })
+                    .filter_map(|stops| self.match_stops(stops, stations))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        matches.sort_by(|a, b| {
+            a.service_date
+                .cmp(&b.service_date)
+                .then(a.train_number.cmp(&b.train_number))
+        });
+        matches
+    }
+}
+
+/// Writes one row per stop of the `matches` of [`Timetable::match_trains`], the times are given
+/// in local time and the delays in seconds.
+pub fn write_matches_csv<W: Write>(matches: &[TripMatch], writer: W) -> io::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(
+        "line,train_number,trip_id,service_date,station,scheduled_arrival,arrival,\
+         arrival_delay,scheduled_departure,departure,departure_delay,reported_delay"
+            .split(','),
+    )?;
+    for matched in matches {
+        for stop in &matched.stops {
+            writer.write_record([
+                matched.line.clone(),
+                matched.train_number.to_string(),
+                matched.trip.id.clone(),
+                matched.service_date.to_string(),
+                stop.station.clone(),
+                local_time(stop.scheduled_arrival).to_rfc3339(),
+                local_time(stop.arrival).to_rfc3339(),
+                format!("{:.0}", stop.arrival_delay()),
+                local_time(stop.scheduled_departure).to_rfc3339(),
+                local_time(stop.departure).to_rfc3339(),
+                format!("{:.0}", stop.departure_delay()),
+                stop.reported_delay
+                    .map_or(String::new(), |d| format!("{d:.0}")),
+            ])?;
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MONDAY_MORNING, STATIONS};
+
+    /// 07:00 in seconds after the start of the service day.
+    const SEVEN: u32 = 7 * 3600;
+
+    /// A trip of the S1 through the test stations, departing A `start` seconds after the start
+    /// of the service day, boarding one minute and driving two minutes between the stations.
+    fn trip(id: &str, train_number: Option<i64>, start: u32) -> Trip {
+        Trip {
+            id: id.to_string(),
+            route: "S1".to_string(),
+            service_id: "weekdays".to_string(),
+            headsign: Some("C".to_string()),
+            train_number,
+            stops: STATIONS
+                .iter()
+                .zip(0..)
+                .map(|(&(name, longitude, latitude), i)| ScheduledStop {
+                    stop_id: name.to_lowercase(),
+                    name: name.to_string(),
+                    position: Some(Coordinate {
+                        latitude,
+                        longitude,
+                    }),
+                    arrival: start + i * 180,
+                    departure: start + i * 180 + 60,
+                })
+                .collect(),
+        }
+    }
+
+    fn timetable(trips: Vec<Trip>) -> Timetable {
+        let weekdays = Service {
+            weekdays: [true, true, true, true, true, false, false],
+            start: NaiveDate::from_ymd_opt(2023, 1, 1),
+            end: NaiveDate::from_ymd_opt(2023, 12, 31),
+            ..Service::default()
+        };
+        Timetable {
+            routes: HashMap::from([("s1".to_string(), (0..trips.len()).collect())]),
+            trips,
+            services: HashMap::from([("weekdays".to_string(), weekdays)]),
+        }
+    }
+
+    /// Matches the trip of train 6400 leaving A `delay` seconds after 07:00.
+    fn match_trip(timetable: &Timetable, delay: f64) -> Option<(String, Vec<(f64, f64)>)> {
+        let stations = test_support::stations();
+        let records = test_support::trip(6400, MONDAY_MORNING + delay * 1000.0, 60.0, 120.0);
+        let stops = SegmentStatistics::stops(&records, &stations);
+        let matched = timetable.match_stops(&stops, &stations)?;
+        assert_eq!(
+            matched.service_date,
+            NaiveDate::from_ymd_opt(2023, 11, 6).expect("valid date")
+        );
+        Some((
+            matched.trip.id.clone(),
+            matched
+                .stops
+                .iter()
+                .map(|s| (s.arrival_delay(), s.departure_delay()))
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn prefers_the_trip_with_the_train_number() {
+        let timetable = timetable(vec![
+            trip("earlier", None, SEVEN),
+            trip("other", Some(6402), SEVEN),
+            trip("numbered", Some(6400), SEVEN - 600),
+        ]);
+        let (id, delays) = match_trip(&timetable, 120.0).expect("matched");
+        assert_eq!(id, "numbered");
+        assert_eq!(delays, [(720.0, 720.0); 3]);
+    }
+
+    #[test]
+    fn matches_unnumbered_trips_by_time() {
+        let timetable = timetable(vec![
+            trip("early", None, SEVEN - 1200),
+            trip("on_time", None, SEVEN),
+            trip("late", None, SEVEN + 1200),
+        ]);
+        let (id, delays) = match_trip(&timetable, 120.0).expect("matched");
+        assert_eq!(id, "on_time");
+        assert_eq!(delays, [(120.0, 120.0); 3]);
+
+        // Too early for the only trip.
+        let timetable = self::timetable(vec![trip("late", None, SEVEN + 1200)]);
+        assert_eq!(match_trip(&timetable, 120.0), None);
+    }
+
+    #[test]
+    fn matches_only_trips_running_on_the_day() {
+        let mut timetable = timetable(vec![trip("numbered", Some(6400), SEVEN)]);
+        let service = timetable.services.get_mut("weekdays").expect("service");
+        service
+            .removed
+            .insert(NaiveDate::from_ymd_opt(2023, 11, 6).expect("valid date"));
+        assert_eq!(match_trip(&timetable, 0.0), None);
+    }
+
+    #[test]
+    fn aligns_the_stops_in_order() {
+        let mut trip = trip("numbered", Some(6400), SEVEN);
+        // A stop the train passes without stopping, and one known by name only.
+        trip.stops.insert(
+            1,
+            ScheduledStop {
+                stop_id: "x".to_string(),
+                name: "X".to_string(),
+                position: Some(Coordinate {
+                    latitude: 48.2,
+                    longitude: 11.51,
+                }),
+                arrival: SEVEN + 100,
+                departure: SEVEN + 100,
+            },
+        );
+        trip.stops[3].position = None;
+        let timetable = timetable(vec![trip]);
+        let (_, delays) = match_trip(&timetable, 30.0).expect("matched");
+        assert_eq!(delays, [(30.0, 30.0); 3]);
+
+        // The stops are aligned in order, a train running backwards does not match A again.
+        let stations = test_support::stations();
+        let mut records = test_support::trip(6400, MONDAY_MORNING, 60.0, 120.0);
+        records.reverse();
+        // Departing C on time at 07:07.
+        for (record, time) in records.iter_mut().zip(0..) {
+            record.timestamp = MONDAY_MORNING + 360_000.0 + f64::from(time) * 20_000.0;
+        }
+        let stops = SegmentStatistics::stops(&records, &stations);
+        let matched = timetable.match_stops(&stops, &stations).expect("matched");
+        let stations: Vec<&str> = matched.stops.iter().map(|s| s.station.as_str()).collect();
+        assert_eq!(stations, ["C"]);
+    }
+
+    #[test]
+    fn writes_a_row_per_matched_stop() {
+        let timetable = timetable(vec![trip("numbered", Some(6400), SEVEN)]);
+        let matches = timetable.match_trains(
+            &test_support::trains(test_support::trip(6400, MONDAY_MORNING, 60.0, 120.0)),
+            &test_support::stations(),
+        );
+        let mut csv = Vec::new();
+        write_matches_csv(&matches, &mut csv).expect("written");
+        let csv = String::from_utf8(csv).expect("valid UTF-8");
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(
+            rows[1],
+            "S1,6400,numbered,2023-11-06,A,2023-11-06T07:00:00+01:00,\
+             2023-11-06T07:00:00+01:00,0,2023-11-06T07:01:00+01:00,2023-11-06T07:01:00+01:00,0,0"
+        );
+    }
+}
@@ -0,0 +1,425 @@
+//! Converts the messages of the websocket into GTFS-Realtime feeds of vehicle positions and trip
+//! updates, which can be served over HTTP as protobuf or JSON.
+//!
+//! Only the parts of the [GTFS-Realtime specification](https://gtfs.org/realtime/reference/)
+//! that can be filled from the websocket are modelled. As there is no static timetable, the
+//! trips are identified by their train number and the routes by the name of their line.
+
+use prost::Message;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::records::{Record, State};
+use crate::response_messages::{Content, ResponseMessage};
+use crate::segments::Direction;
+
+pub const GTFS_REALTIME_VERSION: &str = "2.0";
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+pub struct FeedMessage {
+    #[prost(message, required, tag = "1")]
+    pub header: FeedHeader,
+    #[prost(message, repeated, tag = "2")]
+    pub entity: Vec<FeedEntity>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+pub struct FeedHeader {
+    #[prost(string, required, tag = "1")]
+    pub gtfs_realtime_version: String,
+    #[prost(enumeration = "Incrementality", optional, tag = "2")]
+    pub incrementality: Option<i32>,
+    /// Seconds since the unix epoch.
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Incrementality {
+    FullDataset = 0,
+    Differential = 1,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+pub struct FeedEntity {
+    #[prost(string, required, tag = "1")]
+    pub id: String,
+    #[prost(bool, optional, tag = "2")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_deleted: Option<bool>,
+    #[prost(message, optional, tag = "3")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trip_update: Option<TripUpdate>,
+    #[prost(message, optional, tag = "4")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vehicle: Option<VehiclePosition>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+pub struct TripDescriptor {
+    #[prost(string, optional, tag = "1")]
+    pub trip_id: Option<String>,
+    #[prost(enumeration = "ScheduleRelationship", optional, tag = "4")]
+    pub schedule_relationship: Option<i32>,
+    #[prost(string, optional, tag = "5")]
+    pub route_id: Option<String>,
+    #[prost(uint32, optional, tag = "6")]
+    pub direction_id: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ScheduleRelationship {
+    Scheduled = 0,
+    Added = 1,
+    Unscheduled = 2,
+    Canceled = 3,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+pub struct VehicleDescriptor {
+    #[prost(string, optional, tag = "1")]
+    pub id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub label: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+pub struct Position {
+    #[prost(float, required, tag = "1")]
+    pub latitude: f32,
+    #[prost(float, required, tag = "2")]
+    pub longitude: f32,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+pub struct VehiclePosition {
+    #[prost(message, optional, tag = "1")]
+    pub trip: Option<TripDescriptor>,
+    #[prost(message, optional, tag = "2")]
+    pub position: Option<Position>,
+    #[prost(enumeration = "VehicleStopStatus", optional, tag = "4")]
+    pub current_status: Option<i32>,
+    /// Seconds since the unix epoch.
+    #[prost(uint64, optional, tag = "5")]
+    pub timestamp: Option<u64>,
+    #[prost(message, optional, tag = "8")]
+    pub vehicle: Option<VehicleDescriptor>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum VehicleStopStatus {
+    IncomingAt = 0,
+    StoppedAt = 1,
+    InTransitTo = 2,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+pub struct TripUpdate {
+    #[prost(message, required, tag = "1")]
+    pub trip: TripDescriptor,
+    #[prost(message, optional, tag = "3")]
+    pub vehicle: Option<VehicleDescriptor>,
+    /// Seconds since the unix epoch.
+    #[prost(uint64, optional, tag = "4")]
+    pub timestamp: Option<u64>,
+    /// Current delay of the trip in seconds.
+    #[prost(int32, optional, tag = "5")]
+    pub delay: Option<i32>,
+}
+
+impl From<&Record> for TripDescriptor {
+    fn from(record: &Record) -> Self {
+        Self {
+            trip_id: Some(record.train_number.to_string()),
+            schedule_relationship: Some(if record.is_cancelled() {
+                ScheduleRelationship::Canceled as i32
+            } else {
+                ScheduleRelationship::Scheduled as i32
+            }),
+            route_id: Some(record.line.clone()),
+            direction_id: Some(match Direction::from(record.train_number) {
+                Direction::Even => 0,
+                Direction::Odd => 1,
+            }),
+        }
+    }
+}
+
+impl From<&Record> for VehicleDescriptor {
+    fn from(record: &Record) -> Self {
+        Self {
+            id: Some(record.vehicle_number.clone()),
+            label: Some(format!("{} {}", record.line, record.train_number)),
+        }
+    }
+}
+
+/// The latest state of every vehicle, from which the feeds are created.
+#[derive(Debug, Default)]
+pub struct GtfsRealtimeFeed {
+    /// Latest record per train id.
+    vehicles: HashMap<String, Record>,
+    /// Timestamp of the latest message in milliseconds.
+    timestamp: f64,
+}
+
+impl GtfsRealtimeFeed {
+    /// Vehicles without an update for this many milliseconds are left out of the feeds.
+    pub const MAX_AGE: f64 = 5.0 * 60.0 * 1000.0;
+
+    pub fn new() -> Self {
+        Self {
+            vehicles: HashMap::new(),
+            timestamp: 0.0,
+        }
+    }
+
+    /// Updates the state with a message, messages that do not concern vehicles are ignored.
+    pub fn update(&mut self, message: ResponseMessage) {
+        self.timestamp = self.timestamp.max(message.timestamp);
+        match message.content {
+            Content::TrajectorySchematic(_) => {
+                if let Ok(record) = Record::try_from(message) {
+                    self.vehicles.insert(record.train_id.clone(), record);
+                }
+            }
+            Content::DeletedVehiclesSchematic(Some(ref train_id))
+            | Content::DeletedVehicles(Some(ref train_id)) => {
+                self.vehicles.remove(train_id);
+            }
+            _ => {}
+        }
+    }
+
+    fn header(&self) -> FeedHeader {
+        FeedHeader {
+            gtfs_realtime_version: GTFS_REALTIME_VERSION.to_string(),
+            incrementality: Some(Incrementality::FullDataset as i32),
+            timestamp: Some((self.timestamp / 1000.0) as u64),
+        }
+    }
+
+    /// Current records ordered by train id, so consecutive feeds are easy to compare.
+    fn current(&self) -> Vec<(&String, &Record)> {
+        let mut current: Vec<_> = self
+            .vehicles
+            .iter()
+            .filter(|(_, r)| self.timestamp - r.timestamp <= Self::MAX_AGE)
+            .collect();
+        current.sort_by_key(|(train_id, _)| *train_id);
+        current
+    }
+
+    pub fn vehicle_positions(&self) -> FeedMessage {
+        FeedMessage {
+            header: self.header(),
+            entity: self
+                .current()
+                .into_iter()
+                .map(|(train_id, record)| FeedEntity {
+                    id: train_id.clone(),
+                    is_deleted: None,
+                    trip_update: None,
+                    vehicle: Some(VehiclePosition {
+                        trip: Some(record.into()),
+                        position: Some(Position {
+                            latitude: record.position.latitude as f32,
+                            longitude: record.position.longitude as f32,
+                        }),
+                        current_status: Some(if record.state == State::Boarding {
+                            VehicleStopStatus::StoppedAt as i32
+                        } else {
+                            VehicleStopStatus::InTransitTo as i32
+                        }),
+                        timestamp: Some((record.timestamp / 1000.0) as u64),
+                        vehicle: Some(record.into()),
+                    }),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn trip_updates(&self) -> FeedMessage {
+        FeedMessage {
+            header: self.header(),
+            entity: self
+                .current()
+                .into_iter()
+                .map(|(train_id, record)| FeedEntity {
+                    id: train_id.clone(),
+                    is_deleted: None,
+                    trip_update: Some(TripUpdate {
+                        trip: record.into(),
+                        vehicle: Some(record.into()),
+                        timestamp: Some((record.timestamp / 1000.0) as u64),
+                        delay: record.delay.map(|d| d.round() as i32),
+                    }),
+                    vehicle: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Serves the current feeds on `server`. The vehicle positions are available at
+/// `/vehicle-positions` and the trip updates at `/trip-updates`, both as protobuf, or as JSON by
+/// appending `.json`.
+pub fn serve(feed: Arc<Mutex<GtfsRealtimeFeed>>, server: tiny_http::Server) {
+    for request in server.incoming_requests() {
+        let snapshot = {
+            let feed = feed.lock().expect("feed is not poisoned");
+            match request.url().trim_end_matches('/') {
+                "/vehicle-positions" | "/vehicle-positions.json" => Some(feed.vehicle_positions()),
+                "/trip-updates" | "/trip-updates.json" => Some(feed.trip_updates()),
+                _ => None,
+            }
+        };
+        let response = match snapshot {
+            Some(snapshot) if request.url().ends_with(".json") => {
+                match serde_json::to_vec(&snapshot) {
+                    Ok(json) => (json, "application/json"),
+                    Err(err) => {
+                        eprintln!("unable to serialize the feed: {err}");
+                        let _ = request.respond(tiny_http::Response::empty(500));
+                        continue;
+                    }
+                }
+            }
+            Some(snapshot) => (snapshot.encode_to_vec(), "application/x-protobuf"),
+            None => {
+                let _ = request.respond(tiny_http::Response::empty(404));
+                continue;
+            }
+        };
+        let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], response.1.as_bytes())
+            .expect("valid header");
+        if let Err(err) =
+            request.respond(tiny_http::Response::from_data(response.0).with_header(header))
+        {
+            eprintln!("unable to respond: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn trajectory(
+        train_id: &str,
+        train_number: i64,
+        state: &str,
+        timestamp: f64,
+    ) -> ResponseMessage {
+        serde_json::from_value(json!({
+            "source": "trajectory_schematic",
+            "content": {"type": "Feature", "geometry": null, "properties": {
+                "train_id": train_id,
+                "train_number": train_number,
+                "vehicle_number": "423 001",
+                "line": {"name": "S1", "color": "#16bae7"},
+                "state": state,
+                "ride_state": "REALTIME",
+                "delay": 90400,
+                "raw_coordinates": [11.5, 48.1],
+            }},
+            "timestamp": timestamp,
+            "client_reference": null,
+        }))
+        .expect("valid message")
+    }
+
+    #[test]
+    fn creates_vehicle_positions() {
+        let mut feed = GtfsRealtimeFeed::new();
+        feed.update(trajectory("sbm_b", 6401, "BOARDING", 1699300000000.0));
+        feed.update(trajectory("sbm_a", 6402, "DRIVING", 1699300001000.0));
+
+        let message = feed.vehicle_positions();
+        assert_eq!(message.header.timestamp, Some(1699300001));
+        let ids: Vec<&str> = message.entity.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, ["sbm_a", "sbm_b"]);
+
+        let vehicle = message.entity[1]
+            .vehicle
+            .as_ref()
+            .expect("vehicle position");
+        let trip = vehicle.trip.as_ref().expect("trip");
+        assert_eq!(trip.trip_id.as_deref(), Some("6401"));
+        assert_eq!(trip.route_id.as_deref(), Some("S1"));
+        assert_eq!(trip.direction_id, Some(1));
+        assert_eq!(
+            vehicle.current_status,
+            Some(VehicleStopStatus::StoppedAt as i32)
+        );
+        assert_eq!(vehicle.position.as_ref().map(|p| p.latitude), Some(48.1));
+        assert_eq!(vehicle.timestamp, Some(1699300000));
+        assert_eq!(
+            vehicle.vehicle.as_ref().and_then(|v| v.label.as_deref()),
+            Some("S1 6401")
+        );
+        assert_eq!(
+            message.entity[0].vehicle.as_ref().unwrap().current_status,
+            Some(VehicleStopStatus::InTransitTo as i32)
+        );
+    }
+
+    #[test]
+    fn creates_trip_updates() {
+        let mut feed = GtfsRealtimeFeed::new();
+        feed.update(trajectory(
+            "sbm_a",
+            6402,
+            "JOURNEY_CANCELLED",
+            1699300000000.0,
+        ));
+
+        let message = feed.trip_updates();
+        let update = message.entity[0].trip_update.as_ref().expect("trip update");
+        assert_eq!(update.delay, Some(90));
+        assert_eq!(update.trip.direction_id, Some(0));
+        assert_eq!(
+            update.trip.schedule_relationship,
+            Some(ScheduleRelationship::Canceled as i32)
+        );
+        // Round-trips through protobuf.
+        let decoded = FeedMessage::decode(message.encode_to_vec().as_slice()).expect("protobuf");
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn leaves_out_deleted_and_stale_vehicles() {
+        let mut feed = GtfsRealtimeFeed::new();
+        feed.update(trajectory("sbm_a", 6401, "DRIVING", 1699300000000.0));
+        feed.update(trajectory("sbm_b", 6402, "DRIVING", 1699300000000.0));
+        feed.update(
+            serde_json::from_value(json!({
+                "source": "deleted_vehicles_schematic",
+                "content": "sbm_b",
+                "timestamp": 1699300001000.0,
+                "client_reference": null,
+            }))
+            .expect("valid message"),
+        );
+        assert_eq!(feed.vehicle_positions().entity.len(), 1);
+
+        feed.update(trajectory(
+            "sbm_c",
+            6403,
+            "DRIVING",
+            1699300000000.0 + GtfsRealtimeFeed::MAX_AGE + 1.0,
+        ));
+        let ids: Vec<String> = feed
+            .vehicle_positions()
+            .entity
+            .into_iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids, ["sbm_c"]);
+    }
+}
@@ -0,0 +1,403 @@
+//! Measures the headways between consecutive trains of a line and direction at every station.
+//!
+//! The departure of a train from a station is the last record in which it was boarding there.
+//! Each headway is compared to the nominal interval of its line to flag bunched trains, which
+//! follow the previous one too closely, and gaps, where passengers wait noticeably longer.
+//!
+//! The lines run more often in the peak hours than in the evening or at weekends, and there is
+//! no timetable to read the intervals from. So the nominal interval of a line is the median of
+//! all its headways in the same hour of the day and type of day, which a few bunched trains or
+//! gaps do not shift.
+
+use chrono::{Datelike, Timelike};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{self, Write};
+
+use crate::records::{local_time, Trains};
+use crate::segments::{DayType, Direction, Distribution, SegmentStatistics};
+use crate::stations::Stations;
+
+/// Identifies the departures of a line in one direction at a station.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HeadwayKey {
+    pub line: String,
+    pub direction: Direction,
+    pub station: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HeadwayClass {
+    Regular,
+    /// The train follows the previous one much closer than the nominal interval.
+    Bunched,
+    /// The train follows the previous one much later than the nominal interval.
+    Gap,
+}
+
+impl Display for HeadwayClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeadwayClass::Regular => write!(f, "regular"),
+            HeadwayClass::Bunched => write!(f, "bunched"),
+            HeadwayClass::Gap => write!(f, "gap"),
+        }
+    }
+}
+
+/// Identifies the period a nominal interval is derived for.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct IntervalKey {
+    pub line: String,
+    pub hour: u32,
+    pub day_type: DayType,
+}
+
+impl IntervalKey {
+    /// The period of a departure of `line` at `timestamp` in milliseconds.
+    pub fn new(line: &str, timestamp: f64) -> Self {
+        let time = local_time(timestamp);
+        Self {
+            line: line.to_string(),
+            hour: time.hour(),
+            day_type: time.weekday().into(),
+        }
+    }
+}
+
+/// Time between the departures of two consecutive trains.
+#[derive(Debug, Clone)]
+pub struct Headway {
+    /// Departure of the train in milliseconds since the unix epoch.
+    pub departure: f64,
+    pub train_number: i64,
+    pub previous_departure: f64,
+    pub previous_train_number: i64,
+    /// Interval in seconds the line is expected to run at at the time of the departure.
+    pub nominal_interval: f64,
+    pub class: HeadwayClass,
+}
+
+impl Headway {
+    /// Headway in seconds.
+    pub fn seconds(&self) -> f64 {
+        (self.departure - self.previous_departure) / 1000.0
+    }
+}
+
+/// Thresholds used to derive the nominal intervals and to classify the headways.
+#[derive(Debug, Clone)]
+pub struct HeadwayConfig {
+    /// Nominal interval in seconds of the periods with fewer than `min_samples` headways.
+    pub default_interval: f64,
+    /// Headways of a line in a period needed to derive its nominal interval.
+    pub min_samples: usize,
+    /// Headways shorter than this fraction of the nominal interval are bunched.
+    pub bunching_ratio: f64,
+    /// Headways longer than this multiple of the nominal interval are gaps.
+    pub gap_ratio: f64,
+    /// Headways longer than this many seconds are not measured, e.g. the break at night or
+    /// while nothing was recorded.
+    pub max_headway: f64,
+}
+
+impl Default for HeadwayConfig {
+    fn default() -> Self {
+        Self {
+            default_interval: 20.0 * 60.0,
+            min_samples: 10,
+            bunching_ratio: 0.5,
+            gap_ratio: 1.5,
+            max_headway: 90.0 * 60.0,
+        }
+    }
+}
+
+impl HeadwayConfig {
+    /// Class of a headway of `seconds` on a line running every `interval` seconds.
+    pub fn classify(&self, interval: f64, seconds: f64) -> HeadwayClass {
+        if seconds < interval * self.bunching_ratio {
+            HeadwayClass::Bunched
+        } else if seconds > interval * self.gap_ratio {
+            HeadwayClass::Gap
+        } else {
+            HeadwayClass::Regular
+        }
+    }
+}
+
+/// Aggregated headways of a line and direction at a station.
+#[derive(Debug, Clone)]
+pub struct HeadwaySummary {
+    pub key: HeadwayKey,
+    pub count: usize,
+    /// Mean headway in seconds.
+    pub mean: f64,
+    pub bunched: usize,
+    pub gaps: usize,
+}
+
+#[derive(Debug)]
+pub struct Headways {
+    config: HeadwayConfig,
+    /// Nominal intervals in seconds of the periods with enough headways.
+    intervals: HashMap<IntervalKey, f64>,
+    headways: HashMap<HeadwayKey, Vec<Headway>>,
+}
+
+impl Headways {
+    pub fn from_trains(trains: &Trains, stations: &Stations, config: HeadwayConfig) -> Self {
+        let mut departures: HashMap<HeadwayKey, Vec<(f64, i64)>> = HashMap::new();
+        for vehicle in trains.values() {
+            for stop in SegmentStatistics::stops(&vehicle.records, stations) {
+                departures
+                    .entry(HeadwayKey {
+                        line: stop.departure.line.clone(),
+                        direction: stop.departure.train_number.into(),
+                        station: stop.station.to_string(),
+                    })
+                    .or_default()
+                    .push((stop.departure.timestamp, stop.departure.train_number));
+            }
+        }
+
+        let mut headways: HashMap<HeadwayKey, Vec<Headway>> = departures
+            .into_iter()
+            .map(|(key, mut departures)| {
+                departures.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+                let headways = departures
+                    .windows(2)
+                    .filter_map(|pair| {
+                        let (
+                            (previous_departure, previous_train_number),
+                            (departure, train_number),
+                        ) = (pair[0], pair[1]);
+                        let seconds = (departure - previous_departure) / 1000.0;
+                        // The same train can be assigned to a station twice, e.g. at a terminus.
+                        if train_number == previous_train_number || seconds > config.max_headway {
+                            return None;
+                        }
+                        Some(Headway {
+                            departure,
+                            train_number,
+                            previous_departure,
+                            previous_train_number,
+                            nominal_interval: config.default_interval,
+                            class: HeadwayClass::Regular,
+                        })
+                    })
+                    .collect();
+                (key, headways)
+            })
+            .collect();
+
+        let mut samples: HashMap<IntervalKey, Distribution> = HashMap::new();
+        for (key, headways) in &headways {
+            for headway in headways {
+                samples
+                    .entry(IntervalKey::new(&key.line, headway.departure))
+                    .or_default()
+                    .push(headway.seconds());
+            }
+        }
+        let intervals: HashMap<IntervalKey, f64> = samples
+            .into_iter()
+            .filter(|(_, samples)| samples.len() >= config.min_samples)
+            .filter_map(|(key, samples)| Some((key, samples.median()?)))
+            .collect();
+
+        for (key, headways) in headways.iter_mut() {
+            for headway in headways {
+                headway.nominal_interval = intervals
+                    .get(&IntervalKey::new(&key.line, headway.departure))
+                    .copied()
+                    .unwrap_or(config.default_interval);
+                headway.class = config.classify(headway.nominal_interval, headway.seconds());
+            }
+        }
+
+        Self {
+            config,
+            intervals,
+            headways,
+        }
+    }
+
+    pub fn config(&self) -> &HeadwayConfig {
+        &self.config
+    }
+
+    /// Nominal interval in seconds of a line in a period, the default one if it had too few
+    /// headways to derive it.
+    pub fn nominal_interval(&self, key: &IntervalKey) -> f64 {
+        self.intervals
+            .get(key)
+            .copied()
+            .unwrap_or(self.config.default_interval)
+    }
+
+    /// Headways of a line and direction at a station, ordered by departure.
+    pub fn get(&self, key: &HeadwayKey) -> Option<&[Headway]> {
+        self.headways.get(key).map(Vec::as_slice)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&HeadwayKey, &[Headway])> {
+        self.headways.iter().map(|(k, v)| (k, v.as_slice()))
+    }
+
+    /// All headways classified as `class`, e.g. to list every bunching.
+    pub fn with_class(&self, class: HeadwayClass) -> Vec<(&HeadwayKey, &Headway)> {
+        let mut headways: Vec<_> = self
+            .iter()
+            .flat_map(|(key, headways)| headways.iter().map(move |h| (key, h)))
+            .filter(|(_, h)| h.class == class)
+            .collect();
+        headways.sort_by(|(a_key, a), (b_key, b)| {
+            a_key.cmp(b_key).then(a.departure.total_cmp(&b.departure))
+        });
+        headways
+    }
+
+    /// One summary per line, direction and station, sorted by the key.
+    pub fn summaries(&self) -> Vec<HeadwaySummary> {
+        let mut summaries: Vec<_> = self
+            .iter()
+            .filter(|(_, headways)| !headways.is_empty())
+            .map(|(key, headways)| HeadwaySummary {
+                key: key.clone(),
+                count: headways.len(),
+                mean: headways.iter().map(Headway::seconds).sum::<f64>() / headways.len() as f64,
+                bunched: headways
+                    .iter()
+                    .filter(|h| h.class == HeadwayClass::Bunched)
+                    .count(),
+                gaps: headways
+                    .iter()
+                    .filter(|h| h.class == HeadwayClass::Gap)
+                    .count(),
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.key.cmp(&b.key));
+        summaries
+    }
+
+    /// Writes one row per headway, the departures are given in local time.
+    pub fn write_csv<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(
+            "line,direction,station,previous_train_number,train_number,departure,headway,\
+             nominal_interval,class"
+                .split(','),
+        )?;
+        let mut keys: Vec<_> = self.headways.keys().collect();
+        keys.sort();
+        for key in keys {
+            for headway in &self.headways[key] {
+                writer.write_record([
+                    key.line.clone(),
+                    key.direction.to_string(),
+                    key.station.clone(),
+                    headway.previous_train_number.to_string(),
+                    headway.train_number.to_string(),
+                    local_time(headway.departure).to_rfc3339(),
+                    format!("{:.0}", headway.seconds()),
+                    format!("{:.0}", headway.nominal_interval),
+                    headway.class.to_string(),
+                ])?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MONDAY_MORNING};
+
+    #[test]
+    fn classifies_relative_to_the_interval() {
+        let config = HeadwayConfig::default();
+        assert_eq!(config.classify(600.0, 200.0), HeadwayClass::Bunched);
+        assert_eq!(config.classify(600.0, 300.0), HeadwayClass::Regular);
+        assert_eq!(config.classify(600.0, 540.0), HeadwayClass::Regular);
+        assert_eq!(config.classify(600.0, 900.0), HeadwayClass::Regular);
+        assert_eq!(config.classify(600.0, 1000.0), HeadwayClass::Gap);
+        assert_eq!(config.classify(1200.0, 540.0), HeadwayClass::Bunched);
+    }
+
+    #[test]
+    fn derives_the_interval_from_the_headways() {
+        let minute = 60.0 * 1000.0;
+        // Every 9 minutes from 7:00 on, and one train only 2 minutes after the one at 7:27.
+        let mut records: Vec<_> = (0..6)
+            .flat_map(|i| {
+                test_support::trip(
+                    6400 + 2 * i,
+                    MONDAY_MORNING + i as f64 * 9.0 * minute,
+                    40.0,
+                    120.0,
+                )
+            })
+            .collect();
+        records.extend(test_support::trip(
+            6420,
+            MONDAY_MORNING + 29.0 * minute,
+            40.0,
+            120.0,
+        ));
+        let headways = Headways::from_trains(
+            &test_support::trains(records),
+            &test_support::stations(),
+            HeadwayConfig::default(),
+        );
+
+        let key = IntervalKey::new("S1", MONDAY_MORNING);
+        assert_eq!(key.day_type, DayType::Weekday);
+        assert_eq!(headways.nominal_interval(&key), 9.0 * 60.0);
+        let at_b = headways
+            .get(&HeadwayKey {
+                line: "S1".to_string(),
+                direction: Direction::Even,
+                station: "B".to_string(),
+            })
+            .expect("headways at B");
+        let classes: Vec<_> = at_b.iter().map(|h| (h.train_number, h.class)).collect();
+        assert_eq!(
+            classes,
+            [
+                (6402, HeadwayClass::Regular),
+                (6404, HeadwayClass::Regular),
+                (6406, HeadwayClass::Regular),
+                (6420, HeadwayClass::Bunched),
+                (6408, HeadwayClass::Regular),
+                (6410, HeadwayClass::Regular),
+            ]
+        );
+        assert_eq!(headways.with_class(HeadwayClass::Bunched).len(), 3);
+        assert!(headways.with_class(HeadwayClass::Gap).is_empty());
+    }
+
+    #[test]
+    fn falls_back_to_the_default_interval() {
+        // Two trains 4 minutes apart are too few to derive an interval from.
+        let records = test_support::trip(6400, MONDAY_MORNING, 40.0, 120.0)
+            .into_iter()
+            .chain(test_support::trip(
+                6402,
+                MONDAY_MORNING + 240_000.0,
+                40.0,
+                120.0,
+            ));
+        let headways = Headways::from_trains(
+            &test_support::trains(records),
+            &test_support::stations(),
+            HeadwayConfig::default(),
+        );
+        let bunched = headways.with_class(HeadwayClass::Bunched);
+        assert_eq!(bunched.len(), 3);
+        assert!(bunched
+            .iter()
+            .all(|(_, h)| h.nominal_interval == HeadwayConfig::default().default_interval));
+    }
+}
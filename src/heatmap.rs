@@ -0,0 +1,271 @@
+//! Aggregates the recorded runs and stops spatially, for discussions about the infrastructure:
+//! how much delay trains pick up on every segment between two stations, how fast they run on it
+//! and how long they dwell at every station.
+//!
+//! The segments are the ones of [`Network`], travelled in both directions. The speed is the
+//! straight distance between the stations divided by the run time, so it underestimates the speed
+//! on curvy segments but is comparable over time. The aggregates can be drawn as a layer of the
+//! viewer or exported as GeoJSON with the values as properties.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{self, Write};
+
+use geojson::{Feature, FeatureCollection, GeoJson, Geometry, JsonObject, Value};
+use macroquad::color::Color;
+
+use crate::records::{Coordinate, Trains};
+use crate::render::Network;
+use crate::segments::{Distribution, SegmentStatistics};
+use crate::stations::Stations;
+
+/// Value shown by the heatmap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// Delay picked up between departing one station and arriving at the next one, in seconds.
+    DelayIncrease,
+    /// Average speed on a segment in km/h.
+    Speed,
+    /// Time spent boarding at a station in seconds.
+    Dwell,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 3] = [Metric::DelayIncrease, Metric::Speed, Metric::Dwell];
+
+    /// Values from good to bad, anything beyond is drawn in the color of the bound.
+    pub fn range(self) -> (f64, f64) {
+        match self {
+            Metric::DelayIncrease => (0.0, 120.0),
+            Metric::Speed => (80.0, 20.0),
+            Metric::Dwell => (30.0, 120.0),
+        }
+    }
+
+    pub fn unit(self) -> &'static str {
+        match self {
+            Metric::DelayIncrease | Metric::Dwell => "s",
+            Metric::Speed => "km/h",
+        }
+    }
+
+    /// Color of a value, from green over yellow to red.
+    pub fn color(self, value: f64) -> Color {
+        let (good, bad) = self.range();
+        let t = ((value - good) / (bad - good)).clamp(0.0, 1.0) as f32;
+        if t < 0.5 {
+            Color::new(2.0 * t, 0.7, 0.2, 1.0)
+        } else {
+            Color::new(1.0, 0.7 * (2.0 - 2.0 * t), 0.2 * (2.0 - 2.0 * t), 1.0)
+        }
+    }
+}
+
+impl Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Metric::DelayIncrease => write!(f, "delay increase"),
+            Metric::Speed => write!(f, "speed"),
+            Metric::Dwell => write!(f, "dwell"),
+        }
+    }
+}
+
+/// Runs observed on a segment between two stations.
+#[derive(Debug, Clone)]
+pub struct SegmentAggregate {
+    pub from: String,
+    pub to: String,
+    pub start: Coordinate,
+    pub end: Coordinate,
+    /// Change of the delay over the runs in seconds, negative if the trains recovered.
+    pub delay_increase: Distribution,
+    /// Speed of the runs in km/h.
+    pub speed: Distribution,
+}
+
+impl SegmentAggregate {
+    /// Mean of the `metric`, none for the dwell or without observations.
+    pub fn mean(&self, metric: Metric) -> Option<f64> {
+        match metric {
+            Metric::DelayIncrease => self.delay_increase.mean(),
+            Metric::Speed => self.speed.mean(),
+            Metric::Dwell => None,
+        }
+    }
+}
+
+/// Stops observed at a station.
+#[derive(Debug, Clone)]
+pub struct StationAggregate {
+    pub name: String,
+    pub position: Coordinate,
+    /// Dwell times in seconds.
+    pub dwell: Distribution,
+}
+
+impl StationAggregate {
+    /// Mean of the `metric`, only the dwell is measured at stations.
+    pub fn mean(&self, metric: Metric) -> Option<f64> {
+        match metric {
+            Metric::Dwell => self.dwell.mean(),
+            Metric::DelayIncrease | Metric::Speed => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Heatmap {
+    segments: Vec<SegmentAggregate>,
+    stations: Vec<StationAggregate>,
+}
+
+impl Heatmap {
+    pub fn from_trains(trains: &Trains, stations: &Stations) -> Self {
+        let network = Network::from_trains(trains, stations);
+        let mut segments: Vec<SegmentAggregate> = network
+            .segments()
+            .iter()
+            .map(|segment| SegmentAggregate {
+                from: segment.from.clone(),
+                to: segment.to.clone(),
+                start: segment.start.clone(),
+                end: segment.end.clone(),
+                delay_increase: Distribution::new(),
+                speed: Distribution::new(),
+            })
+            .collect();
+        let index: HashMap<(String, String), usize> = segments
+            .iter()
+            .enumerate()
+            .map(|(i, segment)| ((segment.from.clone(), segment.to.clone()), i))
+            .collect();
+        let mut dwells: HashMap<&str, Distribution> = HashMap::new();
+
+        for vehicle in trains.values() {
+            let stops = SegmentStatistics::stops(&vehicle.records, stations);
+            for stop in &stops {
+                dwells
+                    .entry(stop.station)
+                    .or_default()
+                    .push((stop.departure.timestamp - stop.arrival.timestamp) / 1000.0);
+            }
+            for pair in stops.windows(2) {
+                let (previous, next) = (&pair[0], &pair[1]);
+                if !next.continuous
+                    || previous.station == next.station
+                    || previous.departure.train_number != next.arrival.train_number
+                {
+                    continue;
+                }
+                let run_time = (next.arrival.timestamp - previous.departure.timestamp) / 1000.0;
+                if run_time <= 0.0 || run_time > SegmentStatistics::MAX_RUN_TIME {
+                    continue;
+                }
+                let key = if previous.station < next.station {
+                    (previous.station.to_string(), next.station.to_string())
+                } else {
+                    (next.station.to_string(), previous.station.to_string())
+                };
+                let Some(&i) = index.get(&key) else {
+                    continue;
+                };
+                let segment = &mut segments[i];
+                let distance = segment.start.distance(&segment.end);
+                segment.speed.push(distance / run_time * 3.6);
+                if let (Some(from), Some(to)) = (previous.departure.delay, next.arrival.delay) {
+                    segment.delay_increase.push(to - from);
+                }
+            }
+        }
+
+        Self {
+            segments,
+            stations: network
+                .stations()
+                .iter()
+                .map(|station| StationAggregate {
+                    name: station.name.clone(),
+                    position: station.position.clone(),
+                    dwell: dwells.remove(station.name.as_str()).unwrap_or_default(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn segments(&self) -> &[SegmentAggregate] {
+        &self.segments
+    }
+
+    pub fn stations(&self) -> &[StationAggregate] {
+        &self.stations
+    }
+
+    /// The segments as lines and the stations as points, with the count, mean, median and 90th
+    /// percentile of their values as properties.
+    pub fn to_geojson(&self) -> GeoJson {
+        let point = |c: &Coordinate| vec![c.longitude, c.latitude];
+        let segments = self.segments.iter().map(|segment| {
+            let mut properties = JsonObject::new();
+            properties.insert("kind".to_string(), "segment".into());
+            properties.insert("from".to_string(), segment.from.clone().into());
+            properties.insert("to".to_string(), segment.to.clone().into());
+            insert_distribution(&mut properties, "runs", "speed", &segment.speed);
+            insert_distribution(
+                &mut properties,
+                "delays",
+                "delay_increase",
+                &segment.delay_increase,
+            );
+            feature(
+                Value::LineString(vec![point(&segment.start), point(&segment.end)]),
+                properties,
+            )
+        });
+        let stations = self.stations.iter().map(|station| {
+            let mut properties = JsonObject::new();
+            properties.insert("kind".to_string(), "station".into());
+            properties.insert("name".to_string(), station.name.clone().into());
+            insert_distribution(&mut properties, "stops", "dwell", &station.dwell);
+            feature(Value::Point(point(&station.position)), properties)
+        });
+        GeoJson::FeatureCollection(FeatureCollection {
+            bbox: None,
+            features: segments.chain(stations).collect(),
+            foreign_members: None,
+        })
+    }
+
+    pub fn write_geojson<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "{}", self.to_geojson())
+    }
+}
+
+fn feature(value: Value, properties: JsonObject) -> Feature {
+    Feature {
+        bbox: None,
+        geometry: Some(Geometry::new(value)),
+        id: None,
+        properties: Some(properties),
+        foreign_members: None,
+    }
+}
+
+/// Adds the number of samples as `count` and the statistics prefixed with `name`, the statistics
+/// are null without samples.
+fn insert_distribution(
+    properties: &mut JsonObject,
+    count: &str,
+    name: &str,
+    distribution: &Distribution,
+) {
+    let round = |value: Option<f64>| value.map(|v| (v * 10.0).round() / 10.0);
+    properties.insert(count.to_string(), distribution.len().into());
+    for (statistic, value) in [
+        ("mean", distribution.mean()),
+        ("median", distribution.median()),
+        ("p90", distribution.percentile(0.9)),
+    ] {
+        properties.insert(format!("{name}_{statistic}"), round(value).into());
+    }
+}
@@ -0,0 +1,440 @@
+//! Correlates the messages of the news ticker with the delays and cancellations observed on the
+//! affected lines.
+//!
+//! A message is considered an incident from the first time it was part of the news ticker until a
+//! news ticker without it arrives. As the news ticker is only sent when it changes, a message
+//! still shown at the end of a recording lasts until then, see [`Incidents::extend`]. Records of
+//! an affected line during that window belong to the incident, all other records of the line
+//! form the baseline it is compared to. [`Incidents::split`] separates them the same way, so
+//! predictors can be trained and evaluated without the incidents.
+//!
+//! The facts parsed from the text of a message by [`crate::news`], like the segments and the
+//! validity window, are kept with its incident, so a planner can avoid the affected segments.
+
+use chrono::{DateTime, FixedOffset};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::news::{self, DisruptionKind, ValidityWindow};
+use crate::records::{local_time, Record, Trains};
+use crate::response_messages::{NewsTickerMessage, SbmNewsTicker};
+use crate::stations::{Station, Stations};
+
+#[derive(Debug, Clone)]
+pub struct Incident {
+    pub title: String,
+    /// Names of the lines of the message and the ones mentioned in its text, all lines are
+    /// affected if it is empty.
+    pub lines: Vec<String>,
+    pub content: String,
+    pub updated: Option<DateTime<FixedOffset>>,
+    /// Segments given as `zwischen A und B`, with the station names as they are written.
+    pub segments: Vec<(String, String)>,
+    pub kinds: Vec<DisruptionKind>,
+    /// Period the message says it is valid for.
+    pub validity: ValidityWindow,
+    /// Timestamp of the first news ticker containing the message, in milliseconds.
+    pub first_seen: f64,
+    /// Timestamp of the first news ticker without the message, in milliseconds, or the latest
+    /// one the message is known to be shown at while it is still part of the news ticker.
+    pub last_seen: f64,
+}
+
+impl Incident {
+    /// Parses a message of a news ticker that has been received at `timestamp`.
+    pub fn new(message: &NewsTickerMessage, timestamp: f64) -> Self {
+        Self {
+            title: message.title.clone(),
+            lines: news::lines(message),
+            content: message.content.clone(),
+            updated: DateTime::parse_from_rfc3339(&message.updated).ok(),
+            segments: news::segments(message),
+            kinds: news::kinds(message),
+            validity: news::validity(message, timestamp),
+            first_seen: timestamp,
+            last_seen: timestamp,
+        }
+    }
+
+    pub fn affects(&self, line: &str) -> bool {
+        self.lines.is_empty()
+            || self
+                .lines
+                .iter()
+                .any(|l| l.trim().eq_ignore_ascii_case(line))
+    }
+
+    pub fn contains(&self, timestamp: f64) -> bool {
+        self.first_seen <= timestamp && timestamp <= self.last_seen
+    }
+
+    /// Duration the message was shown in seconds.
+    pub fn duration(&self) -> f64 {
+        (self.last_seen - self.first_seen) / 1000.0
+    }
+
+    /// Whether the disruption is in effect at `timestamp` according to its validity window.
+    /// Without an explicit start or end, the time the message was shown is used instead.
+    pub fn is_in_effect(&self, timestamp: f64) -> bool {
+        let time = local_time(timestamp);
+        let start = self
+            .validity
+            .start
+            .unwrap_or_else(|| local_time(self.first_seen));
+        let end = self
+            .validity
+            .end
+            .unwrap_or_else(|| local_time(self.last_seen));
+        start <= time && time <= end
+    }
+
+    /// Whether the segment between the stations `from` and `to` of `line` is affected. A message
+    /// without any segment affects the whole line.
+    pub fn affects_segment(&self, line: &str, from: &str, to: &str) -> bool {
+        if !self.affects(line) {
+            return false;
+        }
+        let (from, to) = (normalize(from), normalize(to));
+        self.segments.is_empty()
+            || self.segments.iter().any(|(a, b)| {
+                let (a, b) = (normalize(a), normalize(b));
+                (a == from && b == to) || (a == to && b == from)
+            })
+    }
+
+    /// Known stations whose whole name is mentioned in the title or the content.
+    pub fn mentioned_stations<'a>(&self, stations: &'a Stations) -> Vec<&'a Station> {
+        let text = format!(
+            " {} ",
+            normalize(&format!("{}\n{}", self.title, self.content))
+        );
+        let mut mentioned: Vec<&Station> = stations
+            .iter()
+            .filter(|s| text.contains(&format!(" {} ", normalize(&s.name))))
+            .collect();
+        mentioned.sort_by(|a, b| a.name.cmp(&b.name));
+        mentioned
+    }
+}
+
+/// Lower case words of a station name separated by single spaces, so names written with other
+/// punctuation, e.g. `München-Pasing` and `München Pasing`, compare equal.
+fn normalize(name: &str) -> String {
+    name.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Delays and cancellations of a set of records.
+#[derive(Debug, Clone, Default)]
+pub struct DelayStatistics {
+    pub observations: usize,
+    /// Mean delay in seconds of the observations that report a delay.
+    pub mean_delay: Option<f64>,
+    pub cancelled: usize,
+}
+
+impl DelayStatistics {
+    pub fn from_records<'a>(records: impl IntoIterator<Item = &'a Record>) -> Self {
+        let (mut observations, mut cancelled, mut delays, mut delay_sum) = (0, 0, 0, 0.0);
+        for record in records {
+            observations += 1;
+            if record.is_cancelled() {
+                cancelled += 1;
+            }
+            if let Some(delay) = record.delay {
+                delays += 1;
+                delay_sum += delay;
+            }
+        }
+        Self {
+            observations,
+            mean_delay: (delays > 0).then(|| delay_sum / f64::from(delays)),
+            cancelled,
+        }
+    }
+
+    /// Share of the observations that are cancelled.
+    pub fn cancelled_share(&self) -> Option<f64> {
+        (self.observations > 0).then(|| self.cancelled as f64 / self.observations as f64)
+    }
+}
+
+/// Measured impact of an incident on one of its lines.
+#[derive(Debug, Clone)]
+pub struct LineImpact {
+    pub line: String,
+    pub incident: DelayStatistics,
+    pub baseline: DelayStatistics,
+}
+
+impl LineImpact {
+    /// Increase of the mean delay in seconds compared to the baseline.
+    pub fn delay_increase(&self) -> Option<f64> {
+        Some(self.incident.mean_delay? - self.baseline.mean_delay?)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IncidentImpact<'a> {
+    pub incident: &'a Incident,
+    pub lines: Vec<LineImpact>,
+}
+
+#[derive(Debug, Default)]
+pub struct Incidents {
+    incidents: Vec<Incident>,
+    /// Indices of the incidents that were part of the latest news ticker.
+    open: HashMap<(String, Vec<String>), usize>,
+}
+
+impl Incidents {
+    pub fn new() -> Self {
+        Self {
+            incidents: Vec::new(),
+            open: HashMap::new(),
+        }
+    }
+
+    /// Updates the incidents with a news ticker received at `timestamp`. Messages that are no
+    /// longer part of the news ticker are closed at `timestamp`, reappearing ones start a new
+    /// incident. The facts of a message are parsed again if its content changed.
+    pub fn insert(&mut self, ticker: &SbmNewsTicker, timestamp: f64) {
+        self.extend(timestamp);
+        let mut open = HashMap::new();
+        for message in &ticker.messages {
+            let key = (message.title.clone(), message.lines.clone());
+            let index = match self.open.get(&key) {
+                Some(&index) => {
+                    let incident = &mut self.incidents[index];
+                    if incident.content != message.content
+                        || incident.updated != DateTime::parse_from_rfc3339(&message.updated).ok()
+                    {
+                        let first_seen = incident.first_seen;
+                        *incident = Incident::new(message, timestamp);
+                        incident.first_seen = first_seen;
+                    }
+                    index
+                }
+                None => {
+                    self.incidents.push(Incident::new(message, timestamp));
+                    self.incidents.len() - 1
+                }
+            };
+            open.insert(key, index);
+        }
+        self.open = open;
+    }
+
+    /// Extends the incidents that are part of the latest news ticker until `timestamp`, e.g. the
+    /// end of the recording or the current time.
+    pub fn extend(&mut self, timestamp: f64) {
+        for &index in self.open.values() {
+            let incident = &mut self.incidents[index];
+            incident.last_seen = incident.last_seen.max(timestamp);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Incident> {
+        self.incidents.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.incidents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.incidents.is_empty()
+    }
+
+    /// Incidents affecting `line` at `timestamp`.
+    pub fn active<'a>(
+        &'a self,
+        line: &'a str,
+        timestamp: f64,
+    ) -> impl Iterator<Item = &'a Incident> + 'a {
+        self.incidents
+            .iter()
+            .filter(move |i| i.contains(timestamp) && i.affects(line))
+    }
+
+    pub fn is_disrupted(&self, line: &str, timestamp: f64) -> bool {
+        self.active(line, timestamp).next().is_some()
+    }
+
+    /// Incidents in effect at `timestamp` according to their validity windows.
+    pub fn in_effect(&self, timestamp: f64) -> impl Iterator<Item = &Incident> {
+        self.incidents
+            .iter()
+            .filter(move |i| i.is_in_effect(timestamp))
+    }
+
+    /// Whether a trip on `line` between the stations `from` and `to` is known to be disrupted
+    /// at `timestamp`, so it can be avoided when planning.
+    pub fn is_segment_disrupted(&self, line: &str, from: &str, to: &str, timestamp: f64) -> bool {
+        self.in_effect(timestamp)
+            .any(|i| i.affects_segment(line, from, to))
+    }
+
+    /// Splits the records into the ones during incidents of their line and the regular ones,
+    /// so models can be trained without the incidents or for them separately.
+    pub fn split(&self, trains: &Trains) -> (Trains, Trains) {
+        trains.partition(|record| self.is_disrupted(&record.line, record.timestamp))
+    }
+
+    /// Compares the delays and cancellations of every incident to the baseline of its lines.
+    pub fn impacts(&self, trains: &Trains) -> Vec<IncidentImpact<'_>> {
+        let mut by_line: HashMap<&str, Vec<&Record>> = HashMap::new();
+        for record in trains.values().flat_map(|v| v.records.iter()) {
+            by_line.entry(&record.line).or_default().push(record);
+        }
+        for records in by_line.values_mut() {
+            records.sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+        }
+
+        let baselines: HashMap<&str, DelayStatistics> = by_line
+            .iter()
+            .map(|(line, records)| {
+                let regular = records
+                    .iter()
+                    .copied()
+                    .filter(|r| !self.is_disrupted(line, r.timestamp));
+                (*line, DelayStatistics::from_records(regular))
+            })
+            .collect();
+
+        self.incidents
+            .iter()
+            .map(|incident| {
+                let mut lines: Vec<LineImpact> = by_line
+                    .iter()
+                    .filter(|(line, _)| incident.affects(line))
+                    .map(|(line, records)| {
+                        let start = records.partition_point(|r| r.timestamp < incident.first_seen);
+                        let end = records.partition_point(|r| r.timestamp <= incident.last_seen);
+                        LineImpact {
+                            line: line.to_string(),
+                            incident: DelayStatistics::from_records(
+                                records[start..end].iter().copied(),
+                            ),
+                            baseline: baselines[line].clone(),
+                        }
+                    })
+                    .filter(|impact| impact.incident.observations > 0)
+                    .collect();
+                lines.sort_by(|a, b| a.line.cmp(&b.line));
+                IncidentImpact { incident, lines }
+            })
+            .collect()
+    }
+
+    /// Writes one row per incident and affected line, delays are given in seconds. The stations
+    /// mentioned by the message are separated by semicolons.
+    pub fn write_csv<W: Write>(
+        &self,
+        trains: &Trains,
+        stations: &Stations,
+        writer: W,
+    ) -> io::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(
+            "title,first_seen,last_seen,line,stations,observations,mean_delay,\
+             baseline_mean_delay,delay_increase,cancelled_share,baseline_cancelled_share"
+                .split(','),
+        )?;
+        let format = |value: Option<f64>| value.map_or(String::new(), |v| format!("{v:.1}"));
+        let share = |value: Option<f64>| value.map_or(String::new(), |v| format!("{v:.3}"));
+        for impact in self.impacts(trains) {
+            let mentioned: Vec<&str> = impact
+                .incident
+                .mentioned_stations(stations)
+                .iter()
+                .map(|s| s.name.as_str())
+                .collect();
+            for line in &impact.lines {
+                writer.write_record([
+                    impact.incident.title.clone(),
+                    local_time(impact.incident.first_seen).to_rfc3339(),
+                    local_time(impact.incident.last_seen).to_rfc3339(),
+                    line.line.clone(),
+                    mentioned.join(";"),
+                    line.incident.observations.to_string(),
+                    format(line.incident.mean_delay),
+                    format(line.baseline.mean_delay),
+                    format(line.delay_increase()),
+                    share(line.incident.cancelled_share()),
+                    share(line.baseline.cancelled_share()),
+                ])?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MONDAY_MORNING};
+
+    /// A disruption between A and B of the S1, shown from one to two minutes after
+    /// [`MONDAY_MORNING`].
+    fn incidents() -> Incidents {
+        let ticker = |messages| SbmNewsTicker {
+            incident_program: None,
+            messages,
+        };
+        let mut incidents = Incidents::new();
+        incidents.insert(
+            &ticker(vec![NewsTickerMessage {
+                title: "Störung".to_string(),
+                lines: vec!["S1".to_string()],
+                content: "Zwischen A und B kommt es zu Verspätungen.".to_string(),
+                updated: String::new(),
+            }]),
+            MONDAY_MORNING + 60_000.0,
+        );
+        incidents.insert(&ticker(Vec::new()), MONDAY_MORNING + 120_000.0);
+        incidents
+    }
+
+    fn count(trains: &Trains) -> usize {
+        trains.values().map(|v| v.records.len()).sum()
+    }
+
+    #[test]
+    fn splits_the_records_during_incidents_of_their_line() {
+        let mut records = test_support::trip(6400, MONDAY_MORNING, 60.0, 120.0);
+        let mut other_line = test_support::trip(6501, MONDAY_MORNING, 60.0, 120.0);
+        for record in &mut other_line {
+            record.line = "S2".to_string();
+        }
+        records.extend(other_line);
+        let trains = test_support::trains(records);
+
+        let (disrupted, regular) = incidents().split(&trains);
+        assert_eq!(count(&disrupted), 4);
+        assert!(disrupted.values().all(|v| v.number == "423 6400"));
+        assert_eq!(count(&disrupted) + count(&regular), count(&trains));
+    }
+
+    #[test]
+    fn finds_the_disrupted_segments_and_mentioned_stations() {
+        let incidents = incidents();
+        let during = MONDAY_MORNING + 90_000.0;
+        assert!(incidents.is_segment_disrupted("S1", "B", "A", during));
+        assert!(!incidents.is_segment_disrupted("S1", "B", "C", during));
+        assert!(!incidents.is_segment_disrupted("S2", "A", "B", during));
+        assert!(!incidents.is_segment_disrupted("S1", "A", "B", MONDAY_MORNING + 180_000.0));
+
+        let stations = test_support::stations();
+        let incident = incidents.iter().next().expect("one incident");
+        let mentioned: Vec<&str> = incident
+            .mentioned_stations(&stations)
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect();
+        assert_eq!(mentioned, ["A", "B"]);
+    }
+}
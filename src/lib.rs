@@ -1 +1,27 @@
+pub mod backtest;
+pub mod circulation;
+pub mod columnar;
+pub mod dashboard;
+pub mod database;
+pub mod gtfs;
+pub mod gtfs_realtime;
+pub mod headways;
+pub mod heatmap;
+pub mod incidents;
+pub mod merge;
+pub mod news;
+pub mod predictions;
+pub mod propagation;
+pub mod recording;
+pub mod records;
+pub mod render;
+pub mod report;
 pub mod response_messages;
+pub mod segments;
+pub mod source;
+pub mod stations;
+#[cfg(test)]
+mod test_support;
+pub mod time_index;
+pub mod tracker;
+pub mod viewer;
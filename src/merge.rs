@@ -0,0 +1,391 @@
+//! Merges overlapping recordings, e.g. of redundant scrapers, into one time-ordered stream.
+//!
+//! The recordings are read in parallel and the lines are emitted in the order of their
+//! timestamps. Messages can arrive slightly out of order, so they are held back for
+//! [`MergeConfig::reorder_window`] before being emitted, messages arriving even later are
+//! dropped to keep the order. Trajectory updates are duplicates if they have the same train id
+//! and the same timestamp in their properties, the time of the position rather than of the
+//! message, which also removes the snapshots sent again after reconnecting. All other messages
+//! are duplicates if another input received the same source and content within the reorder
+//! window, as the feed repeats e.g. the news ticker and those repetitions are kept.
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Lines, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Just the parts of a message needed to order and deduplicate it.
+#[derive(Deserialize)]
+struct Header {
+    source: Option<String>,
+    timestamp: f64,
+    #[serde(default)]
+    content: Value,
+}
+
+impl Header {
+    fn key(self) -> MessageKey {
+        if let Some("trajectory_schematic" | "trajectory") = self.source.as_deref() {
+            let train_id = self.content.pointer("/properties/train_id");
+            let timestamp = self.content.pointer("/properties/timestamp");
+            if let (Some(Value::String(train_id)), Some(timestamp)) =
+                (train_id, timestamp.and_then(Value::as_f64))
+            {
+                return MessageKey::Trajectory {
+                    train_id: train_id.clone(),
+                    timestamp: timestamp.to_bits(),
+                };
+            }
+        }
+        // The keys of objects are sorted, so equal contents are serialized the same way.
+        MessageKey::Content {
+            source: self.source,
+            content: self.content.to_string(),
+        }
+    }
+}
+
+/// Identifies duplicate messages.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum MessageKey {
+    Trajectory {
+        train_id: String,
+        timestamp: u64,
+    },
+    Content {
+        source: Option<String>,
+        content: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct MergeConfig {
+    /// Time in milliseconds without any message that is reported as a gap.
+    pub max_gap: f64,
+    /// Time in milliseconds a message is held back to sort it among later ones.
+    pub reorder_window: f64,
+    /// Time in milliseconds for which emitted messages are remembered to drop duplicates.
+    pub dedup_window: f64,
+}
+
+impl Default for MergeConfig {
+    fn default() -> Self {
+        Self {
+            max_gap: 60.0 * 1000.0,
+            reorder_window: 10.0 * 1000.0,
+            dedup_window: 15.0 * 60.0 * 1000.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InputReport {
+    pub path: PathBuf,
+    pub messages: usize,
+    /// Lines that are not a message.
+    pub invalid: usize,
+    /// Periods in milliseconds without any message of this input.
+    pub gaps: Vec<Range<f64>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MergeReport {
+    pub inputs: Vec<InputReport>,
+    /// Messages written to the output.
+    pub messages: usize,
+    pub duplicates: usize,
+    /// Messages that arrived after later ones had already been emitted.
+    pub late: usize,
+    /// Periods in milliseconds without any message in all inputs.
+    pub gaps: Vec<Range<f64>>,
+}
+
+/// A line waiting to be emitted, ordered by its timestamp and then by its input.
+struct Pending {
+    timestamp: f64,
+    input: usize,
+    key: MessageKey,
+    line: String,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.timestamp
+            .total_cmp(&other.timestamp)
+            .then(self.input.cmp(&other.input))
+    }
+}
+
+/// Tracks the periods without messages.
+#[derive(Debug, Default)]
+struct GapDetector {
+    latest: Option<f64>,
+    gaps: Vec<Range<f64>>,
+}
+
+impl GapDetector {
+    fn observe(&mut self, timestamp: f64, max_gap: f64) {
+        match self.latest {
+            Some(latest) if timestamp <= latest => {}
+            Some(latest) => {
+                if timestamp - latest > max_gap {
+                    self.gaps.push(latest..timestamp);
+                }
+                self.latest = Some(timestamp);
+            }
+            None => self.latest = Some(timestamp),
+        }
+    }
+}
+
+struct Input {
+    lines: Lines<BufReader<File>>,
+    report: InputReport,
+    gaps: GapDetector,
+}
+
+impl Input {
+    /// Reads the next message of the input, skipping invalid lines.
+    fn next(&mut self, index: usize, config: &MergeConfig) -> io::Result<Option<Pending>> {
+        for line in self.lines.by_ref() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let Ok(header) = serde_json::from_str::<Header>(&line) else {
+                self.report.invalid += 1;
+                continue;
+            };
+            self.report.messages += 1;
+            self.gaps.observe(header.timestamp, config.max_gap);
+            let timestamp = header.timestamp;
+            let key = header.key();
+            return Ok(Some(Pending {
+                timestamp,
+                input: index,
+                key,
+                line,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+/// Merges the recordings at `inputs` into `writer`, one message per line.
+pub fn merge<W: Write>(
+    inputs: &[impl AsRef<Path>],
+    config: &MergeConfig,
+    mut writer: W,
+) -> io::Result<MergeReport> {
+    let mut readers = inputs
+        .iter()
+        .map(|path| {
+            Ok(Input {
+                lines: BufReader::new(File::open(path)?).lines(),
+                report: InputReport {
+                    path: path.as_ref().to_path_buf(),
+                    ..InputReport::default()
+                },
+                gaps: GapDetector::default(),
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // The next message of every input, to pick the earliest one.
+    let mut heads = BinaryHeap::new();
+    for (index, reader) in readers.iter_mut().enumerate() {
+        if let Some(pending) = reader.next(index, config)? {
+            heads.push(Reverse(pending));
+        }
+    }
+
+    let mut window: BinaryHeap<Reverse<Pending>> = BinaryHeap::new();
+    // Keys of the emitted messages with their timestamp and input, to drop duplicates.
+    let mut seen: HashMap<MessageKey, (f64, usize)> = HashMap::new();
+    let mut gaps = GapDetector::default();
+    let (mut messages, mut duplicates, mut late) = (0, 0, 0);
+    let mut latest = f64::NEG_INFINITY;
+    let mut emitted = f64::NEG_INFINITY;
+    let mut pruned = f64::NEG_INFINITY;
+
+    loop {
+        let next = heads.pop().map(|Reverse(p)| p);
+        if let Some(pending) = &next {
+            latest = latest.max(pending.timestamp);
+        }
+        // Everything older than the window can no longer be preceded by another message.
+        while window.peek().is_some_and(|Reverse(p)| {
+            next.is_none() || p.timestamp < latest - config.reorder_window
+        }) {
+            let Reverse(pending) = window.pop().expect("peeked");
+            let duplicate = seen
+                .get(&pending.key)
+                .is_some_and(|&(timestamp, input)| match pending.key {
+                    MessageKey::Trajectory { .. } => true,
+                    MessageKey::Content { .. } => {
+                        input != pending.input
+                            && pending.timestamp - timestamp <= config.reorder_window
+                    }
+                });
+            if duplicate {
+                duplicates += 1;
+            } else if pending.timestamp < emitted {
+                late += 1;
+            } else {
+                gaps.observe(pending.timestamp, config.max_gap);
+                writeln!(writer, "{}", pending.line)?;
+                messages += 1;
+                emitted = pending.timestamp;
+                seen.insert(pending.key, (pending.timestamp, pending.input));
+            }
+        }
+        if emitted - pruned > config.dedup_window {
+            seen.retain(|_, (timestamp, _)| *timestamp >= emitted - config.dedup_window);
+            pruned = emitted;
+        }
+
+        let Some(pending) = next else {
+            break;
+        };
+        let input = pending.input;
+        window.push(Reverse(pending));
+        if let Some(pending) = readers[input].next(input, config)? {
+            heads.push(Reverse(pending));
+        }
+    }
+    writer.flush()?;
+
+    Ok(MergeReport {
+        inputs: readers
+            .into_iter()
+            .map(|reader| InputReport {
+                gaps: reader.gaps.gaps,
+                ..reader.report
+            })
+            .collect(),
+        messages,
+        duplicates,
+        late,
+        gaps: gaps.gaps,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn trajectory(train_id: &str, position_time: f64, received: f64) -> String {
+        format!(
+            r#"{{"source": "trajectory_schematic", "content": {{"type": "Feature", "geometry": null, "properties": {{"train_id": "{train_id}", "timestamp": {position_time}}}}}, "timestamp": {received}}}"#
+        )
+    }
+
+    fn status(received: f64) -> String {
+        format!(
+            r#"{{"source": "websocket", "content": {{"status": "open"}}, "timestamp": {received}}}"#
+        )
+    }
+
+    /// Writes the inputs into a fresh directory and merges them.
+    fn merge_lines(name: &str, inputs: &[&[String]]) -> (Vec<String>, MergeReport) {
+        let directory = TempDir::new(&format!("merge-{name}"));
+        let paths: Vec<PathBuf> = inputs
+            .iter()
+            .enumerate()
+            .map(|(i, lines)| {
+                let path = directory.path().join(format!("{i}.jsonl"));
+                std::fs::write(&path, lines.join("\n")).expect("written");
+                path
+            })
+            .collect();
+        let mut output = Vec::new();
+        let report = merge(&paths, &MergeConfig::default(), &mut output).expect("merged");
+        let output = String::from_utf8(output).expect("utf-8");
+        (output.lines().map(str::to_string).collect(), report)
+    }
+
+    #[test]
+    fn drops_snapshots_sent_again_after_reconnecting() {
+        let input = vec![
+            trajectory("a", 1000.0, 1000.0),
+            trajectory("b", 1500.0, 1500.0),
+            status(2000.0),
+            // The snapshot after reconnecting repeats the latest positions.
+            status(5000.0),
+            trajectory("a", 1000.0, 5001.0),
+            trajectory("b", 1500.0, 5002.0),
+            trajectory("a", 6000.0, 6000.0),
+        ];
+        let (lines, report) = merge_lines("reconnect", &[&input]);
+        assert_eq!(
+            lines,
+            [
+                input[0].as_str(),
+                &input[1],
+                &input[2],
+                &input[3],
+                &input[6]
+            ]
+        );
+        assert_eq!(report.duplicates, 2);
+        assert_eq!(report.messages, 5);
+    }
+
+    #[test]
+    fn drops_other_messages_received_at_different_times() {
+        let news = |received: f64| {
+            format!(
+                r#"{{"source": "sbm_newsticker", "content": {{"incident_program": null, "messages": [{{"title": "Störung", "lines": ["S1"], "content": "", "updated": "2023-11-06T20:00:00+01:00"}}]}}, "timestamp": {received}}}"#
+            )
+        };
+        let deleted = |received: f64| {
+            format!(
+                r#"{{"source": "deleted_vehicles_schematic", "content": "sbm_6401", "timestamp": {received}}}"#
+            )
+        };
+        // The news ticker is repeated by the feed, which is kept.
+        let first = vec![news(1000.0), deleted(2000.0), status(3000.0), news(31000.0)];
+        let second = vec![news(1020.0), deleted(2020.0), status(3020.0), news(31020.0)];
+        let (lines, report) = merge_lines("other", &[&first, &second]);
+        assert_eq!(lines, first);
+        assert_eq!(report.duplicates, 4);
+    }
+
+    #[test]
+    fn merges_redundant_recordings_in_order() {
+        let first = vec![
+            trajectory("a", 1000.0, 1000.0),
+            trajectory("a", 3000.0, 3000.0),
+        ];
+        // Received slightly later by the second scraper, which also missed an update.
+        let second = vec![
+            trajectory("a", 1000.0, 1010.0),
+            trajectory("b", 2000.0, 2010.0),
+            trajectory("a", 3000.0, 3010.0),
+        ];
+        let (lines, report) = merge_lines("redundant", &[&first, &second]);
+        assert_eq!(lines, [first[0].as_str(), &second[1], &first[1]]);
+        assert_eq!(report.duplicates, 2);
+        assert_eq!(report.inputs[1].messages, 3);
+        assert!(report.gaps.is_empty());
+    }
+}
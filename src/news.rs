@@ -0,0 +1,312 @@
+//! Extracts structured facts from the free text messages of the news ticker.
+//!
+//! The messages are written in German, so the affected lines, the segments (`zwischen A und B`),
+//! the kind of the disruption and its validity window are recognized by the wording and patterns
+//! that are commonly used in them. They are stored with the incidents of the messages, see
+//! [`crate::incidents::Incident`].
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, TimeZone};
+use chrono_tz::{Europe::Berlin, Tz};
+use regex::Regex;
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+use crate::records::local_time;
+use crate::response_messages::NewsTickerMessage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DisruptionKind {
+    Closure,
+    Delay,
+    ReplacementBus,
+    Construction,
+}
+
+impl DisruptionKind {
+    /// Words indicating the kind, matched against the lower case words of the message.
+    fn keywords(&self) -> &'static [&'static str] {
+        match self {
+            DisruptionKind::Closure => &[
+                "sperrung",
+                "streckensperrung",
+                "gesperrt",
+                "unterbrochen",
+                "streckenunterbrechung",
+                "unterbrechung",
+            ],
+            DisruptionKind::Delay => &[
+                "verspätung",
+                "verspätungen",
+                "verspätet",
+                "verzögerung",
+                "verzögerungen",
+                "verzögert",
+            ],
+            DisruptionKind::ReplacementBus => &[
+                "sev",
+                "schienenersatzverkehr",
+                "ersatzverkehr",
+                "ersatzbusse",
+                "busse",
+            ],
+            DisruptionKind::Construction => &[
+                "bauarbeiten",
+                "baumaßnahme",
+                "baumaßnahmen",
+                "gleisarbeiten",
+            ],
+        }
+    }
+
+    const ALL: [DisruptionKind; 4] = [
+        DisruptionKind::Closure,
+        DisruptionKind::Delay,
+        DisruptionKind::ReplacementBus,
+        DisruptionKind::Construction,
+    ];
+}
+
+impl Display for DisruptionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisruptionKind::Closure => write!(f, "closure"),
+            DisruptionKind::Delay => write!(f, "delay"),
+            DisruptionKind::ReplacementBus => write!(f, "replacement bus"),
+            DisruptionKind::Construction => write!(f, "construction"),
+        }
+    }
+}
+
+/// Period a message is valid for, open ends are unknown.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidityWindow {
+    pub start: Option<DateTime<Tz>>,
+    pub end: Option<DateTime<Tz>>,
+}
+
+impl ValidityWindow {
+    /// Finds the dates (`24.12.`, `24.12.2023`) and times (`5 Uhr`, `5.30 Uhr`, `05:30`) in the
+    /// text. Missing dates and years are taken from `reference`, the time the message was
+    /// updated.
+    pub fn parse(text: &str, reference: DateTime<Tz>) -> Self {
+        static PATTERN: OnceLock<Regex> = OnceLock::new();
+        let pattern = PATTERN.get_or_init(|| {
+            Regex::new(
+                r"(?x)
+                (?P<date>\b(?P<day>\d{1,2})\.(?P<month>\d{1,2})\.(?P<year>\d{4})?)
+                | (?P<time>\b(?P<hour>\d{1,2})(?:[:.](?P<minute>\d{2}))?\s*Uhr\b
+                    | \b(?P<hour_colon>\d{1,2}):(?P<minute_colon>\d{2})\b)",
+            )
+            .expect("valid pattern")
+        });
+
+        // Every point is a date with an optional time, a time always belongs to the last date.
+        let mut points: Vec<(usize, NaiveDate, Option<NaiveTime>, bool)> = Vec::new();
+        let mut current_date = reference.date_naive();
+        for captures in pattern.captures_iter(text) {
+            let number = |name: &str| captures.name(name).and_then(|m| m.as_str().parse().ok());
+            let position = captures.get(0).map_or(0, |m| m.start());
+            if captures.name("date").is_some() {
+                let (Some(day), Some(month)) = (number("day"), number("month")) else {
+                    continue;
+                };
+                let year = number("year").map(|y: u32| y as i32).unwrap_or_else(|| {
+                    // A date far in the past most probably refers to the next year.
+                    if month + 6 < reference.month() {
+                        reference.year() + 1
+                    } else {
+                        reference.year()
+                    }
+                });
+                let Some(date) = NaiveDate::from_ymd_opt(year, month, day) else {
+                    continue;
+                };
+                current_date = date;
+                points.push((position, date, None, true));
+            } else {
+                let hour = number("hour").or_else(|| number("hour_colon"));
+                let minute = number("minute").or_else(|| number("minute_colon"));
+                let Some(time) =
+                    hour.and_then(|h| NaiveTime::from_hms_opt(h, minute.unwrap_or(0), 0))
+                else {
+                    continue;
+                };
+                match points.last_mut() {
+                    Some((_, date, time_of_date @ None, true)) if *date == current_date => {
+                        *time_of_date = Some(time);
+                    }
+                    _ => points.push((position, current_date, Some(time), false)),
+                }
+            }
+        }
+
+        let to_local = |date: NaiveDate, time: NaiveTime| {
+            Berlin.from_local_datetime(&date.and_time(time)).earliest()
+        };
+        let start_of_day = NaiveTime::MIN;
+        let end_of_day = NaiveTime::from_hms_opt(23, 59, 59).expect("valid time");
+
+        match points.as_slice() {
+            [] => Self::default(),
+            [(position, date, time, _)] => {
+                let preceding = text[..*position].to_lowercase();
+                let preceding = preceding.trim_end();
+                match time {
+                    // `bis 18 Uhr`, `bis zum 24.12.`
+                    _ if preceding.ends_with("bis") || preceding.ends_with("bis zum") => Self {
+                        start: None,
+                        end: to_local(*date, time.unwrap_or(end_of_day)),
+                    },
+                    // `am 24.12.` lasts the whole day
+                    None => Self {
+                        start: to_local(*date, start_of_day),
+                        end: to_local(*date, end_of_day),
+                    },
+                    // `ab 18 Uhr`
+                    Some(time) => Self {
+                        start: to_local(*date, *time),
+                        end: None,
+                    },
+                }
+            }
+            [(_, start_date, start_time, _), .., (_, end_date, end_time, end_has_date)] => {
+                let start = to_local(*start_date, start_time.unwrap_or(start_of_day));
+                let mut end = to_local(*end_date, end_time.unwrap_or(end_of_day));
+                // `von 22 Uhr bis 5 Uhr` continues into the next day.
+                if let (Some(s), Some(e)) = (start, end) {
+                    if e < s && !end_has_date {
+                        end = Some(e + Duration::days(1));
+                    }
+                }
+                Self { start, end }
+            }
+        }
+    }
+
+    pub fn contains(&self, time: &DateTime<Tz>) -> bool {
+        self.start.is_none_or(|s| s <= *time) && self.end.is_none_or(|e| *time <= e)
+    }
+}
+
+/// Lines of the message and the ones mentioned in its text, e.g. `S8`.
+pub fn lines(message: &NewsTickerMessage) -> Vec<String> {
+    static LINE: OnceLock<Regex> = OnceLock::new();
+    let pattern = LINE.get_or_init(|| Regex::new(r"\bS ?(\d{1,2})\b").expect("valid pattern"));
+    let mut lines: Vec<String> = message.lines.iter().map(|l| l.trim().to_string()).collect();
+    for captures in pattern.captures_iter(&text(message)) {
+        let line = format!("S{}", &captures[1]);
+        if !lines.contains(&line) {
+            lines.push(line);
+        }
+    }
+    lines
+}
+
+/// Segments given as `zwischen A und B`, with the station names as they are written.
+pub fn segments(message: &NewsTickerMessage) -> Vec<(String, String)> {
+    static SEGMENT: OnceLock<Regex> = OnceLock::new();
+    let pattern = SEGMENT.get_or_init(|| {
+        Regex::new(r"(?i)zwischen\s+(?P<from>[^,.;:()]+?)\s+und\s+(?P<to>[^,.;:()]+?)(?:\s+(?:kommt|kann|können|ist|sind|fahren|fährt|verkehren|verkehrt|besteht|gibt|wird|werden|fällt|fallen|ein|eingerichtet)\b|[,.;:()]|$)")
+            .expect("valid pattern")
+    });
+    pattern
+        .captures_iter(&text(message))
+        .map(|c| (c["from"].trim().to_string(), c["to"].trim().to_string()))
+        .collect()
+}
+
+/// Kinds of the disruption, recognized by their keywords.
+pub fn kinds(message: &NewsTickerMessage) -> Vec<DisruptionKind> {
+    let words: Vec<String> = text(message)
+        .split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+        .collect();
+    DisruptionKind::ALL
+        .into_iter()
+        .filter(|kind| {
+            kind.keywords()
+                .iter()
+                .any(|keyword| words.iter().any(|w| w == keyword))
+        })
+        .collect()
+}
+
+/// Validity window of a message received at `timestamp`, dates without a year refer to the time
+/// the message was updated.
+pub fn validity(message: &NewsTickerMessage, timestamp: f64) -> ValidityWindow {
+    let reference = DateTime::parse_from_rfc3339(&message.updated).map_or_else(
+        |_| local_time(timestamp),
+        |updated| updated.with_timezone(&Berlin),
+    );
+    ValidityWindow::parse(&text(message), reference)
+}
+
+fn text(message: &NewsTickerMessage) -> String {
+    format!("{}\n{}", message.title, message.content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Tz> {
+        Berlin
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .single()
+            .expect("valid time")
+    }
+
+    fn parse(text: &str) -> ValidityWindow {
+        ValidityWindow::parse(text, at(2023, 11, 6, 20, 0))
+    }
+
+    #[test]
+    fn date_lasts_the_whole_day() {
+        let window = parse("Am 24.12. verkehrt die S1 nur bis Freising.");
+        assert_eq!(window.start, Some(at(2023, 12, 24, 0, 0)));
+        assert_eq!(
+            window.end,
+            Some(at(2023, 12, 24, 23, 59) + Duration::seconds(59))
+        );
+    }
+
+    #[test]
+    fn range_of_dates_and_times() {
+        let window = parse("Vom 24.12. 22 Uhr bis 25.12.2023 5.30 Uhr Schienenersatzverkehr.");
+        assert_eq!(window.start, Some(at(2023, 12, 24, 22, 0)));
+        assert_eq!(window.end, Some(at(2023, 12, 25, 5, 30)));
+    }
+
+    #[test]
+    fn times_continue_into_the_next_day() {
+        let window = parse("Heute von 22 Uhr bis 05:00 Streckensperrung.");
+        assert_eq!(window.start, Some(at(2023, 11, 6, 22, 0)));
+        assert_eq!(window.end, Some(at(2023, 11, 7, 5, 0)));
+    }
+
+    #[test]
+    fn open_ends() {
+        let until = parse("Verspätungen bis 18 Uhr.");
+        assert_eq!(until.start, None);
+        assert_eq!(until.end, Some(at(2023, 11, 6, 18, 0)));
+        let from = parse("Ab 18:30 fahren die Züge wieder.");
+        assert_eq!(from.start, Some(at(2023, 11, 6, 18, 30)));
+        assert_eq!(from.end, None);
+    }
+
+    #[test]
+    fn early_dates_are_in_the_next_year() {
+        let window = parse("Am 10.1. Bauarbeiten.");
+        assert_eq!(window.start, Some(at(2024, 1, 10, 0, 0)));
+    }
+
+    #[test]
+    fn ignores_text_without_dates() {
+        assert_eq!(
+            parse("Störung an einem Stellwerk."),
+            ValidityWindow::default()
+        );
+        assert_eq!(parse("Am 31.02. nichts."), ValidityWindow::default());
+        assert!(parse("").contains(&at(2023, 11, 6, 20, 0)));
+    }
+}
@@ -0,0 +1,733 @@
+//! Keeps track of the vehicles of the live feed and predicts their positions and arrivals, which
+//! are served over HTTP and a websocket.
+//!
+//! The positions are predicted by the [`Tracker`]. The arrivals at the following stations need
+//! to know the route and the run and dwell times of a line, which are learned from a history of
+//! recordings, as are the models projecting the current delay onto the following stations.
+//! Without a history only the positions are predicted. All times are relative to the latest
+//! message, so a replayed recording is predicted like a live feed.
+//!
+//! The websocket follows the protocol of the feed itself: clients send `SUB line S1` or
+//! `SUB station Marienplatz` and `UNSUB` to stop, and receive the predictions of their
+//! subscriptions as JSON every [`PUBLISH_INTERVAL`], wrapped like the messages of the feed.
+
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tungstenite::error::ProtocolError;
+
+use crate::propagation::DelayPropagation;
+use crate::records::{Record, State, Trains};
+use crate::response_messages::{Content, ResponseMessage};
+use crate::segments::{Direction, Distribution, SegmentStatistics};
+use crate::stations::{Station, Stations};
+use crate::tracker::{Tracker, TrackerConfig};
+
+/// Interval in which the subscriptions of a websocket client are published.
+pub const PUBLISH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Number of departures served if the request does not ask for a number.
+pub const DEFAULT_DEPARTURES: usize = 10;
+
+/// Typical duration of a run or dwell in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TravelTime {
+    pub median: f64,
+    /// Half the range between the 10th and the 90th percentile.
+    pub spread: f64,
+}
+
+impl TravelTime {
+    fn from_distribution(distribution: &Distribution) -> Option<Self> {
+        Some(Self {
+            median: distribution.median()?,
+            spread: (distribution.percentile(0.9)? - distribution.percentile(0.1)?) / 2.0,
+        })
+    }
+}
+
+/// Run and dwell times per line and direction, regardless of the time of day.
+#[derive(Debug, Default)]
+pub struct TravelTimes {
+    runs: HashMap<(String, Direction, String, String), TravelTime>,
+    dwells: HashMap<(String, Direction, String), TravelTime>,
+}
+
+impl TravelTimes {
+    pub fn from_segments(segments: &SegmentStatistics) -> Self {
+        let mut runs: HashMap<_, Distribution> = HashMap::new();
+        for (key, distribution) in segments.runs() {
+            let merged = runs
+                .entry((
+                    key.line.clone(),
+                    key.direction,
+                    key.from.clone(),
+                    key.to.clone(),
+                ))
+                .or_default();
+            for &sample in distribution.samples() {
+                merged.push(sample);
+            }
+        }
+        let mut dwells: HashMap<_, Distribution> = HashMap::new();
+        for (key, distribution) in segments.dwells() {
+            let merged = dwells
+                .entry((key.line.clone(), key.direction, key.station.clone()))
+                .or_default();
+            for &sample in distribution.samples() {
+                merged.push(sample);
+            }
+        }
+        Self {
+            runs: runs
+                .into_iter()
+                .filter_map(|(key, distribution)| {
+                    Some((key, TravelTime::from_distribution(&distribution)?))
+                })
+                .collect(),
+            dwells: dwells
+                .into_iter()
+                .filter_map(|(key, distribution)| {
+                    Some((key, TravelTime::from_distribution(&distribution)?))
+                })
+                .collect(),
+        }
+    }
+
+    pub fn run(
+        &self,
+        line: &str,
+        direction: Direction,
+        from: &str,
+        to: &str,
+    ) -> Option<TravelTime> {
+        self.runs
+            .get(&(
+                line.to_string(),
+                direction,
+                from.to_string(),
+                to.to_string(),
+            ))
+            .copied()
+    }
+
+    pub fn dwell(&self, line: &str, direction: Direction, station: &str) -> Option<TravelTime> {
+        self.dwells
+            .get(&(line.to_string(), direction, station.to_string()))
+            .copied()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PredictionConfig {
+    pub tracker: TrackerConfig,
+    /// Seconds after the latest record of a vehicle for which its position is predicted.
+    pub horizons: Vec<f64>,
+    /// Vehicles without an update for this many seconds are left out.
+    pub max_age: f64,
+    /// Seconds of records kept per vehicle to find the stations it stopped at.
+    pub history: f64,
+}
+
+impl Default for PredictionConfig {
+    fn default() -> Self {
+        Self {
+            tracker: TrackerConfig::default(),
+            horizons: vec![30.0, 60.0, 120.0, 300.0],
+            max_age: 5.0 * 60.0,
+            history: 60.0 * 60.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PredictedPosition {
+    /// Milliseconds since the unix epoch.
+    pub timestamp: f64,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Speed in meters per second.
+    pub speed: f64,
+    /// Axes in meters and orientation in radians counterclockwise from east of the ellipse the
+    /// vehicle is within with a probability of 95%.
+    pub semi_major: f64,
+    pub semi_minor: f64,
+    pub orientation: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Arrival {
+    pub station: String,
+    pub vehicle_number: String,
+    pub train_number: i64,
+    pub line: String,
+    /// Last station of the route.
+    pub destination: String,
+    /// Predicted arrival and departure in milliseconds since the unix epoch.
+    pub arrival: f64,
+    pub departure: f64,
+    /// Delay in seconds the train is expected to depart the station with.
+    pub delay: Option<f64>,
+    /// Seconds the departure may be off, from the spread of the run and dwell times before.
+    pub uncertainty: f64,
+    /// Whether the vehicle is boarding at the station, so the arrival is the recorded one.
+    pub boarding: bool,
+}
+
+/// How much to trust a predicted departure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Confidence {
+    Low,
+    Medium,
+    High,
+}
+
+impl Confidence {
+    /// Classifies the uncertainty of a prediction in seconds.
+    pub fn from_uncertainty(uncertainty: f64) -> Self {
+        if uncertainty < 60.0 {
+            Self::High
+        } else if uncertainty < 180.0 {
+            Self::Medium
+        } else {
+            Self::Low
+        }
+    }
+}
+
+impl std::fmt::Display for Confidence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let confidence = match self {
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::High => "high",
+        };
+        write!(f, "{confidence}")
+    }
+}
+
+/// A predicted departure from a station, as shown on a departure board.
+#[derive(Debug, Clone, Serialize)]
+pub struct Departure {
+    pub station: String,
+    pub line: String,
+    pub direction: String,
+    pub destination: String,
+    pub train_number: i64,
+    pub vehicle_number: String,
+    /// Predicted departure in milliseconds since the unix epoch.
+    pub departure: f64,
+    /// Delay in seconds the train is expected to depart with.
+    pub delay: Option<f64>,
+    pub boarding: bool,
+    pub confidence: Confidence,
+}
+
+impl From<Arrival> for Departure {
+    fn from(arrival: Arrival) -> Self {
+        Self {
+            direction: Direction::from(arrival.train_number).to_string(),
+            confidence: Confidence::from_uncertainty(arrival.uncertainty),
+            station: arrival.station,
+            line: arrival.line,
+            destination: arrival.destination,
+            train_number: arrival.train_number,
+            vehicle_number: arrival.vehicle_number,
+            departure: arrival.departure,
+            delay: arrival.delay,
+            boarding: arrival.boarding,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VehiclePrediction {
+    pub vehicle_number: String,
+    pub train_id: String,
+    pub train_number: i64,
+    pub line: String,
+    /// Time, position, state and delay of the latest record.
+    pub timestamp: f64,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub state: String,
+    pub delay: Option<f64>,
+    pub positions: Vec<PredictedPosition>,
+    pub arrivals: Vec<Arrival>,
+}
+
+/// What a client wants to receive, everything of a line or the arrivals at a station.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Subscription {
+    pub lines: BTreeSet<String>,
+    pub stations: BTreeSet<String>,
+}
+
+impl Subscription {
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty() && self.stations.is_empty()
+    }
+
+    /// Applies a command like `SUB line S1` or `UNSUB station Marienplatz`.
+    pub fn apply(&mut self, command: &str) -> Result<(), String> {
+        let mut parts = command.trim().splitn(3, ' ');
+        let (Some(action), Some(kind), Some(name)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!("invalid command '{command}'"));
+        };
+        let set = match kind {
+            "line" => &mut self.lines,
+            "station" => &mut self.stations,
+            _ => return Err(format!("unknown subscription '{kind}'")),
+        };
+        match action {
+            "SUB" => set.insert(name.trim().to_string()),
+            "UNSUB" => set.remove(name.trim()),
+            _ => return Err(format!("unknown action '{action}'")),
+        };
+        Ok(())
+    }
+}
+
+/// A message sent to the clients, wrapped like the messages of the feed.
+#[derive(Debug, Clone, Serialize)]
+pub struct PredictionMessage<T> {
+    pub source: &'static str,
+    pub timestamp: f64,
+    pub content: T,
+}
+
+/// Latest state of the feed and the models to predict from it.
+#[derive(Debug)]
+pub struct LivePredictions {
+    config: PredictionConfig,
+    tracker: Tracker,
+    records: HashMap<String, Vec<Record>>,
+    stations: Stations,
+    propagation: DelayPropagation,
+    travel_times: TravelTimes,
+    timestamp: f64,
+}
+
+impl LivePredictions {
+    pub fn new(config: PredictionConfig) -> Self {
+        Self {
+            tracker: Tracker::new(config.tracker.clone()),
+            config,
+            records: HashMap::new(),
+            stations: Stations::new(),
+            propagation: DelayPropagation::default(),
+            travel_times: TravelTimes::default(),
+            timestamp: 0.0,
+        }
+    }
+
+    /// Learns the routes, travel times and delay propagation from recorded `trains`.
+    pub fn learn(&mut self, trains: &Trains, stations: &Stations) {
+        for station in stations.iter() {
+            self.stations.insert(station.clone());
+        }
+        self.propagation = DelayPropagation::from_trains(trains, &self.stations);
+        self.travel_times =
+            TravelTimes::from_segments(&SegmentStatistics::from_trains(trains, &self.stations));
+    }
+
+    /// Timestamp of the latest message in milliseconds.
+    pub fn timestamp(&self) -> f64 {
+        self.timestamp
+    }
+
+    /// Updates the state with a message, messages that do not concern vehicles or stations are
+    /// ignored.
+    pub fn update(&mut self, message: ResponseMessage) {
+        self.timestamp = self.timestamp.max(message.timestamp);
+        match message.content {
+            Content::TrajectorySchematic(_) => {
+                if let Ok(record) = Record::try_from(message) {
+                    self.insert_record(record);
+                }
+            }
+            Content::Station(_) => {
+                if let Ok(station) = Station::try_from(message.content) {
+                    self.stations.insert(station);
+                }
+            }
+            Content::DeletedVehiclesSchematic(Some(ref train_id))
+            | Content::DeletedVehicles(Some(ref train_id)) => {
+                self.records
+                    .retain(|_, records| records.last().is_none_or(|r| &r.train_id != train_id));
+            }
+            _ => {}
+        }
+        self.prune();
+    }
+
+    /// Adds a record, e.g. of a recording replayed in order of time.
+    pub fn insert_record(&mut self, record: Record) {
+        self.timestamp = self.timestamp.max(record.timestamp);
+        self.tracker.update(&record);
+        let records = self
+            .records
+            .entry(record.vehicle_number.clone())
+            .or_default();
+        let oldest = record.timestamp - self.config.history * 1000.0;
+        records.retain(|r| r.timestamp >= oldest);
+        records.push(record);
+        self.prune();
+    }
+
+    /// Moves the current time forward to `timestamp` in milliseconds without a message.
+    pub fn advance(&mut self, timestamp: f64) {
+        self.timestamp = self.timestamp.max(timestamp);
+        self.prune();
+    }
+
+    /// Drops the vehicles without an update for longer than the maximum age.
+    fn prune(&mut self) {
+        let max_age = self.config.max_age * 1000.0;
+        let timestamp = self.timestamp;
+        self.records.retain(|_, records| {
+            records
+                .last()
+                .is_some_and(|r| timestamp - r.timestamp <= max_age)
+        });
+        self.tracker.prune(self.timestamp);
+    }
+
+    /// Predicted arrivals of a vehicle at the stations following the last one it stopped at,
+    /// starting with that station while the vehicle is boarding there.
+    fn arrivals(&self, records: &[Record]) -> Vec<Arrival> {
+        let Some(latest) = records.last() else {
+            return Vec::new();
+        };
+        let Some(stop) = SegmentStatistics::stops(records, &self.stations)
+            .pop()
+            .filter(|stop| stop.departure.train_number == latest.train_number)
+        else {
+            return Vec::new();
+        };
+        let line = latest.line.as_str();
+        let direction: Direction = latest.train_number.into();
+        let route = self.propagation.route(line, direction, stop.station);
+        let stops: Vec<&str> = std::iter::once(stop.station)
+            .chain(route.iter().map(String::as_str))
+            .collect();
+        let delays = latest
+            .delay
+            .map(|delay| self.propagation.project(line, direction, &stops, delay));
+
+        let destination = stops.last().copied().unwrap_or(stop.station);
+        let arrival = |station: &str, arrival: f64, delay: Option<f64>, variance: f64| {
+            let dwell = self.travel_times.dwell(line, direction, station);
+            Arrival {
+                station: station.to_string(),
+                vehicle_number: latest.vehicle_number.clone(),
+                train_number: latest.train_number,
+                line: latest.line.clone(),
+                destination: destination.to_string(),
+                arrival,
+                departure: arrival + dwell.map_or(0.0, |dwell| dwell.median) * 1000.0,
+                delay,
+                uncertainty: (variance + dwell.map_or(0.0, |dwell| dwell.spread.powi(2))).sqrt(),
+                boarding: false,
+            }
+        };
+
+        let mut arrivals = Vec::new();
+        let boarding =
+            latest.state == State::Boarding && stop.departure.timestamp == latest.timestamp;
+        let (mut time, mut variance) = if boarding {
+            let mut current = arrival(stop.station, stop.arrival.timestamp, latest.delay, 0.0);
+            current.departure = current.departure.max(self.timestamp);
+            current.boarding = true;
+            let departure = (current.departure, current.uncertainty.powi(2));
+            arrivals.push(current);
+            departure
+        } else {
+            (stop.departure.timestamp, 0.0)
+        };
+        for (index, pair) in stops.windows(2).enumerate() {
+            let Some(run) = self.travel_times.run(line, direction, pair[0], pair[1]) else {
+                break;
+            };
+            // A vehicle slower than usual has not arrived yet.
+            time = (time + run.median * 1000.0).max(self.timestamp);
+            variance += run.spread.powi(2);
+            let delay = delays
+                .as_ref()
+                .and_then(|delays| delays.get(index))
+                .map(|projected| projected.delay);
+            let next = arrival(pair[1], time, delay, variance);
+            time = next.departure;
+            variance = next.uncertainty.powi(2);
+            arrivals.push(next);
+        }
+        arrivals
+    }
+
+    fn vehicle(&self, records: &[Record]) -> Option<VehiclePrediction> {
+        let latest = records.last()?;
+        let tracked = self.tracker.get(&latest.vehicle_number)?;
+        let positions = tracked
+            .predictions(&self.config.horizons, self.tracker.config())
+            .into_iter()
+            .map(|prediction| {
+                let ellipse = prediction.ellipse();
+                PredictedPosition {
+                    timestamp: prediction.timestamp,
+                    latitude: prediction.position.latitude,
+                    longitude: prediction.position.longitude,
+                    speed: prediction.speed(),
+                    semi_major: ellipse.semi_major,
+                    semi_minor: ellipse.semi_minor,
+                    orientation: ellipse.orientation,
+                }
+            })
+            .collect();
+        Some(VehiclePrediction {
+            vehicle_number: latest.vehicle_number.clone(),
+            train_id: latest.train_id.clone(),
+            train_number: latest.train_number,
+            line: latest.line.clone(),
+            timestamp: latest.timestamp,
+            latitude: latest.position.latitude,
+            longitude: latest.position.longitude,
+            state: latest.state.to_string(),
+            delay: latest.delay,
+            positions,
+            arrivals: self.arrivals(records),
+        })
+    }
+
+    /// Predictions of the vehicles of `lines`, or of all if empty, ordered by vehicle number.
+    pub fn vehicles(&self, lines: &BTreeSet<String>) -> Vec<VehiclePrediction> {
+        let mut vehicles: Vec<_> = self
+            .records
+            .values()
+            .filter(|records| {
+                records.last().is_some_and(|r| {
+                    lines.is_empty() || lines.iter().any(|l| l.eq_ignore_ascii_case(&r.line))
+                })
+            })
+            .filter_map(|records| self.vehicle(records))
+            .collect();
+        vehicles.sort_by(|a, b| a.vehicle_number.cmp(&b.vehicle_number));
+        vehicles
+    }
+
+    /// Predicted arrivals at `station`, ordered by time.
+    pub fn arrivals_at(&self, station: &str) -> Vec<Arrival> {
+        let mut arrivals: Vec<_> = self
+            .records
+            .values()
+            .flat_map(|records| self.arrivals(records))
+            .filter(|arrival| arrival.station.eq_ignore_ascii_case(station))
+            .collect();
+        arrivals.sort_by(|a, b| a.arrival.total_cmp(&b.arrival));
+        arrivals
+    }
+
+    /// The next `limit` departures from `station`, ordered by time. Trains ending at the station
+    /// do not depart from it.
+    pub fn departures(&self, station: &str, limit: usize) -> Vec<Departure> {
+        let mut departures: Vec<Departure> = self
+            .records
+            .values()
+            .flat_map(|records| self.arrivals(records))
+            .filter(|arrival| {
+                arrival.station.eq_ignore_ascii_case(station)
+                    && !arrival.destination.eq_ignore_ascii_case(station)
+            })
+            .map(Departure::from)
+            .collect();
+        departures.sort_by(|a, b| a.departure.total_cmp(&b.departure));
+        departures.truncate(limit);
+        departures
+    }
+
+    /// Messages for everything `subscription` asks for.
+    pub fn publish(&self, subscription: &Subscription) -> Vec<String> {
+        let mut messages = Vec::new();
+        if !subscription.lines.is_empty() {
+            messages.push(self.message("vehicles", self.vehicles(&subscription.lines)));
+        }
+        for station in &subscription.stations {
+            messages.push(self.message("arrivals", self.arrivals_at(station)));
+        }
+        messages
+    }
+
+    fn message<T: Serialize>(&self, source: &'static str, content: T) -> String {
+        serde_json::to_string(&PredictionMessage {
+            source,
+            timestamp: self.timestamp,
+            content,
+        })
+        .expect("predictions can be serialized")
+    }
+}
+
+/// Answers `GET /vehicles`, optionally with `?line=S1` repeated for every line,
+/// `GET /arrivals?station=Marienplatz` and `GET /departures?station=Marienplatz&limit=10` with
+/// JSON.
+pub fn serve_http(predictions: Arc<Mutex<LivePredictions>>, server: tiny_http::Server) {
+    for request in server.incoming_requests() {
+        let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+        let parameters: Vec<(String, String)> = url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        let body = {
+            let predictions = predictions.lock().expect("predictions are not poisoned");
+            match path.trim_end_matches('/') {
+                "/vehicles" => {
+                    let lines = parameters
+                        .iter()
+                        .filter(|(key, _)| key == "line")
+                        .map(|(_, value)| value.clone())
+                        .collect();
+                    Some(predictions.message("vehicles", predictions.vehicles(&lines)))
+                }
+                "/arrivals" => {
+                    parameters
+                        .iter()
+                        .find(|(key, _)| key == "station")
+                        .map(|(_, station)| {
+                            predictions.message("arrivals", predictions.arrivals_at(station))
+                        })
+                }
+                "/departures" => {
+                    let limit = parameters
+                        .iter()
+                        .find(|(key, _)| key == "limit")
+                        .and_then(|(_, limit)| limit.parse().ok())
+                        .unwrap_or(DEFAULT_DEPARTURES);
+                    parameters
+                        .iter()
+                        .find(|(key, _)| key == "station")
+                        .map(|(_, station)| {
+                            predictions
+                                .message("departures", predictions.departures(station, limit))
+                        })
+                }
+                _ => None,
+            }
+        };
+        let Some(body) = body else {
+            let _ = request.respond(tiny_http::Response::empty(404));
+            continue;
+        };
+        let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+            .expect("valid header");
+        if let Err(err) =
+            request.respond(tiny_http::Response::from_string(body).with_header(header))
+        {
+            eprintln!("unable to respond: {err}");
+        }
+    }
+}
+
+/// Accepts websocket clients, each served by its own thread.
+pub fn serve_websocket(predictions: Arc<Mutex<LivePredictions>>, listener: TcpListener) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("unable to accept: {err}");
+                continue;
+            }
+        };
+        let predictions = Arc::clone(&predictions);
+        thread::spawn(move || {
+            if let Err(err) = handle_client(stream, predictions) {
+                eprintln!("client disconnected: {err}");
+            }
+        });
+    }
+}
+
+fn handle_client(stream: TcpStream, predictions: Arc<Mutex<LivePredictions>>) -> io::Result<()> {
+    let mut socket = tungstenite::accept(stream).map_err(io::Error::other)?;
+    // Waits shortly for commands, so the subscriptions can be published in between.
+    socket
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_millis(200)))?;
+    let mut subscription = Subscription::default();
+    let mut published: Option<Instant> = None;
+    loop {
+        match socket.read() {
+            Ok(tungstenite::Message::Text(command)) => {
+                if let Err(err) = subscription.apply(&command) {
+                    socket
+                        .send(format!("ERR: {err}").into())
+                        .map_err(io::Error::other)?;
+                }
+                published = None;
+            }
+            Ok(tungstenite::Message::Close(_)) => return Ok(()),
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(err))
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(
+                tungstenite::Error::ConnectionClosed
+                | tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake),
+            ) => return Ok(()),
+            Err(err) => return Err(io::Error::other(err)),
+        }
+        if published.is_none_or(|published| published.elapsed() >= PUBLISH_INTERVAL) {
+            let messages = predictions
+                .lock()
+                .expect("predictions are not poisoned")
+                .publish(&subscription);
+            for message in messages {
+                socket.send(message.into()).map_err(io::Error::other)?;
+            }
+            published = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribes_and_unsubscribes() {
+        let mut subscription = Subscription::default();
+        assert!(subscription.is_empty());
+        subscription.apply("SUB line S1").unwrap();
+        subscription
+            .apply(" SUB station Ostbahnhof (München) \n")
+            .unwrap();
+        subscription.apply("SUB line S1").unwrap();
+        assert_eq!(subscription.lines, BTreeSet::from(["S1".to_string()]));
+        assert_eq!(
+            subscription.stations,
+            BTreeSet::from(["Ostbahnhof (München)".to_string()])
+        );
+
+        subscription.apply("UNSUB line S1").unwrap();
+        subscription.apply("UNSUB line S8").unwrap();
+        subscription
+            .apply("UNSUB station Ostbahnhof (München)")
+            .unwrap();
+        assert!(subscription.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        let mut subscription = Subscription::default();
+        for command in ["", "SUB", "SUB line", "SUB train 6401", "sub line S1"] {
+            assert!(subscription.apply(command).is_err(), "{command}");
+        }
+        assert!(subscription.is_empty());
+    }
+}
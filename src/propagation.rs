@@ -0,0 +1,386 @@
+//! Learns how the delay of a train changes between consecutive stations and projects a current
+//! delay onto the remaining stops.
+//!
+//! The feed carries the current delay forward to every later stop. In reality, padding in the
+//! schedule lets late trains recover, while congestion, e.g. on the Stammstrecke, adds delay. For
+//! every line, direction and segment from departing one station to departing the next one, the
+//! departure delay at the next station is fitted linearly to the departure delay at the first
+//! one, so the recovery may depend on how late the train is. Segments without enough
+//! observations carry the delay forward. What a delay does to the next trip of the same unit
+//! after reaching the terminus is estimated by [`crate::circulation::propagate`].
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::records::{Record, Trains};
+use crate::segments::{Direction, SegmentStatistics};
+use crate::stations::Stations;
+
+/// Identifies the segment from departing one station to departing the next one.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SegmentKey {
+    pub line: String,
+    pub direction: Direction,
+    pub from: String,
+    pub to: String,
+}
+
+/// Departure delay at the end of a segment as a linear function of the one at its start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentModel {
+    pub samples: usize,
+    /// Mean change of the delay in seconds over the segment.
+    pub mean_change: f64,
+    pub intercept: f64,
+    pub slope: f64,
+}
+
+impl SegmentModel {
+    /// Segments with fewer observations are not modelled.
+    pub const MIN_SAMPLES: usize = 5;
+    /// Limits the slope, so a few outliers cannot make it explode.
+    pub const MAX_SLOPE: f64 = 2.0;
+
+    /// Fits the model to pairs of the delays at the start and the end of the segment.
+    fn fit(pairs: &[(f64, f64)]) -> Option<Self> {
+        if pairs.len() < Self::MIN_SAMPLES {
+            return None;
+        }
+        let n = pairs.len() as f64;
+        let mean_x = pairs.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = pairs.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let covariance: f64 = pairs.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+        let variance: f64 = pairs.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+        // Without different delays at the start only the mean change can be learned.
+        let slope = if variance / n < 1.0 {
+            1.0
+        } else {
+            (covariance / variance).clamp(0.0, Self::MAX_SLOPE)
+        };
+        Some(Self {
+            samples: pairs.len(),
+            mean_change: mean_y - mean_x,
+            intercept: mean_y - slope * mean_x,
+            slope,
+        })
+    }
+
+    /// Departure delay in seconds at the end of the segment.
+    pub fn project(&self, delay: f64) -> f64 {
+        self.intercept + self.slope * delay
+    }
+}
+
+/// Projected departure delay at a remaining stop.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectedStop {
+    pub station: String,
+    /// Delay in seconds.
+    pub delay: f64,
+    /// Whether the segment to this stop was modelled, otherwise the delay was carried forward.
+    pub modelled: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct DelayPropagation {
+    models: HashMap<SegmentKey, SegmentModel>,
+    /// How often each next station followed a station, to find the remaining stops.
+    successors: HashMap<(String, Direction, String), HashMap<String, usize>>,
+}
+
+impl DelayPropagation {
+    pub fn from_trains(trains: &Trains, stations: &Stations) -> Self {
+        let mut pairs: HashMap<SegmentKey, Vec<(f64, f64)>> = HashMap::new();
+        let mut successors: HashMap<(String, Direction, String), HashMap<String, usize>> =
+            HashMap::new();
+
+        for vehicle in trains.values() {
+            let stops = SegmentStatistics::stops(&vehicle.records, stations);
+            for pair in stops.windows(2) {
+                let (previous, next) = (&pair[0], &pair[1]);
+                if !next.continuous
+                    || previous.station == next.station
+                    || previous.departure.train_number != next.departure.train_number
+                    || previous.departure.line != next.departure.line
+                {
+                    continue;
+                }
+                let line = previous.departure.line.clone();
+                let direction: Direction = previous.departure.train_number.into();
+                *successors
+                    .entry((line.clone(), direction, previous.station.to_string()))
+                    .or_default()
+                    .entry(next.station.to_string())
+                    .or_default() += 1;
+                if let (Some(from), Some(to)) = (previous.departure.delay, next.departure.delay) {
+                    pairs
+                        .entry(SegmentKey {
+                            line,
+                            direction,
+                            from: previous.station.to_string(),
+                            to: next.station.to_string(),
+                        })
+                        .or_default()
+                        .push((from, to));
+                }
+            }
+        }
+
+        Self {
+            models: pairs
+                .into_iter()
+                .filter_map(|(key, pairs)| Some((key, SegmentModel::fit(&pairs)?)))
+                .collect(),
+            successors,
+        }
+    }
+
+    pub fn model(&self, key: &SegmentKey) -> Option<&SegmentModel> {
+        self.models.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.models.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.models.is_empty()
+    }
+
+    /// The stations following `from` on the line, each the one observed most often after the
+    /// previous one.
+    pub fn route(&self, line: &str, direction: Direction, from: &str) -> Vec<String> {
+        let mut route: Vec<String> = Vec::new();
+        let mut current = from.to_string();
+        while let Some(next) = self
+            .successors
+            .get(&(line.to_string(), direction, current.clone()))
+            .and_then(|next| {
+                next.iter()
+                    .max_by(|(a_station, a), (b_station, b)| {
+                        a.cmp(b).then(b_station.cmp(a_station))
+                    })
+                    .map(|(station, _)| station.clone())
+            })
+        {
+            if next == from || route.contains(&next) {
+                break;
+            }
+            route.push(next.clone());
+            current = next;
+        }
+        route
+    }
+
+    /// Projects the departure `delay` in seconds at `stops[0]` onto the following `stops`.
+    pub fn project(
+        &self,
+        line: &str,
+        direction: Direction,
+        stops: &[&str],
+        delay: f64,
+    ) -> Vec<ProjectedStop> {
+        let mut delay = delay;
+        stops
+            .windows(2)
+            .map(|pair| {
+                let model = self.model(&SegmentKey {
+                    line: line.to_string(),
+                    direction,
+                    from: pair[0].to_string(),
+                    to: pair[1].to_string(),
+                });
+                if let Some(model) = model {
+                    delay = model.project(delay);
+                }
+                ProjectedStop {
+                    station: pair[1].to_string(),
+                    delay,
+                    modelled: model.is_some(),
+                }
+            })
+            .collect()
+    }
+
+    /// Projects the latest delay of a vehicle onto the stops after the last station it stopped
+    /// at, `records` have to be ordered by time.
+    pub fn project_vehicle(&self, records: &[Record], stations: &Stations) -> Vec<ProjectedStop> {
+        let (Some(latest), Some(stop)) = (
+            records.last(),
+            SegmentStatistics::stops(records, stations).pop(),
+        ) else {
+            return Vec::new();
+        };
+        let Some(delay) = latest.delay else {
+            return Vec::new();
+        };
+        if stop.departure.train_number != latest.train_number {
+            return Vec::new();
+        }
+        let direction = latest.train_number.into();
+        let route = self.route(&latest.line, direction, stop.station);
+        let stops: Vec<&str> = std::iter::once(stop.station)
+            .chain(route.iter().map(String::as_str))
+            .collect();
+        self.project(&latest.line, direction, &stops, delay)
+    }
+
+    /// Writes one row per modelled segment, sorted by line, direction and stations.
+    pub fn write_csv<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(
+            "line,direction,from,to,samples,mean_change,intercept,slope".split(','),
+        )?;
+        let mut keys: Vec<_> = self.models.keys().collect();
+        keys.sort();
+        for key in keys {
+            let model = &self.models[key];
+            writer.write_record([
+                key.line.clone(),
+                key.direction.to_string(),
+                key.from.clone(),
+                key.to.clone(),
+                model.samples.to_string(),
+                format!("{:.1}", model.mean_change),
+                format!("{:.1}", model.intercept),
+                format!("{:.3}", model.slope),
+            ])?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(from: &str, to: &str) -> SegmentKey {
+        SegmentKey {
+            line: "S1".to_string(),
+            direction: Direction::Even,
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    /// Propagation of the S1 with the given models and how often each segment was observed.
+    fn propagation(
+        models: impl IntoIterator<Item = ((&'static str, &'static str), SegmentModel)>,
+        successors: &[(&str, &str, usize)],
+    ) -> DelayPropagation {
+        let mut propagation = DelayPropagation {
+            models: models
+                .into_iter()
+                .map(|((from, to), model)| (key(from, to), model))
+                .collect(),
+            successors: HashMap::new(),
+        };
+        for &(from, to, count) in successors {
+            propagation
+                .successors
+                .entry(("S1".to_string(), Direction::Even, from.to_string()))
+                .or_default()
+                .insert(to.to_string(), count);
+        }
+        propagation
+    }
+
+    #[test]
+    fn fits_segments_with_enough_samples() {
+        let pairs: Vec<(f64, f64)> = (0..5)
+            .map(|i| (i as f64 * 60.0, i as f64 * 30.0 + 10.0))
+            .collect();
+        assert_eq!(
+            SegmentModel::fit(&pairs[..SegmentModel::MIN_SAMPLES - 1]),
+            None
+        );
+
+        let model = SegmentModel::fit(&pairs).expect("enough samples");
+        assert_eq!(model.samples, 5);
+        assert!((model.slope - 0.5).abs() < 1e-9);
+        assert!((model.intercept - 10.0).abs() < 1e-9);
+        assert!((model.project(240.0) - 130.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn clamps_the_slope() {
+        let steep: Vec<(f64, f64)> = (0..5).map(|i| (i as f64, i as f64 * 10.0)).collect();
+        let model = SegmentModel::fit(&steep).expect("enough samples");
+        assert_eq!(model.slope, SegmentModel::MAX_SLOPE);
+
+        let falling: Vec<(f64, f64)> = (0..5).map(|i| (i as f64, -(i as f64))).collect();
+        let model = SegmentModel::fit(&falling).expect("enough samples");
+        assert_eq!(model.slope, 0.0);
+        assert!((model.intercept - -2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn learns_the_mean_change_without_variance() {
+        let pairs = [
+            (60.0, 20.0),
+            (60.0, 40.0),
+            (60.0, 30.0),
+            (60.0, 50.0),
+            (60.0, 10.0),
+        ];
+        let model = SegmentModel::fit(&pairs).expect("enough samples");
+        assert_eq!(model.slope, 1.0);
+        assert!((model.mean_change - -30.0).abs() < 1e-9);
+        assert!((model.project(60.0) - 30.0).abs() < 1e-9);
+        assert!((model.project(120.0) - 90.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn follows_the_most_frequent_successors() {
+        let propagation = propagation(
+            [],
+            &[
+                ("A", "B", 5),
+                ("A", "X", 2),
+                ("B", "C", 3),
+                ("B", "D", 3),
+                ("C", "E", 1),
+            ],
+        );
+        // Equally frequent successors are chosen by name, so the route does not depend on the
+        // order of the map.
+        assert_eq!(
+            propagation.route("S1", Direction::Even, "A"),
+            ["B", "C", "E"]
+        );
+        assert!(propagation.route("S1", Direction::Odd, "A").is_empty());
+        assert!(propagation.route("S2", Direction::Even, "A").is_empty());
+    }
+
+    #[test]
+    fn stops_routes_at_cycles() {
+        let back_to_start = propagation([], &[("A", "B", 1), ("B", "C", 1), ("C", "A", 1)]);
+        assert_eq!(back_to_start.route("S1", Direction::Even, "A"), ["B", "C"]);
+
+        let loop_later = propagation([], &[("A", "B", 1), ("B", "C", 1), ("C", "B", 1)]);
+        assert_eq!(loop_later.route("S1", Direction::Even, "A"), ["B", "C"]);
+    }
+
+    #[test]
+    fn carries_the_delay_forward_on_unmodelled_segments() {
+        let halving = SegmentModel {
+            samples: 10,
+            mean_change: -60.0,
+            intercept: 0.0,
+            slope: 0.5,
+        };
+        let propagation = propagation([(("A", "B"), halving), (("C", "D"), halving)], &[]);
+        let projected = propagation.project("S1", Direction::Even, &["A", "B", "C", "D"], 240.0);
+        let expected = [("B", 120.0, true), ("C", 120.0, false), ("D", 60.0, true)];
+        assert_eq!(projected.len(), expected.len());
+        for (stop, (station, delay, modelled)) in projected.iter().zip(expected) {
+            assert_eq!(stop.station, station);
+            assert!((stop.delay - delay).abs() < 1e-9);
+            assert_eq!(stop.modelled, modelled);
+        }
+        assert!(propagation
+            .project("S1", Direction::Odd, &["A", "B"], 240.0)
+            .iter()
+            .all(|stop| !stop.modelled && stop.delay == 240.0));
+    }
+}
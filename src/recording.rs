@@ -0,0 +1,250 @@
+//! Loads recordings into memory for the analyses, optionally restricted to a time range and to
+//! some lines.
+//!
+//! A recording is either a JSONL file of the scraper, a directory converted to Parquet by
+//! [`crate::columnar`] or an SQLite database written by [`crate::database`]. Time ranges of JSONL
+//! files are read through their [`TimeIndex`].
+
+use chrono::{NaiveDate, NaiveDateTime, TimeZone};
+use chrono_tz::Europe::Berlin;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::columnar::ColumnarRecording;
+use crate::database::Database;
+use crate::incidents::Incidents;
+use crate::records::{ErrorReport, Record, Trains};
+use crate::response_messages::{Content, ResponseMessage, SbmNewsTicker};
+use crate::stations::{Station, Stations};
+use crate::time_index::TimeIndex;
+
+/// Parses a point in time into milliseconds since the unix epoch. Accepts RFC 3339 or the local
+/// time of Munich as `YYYY-MM-DD HH:MM[:SS]` or `YYYY-MM-DD`.
+pub fn parse_time(value: &str) -> Result<f64, String> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(time.timestamp_millis() as f64);
+    }
+    let local = [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+    .or_else(|| {
+        NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .ok()
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+    })
+    .ok_or_else(|| format!("invalid time '{value}', expected e.g. '2023-11-07 17:30'"))?;
+    Berlin
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|time| time.timestamp_millis() as f64)
+        .ok_or_else(|| format!("'{value}' does not exist in the local time"))
+}
+
+/// Restricts which parts of a recording are loaded.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingFilter {
+    /// Start and end in milliseconds since the unix epoch, unrestricted if `None`.
+    pub from: Option<f64>,
+    pub to: Option<f64>,
+    /// Names of the lines to keep, all if empty.
+    pub lines: Vec<String>,
+}
+
+impl RecordingFilter {
+    pub fn range(&self) -> Range<f64> {
+        self.from.unwrap_or(f64::NEG_INFINITY)..self.to.unwrap_or(f64::INFINITY)
+    }
+
+    pub fn matches_time(&self, timestamp: f64) -> bool {
+        self.range().contains(&timestamp)
+    }
+
+    pub fn matches_line(&self, line: &str) -> bool {
+        self.lines.is_empty() || self.lines.iter().any(|l| l.eq_ignore_ascii_case(line))
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        self.matches_time(record.timestamp) && self.matches_line(&record.line)
+    }
+}
+
+/// Everything the analyses need from one or more recordings.
+#[derive(Default)]
+pub struct Recording {
+    pub trains: Trains,
+    pub stations: Stations,
+    pub incidents: Incidents,
+    pub errors: ErrorReport,
+}
+
+impl Recording {
+    /// Loads all `paths` with the filter applied.
+    pub fn load(paths: &[impl AsRef<Path>], filter: &RecordingFilter) -> io::Result<Self> {
+        Self::load_with(paths, filter, |_| {})
+    }
+
+    /// Loads all `paths` and additionally calls `on_message` for every message within the time
+    /// range of the filter. Only JSONL recordings contain the raw messages, the converted ones
+    /// do not call it.
+    pub fn load_with<F>(
+        paths: &[impl AsRef<Path>],
+        filter: &RecordingFilter,
+        mut on_message: F,
+    ) -> io::Result<Self>
+    where
+        F: FnMut(&ResponseMessage),
+    {
+        let mut recording = Self::default();
+        for path in paths {
+            let path = path.as_ref();
+            let extension = path.extension().and_then(|e| e.to_str());
+            if path.is_dir() {
+                recording.load_columnar(path, filter)?;
+            } else if matches!(extension, Some("db" | "sqlite" | "sqlite3")) {
+                recording.load_database(path, filter)?;
+            } else {
+                recording.load_jsonl(path, filter, &mut on_message)?;
+            }
+        }
+        if paths.len() > 1 {
+            for vehicle in recording.trains.values_mut() {
+                vehicle
+                    .records
+                    .sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+            }
+        }
+        // The messages still in the latest news ticker last until the end of the recording.
+        let end = recording
+            .trains
+            .values()
+            .filter_map(|vehicle| vehicle.records.last())
+            .map(|record| record.timestamp)
+            .reduce(f64::max);
+        if let Some(end) = end {
+            recording.incidents.extend(end);
+        }
+        Ok(recording)
+    }
+
+    fn load_jsonl<F>(
+        &mut self,
+        path: &Path,
+        filter: &RecordingFilter,
+        on_message: &mut F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(&ResponseMessage),
+    {
+        if filter.from.is_some() || filter.to.is_some() {
+            let index = TimeIndex::open(path)?;
+            index.for_each_in(path, filter.range(), |message| {
+                on_message(&message);
+                self.insert(message, filter);
+            })?;
+            if self.stations.is_empty() {
+                self.load_initial_stations(path)?;
+            }
+            return Ok(());
+        }
+        let reader = BufReader::new(File::open(path)?);
+        for line in reader.lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<ResponseMessage>(&line) {
+                Ok(message) => {
+                    on_message(&message);
+                    self.insert(message, filter);
+                }
+                Err(err) => self.errors.insert("message", err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the stations sent after connecting at the start of a recording, which a time range
+    /// usually skips.
+    fn load_initial_stations(&mut self, path: &Path) -> io::Result<()> {
+        let reader = BufReader::new(File::open(path)?);
+        for line in reader.lines() {
+            let Ok(message) = serde_json::from_str::<ResponseMessage>(&line?) else {
+                continue;
+            };
+            match message.content {
+                Content::Station(_) => {
+                    if let Ok(station) = Station::try_from(message.content) {
+                        self.stations.insert(station);
+                    }
+                }
+                Content::TrajectorySchematic(_) if !self.stations.is_empty() => break,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn load_columnar(&mut self, path: &Path, filter: &RecordingFilter) -> io::Result<()> {
+        let to_io = |err| io::Error::other(format!("{}: {err}", path.display()));
+        let columnar = ColumnarRecording::open(path).map_err(to_io)?;
+        let range = Some(filter.range());
+        for station in columnar.stations().map_err(to_io)?.iter() {
+            self.stations.insert(station.clone());
+        }
+        for record in columnar.records(range.clone()).map_err(to_io)? {
+            self.insert_record(record, filter);
+        }
+        for (timestamp, ticker) in columnar.news(range).map_err(to_io)? {
+            self.insert_news(&ticker, timestamp);
+        }
+        Ok(())
+    }
+
+    fn load_database(&mut self, path: &Path, filter: &RecordingFilter) -> io::Result<()> {
+        let to_io = |err| io::Error::other(format!("{}: {err}", path.display()));
+        let database = Database::open(path).map_err(to_io)?;
+        for station in database.stations().map_err(to_io)?.iter() {
+            self.stations.insert(station.clone());
+        }
+        for record in database.records(None, filter.range()).map_err(to_io)? {
+            self.insert_record(record, filter);
+        }
+        for (timestamp, ticker) in database.news(filter.range()).map_err(to_io)? {
+            self.insert_news(&ticker, timestamp);
+        }
+        Ok(())
+    }
+
+    fn insert_record(&mut self, record: Record, filter: &RecordingFilter) {
+        if filter.matches(&record) {
+            self.trains.insert(record);
+        }
+    }
+
+    fn insert_news(&mut self, ticker: &SbmNewsTicker, timestamp: f64) {
+        self.incidents.insert(ticker, timestamp);
+    }
+
+    /// Adds a message, trajectories outside of the filter are dropped.
+    pub fn insert(&mut self, message: ResponseMessage, filter: &RecordingFilter) {
+        match message.content {
+            Content::TrajectorySchematic(_) => match Record::parse(message, &mut self.errors) {
+                Ok(record) => self.insert_record(record, filter),
+                Err(err) => self.errors.insert("record", err),
+            },
+            Content::SbmNewsTicker(ref ticker) => self.insert_news(ticker, message.timestamp),
+            Content::Station(_) => match Station::try_from(message.content) {
+                Ok(station) => self.stations.insert(station),
+                Err(err) => self.errors.insert("station", err),
+            },
+            _ => {}
+        }
+    }
+}
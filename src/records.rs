@@ -0,0 +1,1107 @@
+//! Contains the types the recorded messages are parsed into to be analysed.
+
+use chrono::{DateTime, TimeZone};
+use chrono_tz::{Europe::Berlin, Tz};
+use geojson::GeoJson;
+use macroquad::color::Color;
+use serde::Serialize;
+use serde_json::Value;
+use serde_json::{self, Map};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut};
+use std::string::String;
+
+use crate::response_messages::{Content, ResponseMessage};
+
+#[derive(Debug)]
+pub enum AnalysisError {
+    MissingProperty(String),
+    /// The value at `path` exists, but cannot be interpreted as `expected`.
+    InvalidValue {
+        path: String,
+        expected: String,
+        value: String,
+    },
+    MissingItems {
+        path: String,
+        expected: usize,
+        actual: usize,
+    },
+    /// The line could not be parsed as a message at all.
+    InvalidJson(String),
+}
+
+impl AnalysisError {
+    pub(crate) fn invalid_value(path: &str, expected: &str, value: &impl Serialize) -> Self {
+        AnalysisError::InvalidValue {
+            path: path.to_string(),
+            expected: expected.to_string(),
+            value: serde_json::to_string(value).unwrap_or_default(),
+        }
+    }
+
+    /// Prepends `parent` to the path of the field the error occurred at.
+    pub fn prefixed(self, parent: &str) -> Self {
+        let join = |path: String| {
+            if path.is_empty() {
+                parent.to_string()
+            } else if path.starts_with('[') {
+                format!("{parent}{path}")
+            } else {
+                format!("{parent}.{path}")
+            }
+        };
+        match self {
+            AnalysisError::MissingProperty(path) => AnalysisError::MissingProperty(join(path)),
+            AnalysisError::InvalidValue {
+                path,
+                expected,
+                value,
+            } => AnalysisError::InvalidValue {
+                path: join(path),
+                expected,
+                value,
+            },
+            AnalysisError::MissingItems {
+                path,
+                expected,
+                actual,
+            } => AnalysisError::MissingItems {
+                path: join(path),
+                expected,
+                actual,
+            },
+            other => other,
+        }
+    }
+
+    /// Short name of the variant, used to group errors.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AnalysisError::MissingProperty(_) => "missing property",
+            AnalysisError::InvalidValue { .. } => "invalid value",
+            AnalysisError::MissingItems { .. } => "missing items",
+            AnalysisError::InvalidJson(_) => "invalid json",
+        }
+    }
+
+    /// Path of the field the error occurred at, if it is specific to one field.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            AnalysisError::MissingProperty(path)
+            | AnalysisError::InvalidValue { path, .. }
+            | AnalysisError::MissingItems { path, .. } => Some(path),
+            AnalysisError::InvalidJson(_) => None,
+        }
+    }
+}
+
+impl Display for AnalysisError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnalysisError::MissingProperty(property_name) => {
+                write!(f, "missing property: '{property_name}'")
+            }
+            AnalysisError::InvalidValue {
+                path,
+                expected,
+                value,
+            } => write!(
+                f,
+                "expected '{path}' to be of type '{expected}', but found the value '{value}'!"
+            ),
+            AnalysisError::MissingItems {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Expected {expected} items in '{path}', but found {actual} instead!"
+            ),
+            AnalysisError::InvalidJson(err) => write!(f, "unable to parse message: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for AnalysisError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<serde_json::Error> for AnalysisError {
+    fn from(value: serde_json::Error) -> Self {
+        AnalysisError::InvalidJson(value.to_string())
+    }
+}
+
+/// Collects the errors of a run, to report them grouped by their kind instead of one by one.
+#[derive(Debug, Default)]
+pub struct ErrorReport {
+    /// Occurrences per kind, context and field path, with the first error as an example.
+    errors: HashMap<(&'static str, String, String), (usize, AnalysisError)>,
+}
+
+impl ErrorReport {
+    pub fn new() -> Self {
+        Self {
+            errors: HashMap::new(),
+        }
+    }
+
+    /// Records an error that occurred while parsing `context`, e.g. `"train"`.
+    pub fn insert(&mut self, context: &str, error: AnalysisError) {
+        let key = (
+            error.kind(),
+            context.to_string(),
+            error.path().unwrap_or_default().to_string(),
+        );
+        self.errors
+            .entry(key)
+            .and_modify(|(count, _)| *count += 1)
+            .or_insert((1, error));
+    }
+
+    pub fn len(&self) -> usize {
+        self.errors.values().map(|(count, _)| count).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Number of errors per kind, sorted by the kind.
+    pub fn by_kind(&self) -> BTreeMap<&'static str, usize> {
+        let mut kinds = BTreeMap::new();
+        for ((kind, _, _), (count, _)) in &self.errors {
+            *kinds.entry(*kind).or_default() += count;
+        }
+        kinds
+    }
+}
+
+impl Display for ErrorReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut entries: Vec<_> = self.errors.iter().collect();
+        entries.sort_by(|(a, (a_count, _)), (b, (b_count, _))| {
+            a.0.cmp(b.0).then(b_count.cmp(a_count)).then(a.cmp(b))
+        });
+
+        writeln!(f, "{} errors", self.len())?;
+        let mut current_kind = None;
+        for ((kind, context, _), (count, example)) in entries {
+            if current_kind != Some(kind) {
+                let total: usize = self
+                    .errors
+                    .iter()
+                    .filter(|((k, _, _), _)| k == kind)
+                    .map(|(_, (count, _))| count)
+                    .sum();
+                writeln!(f, "{kind}: {total}")?;
+                current_kind = Some(kind);
+            }
+            writeln!(f, "\t{count:>8}  {context}: {example}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Coordinate {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Coordinate {
+    /// Mean radius of the earth in meters.
+    const EARTH_RADIUS: f64 = 6_371_008.8;
+
+    /// Converts a point given in web mercator (EPSG:3857), which is used for the geometries
+    /// of the websocket, into a coordinate.
+    pub fn from_web_mercator(x: f64, y: f64) -> Self {
+        const RADIUS: f64 = 6_378_137.0;
+        Self {
+            latitude: (2.0 * (y / RADIUS).exp().atan() - std::f64::consts::FRAC_PI_2).to_degrees(),
+            longitude: (x / RADIUS).to_degrees(),
+        }
+    }
+
+    /// Offset in meters to the east and north from `origin`, approximating the earth as flat,
+    /// which is precise enough within a city.
+    pub fn to_local(&self, origin: &Coordinate) -> (f64, f64) {
+        let scale = Self::EARTH_RADIUS.to_radians();
+        (
+            (self.longitude - origin.longitude) * scale * origin.latitude.to_radians().cos(),
+            (self.latitude - origin.latitude) * scale,
+        )
+    }
+
+    /// Inverse of [`Coordinate::to_local`].
+    pub fn from_local(origin: &Coordinate, east: f64, north: f64) -> Self {
+        let scale = Self::EARTH_RADIUS.to_radians();
+        Self {
+            latitude: origin.latitude + north / scale,
+            longitude: origin.longitude + east / (scale * origin.latitude.to_radians().cos()),
+        }
+    }
+
+    /// Great-circle distance to `other` in meters.
+    pub fn distance(&self, other: &Coordinate) -> f64 {
+        let (lat_a, lat_b) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let d_lat = lat_b - lat_a;
+        let d_lon = (other.longitude - self.longitude).to_radians();
+        let a =
+            (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * Self::EARTH_RADIUS * a.sqrt().asin()
+    }
+}
+
+impl TryFrom<serde_json::Value> for Coordinate {
+    type Error = AnalysisError;
+
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        match &value {
+            Value::Array(array) => {
+                if array.len() != 2 {
+                    return Err(AnalysisError::MissingItems {
+                        path: String::new(),
+                        expected: 2,
+                        actual: array.len(),
+                    });
+                }
+                let number = |index: usize| {
+                    array[index].as_f64().ok_or_else(|| {
+                        AnalysisError::invalid_value(&format!("[{index}]"), "f64", &array[index])
+                    })
+                };
+                Ok(Self {
+                    latitude: number(1)?,
+                    longitude: number(0)?,
+                })
+            }
+            _ => Err(AnalysisError::invalid_value("", "array", &value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct Line {
+    pub color: String,
+    pub id: i64,
+    pub name: String,
+    pub stroke: String,
+    pub text_color: String,
+}
+
+impl TryFrom<Value> for Line {
+    type Error = AnalysisError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match &value.as_object() {
+            Some(object) => Ok(Self {
+                color: object.extract("color")?,
+                id: object.extract("id")?,
+                name: object.extract("name")?,
+                stroke: object.extract("stroke")?,
+                text_color: object.extract("text_color")?,
+            }),
+            None => Err(AnalysisError::invalid_value("", "object", &value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Train {
+    pub delay: Option<String>,
+    pub has_journey: bool,
+    pub has_realtime: bool,
+    pub has_realtime_journey: bool,
+    pub line: Option<Line>,
+    pub operator_provides_realtime_journey: String,
+    pub original_line: Option<String>,
+    pub original_rake: Option<String>,
+    pub original_train_number: i32,
+    pub position_correction: i32,
+    pub rake: Option<String>,
+    pub raw_coordinates: Option<Coordinate>,
+    // raw_time
+    pub ride_state: Option<RideState>,
+    // route_identfier: Option<String>,
+    pub state: Option<State>,
+    pub tenant: String, // Most probably always `sbm`
+    // time_intervals
+    // time_since_update
+    // timestamp
+    pub train_id: String,
+    pub train_number: Option<i64>,
+    pub transmitting_vehicle: Option<String>, // Might be a unique id
+    // type: String, // Can only be rail
+    pub vehicle_number: Option<String>, // Might be a unique id
+}
+
+pub(crate) trait Extractor<T> {
+    type Error;
+    fn extract(&self, field_name: &str) -> Result<T, Self::Error>;
+}
+
+impl Extractor<String> for &Map<String, Value> {
+    type Error = AnalysisError;
+    fn extract(&self, field_name: &str) -> Result<String, Self::Error> {
+        match self.get(field_name) {
+            Some(property) => match property {
+                Value::String(value) => Ok(value.to_string()),
+                _ => Err(AnalysisError::invalid_value(field_name, "String", property)),
+            },
+            None => Err(AnalysisError::MissingProperty(field_name.to_string())),
+        }
+    }
+}
+
+impl Extractor<Option<String>> for &Map<String, Value> {
+    type Error = AnalysisError;
+    fn extract(&self, field_name: &str) -> Result<Option<String>, Self::Error> {
+        match self.get(field_name) {
+            Some(property) => match property {
+                Value::String(value) => Ok(Some(value.to_string())),
+                Value::Null => Ok(None),
+                _ => Err(AnalysisError::invalid_value(
+                    field_name,
+                    "Option<String>",
+                    property,
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl Extractor<i64> for &Map<String, Value> {
+    type Error = AnalysisError;
+    fn extract(&self, field_name: &str) -> Result<i64, Self::Error> {
+        match self.get(field_name) {
+            Some(property) => match property {
+                Value::Number(value) => value
+                    .as_i64()
+                    .ok_or_else(|| AnalysisError::invalid_value(field_name, "i64", property)),
+                _ => Err(AnalysisError::invalid_value(field_name, "i64", property)),
+            },
+            None => Err(AnalysisError::MissingProperty(field_name.to_string())),
+        }
+    }
+}
+
+impl Extractor<Option<i64>> for &Map<String, Value> {
+    type Error = AnalysisError;
+    fn extract(&self, field_name: &str) -> Result<Option<i64>, Self::Error> {
+        match self.get(field_name) {
+            Some(property) => match property {
+                Value::Number(value) => value.as_i64().map(Some).ok_or_else(|| {
+                    AnalysisError::invalid_value(field_name, "Option<i64>", property)
+                }),
+                Value::Null => Ok(None),
+                _ => Err(AnalysisError::invalid_value(
+                    field_name,
+                    "Option<i64>",
+                    property,
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl TryFrom<Content> for Train {
+    type Error = AnalysisError;
+
+    fn try_from(value: Content) -> Result<Self, Self::Error> {
+        match value {
+            Content::TrajectorySchematic(raw_train) => match raw_train {
+                GeoJson::Feature(feature) => match &feature.properties {
+                    Some(properties) => Ok(Self {
+                        delay: properties.extract("delay")?,
+                        has_journey: matches!(
+                            properties.get("has_journey"),
+                            Some(Value::Bool(true))
+                        ),
+                        has_realtime: matches!(
+                            properties.get("has_realtime"),
+                            Some(Value::Bool(true))
+                        ),
+                        has_realtime_journey: matches!(
+                            properties.get("has_realtime_journey"),
+                            Some(Value::Bool(true))
+                        ),
+                        line: properties
+                            .get("line")
+                            .filter(|l| !l.is_null())
+                            .map(|l| Line::try_from(l.to_owned()).map_err(|e| e.prefixed("line")))
+                            .transpose()?,
+                        operator_provides_realtime_journey: properties
+                            .extract("operator_provides_realtime_journey")?,
+                        original_line: properties.extract("original_line")?,
+                        original_rake: properties.extract("original_rake")?,
+                        original_train_number: 0,
+                        position_correction: 0,
+                        rake: properties.extract("rake")?,
+                        raw_coordinates: properties
+                            .get("raw_coordinates")
+                            .filter(|c| !c.is_null())
+                            .map(|c| {
+                                Coordinate::try_from(c.to_owned())
+                                    .map_err(|e| e.prefixed("raw_coordinates"))
+                            })
+                            .transpose()?,
+                        ride_state: properties
+                            .extract("ride_state")
+                            .map(|s: Option<String>| s.map(RideState::from))?,
+                        // route_identfier: properties.extract("route_identfier")?,
+                        state: properties
+                            .extract("state")
+                            .map(|s: Option<String>| s.map(State::from))?,
+                        tenant: properties.extract("tenant")?,
+                        train_id: properties.extract("train_id")?,
+                        train_number: properties.extract("train_number")?,
+                        transmitting_vehicle: properties.extract("transmitting_vehicle")?,
+                        vehicle_number: properties.extract("vehicle_number")?,
+                    }),
+                    None => Err(AnalysisError::MissingProperty("properties".to_string())),
+                },
+                other => Err(AnalysisError::invalid_value("content", "Feature", &other)),
+            },
+            other => Err(AnalysisError::invalid_value(
+                "source",
+                "trajectory_schematic",
+                &other.source(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Counter<T>(HashMap<T, usize>);
+
+impl<T> Counter<T> {
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    pub fn insert(&mut self, k: T) -> Option<usize>
+    where
+        T: Eq + Hash,
+    {
+        match self.0.get_mut(&k) {
+            Some(v) => {
+                *v += 1;
+                Some(*v)
+            }
+            None => self.0.insert(k, 1),
+        }
+    }
+}
+
+impl<T> Default for Counter<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Deref for Counter<T> {
+    type Target = HashMap<T, usize>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[derive(Debug)]
+pub enum ColorConversionError {
+    WrongBeginning,
+    WrongLength,
+    ParseInt(std::num::ParseIntError),
+}
+
+impl Display for ColorConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColorConversionError::WrongBeginning => write!(f, "does not start with '#'"),
+            ColorConversionError::WrongLength => write!(f, "is not of the form '#rrggbb'"),
+            ColorConversionError::ParseInt(err) => write!(f, "{err:?}"),
+        }
+    }
+}
+
+impl Error for ColorConversionError {}
+
+impl From<std::num::ParseIntError> for ColorConversionError {
+    fn from(value: std::num::ParseIntError) -> Self {
+        ColorConversionError::ParseInt(value)
+    }
+}
+
+/// Formats a color as `#rrggbb`, the inverse of [`try_color_from_string`].
+pub fn color_to_string(color: Color) -> String {
+    let [r, g, b, _]: [u8; 4] = color.into();
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Colors a line is drawn with on the official live map: a badge or circle in its `color` with
+/// an outline in `stroke` and its name in `text_color`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineStyle {
+    pub color: Color,
+    pub stroke: Color,
+    pub text_color: Color,
+}
+
+impl LineStyle {
+    /// Style of a line of which only the color is known, outlined in the same color and with
+    /// black or white text, whichever contrasts more.
+    pub fn from_color(color: Color) -> Self {
+        let luminance = 0.2126 * color.r + 0.7152 * color.g + 0.0722 * color.b;
+        Self {
+            color,
+            stroke: color,
+            text_color: if luminance > 0.5 {
+                Color::new(0.0, 0.0, 0.0, 1.0)
+            } else {
+                Color::new(1.0, 1.0, 1.0, 1.0)
+            },
+        }
+    }
+}
+
+pub fn try_color_from_string(s: String) -> Result<Color, ColorConversionError> {
+    if !s.starts_with('#') {
+        return Err(ColorConversionError::WrongBeginning);
+    }
+
+    let component = |range| s.get(range).ok_or(ColorConversionError::WrongLength);
+    let r: u8 = u8::from_str_radix(component(1..3)?, 16)?;
+    let g: u8 = u8::from_str_radix(component(3..5)?, 16)?;
+    let b: u8 = u8::from_str_radix(component(5..7)?, 16)?;
+
+    Ok(Color {
+        r: f32::from(r) / 255.0,
+        g: f32::from(g) / 255.0,
+        b: f32::from(b) / 255.0,
+        a: 1.0,
+    })
+}
+
+/// State of a vehicle as sent in the `state` property of a trajectory.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum State {
+    Driving,
+    Boarding,
+    JourneyCancelled,
+    StopCancelled,
+    /// Any state that is not known yet, containing the raw value.
+    Unknown(String),
+}
+
+impl From<String> for State {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "DRIVING" => Self::Driving,
+            "BOARDING" => Self::Boarding,
+            "JOURNEY_CANCELLED" => Self::JourneyCancelled,
+            "STOP_CANCELLED" => Self::StopCancelled,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            State::Driving => write!(f, "DRIVING"),
+            State::Boarding => write!(f, "BOARDING"),
+            State::JourneyCancelled => write!(f, "JOURNEY_CANCELLED"),
+            State::StopCancelled => write!(f, "STOP_CANCELLED"),
+            State::Unknown(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+/// State of the ride as sent in the `ride_state` property of a trajectory.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RideState {
+    Scheduled,
+    Realtime,
+    Cancelled,
+    /// Any ride state that is not known yet, containing the raw value.
+    Unknown(String),
+}
+
+impl From<String> for RideState {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "SCHEDULED" => Self::Scheduled,
+            "REALTIME" => Self::Realtime,
+            "CANCELLED" => Self::Cancelled,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+impl Display for RideState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RideState::Scheduled => write!(f, "SCHEDULED"),
+            RideState::Realtime => write!(f, "REALTIME"),
+            RideState::Cancelled => write!(f, "CANCELLED"),
+            RideState::Unknown(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    /// Milliseconds since the unix epoch, as sent by the websocket.
+    pub timestamp: f64,
+    pub position: Coordinate,
+    pub line: String,
+    pub line_color: Color,
+    /// Outline and text color of the line, `None` if the recording was stored without them.
+    pub line_stroke: Option<Color>,
+    pub line_text_color: Option<Color>,
+    pub state: State,
+    pub ride_state: Option<RideState>,
+    /// Delay in seconds as reported by the feed.
+    pub delay: Option<f64>,
+    pub train_id: String,
+    pub vehicle_number: String,
+    pub train_number: i64,
+    /// Units the train consists of as sent by the feed, see [`crate::circulation::decode_rake`].
+    pub rake: Option<String>,
+    /// Units the trip is planned to run with, in the format of `rake`.
+    pub original_rake: Option<String>,
+}
+
+/// Parses the `delay` of a trajectory, which is given in milliseconds either as a number or as a
+/// string, into seconds.
+pub fn parse_delay(value: Option<&Value>) -> Result<Option<f64>, AnalysisError> {
+    match value {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Number(number)) => Ok(number.as_f64().map(|ms| ms / 1000.0)),
+        Some(value @ Value::String(string)) => string
+            .trim()
+            .parse::<f64>()
+            .map(|ms| Some(ms / 1000.0))
+            .map_err(|_| AnalysisError::invalid_value("delay", "milliseconds", value)),
+        Some(value) => Err(AnalysisError::invalid_value("delay", "milliseconds", value)),
+    }
+}
+
+/// Converts milliseconds since the unix epoch into the local time of Munich, timestamps out of
+/// the range that can be represented fall back to the epoch.
+pub fn local_time(timestamp: f64) -> DateTime<Tz> {
+    Berlin
+        .timestamp_millis_opt(timestamp as i64)
+        .single()
+        .unwrap_or_else(|| Berlin.timestamp_nanos(0))
+}
+
+impl Record {
+    /// Colors of the line, with defaults for the ones that are not known.
+    pub fn line_style(&self) -> LineStyle {
+        let default = LineStyle::from_color(self.line_color);
+        LineStyle {
+            color: self.line_color,
+            stroke: self.line_stroke.unwrap_or(default.stroke),
+            text_color: self.line_text_color.unwrap_or(default.text_color),
+        }
+    }
+
+    /// Whether the journey or the next stop of the train has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self.state, State::JourneyCancelled | State::StopCancelled)
+            || self.ride_state == Some(RideState::Cancelled)
+    }
+
+    /// The time of the record in the local time of Munich. Unlike [`local_time`], which falls
+    /// back to the epoch for timestamps out of range, this is always the actual time, as the
+    /// timestamp is checked to be in range when the record is parsed.
+    pub fn local_time(&self) -> DateTime<Tz> {
+        local_time(self.timestamp)
+    }
+
+    /// Parses the record of a trajectory like [`TryFrom`], but a delay that cannot be parsed is
+    /// stored as unknown and reported in `errors` instead of discarding the whole record.
+    pub fn parse(value: ResponseMessage, errors: &mut ErrorReport) -> Result<Self, AnalysisError> {
+        match value.content {
+            Content::TrajectorySchematic(trajectory) => match trajectory {
+                GeoJson::Feature(feature) => match &feature.properties {
+                    Some(properties) => {
+                        if Berlin
+                            .timestamp_millis_opt(value.timestamp as i64)
+                            .single()
+                            .is_none()
+                        {
+                            return Err(AnalysisError::InvalidValue {
+                                path: "timestamp".to_string(),
+                                expected: "timestamp in milliseconds".to_string(),
+                                value: value.timestamp.to_string(),
+                            });
+                        }
+                        let line = properties
+                            .get("line")
+                            .ok_or(AnalysisError::MissingProperty("line".to_string()))?;
+                        let line = line
+                            .as_object()
+                            .ok_or_else(|| AnalysisError::invalid_value("line", "object", line))?;
+                        let line_color: String = line
+                            .extract("color")
+                            .map_err(|e: AnalysisError| e.prefixed("line"))?;
+                        // Only used for drawing, so an invalid one is left out.
+                        let optional_color = |field: &str| {
+                            line.extract(field)
+                                .ok()
+                                .flatten()
+                                .and_then(|color: String| try_color_from_string(color).ok())
+                        };
+
+                        Ok(Self {
+                            timestamp: value.timestamp,
+                            position: properties
+                                .get("raw_coordinates")
+                                .map(std::borrow::ToOwned::to_owned)
+                                .ok_or(AnalysisError::MissingProperty(
+                                    "raw_coordinates".to_string(),
+                                ))?
+                                .try_into()
+                                .map_err(|e: AnalysisError| e.prefixed("raw_coordinates"))?,
+                            line: line
+                                .extract("name")
+                                .map_err(|e: AnalysisError| e.prefixed("line"))?,
+                            line_color: try_color_from_string(line_color.clone()).map_err(
+                                |_| AnalysisError::InvalidValue {
+                                    path: "line.color".to_string(),
+                                    expected: "color".to_string(),
+                                    value: line_color,
+                                },
+                            )?,
+                            line_stroke: optional_color("stroke"),
+                            line_text_color: optional_color("text_color"),
+                            state: properties
+                                .extract("state")
+                                .map(|s: String| State::from(s))?,
+                            ride_state: properties
+                                .extract("ride_state")
+                                .map(|s: Option<String>| s.map(RideState::from))?,
+                            delay: parse_delay(properties.get("delay")).unwrap_or_else(|err| {
+                                errors.insert("record", err);
+                                None
+                            }),
+                            train_id: properties.extract("train_id")?,
+                            vehicle_number: properties.extract("vehicle_number")?,
+                            train_number: properties.extract("train_number")?,
+                            rake: properties.extract("rake")?,
+                            original_rake: properties.extract("original_rake")?,
+                        })
+                    }
+                    None => Err(AnalysisError::MissingProperty("properties".to_string())),
+                },
+                other => Err(AnalysisError::invalid_value("content", "Feature", &other)),
+            },
+            other => Err(AnalysisError::invalid_value(
+                "source",
+                "trajectory_schematic",
+                &other.source(),
+            )),
+        }
+    }
+}
+
+/// Discards problems that do not prevent parsing the record, use [`Record::parse`] to get them.
+impl TryFrom<ResponseMessage> for Record {
+    type Error = AnalysisError;
+
+    fn try_from(value: ResponseMessage) -> Result<Self, Self::Error> {
+        Self::parse(value, &mut ErrorReport::new())
+    }
+}
+
+/// A contiguous period in which a vehicle reported the same state for the same train.
+#[derive(Debug, Clone)]
+pub struct Phase<'a> {
+    pub state: &'a State,
+    pub train_number: i64,
+    pub records: &'a [Record],
+}
+
+impl<'a> Phase<'a> {
+    /// Splits `records` ordered by time into the periods of unchanged state and train number.
+    pub fn split(records: &'a [Record]) -> Vec<Self> {
+        records
+            .chunk_by(|a, b| a.state == b.state && a.train_number == b.train_number)
+            .map(|records| Phase {
+                state: &records[0].state,
+                train_number: records[0].train_number,
+                records,
+            })
+            .collect()
+    }
+
+    /// Timestamp of the first record of the phase.
+    pub fn start(&self) -> f64 {
+        self.records.first().map_or(0.0, |r| r.timestamp)
+    }
+
+    /// Timestamp of the last record of the phase.
+    pub fn end(&self) -> f64 {
+        self.records.last().map_or(0.0, |r| r.timestamp)
+    }
+
+    /// Observed duration of the phase in seconds.
+    pub fn duration(&self) -> f64 {
+        (self.end() - self.start()) / 1000.0
+    }
+}
+
+/// Change of the state of a vehicle, e.g. from `DRIVING` to `BOARDING`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateTransition {
+    pub from: State,
+    pub to: State,
+    /// Timestamp of the first record with the new state.
+    pub timestamp: f64,
+    pub train_number: i64,
+}
+
+pub struct Vehicle {
+    pub number: String,
+    pub records: Vec<Record>,
+}
+
+impl Vehicle {
+    pub fn from_record(r: Record) -> Self {
+        Self {
+            number: r.vehicle_number.clone(),
+            records: vec![r],
+        }
+    }
+
+    pub fn update(&mut self, r: Record) {
+        self.records.push(r);
+    }
+
+    /// Splits the records into the periods of unchanged state and train number.
+    pub fn phases(&self) -> Vec<Phase<'_>> {
+        Phase::split(&self.records)
+    }
+
+    /// Lists every change of the state, a change of the train number alone is not a transition.
+    pub fn transitions(&self) -> Vec<StateTransition> {
+        self.phases()
+            .windows(2)
+            .filter(|pair| pair[0].state != pair[1].state)
+            .map(|pair| StateTransition {
+                from: pair[0].state.clone(),
+                to: pair[1].state.clone(),
+                timestamp: pair[1].start(),
+                train_number: pair[1].train_number,
+            })
+            .collect()
+    }
+}
+
+pub struct Trains(HashMap<String, Vehicle>);
+
+impl Trains {
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    /// Splits the records into the ones matching `predicate` and the rest, keeping their order.
+    pub fn partition<F>(&self, mut predicate: F) -> (Trains, Trains)
+    where
+        F: FnMut(&Record) -> bool,
+    {
+        let (mut matching, mut rest) = (Trains::new(), Trains::new());
+        for record in self.values().flat_map(|v| v.records.iter()) {
+            if predicate(record) {
+                matching.insert(record.clone());
+            } else {
+                rest.insert(record.clone());
+            }
+        }
+        (matching, rest)
+    }
+
+    pub fn insert(&mut self, r: Record) {
+        match self.get_mut(&r.vehicle_number) {
+            Some(v) => v.update(r),
+            None => {
+                self.0
+                    .insert(r.vehicle_number.clone(), Vehicle::from_record(r));
+            }
+        }
+    }
+}
+
+impl Default for Trains {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for Trains {
+    type Target = HashMap<String, Vehicle>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Trains {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(properties: Value) -> ResponseMessage {
+        serde_json::from_value(json!({
+            "source": "trajectory_schematic",
+            "content": {"type": "Feature", "geometry": null, "properties": properties},
+            "timestamp": 1699300000000.0,
+            "client_reference": null,
+        }))
+        .expect("valid message")
+    }
+
+    fn properties() -> Value {
+        json!({
+            "train_id": "sbm_6401",
+            "train_number": 6401,
+            "vehicle_number": "423 6401",
+            "line": {"name": "S1", "color": "#16bae7", "text_color": "#ffffff"},
+            "state": "BOARDING",
+            "ride_state": "REALTIME",
+            "delay": "80618",
+            "raw_coordinates": [11.5, 48.1],
+        })
+    }
+
+    #[test]
+    fn parses_record() {
+        let record = Record::try_from(message(properties())).expect("valid record");
+        assert_eq!(record.line, "S1");
+        assert_eq!(record.train_number, 6401);
+        assert_eq!(record.position.latitude, 48.1);
+        assert_eq!(record.position.longitude, 11.5);
+        assert_eq!(record.delay, Some(80.618));
+    }
+
+    #[test]
+    fn reports_path_of_invalid_coordinate() {
+        let mut properties = properties();
+        properties["raw_coordinates"] = json!([11.5, "48.1"]);
+        let error = Record::try_from(message(properties)).unwrap_err();
+        assert!(matches!(
+            &error,
+            AnalysisError::InvalidValue { path, expected, value }
+                if path == "raw_coordinates[1]" && expected == "f64" && value == "\"48.1\""
+        ));
+    }
+
+    #[test]
+    fn reports_missing_property() {
+        let mut properties = properties();
+        properties
+            .as_object_mut()
+            .unwrap()
+            .remove("raw_coordinates");
+        let error = Record::try_from(message(properties)).unwrap_err();
+        assert!(
+            matches!(&error, AnalysisError::MissingProperty(path) if path == "raw_coordinates")
+        );
+    }
+
+    #[test]
+    fn reports_invalid_line_color() {
+        let mut properties = properties();
+        properties["line"]["color"] = json!("blue");
+        let error = Record::try_from(message(properties)).unwrap_err();
+        assert_eq!(error.path(), Some("line.color"));
+    }
+
+    #[test]
+    fn rejects_other_sources() {
+        let message: ResponseMessage = serde_json::from_value(json!({
+            "source": "deleted_vehicles_schematic",
+            "content": "sbm_6401",
+            "timestamp": 1699300000000.0,
+            "client_reference": null,
+        }))
+        .expect("valid message");
+        let error = Record::try_from(message).unwrap_err();
+        assert!(matches!(
+            &error,
+            AnalysisError::InvalidValue { path, expected, value }
+                if path == "source"
+                    && expected == "trajectory_schematic"
+                    && value == "\"deleted_vehicles_schematic\""
+        ));
+    }
+
+    #[test]
+    fn coordinate_expects_two_numbers() {
+        let coordinate = Coordinate::try_from(json!([11.5, 48.1])).expect("valid coordinate");
+        assert_eq!(coordinate.latitude, 48.1);
+        assert_eq!(coordinate.longitude, 11.5);
+        assert!(matches!(
+            Coordinate::try_from(json!([11.5])),
+            Err(AnalysisError::MissingItems {
+                expected: 2,
+                actual: 1,
+                ..
+            })
+        ));
+        assert!(matches!(
+            Coordinate::try_from(json!({"latitude": 48.1})),
+            Err(AnalysisError::InvalidValue { expected, .. }) if expected == "array"
+        ));
+        let error = Coordinate::try_from(json!([null, 48.1])).unwrap_err();
+        assert_eq!(error.path(), Some("[0]"));
+    }
+
+    #[test]
+    fn parses_delay_in_seconds() {
+        assert_eq!(parse_delay(None).unwrap(), None);
+        assert_eq!(parse_delay(Some(&Value::Null)).unwrap(), None);
+        assert_eq!(parse_delay(Some(&json!(90000))).unwrap(), Some(90.0));
+        assert_eq!(parse_delay(Some(&json!(-1500.0))).unwrap(), Some(-1.5));
+        assert_eq!(parse_delay(Some(&json!(" 80618 "))).unwrap(), Some(80.618));
+    }
+
+    #[test]
+    fn rejects_invalid_delay() {
+        for value in [json!("late"), json!(""), json!(true), json!([1000])] {
+            let error = parse_delay(Some(&value)).unwrap_err();
+            assert!(
+                matches!(&error, AnalysisError::InvalidValue { path, .. } if path == "delay"),
+                "{value}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_record_with_invalid_delay() {
+        let mut properties = properties();
+        properties["delay"] = json!("late");
+        let mut errors = ErrorReport::new();
+        let record = Record::parse(message(properties), &mut errors).expect("valid record");
+        assert_eq!(record.delay, None);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors.by_kind().get("invalid value"), Some(&1));
+    }
+}
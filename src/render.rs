@@ -0,0 +1,547 @@
+//! Draws the network and the vehicles of a recording into images without a window, so replays can
+//! be exported as PNG frames or animated GIFs on a server, e.g. for incident reports.
+//!
+//! The network consists of the stations and the segments between consecutive stops observed in
+//! the recording. Between two records a vehicle is interpolated linearly, so the frames can be
+//! closer together than the updates of the feed.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use embedded_graphics::mono_font::ascii::FONT_10X20;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{
+    Circle, Line, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle,
+};
+use embedded_graphics::text::{Baseline, Text};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, ImageError, ImageResult, Rgba, RgbaImage};
+use macroquad::color::Color;
+
+use crate::records::{local_time, Coordinate, Record, Trains};
+use crate::segments::SegmentStatistics;
+use crate::stations::{Station, Stations};
+
+/// Maps coordinates to pixels, keeping the aspect ratio of the area.
+#[derive(Debug, Clone)]
+pub struct Projection {
+    origin: Coordinate,
+    /// Offset in meters of the center of the image from the origin.
+    center: (f64, f64),
+    /// Pixels per meter.
+    scale: f64,
+    width: u32,
+    height: u32,
+}
+
+impl Projection {
+    /// Share of the image left free at each border.
+    const MARGIN: f64 = 0.05;
+
+    /// Fits `positions` into an image of `width` × `height` pixels. Without any position the
+    /// center of Munich is shown.
+    pub fn fit<'a>(
+        positions: impl IntoIterator<Item = &'a Coordinate>,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let positions: Vec<&Coordinate> = positions.into_iter().collect();
+        let bound = |value: fn(&Coordinate) -> f64| {
+            let values = positions.iter().map(|&position| value(position));
+            (
+                values.clone().fold(f64::INFINITY, f64::min),
+                values.fold(f64::NEG_INFINITY, f64::max),
+            )
+        };
+        let (south, north) = bound(|position| position.latitude);
+        let (west, east) = bound(|position| position.longitude);
+        if !(south.is_finite() && west.is_finite()) {
+            return Self {
+                origin: Coordinate {
+                    latitude: 48.14,
+                    longitude: 11.56,
+                },
+                center: (0.0, 0.0),
+                scale: f64::from(width.min(height)) / 50_000.0,
+                width,
+                height,
+            };
+        }
+        let origin = Coordinate {
+            latitude: (south + north) / 2.0,
+            longitude: (west + east) / 2.0,
+        };
+        let (west, south) = Coordinate {
+            latitude: south,
+            longitude: west,
+        }
+        .to_local(&origin);
+        let (east, north) = Coordinate {
+            latitude: north,
+            longitude: east,
+        }
+        .to_local(&origin);
+        let usable = 1.0 - 2.0 * Self::MARGIN;
+        Self {
+            origin,
+            center: ((west + east) / 2.0, (south + north) / 2.0),
+            scale: (f64::from(width) * usable / (east - west).max(1.0))
+                .min(f64::from(height) * usable / (north - south).max(1.0)),
+            width,
+            height,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Pixels per meter.
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// Pixel of `position`, x to the right and y downwards.
+    pub fn project(&self, position: &Coordinate) -> (f32, f32) {
+        let (east, north) = position.to_local(&self.origin);
+        (
+            (f64::from(self.width) / 2.0 + (east - self.center.0) * self.scale) as f32,
+            (f64::from(self.height) / 2.0 - (north - self.center.1) * self.scale) as f32,
+        )
+    }
+
+    /// Inverse of [`Projection::project`].
+    pub fn unproject(&self, x: f32, y: f32) -> Coordinate {
+        let east = (f64::from(x) - f64::from(self.width) / 2.0) / self.scale + self.center.0;
+        let north = (f64::from(self.height) / 2.0 - f64::from(y)) / self.scale + self.center.1;
+        Coordinate::from_local(&self.origin, east, north)
+    }
+
+    /// Scales by `factor`, keeping the coordinate at the pixel `(x, y)` in place.
+    pub fn zoom(&mut self, factor: f64, x: f32, y: f32) {
+        let (x, y) = (
+            f64::from(x) - f64::from(self.width) / 2.0,
+            f64::from(self.height) / 2.0 - f64::from(y),
+        );
+        let (east, north) = (
+            x / self.scale + self.center.0,
+            y / self.scale + self.center.1,
+        );
+        self.scale *= factor;
+        self.center = (east - x / self.scale, north - y / self.scale);
+    }
+
+    /// Moves the content by `dx` pixels to the right and `dy` pixels downwards.
+    pub fn pan(&mut self, dx: f32, dy: f32) {
+        self.center.0 -= f64::from(dx) / self.scale;
+        self.center.1 += f64::from(dy) / self.scale;
+    }
+}
+
+/// Segment between two consecutive stops.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub from: String,
+    pub to: String,
+    pub start: Coordinate,
+    pub end: Coordinate,
+}
+
+/// Stations and the segments between them that trains were observed on.
+#[derive(Debug, Clone, Default)]
+pub struct Network {
+    stations: Vec<Station>,
+    segments: Vec<Segment>,
+}
+
+impl Network {
+    /// Builds the network from the consecutive stops of the `trains`. A segment travelled in both
+    /// directions is only contained once.
+    pub fn from_trains(trains: &Trains, stations: &Stations) -> Self {
+        let mut pairs: BTreeSet<(&str, &str)> = BTreeSet::new();
+        for vehicle in trains.values() {
+            let stops = SegmentStatistics::stops(&vehicle.records, stations);
+            for pair in stops.windows(2) {
+                let (previous, next) = (&pair[0], &pair[1]);
+                if next.continuous
+                    && previous.station != next.station
+                    && previous.departure.train_number == next.departure.train_number
+                {
+                    pairs.insert(if previous.station < next.station {
+                        (previous.station, next.station)
+                    } else {
+                        (next.station, previous.station)
+                    });
+                }
+            }
+        }
+        let mut stations_sorted: Vec<Station> = stations.iter().cloned().collect();
+        stations_sorted.sort_by(|a, b| a.name.cmp(&b.name));
+        Self {
+            segments: pairs
+                .into_iter()
+                .filter_map(|(from, to)| {
+                    Some(Segment {
+                        from: from.to_string(),
+                        to: to.to_string(),
+                        start: stations.get(from)?.position.clone(),
+                        end: stations.get(to)?.position.clone(),
+                    })
+                })
+                .collect(),
+            stations: stations_sorted,
+        }
+    }
+
+    pub fn stations(&self) -> &[Station] {
+        &self.stations
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+}
+
+/// Position of a vehicle at `timestamp` in milliseconds, interpolated between its `records`
+/// ordered by time, together with its latest record. Vehicles without a record within the
+/// `max_age` in seconds before are not shown.
+pub fn position_at(
+    records: &[Record],
+    timestamp: f64,
+    max_age: f64,
+) -> Option<(Coordinate, &Record)> {
+    let index = records.partition_point(|r| r.timestamp <= timestamp);
+    let previous = records.get(index.checked_sub(1)?)?;
+    let max_age = max_age * 1000.0;
+    if timestamp - previous.timestamp > max_age {
+        return None;
+    }
+    let position = match records.get(index) {
+        Some(next) if next.timestamp - previous.timestamp <= max_age => {
+            let t = (timestamp - previous.timestamp) / (next.timestamp - previous.timestamp);
+            Coordinate {
+                latitude: previous.position.latitude
+                    + t * (next.position.latitude - previous.position.latitude),
+                longitude: previous.position.longitude
+                    + t * (next.position.longitude - previous.position.longitude),
+            }
+        }
+        _ => previous.position.clone(),
+    };
+    Some((position, previous))
+}
+
+/// Heading of a vehicle at `timestamp` in degrees clockwise from north, taken from its movement
+/// towards the next record or, while it stands, from its last movement within the `max_age` in
+/// seconds. The feed does not send the heading itself.
+pub fn heading_at(records: &[Record], timestamp: f64, max_age: f64) -> Option<f64> {
+    /// Minimum distance in meters between two positions to derive a heading, below it the
+    /// difference is noise of the positioning.
+    const MIN_DISTANCE: f64 = 5.0;
+    let index = records.partition_point(|r| r.timestamp <= timestamp);
+    let oldest = timestamp - max_age * 1000.0;
+    let end = (index + 1).min(records.len());
+    records[..end]
+        .windows(2)
+        .rev()
+        .take_while(|pair| pair[1].timestamp >= oldest)
+        .filter(|pair| pair[1].timestamp - pair[0].timestamp <= max_age * 1000.0)
+        .find(|pair| pair[0].position.distance(&pair[1].position) >= MIN_DISTANCE)
+        .map(|pair| {
+            let (east, north) = pair[1].position.to_local(&pair[0].position);
+            east.atan2(north).to_degrees().rem_euclid(360.0)
+        })
+}
+
+/// An image drawn in software.
+#[derive(Debug, Clone)]
+pub struct Canvas {
+    image: RgbaImage,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32, background: Color) -> Self {
+        Self {
+            image: RgbaImage::from_pixel(width, height, Rgba(rgba(background))),
+        }
+    }
+
+    pub fn draw_line(&mut self, from: (f32, f32), to: (f32, f32), width: u32, color: Color) {
+        let _ = Line::new(point(from), point(to))
+            .into_styled(PrimitiveStyle::with_stroke(rgb(color), width))
+            .draw(self);
+    }
+
+    /// Draws a filled circle, with an outline if `outline` is given.
+    pub fn draw_circle(
+        &mut self,
+        center: (f32, f32),
+        diameter: u32,
+        color: Color,
+        outline: Option<Color>,
+    ) {
+        let mut style = PrimitiveStyleBuilder::new().fill_color(rgb(color));
+        if let Some(outline) = outline {
+            style = style.stroke_color(rgb(outline)).stroke_width(1);
+        }
+        let _ = Circle::with_center(point(center), diameter)
+            .into_styled(style.build())
+            .draw(self);
+    }
+
+    /// Draws `text` with its top left corner at `position` onto a box of the `background`.
+    pub fn draw_label(
+        &mut self,
+        position: (f32, f32),
+        text: &str,
+        color: Color,
+        background: Color,
+    ) {
+        let text = Text::with_baseline(
+            text,
+            point(position),
+            MonoTextStyle::new(&FONT_10X20, rgb(color)),
+            Baseline::Top,
+        );
+        let bounds = text.bounding_box();
+        let _ = Rectangle::new(
+            bounds.top_left - Point::new(4, 2),
+            bounds.size + Size::new(8, 4),
+        )
+        .into_styled(PrimitiveStyle::with_fill(rgb(background)))
+        .draw(self);
+        let _ = text.draw(self);
+    }
+
+    pub fn image(&self) -> &RgbaImage {
+        &self.image
+    }
+
+    pub fn into_image(self) -> RgbaImage {
+        self.image
+    }
+
+    pub fn save_png(&self, path: impl AsRef<Path>) -> ImageResult<()> {
+        self.image.save_with_format(path, image::ImageFormat::Png)
+    }
+}
+
+impl OriginDimensions for Canvas {
+    fn size(&self) -> Size {
+        Size::new(self.image.width(), self.image.height())
+    }
+}
+
+impl DrawTarget for Canvas {
+    type Color = Rgb888;
+    type Error = std::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(position, color) in pixels {
+            if let (Ok(x), Ok(y)) = (u32::try_from(position.x), u32::try_from(position.y)) {
+                if x < self.image.width() && y < self.image.height() {
+                    self.image
+                        .put_pixel(x, y, Rgba([color.r(), color.g(), color.b(), 255]));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn point((x, y): (f32, f32)) -> Point {
+    Point::new(x.round() as i32, y.round() as i32)
+}
+
+fn rgba(color: Color) -> [u8; 4] {
+    let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+    [
+        channel(color.r),
+        channel(color.g),
+        channel(color.b),
+        channel(color.a),
+    ]
+}
+
+fn rgb(color: Color) -> Rgb888 {
+    let [r, g, b, _] = rgba(color);
+    Rgb888::new(r, g, b)
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    pub width: u32,
+    pub height: u32,
+    /// Seconds of the recording between two frames.
+    pub step: f64,
+    /// Milliseconds each frame of an animation is shown.
+    pub frame_duration: u32,
+    /// Vehicles without a record for this many seconds are not drawn.
+    pub max_age: f64,
+    /// Diameter of a vehicle in pixels.
+    pub vehicle_size: u32,
+    /// Whether the time is drawn in the top left corner.
+    pub timestamp: bool,
+    pub background: Color,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            width: 1280,
+            height: 720,
+            step: 6.0,
+            frame_duration: 100,
+            max_age: 120.0,
+            vehicle_size: 12,
+            timestamp: true,
+            background: Color::from_hex(0x00F0_F0F0),
+        }
+    }
+}
+
+/// Renders the trains of a recording at any time.
+pub struct Replay<'a> {
+    trains: &'a Trains,
+    network: Network,
+    projection: Projection,
+    config: ReplayConfig,
+}
+
+impl<'a> Replay<'a> {
+    /// Fits the image to the stations, or to the trains if there are none.
+    pub fn new(trains: &'a Trains, stations: &Stations, config: ReplayConfig) -> Self {
+        let network = Network::from_trains(trains, stations);
+        let projection = if network.stations().is_empty() {
+            Projection::fit(
+                trains
+                    .values()
+                    .flat_map(|vehicle| &vehicle.records)
+                    .map(|record| &record.position),
+                config.width,
+                config.height,
+            )
+        } else {
+            Projection::fit(
+                network.stations().iter().map(|station| &station.position),
+                config.width,
+                config.height,
+            )
+        };
+        Self {
+            trains,
+            network,
+            projection,
+            config,
+        }
+    }
+
+    pub fn projection(&self) -> &Projection {
+        &self.projection
+    }
+
+    /// Timestamps of the first and the last record in milliseconds.
+    pub fn time_range(&self) -> Option<(f64, f64)> {
+        let records = || self.trains.values().flat_map(|vehicle| &vehicle.records);
+        let first = records().map(|r| r.timestamp).reduce(f64::min)?;
+        let last = records().map(|r| r.timestamp).reduce(f64::max)?;
+        Some((first, last))
+    }
+
+    /// Timestamps of the frames from `from` to `to` in milliseconds.
+    pub fn timestamps(&self, from: f64, to: f64) -> impl Iterator<Item = f64> {
+        let step = self.config.step.max(0.001) * 1000.0;
+        let count = ((to - from) / step).floor().max(0.0) as usize + 1;
+        (0..count).map(move |frame| from + frame as f64 * step)
+    }
+
+    pub fn render(&self, timestamp: f64) -> Canvas {
+        let config = &self.config;
+        let mut canvas = Canvas::new(config.width, config.height, config.background);
+        for segment in self.network.segments() {
+            canvas.draw_line(
+                self.projection.project(&segment.start),
+                self.projection.project(&segment.end),
+                3,
+                Color::from_hex(0x00C8_C8C8),
+            );
+        }
+        for station in self.network.stations() {
+            canvas.draw_circle(
+                self.projection.project(&station.position),
+                5,
+                Color::from_hex(0x0078_7878),
+                None,
+            );
+        }
+        let mut vehicles: Vec<(Coordinate, &Record)> = self
+            .trains
+            .values()
+            .filter_map(|vehicle| position_at(&vehicle.records, timestamp, config.max_age))
+            .collect();
+        // Drawn in a fixed order, so vehicles on top of each other do not flicker.
+        vehicles.sort_by(|(_, a), (_, b)| a.vehicle_number.cmp(&b.vehicle_number));
+        for (position, record) in vehicles {
+            canvas.draw_circle(
+                self.projection.project(&position),
+                config.vehicle_size,
+                record.line_color,
+                Some(Color::from_hex(0x0020_2020)),
+            );
+        }
+        if config.timestamp {
+            canvas.draw_label(
+                (12.0, 10.0),
+                &local_time(timestamp)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string(),
+                Color::from_hex(0x0020_2020),
+                Color::from_hex(0x00FF_FFFF),
+            );
+        }
+        canvas
+    }
+
+    /// Writes the frames from `from` to `to` as `frame-00000.png` and so on into `directory`,
+    /// returning the number of frames.
+    pub fn write_frames(&self, directory: &Path, from: f64, to: f64) -> ImageResult<usize> {
+        fs::create_dir_all(directory).map_err(ImageError::IoError)?;
+        let mut frames = 0;
+        for timestamp in self.timestamps(from, to) {
+            self.render(timestamp)
+                .save_png(directory.join(format!("frame-{frames:05}.png")))?;
+            frames += 1;
+        }
+        Ok(frames)
+    }
+
+    /// Writes the frames from `from` to `to` as an animated GIF that repeats forever, returning
+    /// the number of frames.
+    pub fn write_gif<W: Write>(&self, writer: W, from: f64, to: f64) -> ImageResult<usize> {
+        // The palette of a frame is small, so a fast quantization is good enough.
+        let mut encoder = GifEncoder::new_with_speed(writer, 10);
+        encoder.set_repeat(Repeat::Infinite)?;
+        let mut frames = 0;
+        for timestamp in self.timestamps(from, to) {
+            encoder.encode_frame(image::Frame::from_parts(
+                self.render(timestamp).into_image(),
+                0,
+                0,
+                Delay::from_numer_denom_ms(self.config.frame_duration, 1),
+            ))?;
+            frames += 1;
+        }
+        Ok(frames)
+    }
+}
@@ -0,0 +1,492 @@
+//! Summarizes a recording as a statistics report, which can be written as JSON or Markdown.
+//!
+//! All maps are ordered by their key and all lists by time, so reports of different recordings
+//! can be compared with a plain diff. Times are given in the local time of Munich, delays and
+//! durations in seconds.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::io::{self, Write};
+
+use crate::recording::Recording;
+use crate::records::local_time;
+use crate::response_messages::ResponseMessage;
+use crate::segments::Distribution;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TimeRange {
+    pub start: String,
+    pub end: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DistinctCounts {
+    pub vehicles: usize,
+    pub train_numbers: usize,
+    pub train_ids: usize,
+    pub lines: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DelayDistribution {
+    /// Records reporting a delay.
+    pub count: usize,
+    /// Records without a delay.
+    pub missing: usize,
+    pub mean: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Percentiles keyed by e.g. `p50`.
+    pub percentiles: BTreeMap<String, f64>,
+    /// Number of delays per whole minute, e.g. `2` for 2 to 3 minutes late.
+    pub minutes: BTreeMap<i64, usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LineStatistics {
+    pub records: usize,
+    pub vehicles: usize,
+    pub mean_delay: Option<f64>,
+    pub cancelled: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HourCoverage {
+    /// Start of the hour.
+    pub hour: String,
+    /// Raw messages, only known for JSONL recordings.
+    pub messages: usize,
+    pub records: usize,
+    pub vehicles: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Gap {
+    pub start: String,
+    pub end: String,
+    pub seconds: f64,
+}
+
+/// An incident of the news ticker with what was parsed from its text.
+#[derive(Debug, Clone, Serialize)]
+pub struct Disruption {
+    pub title: String,
+    pub first_seen: String,
+    pub last_seen: String,
+    pub kinds: Vec<String>,
+    /// Affected lines, all lines if empty.
+    pub lines: Vec<String>,
+    /// Segments as `A - B`.
+    pub segments: Vec<String>,
+    /// Period the message says it is valid for, open ends are unknown.
+    pub valid_from: Option<String>,
+    pub valid_until: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DataQuality {
+    /// Errors while parsing per kind.
+    pub parse_errors: BTreeMap<String, usize>,
+    /// Records of a train id with the same timestamp as a previous one.
+    pub duplicate_records: usize,
+    /// Messages with an earlier timestamp than the message before.
+    pub out_of_order_messages: usize,
+    /// Vehicles that were only seen once.
+    pub single_record_vehicles: usize,
+    /// Periods without any message, or without any record for converted recordings.
+    pub gaps: Vec<Gap>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatisticsReport {
+    pub time_range: Option<TimeRange>,
+    /// Raw messages per source, only known for JSONL recordings.
+    pub messages: BTreeMap<String, usize>,
+    pub records: usize,
+    pub distinct: DistinctCounts,
+    pub delay: DelayDistribution,
+    pub states: BTreeMap<String, usize>,
+    /// Ride states, records without one are counted as `none`.
+    pub ride_states: BTreeMap<String, usize>,
+    /// Changes of the state per previous and next state.
+    pub transitions: BTreeMap<String, BTreeMap<String, usize>>,
+    pub lines: BTreeMap<String, LineStatistics>,
+    pub coverage: Vec<HourCoverage>,
+    /// Incidents of the news ticker ordered by when they were first seen.
+    pub disruptions: Vec<Disruption>,
+    pub quality: DataQuality,
+}
+
+/// Collects what can only be seen in the raw messages while a recording is loaded.
+#[derive(Debug, Clone, Default)]
+pub struct MessageStatistics {
+    messages: BTreeMap<String, usize>,
+    /// Messages per start of the hour in milliseconds.
+    hours: BTreeMap<i64, usize>,
+    timestamps: Vec<f64>,
+    out_of_order: usize,
+}
+
+impl MessageStatistics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, message: &ResponseMessage) {
+        *self
+            .messages
+            .entry(message.content.source().to_string())
+            .or_default() += 1;
+        *self.hours.entry(hour_start(message.timestamp)).or_default() += 1;
+        if self
+            .timestamps
+            .last()
+            .is_some_and(|&last| message.timestamp < last)
+        {
+            self.out_of_order += 1;
+        }
+        self.timestamps.push(message.timestamp);
+    }
+}
+
+fn hour_start(timestamp: f64) -> i64 {
+    let hour = 60 * 60 * 1000;
+    (timestamp as i64).div_euclid(hour) * hour
+}
+
+fn write_counts<W: Write, K: Display>(
+    mut writer: W,
+    title: &str,
+    counts: &BTreeMap<K, usize>,
+) -> io::Result<()> {
+    writeln!(writer)?;
+    writeln!(writer, "## {title}")?;
+    writeln!(writer)?;
+    writeln!(writer, "| | Count |")?;
+    writeln!(writer, "|---|---:|")?;
+    for (key, count) in counts {
+        writeln!(writer, "| {key} | {count} |")?;
+    }
+    Ok(())
+}
+
+fn gaps(timestamps: &mut [f64], max_gap: f64) -> Vec<Gap> {
+    timestamps.sort_by(f64::total_cmp);
+    timestamps
+        .windows(2)
+        .filter(|pair| pair[1] - pair[0] > max_gap)
+        .map(|pair| Gap {
+            start: local_time(pair[0]).to_rfc3339(),
+            end: local_time(pair[1]).to_rfc3339(),
+            seconds: (pair[1] - pair[0]) / 1000.0,
+        })
+        .collect()
+}
+
+impl StatisticsReport {
+    /// Periods longer than this many milliseconds without data are reported as gaps.
+    pub const MAX_GAP: f64 = 60.0 * 1000.0;
+    pub const PERCENTILES: [u32; 7] = [1, 10, 25, 50, 75, 90, 99];
+
+    /// Creates the report of a recording, `messages` are the statistics of its raw messages.
+    pub fn new(recording: &Recording, messages: &MessageStatistics) -> Self {
+        let mut report = Self {
+            messages: messages.messages.clone(),
+            ..Self::default()
+        };
+        let mut delays = Distribution::new();
+        let (mut train_numbers, mut train_ids) = (BTreeSet::new(), BTreeSet::new());
+        let mut line_vehicles: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+        let mut line_delays: BTreeMap<&str, Distribution> = BTreeMap::new();
+        let mut hours: BTreeMap<i64, (usize, BTreeSet<&str>)> = BTreeMap::new();
+        let mut record_timestamps = Vec::new();
+
+        for vehicle in recording.trains.values() {
+            if vehicle.records.len() == 1 {
+                report.quality.single_record_vehicles += 1;
+            }
+            let mut seen = BTreeSet::new();
+            for record in &vehicle.records {
+                report.records += 1;
+                record_timestamps.push(record.timestamp);
+                if !seen.insert((record.train_id.as_str(), record.timestamp.to_bits())) {
+                    report.quality.duplicate_records += 1;
+                }
+                train_numbers.insert(record.train_number);
+                train_ids.insert(record.train_id.as_str());
+                line_vehicles
+                    .entry(&record.line)
+                    .or_default()
+                    .insert(&vehicle.number);
+                let line = report.lines.entry(record.line.clone()).or_default();
+                line.records += 1;
+                if record.is_cancelled() {
+                    line.cancelled += 1;
+                }
+                match record.delay {
+                    Some(delay) => {
+                        delays.push(delay);
+                        line_delays.entry(&record.line).or_default().push(delay);
+                        let minutes = (delay / 60.0).floor() as i64;
+                        *report.delay.minutes.entry(minutes).or_default() += 1;
+                    }
+                    None => report.delay.missing += 1,
+                }
+                *report.states.entry(record.state.to_string()).or_default() += 1;
+                let ride_state = record
+                    .ride_state
+                    .as_ref()
+                    .map_or("none".to_string(), ToString::to_string);
+                *report.ride_states.entry(ride_state).or_default() += 1;
+                let hour = hours.entry(hour_start(record.timestamp)).or_default();
+                hour.0 += 1;
+                hour.1.insert(&vehicle.number);
+            }
+            for transition in vehicle.transitions() {
+                *report
+                    .transitions
+                    .entry(transition.from.to_string())
+                    .or_default()
+                    .entry(transition.to.to_string())
+                    .or_default() += 1;
+            }
+        }
+
+        report.distinct = DistinctCounts {
+            vehicles: recording.trains.len(),
+            train_numbers: train_numbers.len(),
+            train_ids: train_ids.len(),
+            lines: report.lines.len(),
+        };
+        report.delay.count = delays.len();
+        report.delay.mean = delays.mean();
+        report.delay.min = delays.min();
+        report.delay.max = delays.max();
+        for p in Self::PERCENTILES {
+            if let Some(value) = delays.percentile(f64::from(p) / 100.0) {
+                report.delay.percentiles.insert(format!("p{p:02}"), value);
+            }
+        }
+        for (line, statistics) in report.lines.iter_mut() {
+            statistics.vehicles = line_vehicles.get(line.as_str()).map_or(0, BTreeSet::len);
+            statistics.mean_delay = line_delays.get(line.as_str()).and_then(Distribution::mean);
+        }
+
+        let all_hours: BTreeSet<i64> = hours.keys().chain(messages.hours.keys()).copied().collect();
+        report.coverage = all_hours
+            .into_iter()
+            .map(|hour| {
+                let (records, vehicles) = hours
+                    .get(&hour)
+                    .map_or((0, 0), |(records, vehicles)| (*records, vehicles.len()));
+                HourCoverage {
+                    hour: local_time(hour as f64).to_rfc3339(),
+                    messages: messages.hours.get(&hour).copied().unwrap_or_default(),
+                    records,
+                    vehicles,
+                }
+            })
+            .collect();
+
+        let mut incidents: Vec<_> = recording.incidents.iter().collect();
+        incidents.sort_by(|a, b| {
+            a.first_seen
+                .total_cmp(&b.first_seen)
+                .then_with(|| a.title.cmp(&b.title))
+        });
+        report.disruptions = incidents
+            .into_iter()
+            .map(|incident| Disruption {
+                title: incident.title.clone(),
+                first_seen: local_time(incident.first_seen).to_rfc3339(),
+                last_seen: local_time(incident.last_seen).to_rfc3339(),
+                kinds: incident.kinds.iter().map(ToString::to_string).collect(),
+                lines: incident.lines.clone(),
+                segments: incident
+                    .segments
+                    .iter()
+                    .map(|(from, to)| format!("{from} - {to}"))
+                    .collect(),
+                valid_from: incident.validity.start.map(|start| start.to_rfc3339()),
+                valid_until: incident.validity.end.map(|end| end.to_rfc3339()),
+            })
+            .collect();
+
+        let mut timestamps = if messages.timestamps.is_empty() {
+            record_timestamps
+        } else {
+            messages.timestamps.clone()
+        };
+        report.quality.gaps = gaps(&mut timestamps, Self::MAX_GAP);
+        if let (Some(start), Some(end)) = (timestamps.first(), timestamps.last()) {
+            report.time_range = Some(TimeRange {
+                start: local_time(*start).to_rfc3339(),
+                end: local_time(*end).to_rfc3339(),
+            });
+        }
+        report.quality.out_of_order_messages = messages.out_of_order;
+        report.quality.parse_errors = recording
+            .errors
+            .by_kind()
+            .into_iter()
+            .map(|(kind, count)| (kind.to_string(), count))
+            .collect();
+        report
+    }
+
+    pub fn write_json<W: Write>(&self, writer: W) -> io::Result<()> {
+        serde_json::to_writer_pretty(writer, self).map_err(io::Error::other)
+    }
+
+    pub fn write_markdown<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let seconds = |value: Option<f64>| value.map_or("-".to_string(), |v| format!("{v:.1}"));
+
+        writeln!(writer, "# Statistics")?;
+        writeln!(writer)?;
+        if let Some(range) = &self.time_range {
+            writeln!(writer, "From {} to {}.", range.start, range.end)?;
+            writeln!(writer)?;
+        }
+        writeln!(writer, "| | Count |")?;
+        writeln!(writer, "|---|---:|")?;
+        writeln!(writer, "| records | {} |", self.records)?;
+        writeln!(writer, "| vehicles | {} |", self.distinct.vehicles)?;
+        writeln!(
+            writer,
+            "| train numbers | {} |",
+            self.distinct.train_numbers
+        )?;
+        writeln!(writer, "| train ids | {} |", self.distinct.train_ids)?;
+        writeln!(writer, "| lines | {} |", self.distinct.lines)?;
+
+        write_counts(&mut writer, "Messages", &self.messages)?;
+
+        writeln!(writer)?;
+        writeln!(writer, "## Delay")?;
+        writeln!(writer)?;
+        writeln!(
+            writer,
+            "{} records with a delay, {} without. Mean {}s, min {}s, max {}s.",
+            self.delay.count,
+            self.delay.missing,
+            seconds(self.delay.mean),
+            seconds(self.delay.min),
+            seconds(self.delay.max)
+        )?;
+        writeln!(writer)?;
+        writeln!(writer, "| Percentile | Delay [s] |")?;
+        writeln!(writer, "|---|---:|")?;
+        for (percentile, delay) in &self.delay.percentiles {
+            writeln!(writer, "| {percentile} | {delay:.1} |")?;
+        }
+        write_counts(&mut writer, "Delay in minutes", &self.delay.minutes)?;
+        write_counts(&mut writer, "States", &self.states)?;
+        write_counts(&mut writer, "Ride states", &self.ride_states)?;
+
+        writeln!(writer)?;
+        writeln!(writer, "## State transitions")?;
+        writeln!(writer)?;
+        writeln!(writer, "| From | To | Count |")?;
+        writeln!(writer, "|---|---|---:|")?;
+        for (from, counts) in &self.transitions {
+            for (to, count) in counts {
+                writeln!(writer, "| {from} | {to} | {count} |")?;
+            }
+        }
+
+        writeln!(writer)?;
+        writeln!(writer, "## Lines")?;
+        writeln!(writer)?;
+        writeln!(
+            writer,
+            "| Line | Records | Vehicles | Mean delay [s] | Cancelled |"
+        )?;
+        writeln!(writer, "|---|---:|---:|---:|---:|")?;
+        for (line, statistics) in &self.lines {
+            writeln!(
+                writer,
+                "| {line} | {} | {} | {} | {} |",
+                statistics.records,
+                statistics.vehicles,
+                seconds(statistics.mean_delay),
+                statistics.cancelled
+            )?;
+        }
+
+        writeln!(writer)?;
+        writeln!(writer, "## Coverage")?;
+        writeln!(writer)?;
+        writeln!(writer, "| Hour | Messages | Records | Vehicles |")?;
+        writeln!(writer, "|---|---:|---:|---:|")?;
+        for hour in &self.coverage {
+            writeln!(
+                writer,
+                "| {} | {} | {} | {} |",
+                hour.hour, hour.messages, hour.records, hour.vehicles
+            )?;
+        }
+
+        writeln!(writer)?;
+        writeln!(writer, "## Disruptions")?;
+        writeln!(writer)?;
+        writeln!(
+            writer,
+            "| Title | Seen from | Seen until | Kinds | Lines | Segments | Valid from | Valid until |"
+        )?;
+        writeln!(writer, "|---|---|---|---|---|---|---|---|")?;
+        let or_dash = |value: Option<&String>| value.map_or("-".to_string(), Clone::clone);
+        for disruption in &self.disruptions {
+            writeln!(
+                writer,
+                "| {} | {} | {} | {} | {} | {} | {} | {} |",
+                disruption.title.replace('|', "\\|"),
+                disruption.first_seen,
+                disruption.last_seen,
+                disruption.kinds.join(", "),
+                disruption.lines.join(", "),
+                disruption.segments.join(", "),
+                or_dash(disruption.valid_from.as_ref()),
+                or_dash(disruption.valid_until.as_ref())
+            )?;
+        }
+
+        writeln!(writer)?;
+        writeln!(writer, "## Data quality")?;
+        writeln!(writer)?;
+        writeln!(writer, "| | Count |")?;
+        writeln!(writer, "|---|---:|")?;
+        for (kind, count) in &self.quality.parse_errors {
+            writeln!(writer, "| parse errors: {kind} | {count} |")?;
+        }
+        writeln!(
+            writer,
+            "| duplicate records | {} |",
+            self.quality.duplicate_records
+        )?;
+        writeln!(
+            writer,
+            "| out of order messages | {} |",
+            self.quality.out_of_order_messages
+        )?;
+        writeln!(
+            writer,
+            "| vehicles seen once | {} |",
+            self.quality.single_record_vehicles
+        )?;
+        writeln!(writer, "| gaps | {} |", self.quality.gaps.len())?;
+        if !self.quality.gaps.is_empty() {
+            writeln!(writer)?;
+            writeln!(writer, "| Gap start | Gap end | Duration [s] |")?;
+            writeln!(writer, "|---|---|---:|")?;
+            for gap in &self.quality.gaps {
+                writeln!(
+                    writer,
+                    "| {} | {} | {:.0} |",
+                    gap.start, gap.end, gap.seconds
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -12,9 +12,9 @@ pub enum WebSocket {
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct HealthCheck {
-    service: String,
-    healthy: bool,
-    tenant: Option<String>,
+    pub service: String,
+    pub healthy: bool,
+    pub tenant: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -30,16 +30,16 @@ pub struct ExtraGeoms {
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct NewsTickerMessage {
-    title: String,
-    lines: Vec<String>,
-    content: String,
-    updated: String, // TODO: use date time instead
+    pub title: String,
+    pub lines: Vec<String>,
+    pub content: String,
+    pub updated: String, // TODO: use date time instead
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct SbmNewsTicker {
-    incident_program: Option<bool>,
-    messages: Vec<NewsTickerMessage>,
+    pub incident_program: Option<bool>,
+    pub messages: Vec<NewsTickerMessage>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -67,6 +67,24 @@ pub enum Content {
     Station(GeoJson),
 }
 
+impl Content {
+    /// Name of the source of the websocket that sends this content.
+    pub fn source(&self) -> &'static str {
+        match self {
+            Content::TrajectorySchematic(_) => "trajectory_schematic",
+            Content::DeletedVehiclesSchematic(_) => "deleted_vehicles_schematic",
+            Content::StationSchematic(_) => "station_schematic",
+            Content::Websocket(_) => "websocket",
+            Content::ExtraGeoms(_) => "extra_geoms",
+            Content::Healthcheck(_) => "healthcheck",
+            Content::SbmNewsTicker(_) => "sbm_newsticker",
+            Content::Trajectory(_) => "trajectory",
+            Content::DeletedVehicles(_) => "deleted_vehicles",
+            Content::Station(_) => "station",
+        }
+    }
+}
+
 // {"source": "deleted_vehicles_schematic", "content": "sbm_140404727073712", "timestamp": 1697454536271.5, "client_reference": null}
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ResponseMessage {
@@ -0,0 +1,528 @@
+//! Derives the run times between consecutive stations and the dwell times at the stations from
+//! the recorded positions of the vehicles.
+//!
+//! A vehicle is considered to be at a station while it reports [`State::Boarding`] close to it.
+//! The time between leaving one station and arriving at the next one is a run, the time spent
+//! boarding at a station is a dwell. Both are collected per line, direction, hour of the day and
+//! type of day, so they can be queried or exported as CSV.
+
+use chrono::{Datelike, Timelike, Weekday};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{self, Write};
+
+use crate::records::{Phase, Record, State, Trains, Vehicle};
+use crate::stations::Stations;
+
+/// Direction of travel, following the German convention that the parity of the train number
+/// encodes the direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Direction {
+    Even,
+    Odd,
+}
+
+impl From<i64> for Direction {
+    fn from(train_number: i64) -> Self {
+        if train_number % 2 == 0 {
+            Self::Even
+        } else {
+            Self::Odd
+        }
+    }
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Even => write!(f, "even"),
+            Direction::Odd => write!(f, "odd"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DayType {
+    Weekday,
+    Weekend,
+}
+
+impl From<Weekday> for DayType {
+    fn from(value: Weekday) -> Self {
+        match value {
+            Weekday::Sat | Weekday::Sun => Self::Weekend,
+            _ => Self::Weekday,
+        }
+    }
+}
+
+impl Display for DayType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DayType::Weekday => write!(f, "weekday"),
+            DayType::Weekend => write!(f, "weekend"),
+        }
+    }
+}
+
+/// Identifies the run between two consecutive stations.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RunKey {
+    pub line: String,
+    pub direction: Direction,
+    pub from: String,
+    pub to: String,
+    pub hour: u32,
+    pub day_type: DayType,
+}
+
+/// Identifies the dwell at a station.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DwellKey {
+    pub line: String,
+    pub direction: Direction,
+    pub station: String,
+    pub hour: u32,
+    pub day_type: DayType,
+}
+
+/// Collection of durations in seconds.
+#[derive(Debug, Clone, Default)]
+pub struct Distribution {
+    samples: Vec<f64>,
+}
+
+impl Distribution {
+    pub fn new() -> Self {
+        Self {
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, sample: f64) {
+        self.samples.push(sample);
+    }
+
+    pub fn samples(&self) -> &[f64] {
+        &self.samples
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        Some(self.samples.iter().sum::<f64>() / self.samples.len() as f64)
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        self.samples.iter().copied().min_by(f64::total_cmp)
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        self.samples.iter().copied().max_by(f64::total_cmp)
+    }
+
+    /// Nearest-rank percentile, `p` has to be in the range of `0.0..=1.0`.
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted = self.samples.clone();
+        sorted.sort_by(f64::total_cmp);
+        let rank = (p.clamp(0.0, 1.0) * (sorted.len() - 1) as f64).round() as usize;
+        Some(sorted[rank])
+    }
+
+    pub fn median(&self) -> Option<f64> {
+        self.percentile(0.5)
+    }
+}
+
+/// Restricts which entries of [`SegmentStatistics`] are returned, unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct SegmentFilter {
+    pub line: Option<String>,
+    pub direction: Option<Direction>,
+    /// Matches the station of a dwell, or either end of a run.
+    pub station: Option<String>,
+    pub hour: Option<u32>,
+    pub day_type: Option<DayType>,
+}
+
+impl SegmentFilter {
+    fn matches(
+        &self,
+        line: &str,
+        direction: Direction,
+        stations: &[&str],
+        hour: u32,
+        day_type: DayType,
+    ) -> bool {
+        self.line.as_ref().is_none_or(|l| l == line)
+            && self.direction.is_none_or(|d| d == direction)
+            && self
+                .station
+                .as_ref()
+                .is_none_or(|s| stations.contains(&s.as_str()))
+            && self.hour.is_none_or(|h| h == hour)
+            && self.day_type.is_none_or(|d| d == day_type)
+    }
+
+    pub fn matches_run(&self, key: &RunKey) -> bool {
+        self.matches(
+            &key.line,
+            key.direction,
+            &[&key.from, &key.to],
+            key.hour,
+            key.day_type,
+        )
+    }
+
+    pub fn matches_dwell(&self, key: &DwellKey) -> bool {
+        self.matches(
+            &key.line,
+            key.direction,
+            &[&key.station],
+            key.hour,
+            key.day_type,
+        )
+    }
+}
+
+/// A contiguous stay of a vehicle at a station.
+#[derive(Debug, Clone)]
+pub struct Stop<'a> {
+    pub station: &'a str,
+    /// First record boarding at the station.
+    pub arrival: &'a Record,
+    /// Last record boarding at the station.
+    pub departure: &'a Record,
+    /// Whether the vehicle was continuously observed since the previous stop.
+    pub continuous: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct SegmentStatistics {
+    runs: HashMap<RunKey, Distribution>,
+    dwells: HashMap<DwellKey, Distribution>,
+}
+
+impl SegmentStatistics {
+    /// Maximum distance in meters between a boarding vehicle and the station it is assigned to.
+    pub const MAX_STATION_DISTANCE: f64 = 300.0;
+    /// Maximum time in seconds without an update before the vehicle is considered unobserved.
+    pub const MAX_UPDATE_GAP: f64 = 5.0 * 60.0;
+    /// Runs that take longer than this many seconds are discarded as implausible.
+    pub const MAX_RUN_TIME: f64 = 30.0 * 60.0;
+
+    pub fn new() -> Self {
+        Self {
+            runs: HashMap::new(),
+            dwells: HashMap::new(),
+        }
+    }
+
+    pub fn from_trains(trains: &Trains, stations: &Stations) -> Self {
+        let mut statistics = Self::new();
+        for vehicle in trains.values() {
+            statistics.insert_vehicle(vehicle, stations);
+        }
+        statistics
+    }
+
+    /// Adds the runs and dwells of a single vehicle, its records have to be ordered by time.
+    pub fn insert_vehicle(&mut self, vehicle: &Vehicle, stations: &Stations) {
+        let stops = Self::stops(&vehicle.records, stations);
+
+        for stop in &stops {
+            let time = stop.arrival.local_time();
+            self.dwells
+                .entry(DwellKey {
+                    line: stop.arrival.line.clone(),
+                    direction: stop.arrival.train_number.into(),
+                    station: stop.station.to_string(),
+                    hour: time.hour(),
+                    day_type: time.weekday().into(),
+                })
+                .or_default()
+                .push((stop.departure.timestamp - stop.arrival.timestamp) / 1000.0);
+        }
+
+        for pair in stops.windows(2) {
+            let (previous, next) = (&pair[0], &pair[1]);
+            if !next.continuous
+                || previous.station == next.station
+                || previous.departure.train_number != next.arrival.train_number
+                || previous.departure.line != next.arrival.line
+            {
+                continue;
+            }
+            let run_time = (next.arrival.timestamp - previous.departure.timestamp) / 1000.0;
+            if run_time <= 0.0 || run_time > Self::MAX_RUN_TIME {
+                continue;
+            }
+            let time = previous.departure.local_time();
+            self.runs
+                .entry(RunKey {
+                    line: previous.departure.line.clone(),
+                    direction: previous.departure.train_number.into(),
+                    from: previous.station.to_string(),
+                    to: next.station.to_string(),
+                    hour: time.hour(),
+                    day_type: time.weekday().into(),
+                })
+                .or_default()
+                .push(run_time);
+        }
+    }
+
+    /// Splits the records of a vehicle into its stays at stations, which are its boarding phases
+    /// close to a station. A phase interrupted by a gap in the updates becomes separate stays.
+    pub fn stops<'a>(records: &'a [Record], stations: &'a Stations) -> Vec<Stop<'a>> {
+        let mut stops: Vec<Stop> = Vec::new();
+        let mut continuous = false;
+        let mut previous: Option<&Record> = None;
+        let observed =
+            |a: &Record, b: &Record| (b.timestamp - a.timestamp) / 1000.0 <= Self::MAX_UPDATE_GAP;
+
+        for phase in Phase::split(records) {
+            for part in phase.records.chunk_by(|a, b| observed(a, b)) {
+                if previous.is_some_and(|previous| !observed(previous, &part[0])) {
+                    continuous = false;
+                }
+                previous = part.last();
+                if *phase.state != State::Boarding {
+                    continue;
+                }
+
+                let mut at_station = part.iter().filter_map(|record| {
+                    stations
+                        .nearest(&record.position, Self::MAX_STATION_DISTANCE)
+                        .map(|station| (station, record))
+                });
+                let Some((station, arrival)) = at_station.next() else {
+                    continue;
+                };
+                let departure = at_station
+                    .rfind(|(other, _)| other.name == station.name)
+                    .map_or(arrival, |(_, record)| record);
+                match stops.last_mut() {
+                    // Boarding again after a short other state, e.g. when the doors are closed
+                    // and opened again.
+                    Some(stop)
+                        if continuous
+                            && stop.station == station.name
+                            && stop.departure.train_number == phase.train_number =>
+                    {
+                        stop.departure = departure;
+                    }
+                    _ => stops.push(Stop {
+                        station: &station.name,
+                        arrival,
+                        departure,
+                        continuous,
+                    }),
+                }
+                continuous = true;
+            }
+        }
+
+        stops
+    }
+
+    pub fn runs(&self) -> impl Iterator<Item = (&RunKey, &Distribution)> {
+        self.runs.iter()
+    }
+
+    pub fn dwells(&self) -> impl Iterator<Item = (&DwellKey, &Distribution)> {
+        self.dwells.iter()
+    }
+
+    /// Returns the runs matching the filter, sorted by their key.
+    pub fn query_runs(&self, filter: &SegmentFilter) -> Vec<(&RunKey, &Distribution)> {
+        let mut runs: Vec<_> = self.runs().filter(|(k, _)| filter.matches_run(k)).collect();
+        runs.sort_by_key(|(k, _)| *k);
+        runs
+    }
+
+    /// Returns the dwells matching the filter, sorted by their key.
+    pub fn query_dwells(&self, filter: &SegmentFilter) -> Vec<(&DwellKey, &Distribution)> {
+        let mut dwells: Vec<_> = self
+            .dwells()
+            .filter(|(k, _)| filter.matches_dwell(k))
+            .collect();
+        dwells.sort_by_key(|(k, _)| *k);
+        dwells
+    }
+
+    /// Writes one row per run and dwell, all durations are given in seconds.
+    pub fn write_csv<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(
+            "kind,line,direction,from,to,hour,day_type,count,mean,median,p10,p90,min,max"
+                .split(','),
+        )?;
+        let filter = SegmentFilter::default();
+        for (key, distribution) in self.query_runs(&filter) {
+            let fields = [
+                "run".to_string(),
+                key.line.clone(),
+                key.direction.to_string(),
+                key.from.clone(),
+                key.to.clone(),
+                key.hour.to_string(),
+                key.day_type.to_string(),
+            ];
+            writer.write_record(fields.into_iter().chain(distribution_fields(distribution)))?;
+        }
+        for (key, distribution) in self.query_dwells(&filter) {
+            let fields = [
+                "dwell".to_string(),
+                key.line.clone(),
+                key.direction.to_string(),
+                key.station.clone(),
+                String::new(),
+                key.hour.to_string(),
+                key.day_type.to_string(),
+            ];
+            writer.write_record(fields.into_iter().chain(distribution_fields(distribution)))?;
+        }
+        writer.flush()
+    }
+}
+
+fn distribution_fields(distribution: &Distribution) -> [String; 7] {
+    let format = |value: Option<f64>| value.map_or(String::new(), |v| format!("{v:.1}"));
+    [
+        distribution.len().to_string(),
+        format(distribution.mean()),
+        format(distribution.median()),
+        format(distribution.percentile(0.1)),
+        format(distribution.percentile(0.9)),
+        format(distribution.min()),
+        format(distribution.max()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MONDAY_MORNING};
+
+    /// Seconds after [`MONDAY_MORNING`] of the arrival and departure of every stop.
+    fn times<'a>(stops: &'a [Stop]) -> Vec<(&'a str, f64, f64, bool)> {
+        stops
+            .iter()
+            .map(|stop| {
+                (
+                    stop.station,
+                    (stop.arrival.timestamp - MONDAY_MORNING) / 1000.0,
+                    (stop.departure.timestamp - MONDAY_MORNING) / 1000.0,
+                    stop.continuous,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_stops_of_a_trip() {
+        let stations = test_support::stations();
+        let records = test_support::trip(6400, MONDAY_MORNING, 60.0, 120.0);
+        let stops = SegmentStatistics::stops(&records, &stations);
+        assert_eq!(
+            times(&stops),
+            [
+                ("A", 0.0, 60.0, false),
+                ("B", 180.0, 240.0, true),
+                ("C", 360.0, 420.0, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn joins_boarding_interrupted_at_the_same_station() {
+        let stations = test_support::stations();
+        let mut records = test_support::trip(6400, MONDAY_MORNING, 60.0, 120.0);
+        let reopened = records
+            .iter_mut()
+            .find(|r| r.timestamp == MONDAY_MORNING + 200_000.0)
+            .expect("boarding at B");
+        reopened.state = State::Driving;
+        let stops = SegmentStatistics::stops(&records, &stations);
+        assert_eq!(times(&stops)[1], ("B", 180.0, 240.0, true));
+        assert_eq!(stops.len(), 3);
+    }
+
+    #[test]
+    fn separates_stops_after_a_gap() {
+        let stations = test_support::stations();
+        let mut records = test_support::trip(6400, MONDAY_MORNING, 60.0, 400.0);
+        // Not observed while driving from B to C.
+        let (left_b, reached_c) = (MONDAY_MORNING + 520_000.0, MONDAY_MORNING + 920_000.0);
+        records.retain(|r| r.timestamp <= left_b || r.timestamp >= reached_c);
+        let stops = SegmentStatistics::stops(&records, &stations);
+        assert_eq!(times(&stops)[2], ("C", 920.0, 980.0, false));
+
+        let statistics = SegmentStatistics::from_trains(&test_support::trains(records), &stations);
+        let runs: Vec<_> = statistics
+            .query_runs(&SegmentFilter::default())
+            .into_iter()
+            .map(|(key, _)| (key.from.as_str(), key.to.as_str()))
+            .collect();
+        assert_eq!(runs, [("A", "B")]);
+        assert_eq!(statistics.dwells().count(), 3);
+    }
+
+    #[test]
+    fn collects_runs_and_dwells() {
+        let stations = test_support::stations();
+        let trains = test_support::trains(
+            test_support::trip(6400, MONDAY_MORNING, 60.0, 120.0)
+                .into_iter()
+                .chain(test_support::trip(
+                    6402,
+                    MONDAY_MORNING + 20.0 * 60_000.0,
+                    40.0,
+                    160.0,
+                )),
+        );
+        let statistics = SegmentStatistics::from_trains(&trains, &stations);
+
+        let runs = statistics.query_runs(&SegmentFilter {
+            station: Some("B".to_string()),
+            ..SegmentFilter::default()
+        });
+        assert_eq!(runs.len(), 2);
+        for (key, distribution) in runs {
+            assert_eq!((key.line.as_str(), key.direction), ("S1", Direction::Even));
+            assert_eq!((key.hour, key.day_type), (7, DayType::Weekday));
+            assert_eq!(distribution.len(), 2);
+            assert_eq!(distribution.mean(), Some(140.0));
+            assert_eq!(
+                (distribution.min(), distribution.max()),
+                (Some(120.0), Some(160.0))
+            );
+        }
+
+        let dwells = statistics.query_dwells(&SegmentFilter::default());
+        let stations: Vec<_> = dwells.iter().map(|(key, _)| key.station.as_str()).collect();
+        assert_eq!(stations, ["A", "B", "C"]);
+        assert!(dwells.iter().all(|(_, distribution)| {
+            (distribution.min(), distribution.max()) == (Some(40.0), Some(60.0))
+        }));
+        assert!(statistics
+            .query_runs(&SegmentFilter {
+                direction: Some(Direction::Odd),
+                ..SegmentFilter::default()
+            })
+            .is_empty());
+    }
+}
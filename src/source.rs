@@ -0,0 +1,180 @@
+//! Provides the messages either live from the websocket or replayed from a recording.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::WebSocket;
+
+use crate::response_messages::{Content, ResponseMessage};
+use crate::time_index::TimeIndex;
+
+pub type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+/// Sources that are requested and subscribed to after connecting.
+pub const SUBSCRIPTIONS: [&str; 9] = [
+    "extra_geoms",
+    "healthcheck",
+    "sbm_newsticker",
+    "station_schematic",
+    "deleted_vehicles_schematic",
+    "trajectory_schematic",
+    "station",
+    "deleted_vehicles",
+    "trajectory",
+];
+
+/// Interval in which the connection is kept alive.
+pub const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How far back a followed recording is read when starting to follow it.
+pub const FOLLOW_BACKLOG: Duration = Duration::from_secs(5 * 60);
+
+/// Interval in which a followed recording is checked for new messages.
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn url(api_key: &str) -> url::Url {
+    format!("wss://api.geops.io/realtime-ws/v1/?key={api_key}")
+        .parse()
+        .expect("valid url")
+}
+
+/// Connects to the websocket and subscribes to all sources of the S-Bahn Munich.
+#[allow(clippy::result_large_err)]
+pub fn connect(url: &url::Url) -> tungstenite::Result<Socket> {
+    let (mut socket, _) = tungstenite::connect(url.clone())?;
+    socket.send("BBOX 1152072 6048052 1433666 6205578 5 tenant=sbm".into())?;
+    socket.send("BUFFER 100 100".into())?;
+    for source in SUBSCRIPTIONS {
+        socket.send(format!("GET {source}").into())?;
+        socket.send(format!("SUB {source}").into())?;
+    }
+    socket.send("PING".into())?;
+    Ok(socket)
+}
+
+/// Where the messages are read from.
+#[derive(Debug, Clone)]
+pub enum Source {
+    /// The websocket, reconnecting whenever the connection is lost.
+    Live(url::Url),
+    /// A recording in the JSONL format of the scraper. With a `speed` the messages are delayed
+    /// like they were received, e.g. `2.0` replays twice as fast, otherwise as fast as possible.
+    Recording { path: PathBuf, speed: Option<f64> },
+    /// A recording that is still written by the scraper. The stations at its start and the
+    /// messages of the last [`FOLLOW_BACKLOG`] are read first, then it waits for new messages.
+    Follow(PathBuf),
+}
+
+impl Source {
+    /// Calls `on_message` for every message until the recording ends or `on_message` returns
+    /// `false`. Lines that cannot be parsed are skipped.
+    pub fn for_each<F>(&self, mut on_message: F) -> io::Result<()>
+    where
+        F: FnMut(ResponseMessage) -> bool,
+    {
+        match self {
+            Source::Live(url) => loop {
+                let mut socket = match connect(url) {
+                    Ok(socket) => socket,
+                    Err(err) => {
+                        eprintln!("ERR: {err}");
+                        thread::sleep(PING_INTERVAL);
+                        continue;
+                    }
+                };
+                let mut last_ping = SystemTime::now();
+                loop {
+                    match socket.read() {
+                        Ok(tungstenite::Message::Text(text)) => {
+                            if let Ok(message) = serde_json::from_str::<ResponseMessage>(&text) {
+                                if !on_message(message) {
+                                    return Ok(());
+                                }
+                            }
+                        }
+                        Ok(tungstenite::Message::Close(_)) => break,
+                        Ok(_) => {}
+                        Err(err) => {
+                            eprintln!("ERR: {err}");
+                            break;
+                        }
+                    }
+                    if last_ping.elapsed().unwrap_or_default() >= PING_INTERVAL {
+                        let _ = socket.send("PING".into());
+                        last_ping = SystemTime::now();
+                    }
+                }
+            },
+            Source::Recording { path, speed } => {
+                let reader = BufReader::new(File::open(path)?);
+                let mut previous: Option<f64> = None;
+                for line in reader.lines() {
+                    let line = line?;
+                    let Ok(message) = serde_json::from_str::<ResponseMessage>(&line) else {
+                        continue;
+                    };
+                    if let (Some(speed), Some(previous)) = (speed, previous) {
+                        let wait = (message.timestamp - previous) / 1000.0 / speed;
+                        if wait > 0.0 {
+                            thread::sleep(Duration::from_secs_f64(wait));
+                        }
+                    }
+                    previous = Some(message.timestamp);
+                    if !on_message(message) {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            Source::Follow(path) => {
+                // The stations are only sent after connecting, which a backlog usually skips.
+                let reader = BufReader::new(File::open(path)?);
+                let mut has_stations = false;
+                for line in reader.lines() {
+                    let Ok(message) = serde_json::from_str::<ResponseMessage>(&line?) else {
+                        continue;
+                    };
+                    match message.content {
+                        Content::Station(_) | Content::StationSchematic(_) => {
+                            has_stations = true;
+                            if !on_message(message) {
+                                return Ok(());
+                            }
+                        }
+                        Content::TrajectorySchematic(_) if has_stations => break,
+                        _ => {}
+                    }
+                }
+
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let start = now.saturating_sub(FOLLOW_BACKLOG).as_millis() as f64;
+                // The index is only read, the scraper is appending to it.
+                let offset =
+                    TimeIndex::load(TimeIndex::path_for(path)).map_or(0, |index| index.seek(start));
+                let mut reader = BufReader::new(File::open(path)?);
+                reader.seek(SeekFrom::Start(offset))?;
+                let mut line = String::new();
+                loop {
+                    if reader.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+                        // A partially written line is completed by the next read.
+                        thread::sleep(FOLLOW_INTERVAL);
+                        continue;
+                    }
+                    if let Ok(message) = serde_json::from_str::<ResponseMessage>(&line) {
+                        if message.timestamp >= start && !on_message(message) {
+                            return Ok(());
+                        }
+                    }
+                    line.clear();
+                }
+            }
+        }
+    }
+}
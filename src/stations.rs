@@ -0,0 +1,100 @@
+//! Contains the stations of the network, as they are sent by the `station` source.
+
+use geojson::{GeoJson, Value as Geometry};
+use std::collections::HashMap;
+
+use crate::records::{AnalysisError, Coordinate, Extractor};
+use crate::response_messages::Content;
+
+#[derive(Debug, Clone)]
+pub struct Station {
+    pub name: String,
+    pub position: Coordinate,
+}
+
+/// Parses a station of the `station` source, or of the `station_schematic` source, whose positions
+/// are the ones on the schematic map instead of geographic ones.
+impl TryFrom<Content> for Station {
+    type Error = AnalysisError;
+
+    fn try_from(value: Content) -> Result<Self, Self::Error> {
+        match value {
+            Content::Station(GeoJson::Feature(feature))
+            | Content::StationSchematic(GeoJson::Feature(feature)) => {
+                let name = match &feature.properties {
+                    Some(properties) => properties.extract("name")?,
+                    None => return Err(AnalysisError::MissingProperty("properties".to_string())),
+                };
+                let position = match feature.geometry.map(|g| g.value) {
+                    Some(Geometry::Point(point)) if point.len() >= 2 => {
+                        // The geometries of the websocket are in web mercator, but be lenient
+                        // in case they already are in longitude and latitude.
+                        if point[0].abs() > 180.0 || point[1].abs() > 90.0 {
+                            Coordinate::from_web_mercator(point[0], point[1])
+                        } else {
+                            Coordinate {
+                                latitude: point[1],
+                                longitude: point[0],
+                            }
+                        }
+                    }
+                    Some(geometry) => {
+                        return Err(AnalysisError::invalid_value(
+                            "content.geometry",
+                            "Point",
+                            &geometry,
+                        ))
+                    }
+                    None => return Err(AnalysisError::MissingProperty("geometry".to_string())),
+                };
+                Ok(Self { name, position })
+            }
+            other => Err(AnalysisError::invalid_value(
+                "source",
+                "station or station_schematic",
+                &other.source(),
+            )),
+        }
+    }
+}
+
+/// All known stations, keyed by their name.
+#[derive(Debug, Default)]
+pub struct Stations(HashMap<String, Station>);
+
+impl Stations {
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    /// Inserts or updates the station, later messages replace earlier ones.
+    pub fn insert(&mut self, station: Station) {
+        self.0.insert(station.name.clone(), station);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Station> {
+        self.0.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Station> {
+        self.0.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Finds the station closest to `position` that is at most `max_distance` meters away.
+    pub fn nearest(&self, position: &Coordinate, max_distance: f64) -> Option<&Station> {
+        self.0
+            .values()
+            .map(|station| (station, station.position.distance(position)))
+            .filter(|(_, distance)| *distance <= max_distance)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(station, _)| station)
+    }
+}
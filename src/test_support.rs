@@ -0,0 +1,107 @@
+//! Builds recordings for the tests of the analyses.
+
+use std::path::{Path, PathBuf};
+
+use crate::records::{try_color_from_string, Coordinate, Record, RideState, State, Trains};
+use crate::stations::{Station, Stations};
+
+/// 2023-11-06 07:00 in Munich, a Monday, in milliseconds.
+pub const MONDAY_MORNING: f64 = 1_699_250_400_000.0;
+
+/// Three stations about 1.5 km apart from west to east.
+pub const STATIONS: [(&str, f64, f64); 3] =
+    [("A", 11.50, 48.1), ("B", 11.52, 48.1), ("C", 11.54, 48.1)];
+
+pub fn stations() -> Stations {
+    let mut stations = Stations::new();
+    for (name, longitude, latitude) in STATIONS {
+        stations.insert(Station {
+            name: name.to_string(),
+            position: Coordinate {
+                latitude,
+                longitude,
+            },
+        });
+    }
+    stations
+}
+
+/// A record of the S1 train `train_number` on time, sent by the vehicle of the same number.
+pub fn record(train_number: i64, state: State, longitude: f64, timestamp: f64) -> Record {
+    Record {
+        timestamp,
+        position: Coordinate {
+            latitude: 48.1,
+            longitude,
+        },
+        line: "S1".to_string(),
+        line_color: try_color_from_string("#16bae7".to_string()).expect("valid color"),
+        line_stroke: None,
+        line_text_color: None,
+        state,
+        ride_state: Some(RideState::Realtime),
+        delay: Some(0.0),
+        train_id: format!("sbm_{train_number}"),
+        vehicle_number: format!("423 {train_number}"),
+        train_number,
+        rake: None,
+        original_rake: None,
+    }
+}
+
+/// Records every 20 seconds of a train leaving at `start` in milliseconds, boarding `dwell`
+/// seconds at every station of [`STATIONS`] and driving `run` seconds between them.
+pub fn trip(train_number: i64, start: f64, dwell: f64, run: f64) -> Vec<Record> {
+    let mut records = Vec::new();
+    let mut time = start;
+    for (i, (_, longitude, _)) in STATIONS.iter().enumerate() {
+        let arrival = time;
+        while time <= arrival + dwell * 1000.0 {
+            records.push(record(train_number, State::Boarding, *longitude, time));
+            time += 20_000.0;
+        }
+        let Some((_, next, _)) = STATIONS.get(i + 1) else {
+            break;
+        };
+        let departure = time - 20_000.0;
+        while time < departure + run * 1000.0 {
+            let progress = (time - departure) / (run * 1000.0);
+            let position = longitude + (next - longitude) * progress;
+            records.push(record(train_number, State::Driving, position, time));
+            time += 20_000.0;
+        }
+    }
+    records
+}
+
+pub fn trains(records: impl IntoIterator<Item = Record>) -> Trains {
+    let mut trains = Trains::new();
+    for record in records {
+        trains.insert(record);
+    }
+    trains
+}
+
+/// Directory in the temporary directory of the system that is removed with its contents when
+/// dropped, also if the test panics.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates an empty directory, `name` has to be unique among the tests.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("temporary directory");
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
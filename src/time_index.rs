@@ -0,0 +1,321 @@
+//! Maps the timestamps of a JSONL recording to byte offsets, so a time range can be read without
+//! parsing everything before it.
+//!
+//! The index is stored next to the recording with the extension `.idx` appended. It starts with
+//! a magic number, followed by one entry of a little endian `f64` timestamp and `u64` offset
+//! whenever at least [`TimeIndex::INTERVAL`] passed since the previous entry. The scraper
+//! appends entries while recording, otherwise the index is built or extended when it is opened.
+
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::response_messages::ResponseMessage;
+
+const MAGIC: &[u8; 8] = b"SBTIDX01";
+
+/// Only the timestamp of a message, which is much cheaper to parse than the whole message.
+#[derive(Deserialize)]
+struct Timestamp {
+    timestamp: f64,
+}
+
+/// Parses the timestamp of a line of a recording.
+pub fn timestamp(line: &str) -> Option<f64> {
+    serde_json::from_str::<Timestamp>(line)
+        .ok()
+        .map(|t| t.timestamp)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexEntry {
+    /// Timestamp of the message in milliseconds.
+    pub timestamp: f64,
+    /// Byte offset of the line of the message.
+    pub offset: u64,
+}
+
+impl IndexEntry {
+    fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&self.timestamp.to_le_bytes())?;
+        writer.write_all(&self.offset.to_le_bytes())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TimeIndex {
+    entries: Vec<IndexEntry>,
+}
+
+impl TimeIndex {
+    /// Minimum time in milliseconds between two entries.
+    pub const INTERVAL: f64 = 60.0 * 1000.0;
+
+    pub fn path_for(recording: impl AsRef<Path>) -> PathBuf {
+        let mut path = recording.as_ref().as_os_str().to_owned();
+        path.push(".idx");
+        PathBuf::from(path)
+    }
+
+    /// Loads the index of `recording`, building or extending it if it does not cover the whole
+    /// recording, in which case it is saved again if possible.
+    pub fn open(recording: impl AsRef<Path>) -> io::Result<Self> {
+        let recording = recording.as_ref();
+        let path = Self::path_for(recording);
+        let mut index = match Self::load(&path) {
+            Ok(index) => index,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Self::default(),
+            // An index that cannot be read is rebuilt.
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => Self::default(),
+            Err(err) => return Err(err),
+        };
+        let known = index.entries.len();
+        index.extend(recording)?;
+        if index.entries.len() != known || !path.exists() {
+            // Archives may be read-only, the index is built again next time then.
+            let _ = index.save(&path);
+        }
+        Ok(index)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a time index",
+            ));
+        }
+        let mut entries = Vec::new();
+        let mut entry = [0; 16];
+        loop {
+            match reader.read_exact(&mut entry) {
+                Ok(()) => {}
+                // A partially written entry at the end is ignored.
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+            let (timestamp, offset) = entry.split_at(8);
+            entries.push(IndexEntry {
+                timestamp: f64::from_le_bytes(timestamp.try_into().expect("8 bytes")),
+                offset: u64::from_le_bytes(offset.try_into().expect("8 bytes")),
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        for entry in &self.entries {
+            entry.write(&mut writer)?;
+        }
+        writer.flush()
+    }
+
+    /// Scans the recording from the last entry on and adds the missing entries.
+    pub fn extend(&mut self, recording: impl AsRef<Path>) -> io::Result<()> {
+        let mut reader = BufReader::new(File::open(recording)?);
+        let mut offset = self.entries.last().map_or(0, |e| e.offset);
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            if let Some(timestamp) = timestamp(&line) {
+                self.push(timestamp, offset);
+            }
+            offset += read as u64;
+        }
+        Ok(())
+    }
+
+    /// Adds an entry if at least [`Self::INTERVAL`] passed since the last one, and returns it.
+    pub fn push(&mut self, timestamp: f64, offset: u64) -> Option<IndexEntry> {
+        if self
+            .entries
+            .last()
+            .is_some_and(|e| timestamp < e.timestamp + Self::INTERVAL || offset <= e.offset)
+        {
+            return None;
+        }
+        let entry = IndexEntry { timestamp, offset };
+        self.entries.push(entry);
+        Some(entry)
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Offset from which on all messages at or after `timestamp` are found.
+    pub fn seek(&self, timestamp: f64) -> u64 {
+        let index = self.entries.partition_point(|e| e.timestamp < timestamp);
+        index
+            .checked_sub(1)
+            .map_or(0, |index| self.entries[index].offset)
+    }
+
+    /// Calls `on_message` for every message of `recording` within `range`, reading only from
+    /// the entry before the start of the range on. Lines that cannot be parsed are skipped.
+    pub fn for_each_in<F>(
+        &self,
+        recording: impl AsRef<Path>,
+        range: Range<f64>,
+        mut on_message: F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(ResponseMessage),
+    {
+        let mut reader = BufReader::new(File::open(recording)?);
+        reader.seek(SeekFrom::Start(self.seek(range.start)))?;
+        for line in reader.lines() {
+            let line = line?;
+            let Ok(message) = serde_json::from_str::<ResponseMessage>(&line) else {
+                continue;
+            };
+            if message.timestamp >= range.end {
+                break;
+            }
+            if message.timestamp >= range.start {
+                on_message(message);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends entries to the index of a recording while it is written.
+#[derive(Debug)]
+pub struct TimeIndexWriter {
+    file: File,
+    index: TimeIndex,
+}
+
+impl TimeIndexWriter {
+    /// Opens the index of `recording`, bringing it up to date with what was recorded before.
+    pub fn open(recording: impl AsRef<Path>) -> io::Result<Self> {
+        let recording = recording.as_ref();
+        let index = if recording.exists() {
+            TimeIndex::open(recording)?
+        } else {
+            TimeIndex::default()
+        };
+        // Saved again instead of appending to it, as a crash may have left a partially written
+        // entry at the end, after which every appended entry would be misaligned.
+        let path = TimeIndex::path_for(recording);
+        index.save(&path)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Self { file, index })
+    }
+
+    /// Records that the line at `offset` holds a message received at `timestamp`.
+    pub fn record(&mut self, timestamp: f64, offset: u64) -> io::Result<()> {
+        if let Some(entry) = self.index.push(timestamp, offset) {
+            let mut bytes = Vec::with_capacity(16);
+            entry.write(&mut bytes)?;
+            self.file.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn index(entries: &[(f64, u64)]) -> TimeIndex {
+        TimeIndex {
+            entries: entries
+                .iter()
+                .map(|&(timestamp, offset)| IndexEntry { timestamp, offset })
+                .collect(),
+        }
+    }
+
+    /// A recording with one message per 30 seconds in a fresh directory, which is removed
+    /// when it is dropped.
+    fn recording(name: &str, messages: usize) -> (TempDir, PathBuf) {
+        let directory = TempDir::new(&format!("time-index-{name}"));
+        let path = directory.path().join("recording.jsonl");
+        let mut file = File::create(&path).expect("recording");
+        for i in 0..messages {
+            writeln!(
+                file,
+                r#"{{"source": "websocket", "content": {{"status": "open"}}, "timestamp": {}}}"#,
+                i as f64 * 30.0 * 1000.0
+            )
+            .expect("written");
+        }
+        (directory, path)
+    }
+
+    #[test]
+    fn seeks_to_the_entry_before_the_timestamp() {
+        let index = index(&[(0.0, 0), (60_000.0, 100), (120_000.0, 200)]);
+        assert_eq!(index.seek(-1.0), 0);
+        assert_eq!(index.seek(0.0), 0);
+        assert_eq!(index.seek(60_000.0), 0);
+        assert_eq!(index.seek(60_001.0), 100);
+        assert_eq!(index.seek(1e12), 200);
+        assert_eq!(TimeIndex::default().seek(60_000.0), 0);
+    }
+
+    #[test]
+    fn indexes_and_reads_a_range() {
+        let (_directory, path) = recording("range", 10);
+        let index = TimeIndex::open(&path).expect("index");
+        // Every other message, as they are 30 seconds apart.
+        assert_eq!(index.len(), 5);
+        let mut timestamps = Vec::new();
+        index
+            .for_each_in(&path, 90_000.0..180_000.0, |message| {
+                timestamps.push(message.timestamp)
+            })
+            .expect("read");
+        assert_eq!(timestamps, [90_000.0, 120_000.0, 150_000.0]);
+    }
+
+    #[test]
+    fn recovers_from_a_partial_entry() {
+        let (_directory, path) = recording("partial", 4);
+        let index_path = TimeIndex::path_for(&path);
+        let mut writer = TimeIndexWriter::open(&path).expect("writer");
+        assert_eq!(writer.index.len(), 2);
+        drop(writer);
+        // A crash while writing an entry.
+        let mut file = OpenOptions::new().append(true).open(&index_path).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+
+        let offset = std::fs::metadata(&path).unwrap().len();
+        writer = TimeIndexWriter::open(&path).expect("writer");
+        writer.record(120_000.0, offset).expect("recorded");
+        drop(writer);
+        let loaded = TimeIndex::load(&index_path).expect("index");
+        assert_eq!(
+            loaded.entries().last(),
+            Some(&IndexEntry {
+                timestamp: 120_000.0,
+                offset
+            })
+        );
+        assert_eq!(loaded.len(), 3);
+    }
+}
@@ -0,0 +1,560 @@
+//! Estimates the position, speed and acceleration of every vehicle with a Kalman filter, to
+//! predict where it is going to be in the next minutes.
+//!
+//! The feed only sends `raw_coordinates`, so without the geometry of the tracks the vehicles
+//! are tracked in the plane, in meters east and north of their first position. Both axes are
+//! filtered independently with a Singer model, in which the acceleration decays towards zero
+//! within [`TrackerConfig::maneuver_time`], so predictions over minutes do not run away. While a
+//! vehicle is boarding its speed is known to be zero. Updates far off the prediction are
+//! rejected as outliers, and a vehicle that was not updated for a while or keeps being rejected
+//! is tracked anew.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use crate::backtest::MAX_TARGET_DISTANCE;
+use crate::records::{Coordinate, Record, State, Trains};
+
+type Vector = [f64; 3];
+type Matrix = [[f64; 3]; 3];
+
+/// Squared Mahalanobis distance within which 95% of two-dimensional normal samples lie.
+const CONFIDENCE_95: f64 = 5.991;
+/// Squared Mahalanobis distance beyond which an update is an outlier, 99.9% in two dimensions.
+const GATE: f64 = 13.816;
+/// Longest time step in seconds the covariance is propagated with at once.
+const STEP: f64 = 2.0;
+
+#[derive(Debug, Clone)]
+pub struct TrackerConfig {
+    /// Standard deviation in meters of the reported positions.
+    pub position_noise: f64,
+    /// Standard deviation in meters per second of the speed while boarding.
+    pub stationary_noise: f64,
+    /// Standard deviation in meters per second squared of the acceleration.
+    pub acceleration_noise: f64,
+    /// Time constant in seconds with which the acceleration decays.
+    pub maneuver_time: f64,
+    /// Standard deviation in meters per second of the speed of a new track.
+    pub initial_speed: f64,
+    /// Time in seconds without an update after which a vehicle is tracked anew.
+    pub max_gap: f64,
+    /// Consecutive rejected updates after which a vehicle is tracked anew.
+    pub max_rejected: usize,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            position_noise: 25.0,
+            stationary_noise: 0.5,
+            acceleration_noise: 0.3,
+            maneuver_time: 20.0,
+            initial_speed: 20.0,
+            max_gap: 5.0 * 60.0,
+            max_rejected: 3,
+        }
+    }
+}
+
+/// Position, speed and acceleration along one axis.
+#[derive(Debug, Clone)]
+struct AxisFilter {
+    state: Vector,
+    covariance: Matrix,
+}
+
+impl AxisFilter {
+    fn new(position: f64, config: &TrackerConfig) -> Self {
+        Self {
+            state: [position, 0.0, 0.0],
+            covariance: [
+                [config.position_noise.powi(2), 0.0, 0.0],
+                [0.0, config.initial_speed.powi(2), 0.0],
+                [0.0, 0.0, config.acceleration_noise.powi(2)],
+            ],
+        }
+    }
+
+    /// Moves the estimate `dt` seconds ahead.
+    fn predict(&mut self, dt: f64, config: &TrackerConfig) {
+        if dt <= 0.0 {
+            return;
+        }
+        let steps = (dt / STEP).ceil();
+        let h = dt / steps;
+        let alpha = 1.0 / config.maneuver_time;
+        let decay = (-alpha * h).exp();
+        let transition: Matrix = [
+            [1.0, h, (alpha * h - 1.0 + decay) / alpha.powi(2)],
+            [0.0, 1.0, (1.0 - decay) / alpha],
+            [0.0, 0.0, decay],
+        ];
+        // White noise on the jerk that keeps the variance of the acceleration stationary.
+        let q = 2.0 * alpha * config.acceleration_noise.powi(2);
+        let noise: Matrix = [
+            [h.powi(5) / 20.0, h.powi(4) / 8.0, h.powi(3) / 6.0],
+            [h.powi(4) / 8.0, h.powi(3) / 3.0, h.powi(2) / 2.0],
+            [h.powi(3) / 6.0, h.powi(2) / 2.0, h],
+        ];
+        for _ in 0..steps as usize {
+            self.state = multiply_vector(&transition, &self.state);
+            let propagated = multiply(
+                &multiply(&transition, &self.covariance),
+                &transpose(&transition),
+            );
+            for i in 0..3 {
+                for j in 0..3 {
+                    self.covariance[i][j] = propagated[i][j] + q * noise[i][j];
+                }
+            }
+        }
+    }
+
+    /// Difference of a measurement of the state at `index` to the estimate, and its variance.
+    fn innovation(&self, index: usize, value: f64, variance: f64) -> (f64, f64) {
+        (
+            value - self.state[index],
+            self.covariance[index][index] + variance,
+        )
+    }
+
+    fn correct(&mut self, index: usize, innovation: f64, variance: f64) {
+        let gain: Vector = std::array::from_fn(|i| self.covariance[i][index] / variance);
+        let row = self.covariance[index];
+        for ((state, covariance), gain) in self.state.iter_mut().zip(&mut self.covariance).zip(gain)
+        {
+            *state += gain * innovation;
+            for (value, row) in covariance.iter_mut().zip(row) {
+                *value -= gain * row;
+            }
+        }
+    }
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    std::array::from_fn(|i| std::array::from_fn(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+fn multiply_vector(a: &Matrix, v: &Vector) -> Vector {
+    std::array::from_fn(|i| (0..3).map(|k| a[i][k] * v[k]).sum())
+}
+
+fn transpose(a: &Matrix) -> Matrix {
+    std::array::from_fn(|i| std::array::from_fn(|j| a[j][i]))
+}
+
+/// Region around a predicted position in meters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ellipse {
+    pub semi_major: f64,
+    pub semi_minor: f64,
+    /// Angle of the major axis in radians, counterclockwise from east.
+    pub orientation: f64,
+}
+
+/// Estimated state of a vehicle at a point in time.
+#[derive(Debug, Clone)]
+pub struct Prediction {
+    /// Milliseconds since the unix epoch.
+    pub timestamp: f64,
+    pub position: Coordinate,
+    /// Velocity in meters per second to the east and north.
+    pub velocity: (f64, f64),
+    /// Covariance in square meters of the position east and north.
+    pub covariance: [[f64; 2]; 2],
+}
+
+impl Prediction {
+    /// Speed in meters per second.
+    pub fn speed(&self) -> f64 {
+        self.velocity.0.hypot(self.velocity.1)
+    }
+
+    /// Region the vehicle is within with a probability of 95%.
+    pub fn ellipse(&self) -> Ellipse {
+        let [[a, b], [_, d]] = self.covariance;
+        let mean = (a + d) / 2.0;
+        let spread = (((a - d) / 2.0).powi(2) + b * b).sqrt();
+        Ellipse {
+            semi_major: (CONFIDENCE_95 * (mean + spread)).sqrt(),
+            semi_minor: (CONFIDENCE_95 * (mean - spread).max(0.0)).sqrt(),
+            orientation: 0.5 * (2.0 * b).atan2(a - d),
+        }
+    }
+
+    /// Squared Mahalanobis distance of `position` to the prediction.
+    fn mahalanobis(&self, position: &Coordinate) -> f64 {
+        let center = &self.position;
+        let (east, north) = position.to_local(center);
+        let [[a, b], [_, d]] = self.covariance;
+        let determinant = a * d - b * b;
+        if determinant <= 0.0 {
+            return f64::INFINITY;
+        }
+        (d * east * east - 2.0 * b * east * north + a * north * north) / determinant
+    }
+
+    /// Whether `position` is within [`Prediction::ellipse`].
+    pub fn contains(&self, position: &Coordinate) -> bool {
+        self.mahalanobis(position) <= CONFIDENCE_95
+    }
+}
+
+/// Tracks a single vehicle.
+#[derive(Debug, Clone)]
+pub struct VehicleTracker {
+    origin: Coordinate,
+    east: AxisFilter,
+    north: AxisFilter,
+    /// Milliseconds since the unix epoch of the last accepted update.
+    timestamp: f64,
+    rejected: usize,
+}
+
+impl VehicleTracker {
+    pub fn new(record: &Record, config: &TrackerConfig) -> Self {
+        let mut tracker = Self {
+            origin: record.position.clone(),
+            east: AxisFilter::new(0.0, config),
+            north: AxisFilter::new(0.0, config),
+            timestamp: record.timestamp,
+            rejected: 0,
+        };
+        tracker.correct_stationary(record, config);
+        tracker
+    }
+
+    /// Timestamp of the last accepted update.
+    pub fn last_update(&self) -> f64 {
+        self.timestamp
+    }
+
+    fn correct_stationary(&mut self, record: &Record, config: &TrackerConfig) {
+        if record.state == State::Boarding {
+            let variance = config.stationary_noise.powi(2);
+            for axis in [&mut self.east, &mut self.north] {
+                let (innovation, variance) = axis.innovation(1, 0.0, variance);
+                axis.correct(1, innovation, variance);
+            }
+        }
+    }
+
+    /// Adds a record of the vehicle and returns whether it was accepted. Records older than
+    /// the last update are ignored, a record far off the prediction is rejected.
+    pub fn update(&mut self, record: &Record, config: &TrackerConfig) -> bool {
+        let dt = (record.timestamp - self.timestamp) / 1000.0;
+        if dt < 0.0 {
+            return false;
+        }
+        if dt > config.max_gap {
+            *self = Self::new(record, config);
+            return true;
+        }
+        let mut east = self.east.clone();
+        let mut north = self.north.clone();
+        east.predict(dt, config);
+        north.predict(dt, config);
+
+        let (x, y) = record.position.to_local(&self.origin);
+        let variance = config.position_noise.powi(2);
+        let (east_innovation, east_variance) = east.innovation(0, x, variance);
+        let (north_innovation, north_variance) = north.innovation(0, y, variance);
+        let distance =
+            east_innovation.powi(2) / east_variance + north_innovation.powi(2) / north_variance;
+        if distance > GATE {
+            self.rejected += 1;
+            if self.rejected >= config.max_rejected {
+                *self = Self::new(record, config);
+                return true;
+            }
+            return false;
+        }
+
+        east.correct(0, east_innovation, east_variance);
+        north.correct(0, north_innovation, north_variance);
+        self.east = east;
+        self.north = north;
+        self.timestamp = record.timestamp;
+        self.rejected = 0;
+        self.correct_stationary(record, config);
+        true
+    }
+
+    /// Predicts the state at `timestamp` in milliseconds, which should not be before the last
+    /// update.
+    pub fn predict(&self, timestamp: f64, config: &TrackerConfig) -> Prediction {
+        let dt = (timestamp - self.timestamp) / 1000.0;
+        let mut east = self.east.clone();
+        let mut north = self.north.clone();
+        east.predict(dt, config);
+        north.predict(dt, config);
+        Prediction {
+            timestamp,
+            position: Coordinate::from_local(&self.origin, east.state[0], north.state[0]),
+            velocity: (east.state[1], north.state[1]),
+            covariance: [[east.covariance[0][0], 0.0], [0.0, north.covariance[0][0]]],
+        }
+    }
+
+    /// Predicts the state `horizons` seconds after the last update.
+    pub fn predictions(&self, horizons: &[f64], config: &TrackerConfig) -> Vec<Prediction> {
+        horizons
+            .iter()
+            .map(|horizon| self.predict(self.timestamp + horizon * 1000.0, config))
+            .collect()
+    }
+}
+
+/// Tracks every vehicle of the feed, by vehicle number like [`Trains`].
+#[derive(Debug, Default)]
+pub struct Tracker {
+    config: TrackerConfig,
+    vehicles: HashMap<String, VehicleTracker>,
+}
+
+impl Tracker {
+    pub fn new(config: TrackerConfig) -> Self {
+        Self {
+            config,
+            vehicles: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &TrackerConfig {
+        &self.config
+    }
+
+    /// Adds a record and returns whether it was accepted.
+    pub fn update(&mut self, record: &Record) -> bool {
+        match self.vehicles.get_mut(&record.vehicle_number) {
+            Some(vehicle) => vehicle.update(record, &self.config),
+            None => {
+                self.vehicles.insert(
+                    record.vehicle_number.clone(),
+                    VehicleTracker::new(record, &self.config),
+                );
+                true
+            }
+        }
+    }
+
+    pub fn get(&self, vehicle_number: &str) -> Option<&VehicleTracker> {
+        self.vehicles.get(vehicle_number)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &VehicleTracker)> {
+        self.vehicles.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.vehicles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vehicles.is_empty()
+    }
+
+    /// Stops tracking the vehicles that were not updated for [`TrackerConfig::max_gap`] before
+    /// `timestamp`.
+    pub fn prune(&mut self, timestamp: f64) {
+        let max_gap = self.config.max_gap * 1000.0;
+        self.vehicles
+            .retain(|_, vehicle| timestamp - vehicle.last_update() <= max_gap);
+    }
+
+    /// Predicts the state of `vehicle_number` at `timestamp` in milliseconds.
+    pub fn predict(&self, vehicle_number: &str, timestamp: f64) -> Option<Prediction> {
+        self.get(vehicle_number)
+            .map(|vehicle| vehicle.predict(timestamp, &self.config))
+    }
+}
+
+/// Accuracy of the predicted positions for one horizon.
+#[derive(Debug, Clone)]
+pub struct TrackingResult {
+    /// Horizon in seconds.
+    pub horizon: f64,
+    pub predictions: usize,
+    /// Mean distance in meters between the predicted and the reported position.
+    pub mean_error: f64,
+    /// Fraction of the reported positions within the 95% ellipse.
+    pub coverage: f64,
+}
+
+impl Display for TrackingResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "+{:.0}s: {} predictions, mean error {:.0}m, {:.1}% within the 95% ellipse",
+            self.horizon,
+            self.predictions,
+            self.mean_error,
+            self.coverage * 100.0
+        )
+    }
+}
+
+/// Replays the records of every vehicle through a tracker and compares the positions predicted
+/// `horizons` seconds ahead to the first record at or after the horizon.
+pub fn evaluate(trains: &Trains, config: &TrackerConfig, horizons: &[f64]) -> Vec<TrackingResult> {
+    let mut errors: Vec<(usize, f64, usize)> = vec![(0, 0.0, 0); horizons.len()];
+    for vehicle in trains.values() {
+        let records = &vehicle.records;
+        let Some(first) = records.first() else {
+            continue;
+        };
+        let mut tracker = VehicleTracker::new(first, config);
+        for (index, record) in records.iter().enumerate().skip(1) {
+            if !tracker.update(record, config) {
+                continue;
+            }
+            for (&horizon, (predictions, error, covered)) in horizons.iter().zip(&mut errors) {
+                let target_time = record.timestamp + horizon * 1000.0;
+                let target = records[index..].partition_point(|r| r.timestamp < target_time);
+                let Some(target) = records.get(index + target) else {
+                    continue;
+                };
+                if (target.timestamp - target_time) / 1000.0 > MAX_TARGET_DISTANCE {
+                    continue;
+                }
+                let prediction = tracker.predict(target.timestamp, config);
+                *predictions += 1;
+                *error += prediction.position.distance(&target.position);
+                if prediction.contains(&target.position) {
+                    *covered += 1;
+                }
+            }
+        }
+    }
+    horizons
+        .iter()
+        .zip(errors)
+        .map(|(&horizon, (predictions, error, covered))| {
+            let n = predictions.max(1) as f64;
+            TrackingResult {
+                horizon,
+                predictions,
+                mean_error: error / n,
+                coverage: covered as f64 / n,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MONDAY_MORNING};
+
+    /// Speed in meters per second of the vehicle driving east.
+    const SPEED: f64 = 15.0;
+
+    fn origin() -> Coordinate {
+        Coordinate {
+            latitude: 48.1,
+            longitude: 11.5,
+        }
+    }
+
+    /// A record of a driving vehicle `east` and `north` meters off [`origin`], `seconds` after
+    /// [`MONDAY_MORNING`].
+    fn record(east: f64, north: f64, seconds: f64) -> Record {
+        let mut record =
+            test_support::record(6400, State::Driving, 0.0, MONDAY_MORNING + seconds * 1000.0);
+        record.position = Coordinate::from_local(&origin(), east, north);
+        record
+    }
+
+    /// A tracker fed with five minutes of records every 10 seconds of a vehicle driving east.
+    fn driving(config: &TrackerConfig) -> VehicleTracker {
+        let mut tracker = VehicleTracker::new(&record(0.0, 0.0, 0.0), config);
+        for i in 1..=30 {
+            let seconds = i as f64 * 10.0;
+            assert!(tracker.update(&record(SPEED * seconds, 0.0, seconds), config));
+        }
+        tracker
+    }
+
+    #[test]
+    fn converges_on_a_constant_velocity() {
+        let config = TrackerConfig::default();
+        let tracker = driving(&config);
+        let now = tracker.predict(tracker.last_update(), &config);
+        assert!((now.velocity.0 - SPEED).abs() < 0.5, "{:?}", now.velocity);
+        assert!(now.velocity.1.abs() < 0.5, "{:?}", now.velocity);
+
+        let ahead = tracker.predict(tracker.last_update() + 60_000.0, &config);
+        let expected = Coordinate::from_local(&origin(), SPEED * 360.0, 0.0);
+        assert!(ahead.position.distance(&expected) < 30.0);
+        assert!(ahead.contains(&expected));
+        assert!(ahead.ellipse().semi_major > now.ellipse().semi_major);
+    }
+
+    #[test]
+    fn rejects_outliers_until_max_rejected() {
+        let config = TrackerConfig::default();
+        let mut tracker = driving(&config);
+        let last_update = tracker.last_update();
+
+        // Far off the track, the next record on the track is accepted again.
+        assert!(!tracker.update(&record(SPEED * 310.0, 2000.0, 310.0), &config));
+        assert_eq!(tracker.last_update(), last_update);
+        assert!(tracker.update(&record(SPEED * 320.0, 0.0, 320.0), &config));
+
+        // Consecutive outliers start a new track at the last of them.
+        assert!(!tracker.update(&record(0.0, 2000.0, 330.0), &config));
+        assert!(!tracker.update(&record(0.0, 2000.0, 340.0), &config));
+        assert!(tracker.update(&record(0.0, 2000.0, 350.0), &config));
+        let prediction = tracker.predict(tracker.last_update(), &config);
+        let jumped = Coordinate::from_local(&origin(), 0.0, 2000.0);
+        assert!(prediction.position.distance(&jumped) < 1.0);
+        assert_eq!(prediction.velocity, (0.0, 0.0));
+    }
+
+    #[test]
+    fn tracks_anew_after_a_gap() {
+        let config = TrackerConfig::default();
+        let mut tracker = driving(&config);
+        let seconds = 300.0 + config.max_gap + 10.0;
+        // Stopped far behind where the vehicle would have been, accepted as a new track.
+        assert!(tracker.update(&record(0.0, 0.0, seconds), &config));
+        assert_eq!(tracker.last_update(), MONDAY_MORNING + seconds * 1000.0);
+        let prediction = tracker.predict(tracker.last_update(), &config);
+        assert!(prediction.position.distance(&origin()) < 1.0);
+        assert_eq!(prediction.velocity, (0.0, 0.0));
+        assert_eq!(
+            prediction.covariance,
+            [
+                [config.position_noise.powi(2), 0.0],
+                [0.0, config.position_noise.powi(2)]
+            ]
+        );
+    }
+
+    #[test]
+    fn computes_the_ellipse_of_a_diagonal_covariance() {
+        let prediction = |covariance| Prediction {
+            timestamp: MONDAY_MORNING,
+            position: origin(),
+            velocity: (0.0, 0.0),
+            covariance,
+        };
+        let wide = prediction([[400.0, 0.0], [0.0, 100.0]]).ellipse();
+        assert!((wide.semi_major - (CONFIDENCE_95 * 400.0).sqrt()).abs() < 1e-9);
+        assert!((wide.semi_minor - (CONFIDENCE_95 * 100.0).sqrt()).abs() < 1e-9);
+        assert_eq!(wide.orientation, 0.0);
+
+        let tall = prediction([[100.0, 0.0], [0.0, 400.0]]);
+        let ellipse = tall.ellipse();
+        assert!((ellipse.semi_major - (CONFIDENCE_95 * 400.0).sqrt()).abs() < 1e-9);
+        assert!((ellipse.semi_minor - (CONFIDENCE_95 * 100.0).sqrt()).abs() < 1e-9);
+        assert!((ellipse.orientation - std::f64::consts::FRAC_PI_2).abs() < 1e-9);
+
+        // Just inside along the major axis is outside along the minor one.
+        let north = Coordinate::from_local(&origin(), 0.0, ellipse.semi_major - 0.1);
+        let east = Coordinate::from_local(&origin(), ellipse.semi_major - 0.1, 0.0);
+        assert!(tall.contains(&north));
+        assert!(!tall.contains(&east));
+    }
+}
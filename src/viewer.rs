@@ -0,0 +1,763 @@
+//! Interactive map of a recording, replayed in time in a window.
+//!
+//! Hovering a vehicle shows its line, train and vehicle number, delay, state and the age of its
+//! latest record. Clicking selects it, which also draws its track of the last minutes on top of
+//! its whole trajectory of the service day. Space pauses the replay, the arrow keys skip a minute
+//! and Escape clears the selection.
+//!
+//! The legend lists the lines, clicking one hides or shows its vehicles, and `A` shows all again.
+//! `C` switches what the color of a vehicle shows: its line, its delay, how old its latest record
+//! is or its state.
+//!
+//! `H` switches through the heatmaps of the delay increase and the speed on the segments and the
+//! dwell at the stations, see [`crate::heatmap`].
+//!
+//! The mouse wheel zooms, dragging with the right button moves the map and `R` shows the whole
+//! network again. Zoomed in, the vehicles are drawn as badges of their lines like on the official
+//! live map, placed next to the vehicle if another badge is in the way. An arrow points in the
+//! direction a vehicle is heading.
+
+use std::collections::BTreeSet;
+use std::fmt::Display;
+
+use chrono::{NaiveDate, TimeDelta};
+use macroquad::color::{Color, BLACK, WHITE};
+use macroquad::input::{
+    is_key_pressed, is_mouse_button_down, is_mouse_button_pressed, mouse_position, mouse_wheel,
+    KeyCode, MouseButton,
+};
+use macroquad::math::{Rect, Vec2};
+use macroquad::shapes::{
+    draw_circle, draw_circle_lines, draw_line, draw_rectangle, draw_rectangle_lines, draw_triangle,
+};
+use macroquad::text::{draw_text, measure_text};
+use macroquad::time::get_frame_time;
+use macroquad::window::{clear_background, next_frame, screen_height, screen_width};
+
+use crate::heatmap::{Heatmap, Metric};
+use crate::records::{local_time, LineStyle, Record, State, Trains};
+use crate::render::{heading_at, position_at, Network, Projection};
+use crate::stations::Stations;
+
+#[derive(Debug, Clone)]
+pub struct ViewerConfig {
+    /// Seconds of the recording replayed per second.
+    pub speed: f64,
+    /// Vehicles without a record for this many seconds are not drawn.
+    pub max_age: f64,
+    /// Seconds of the track drawn behind the selected vehicle.
+    pub track: f64,
+    /// Hour at which the service day starts, for the trajectory of the selected vehicle.
+    pub service_day_start: u32,
+}
+
+impl Default for ViewerConfig {
+    fn default() -> Self {
+        Self {
+            speed: 60.0,
+            max_age: 120.0,
+            track: 10.0 * 60.0,
+            service_day_start: 3,
+        }
+    }
+}
+
+/// What the color of a vehicle shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMode {
+    /// The colors of the line, as on the official live map.
+    #[default]
+    Line,
+    Delay,
+    /// How old the latest record is.
+    Staleness,
+    State,
+}
+
+impl ColorMode {
+    const ALL: [ColorMode; 4] = [
+        ColorMode::Line,
+        ColorMode::Delay,
+        ColorMode::Staleness,
+        ColorMode::State,
+    ];
+
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|&mode| mode == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    /// The buckets of the mode with their colors, empty for the line colors.
+    pub fn legend(self) -> Vec<(&'static str, Color)> {
+        let (green, yellow, orange, red, gray) = (
+            Color::from_hex(0x002E_7D32),
+            Color::from_hex(0x00F9_A825),
+            Color::from_hex(0x00EF_6C00),
+            Color::from_hex(0x00C6_2828),
+            Color::from_hex(0x0061_6161),
+        );
+        match self {
+            ColorMode::Line => Vec::new(),
+            ColorMode::Delay => vec![
+                ("on time", green),
+                ("2-5 min", yellow),
+                ("5-10 min", orange),
+                ("over 10 min", red),
+                ("unknown", gray),
+            ],
+            ColorMode::Staleness => vec![
+                ("under 30 s", green),
+                ("30-60 s", yellow),
+                ("1-2 min", orange),
+                ("over 2 min", red),
+            ],
+            ColorMode::State => vec![
+                ("driving", Color::from_hex(0x0015_65C0)),
+                ("boarding", green),
+                ("cancelled", red),
+                ("other", gray),
+            ],
+        }
+    }
+
+    /// Index of the bucket of the legend a vehicle falls into, whose latest `record` is `age`
+    /// seconds old.
+    fn bucket(self, record: &Record, age: f64) -> usize {
+        match self {
+            ColorMode::Line => 0,
+            ColorMode::Delay => match record.delay.map(|delay| delay / 60.0) {
+                Some(minutes) if minutes < 2.0 => 0,
+                Some(minutes) if minutes < 5.0 => 1,
+                Some(minutes) if minutes < 10.0 => 2,
+                Some(_) => 3,
+                None => 4,
+            },
+            ColorMode::Staleness => match age {
+                age if age < 30.0 => 0,
+                age if age < 60.0 => 1,
+                age if age < 120.0 => 2,
+                _ => 3,
+            },
+            ColorMode::State => match record.state {
+                State::Driving => 0,
+                State::Boarding => 1,
+                State::JourneyCancelled | State::StopCancelled => 2,
+                State::Unknown(_) => 3,
+            },
+        }
+    }
+
+    /// Colors of a vehicle whose latest `record` is `age` seconds old.
+    pub fn style(self, record: &Record, age: f64) -> LineStyle {
+        if self == ColorMode::Line {
+            return record.line_style();
+        }
+        LineStyle {
+            stroke: Color::from_hex(0x0020_2020),
+            ..LineStyle::from_color(self.legend()[self.bucket(record, age)].1)
+        }
+    }
+}
+
+impl Display for ColorMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColorMode::Line => write!(f, "line"),
+            ColorMode::Delay => write!(f, "delay"),
+            ColorMode::Staleness => write!(f, "staleness"),
+            ColorMode::State => write!(f, "state"),
+        }
+    }
+}
+
+/// A vehicle as it is drawn.
+struct Marker<'a> {
+    /// Pixel of the vehicle.
+    position: (f32, f32),
+    record: &'a Record,
+    /// Unit vector in the window pointing in the direction the vehicle is heading.
+    direction: Option<Vec2>,
+    /// Where the badge of the line is drawn, `None` while zoomed out or if there is no free space
+    /// close to the vehicle.
+    badge: Option<Rect>,
+}
+
+impl Marker<'_> {
+    fn contains(&self, x: f32, y: f32) -> bool {
+        self.badge
+            .is_some_and(|badge| badge.contains(Vec2::new(x, y)))
+            || (self.position.0 - x).hypot(self.position.1 - y) <= Viewer::VEHICLE_RADIUS + 3.0
+    }
+}
+
+pub struct Viewer {
+    trains: Trains,
+    network: Network,
+    config: ViewerConfig,
+    projection: Projection,
+    /// Timestamps of the first and the last record in milliseconds.
+    range: (f64, f64),
+    time: f64,
+    paused: bool,
+    hovered: Option<String>,
+    selected: Option<String>,
+    /// Lines of the recording with their colors, ordered by name.
+    lines: Vec<(String, LineStyle)>,
+    hidden: BTreeSet<String>,
+    color_mode: ColorMode,
+    heatmap: Heatmap,
+    layer: Option<Metric>,
+    /// Scale relative to the whole network.
+    zoom: f64,
+    /// Mouse position while dragging the map.
+    drag: Option<(f32, f32)>,
+}
+
+impl Viewer {
+    /// Radius of a vehicle in pixels.
+    pub const VEHICLE_RADIUS: f32 = 5.0;
+    /// Height of a row of the legend in pixels.
+    const LEGEND_ROW: f32 = 20.0;
+    const LEGEND_WIDTH: f32 = 200.0;
+    /// Zoom from which on the vehicles are drawn as badges.
+    const BADGE_ZOOM: f64 = 2.5;
+    const BADGE_HEIGHT: f32 = 18.0;
+
+    pub fn new(trains: Trains, stations: &Stations, config: ViewerConfig) -> Self {
+        let network = Network::from_trains(&trains, stations);
+        let heatmap = Heatmap::from_trains(&trains, stations);
+        let records = || trains.values().flat_map(|vehicle| &vehicle.records);
+        let range = (
+            records()
+                .map(|r| r.timestamp)
+                .reduce(f64::min)
+                .unwrap_or(0.0),
+            records()
+                .map(|r| r.timestamp)
+                .reduce(f64::max)
+                .unwrap_or(0.0),
+        );
+        let mut lines: Vec<(String, LineStyle)> = Vec::new();
+        for record in records() {
+            if !lines.iter().any(|(line, _)| line == &record.line) {
+                lines.push((record.line.clone(), record.line_style()));
+            }
+        }
+        // S2 before S20.
+        lines.sort_by(|(a, _), (b, _)| (a.len(), a).cmp(&(b.len(), b)));
+        Self {
+            projection: fit(&trains, &network, 1, 1),
+            trains,
+            network,
+            config,
+            range,
+            time: range.0,
+            paused: false,
+            hovered: None,
+            selected: None,
+            lines,
+            hidden: BTreeSet::new(),
+            color_mode: ColorMode::default(),
+            heatmap,
+            layer: None,
+            zoom: 1.0,
+            drag: None,
+        }
+    }
+
+    /// Vehicles shown at the current time with their position in the window.
+    fn visible(&self) -> Vec<Marker<'_>> {
+        let mut visible: Vec<_> = self
+            .trains
+            .values()
+            .filter_map(|vehicle| {
+                let (position, record) =
+                    position_at(&vehicle.records, self.time, self.config.max_age)?;
+                if self.hidden.contains(&record.line) {
+                    return None;
+                }
+                // North is up in the window.
+                let direction =
+                    heading_at(&vehicle.records, self.time, self.config.max_age).map(|heading| {
+                        let heading = heading.to_radians() as f32;
+                        Vec2::new(heading.sin(), -heading.cos())
+                    });
+                Some(Marker {
+                    position: self.projection.project(&position),
+                    record,
+                    direction,
+                    badge: None,
+                })
+            })
+            .collect();
+        // Drawn in a fixed order, so vehicles on top of each other do not flicker.
+        visible.sort_by(|a, b| a.record.vehicle_number.cmp(&b.record.vehicle_number));
+        if self.zoom >= Self::BADGE_ZOOM {
+            place_badges(&mut visible);
+        }
+        visible
+    }
+
+    fn service_day(&self, timestamp: f64) -> NaiveDate {
+        (local_time(timestamp) - TimeDelta::hours(i64::from(self.config.service_day_start)))
+            .date_naive()
+    }
+
+    /// Handles the input and advances the time by the duration of the last frame.
+    pub fn update(&mut self) {
+        let (width, height) = (screen_width() as u32, screen_height() as u32);
+        if (width, height) != (self.projection.width(), self.projection.height())
+            || is_key_pressed(KeyCode::R)
+        {
+            self.projection = fit(&self.trains, &self.network, width, height);
+            self.zoom = 1.0;
+        }
+
+        if is_key_pressed(KeyCode::Space) {
+            self.paused = !self.paused;
+        }
+        if is_key_pressed(KeyCode::Right) {
+            self.time += 60_000.0;
+        }
+        if is_key_pressed(KeyCode::Left) {
+            self.time -= 60_000.0;
+        }
+        if is_key_pressed(KeyCode::Escape) {
+            self.selected = None;
+        }
+        if is_key_pressed(KeyCode::C) {
+            self.color_mode = self.color_mode.next();
+        }
+        if is_key_pressed(KeyCode::A) {
+            self.hidden.clear();
+        }
+        if is_key_pressed(KeyCode::H) {
+            self.layer = match self.layer {
+                None => Some(Metric::ALL[0]),
+                Some(metric) => Metric::ALL
+                    .iter()
+                    .position(|&m| m == metric)
+                    .and_then(|index| Metric::ALL.get(index + 1))
+                    .copied(),
+            };
+        }
+        if !self.paused {
+            self.time += f64::from(get_frame_time()) * self.config.speed * 1000.0;
+        }
+        if self.time > self.range.1 {
+            self.time = self.range.0;
+        }
+        self.time = self.time.max(self.range.0);
+
+        let (mouse_x, mouse_y) = mouse_position();
+        let wheel = mouse_wheel().1;
+        if wheel != 0.0 {
+            let factor = if wheel > 0.0 { 1.25 } else { 0.8 };
+            let zoom = (self.zoom * factor).clamp(0.5, 64.0);
+            self.projection.zoom(zoom / self.zoom, mouse_x, mouse_y);
+            self.zoom = zoom;
+        }
+        if is_mouse_button_down(MouseButton::Right) {
+            if let Some((x, y)) = self.drag {
+                self.projection.pan(mouse_x - x, mouse_y - y);
+            }
+            self.drag = Some((mouse_x, mouse_y));
+        } else {
+            self.drag = None;
+        }
+
+        let distance = |(x, y): (f32, f32)| (x - mouse_x).hypot(y - mouse_y);
+        self.hovered = self
+            .visible()
+            .into_iter()
+            .filter(|marker| marker.contains(mouse_x, mouse_y))
+            .min_by(|a, b| distance(a.position).total_cmp(&distance(b.position)))
+            .map(|marker| marker.record.vehicle_number.clone());
+        if is_mouse_button_pressed(MouseButton::Left) {
+            if let Some(line) = self.legend_line_at(mouse_x, mouse_y) {
+                if !self.hidden.remove(&line) {
+                    self.hidden.insert(line);
+                }
+            } else {
+                self.selected = self.hovered.clone();
+            }
+        }
+    }
+
+    /// Top of the legend, which is drawn in the bottom left corner.
+    fn legend_top(&self) -> f32 {
+        let mut rows = 1 + self.lines.len();
+        if self.color_mode != ColorMode::Line {
+            rows += 1 + self.color_mode.legend().len();
+        }
+        if let Some(metric) = self.layer {
+            rows += 1 + heatmap_legend(metric).len();
+        }
+        screen_height() - 10.0 - rows as f32 * Self::LEGEND_ROW
+    }
+
+    /// The line whose row of the legend is at the pixel.
+    fn legend_line_at(&self, x: f32, y: f32) -> Option<String> {
+        if !(10.0..10.0 + Self::LEGEND_WIDTH).contains(&x) {
+            return None;
+        }
+        let row = ((y - self.legend_top()) / Self::LEGEND_ROW).floor();
+        if row < 1.0 {
+            return None;
+        }
+        self.lines
+            .get(row as usize - 1)
+            .map(|(line, _)| line.clone())
+    }
+
+    pub fn draw(&self) {
+        clear_background(Color::from_hex(0x009E_9E9E));
+        for segment in self.network.segments() {
+            let (x1, y1) = self.projection.project(&segment.start);
+            let (x2, y2) = self.projection.project(&segment.end);
+            draw_line(x1, y1, x2, y2, 2.0, Color::from_hex(0x00C8_C8C8));
+        }
+        if let Some(metric) = self.layer {
+            self.draw_heatmap(metric);
+        }
+        for station in self.network.stations() {
+            let (x, y) = self.projection.project(&station.position);
+            draw_circle(x, y, 2.5, Color::from_hex(0x0050_5050));
+        }
+
+        if let Some(vehicle) = self.selected.as_ref().and_then(|s| self.trains.get(s)) {
+            self.draw_trajectory(&vehicle.records);
+        }
+
+        let visible = self.visible();
+        for marker in &visible {
+            self.draw_marker(marker);
+        }
+        for marker in &visible {
+            let color = if self.selected.as_ref() == Some(&marker.record.vehicle_number) {
+                BLACK
+            } else if self.hovered.as_ref() == Some(&marker.record.vehicle_number) {
+                WHITE
+            } else {
+                continue;
+            };
+            match marker.badge {
+                Some(badge) => draw_rectangle_lines(
+                    badge.x - 3.0,
+                    badge.y - 3.0,
+                    badge.w + 6.0,
+                    badge.h + 6.0,
+                    2.0,
+                    color,
+                ),
+                None => {
+                    let (x, y) = marker.position;
+                    draw_circle_lines(x, y, Self::VEHICLE_RADIUS + 3.0, 2.0, color);
+                }
+            }
+        }
+
+        let mut time = local_time(self.time)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string();
+        if self.paused {
+            time.push_str(" (paused)");
+        }
+        draw_text(&time, 10.0, 26.0, 28.0, BLACK);
+
+        let inspected = self.selected.as_ref().or(self.hovered.as_ref());
+        if let Some(marker) = inspected.and_then(|number| {
+            visible
+                .iter()
+                .find(|marker| &marker.record.vehicle_number == number)
+        }) {
+            self.draw_panel(marker.record);
+        }
+        self.draw_legend();
+    }
+
+    /// Draws the vehicle as a dot or as the badge of its line, with an arrow in its direction.
+    fn draw_marker(&self, marker: &Marker) {
+        let record = marker.record;
+        let style = self
+            .color_mode
+            .style(record, (self.time - record.timestamp) / 1000.0);
+        let (x, y) = marker.position;
+        let Some(badge) = marker.badge else {
+            if let Some(direction) = marker.direction {
+                draw_arrow(
+                    Vec2::new(x, y),
+                    direction,
+                    Self::VEHICLE_RADIUS,
+                    style.stroke,
+                );
+            }
+            draw_circle(x, y, Self::VEHICLE_RADIUS, style.color);
+            draw_circle_lines(x, y, Self::VEHICLE_RADIUS, 1.5, style.stroke);
+            return;
+        };
+
+        let center = badge.center();
+        if center.distance(Vec2::new(x, y)) > 1.0 {
+            draw_line(x, y, center.x, center.y, 1.5, style.stroke);
+            draw_circle(x, y, 3.0, style.stroke);
+        }
+        if let Some(direction) = marker.direction {
+            // Distance from the center to the border of the badge in the direction.
+            let distance =
+                (badge.w / 2.0 / direction.x.abs()).min(badge.h / 2.0 / direction.y.abs());
+            draw_arrow(center, direction, distance, style.stroke);
+        }
+        let radius = badge.h / 2.0;
+        for (grow, color) in [(1.5, style.stroke), (0.0, style.color)] {
+            draw_circle(badge.x + radius, center.y, radius + grow, color);
+            draw_circle(badge.right() - radius, center.y, radius + grow, color);
+            draw_rectangle(
+                badge.x + radius,
+                badge.y - grow,
+                badge.w - 2.0 * radius,
+                badge.h + 2.0 * grow,
+                color,
+            );
+        }
+        let size = measure_text(&record.line, None, BADGE_FONT_SIZE, 1.0);
+        draw_text(
+            &record.line,
+            center.x - size.width / 2.0,
+            center.y + size.offset_y / 2.0,
+            f32::from(BADGE_FONT_SIZE),
+            style.text_color,
+        );
+    }
+
+    /// Draws the segments or, for the dwell, the stations in the color of their mean value.
+    fn draw_heatmap(&self, metric: Metric) {
+        for segment in self.heatmap.segments() {
+            let Some(value) = segment.mean(metric) else {
+                continue;
+            };
+            let (x1, y1) = self.projection.project(&segment.start);
+            let (x2, y2) = self.projection.project(&segment.end);
+            draw_line(x1, y1, x2, y2, 6.0, metric.color(value));
+        }
+        for station in self.heatmap.stations() {
+            let Some(value) = station.mean(metric) else {
+                continue;
+            };
+            let (x, y) = self.projection.project(&station.position);
+            draw_circle(x, y, 8.0, metric.color(value));
+        }
+    }
+
+    fn draw_legend(&self) {
+        let row = Self::LEGEND_ROW;
+        let top = self.legend_top();
+        let modes = self.color_mode.legend();
+        let heatmap = self.layer.map(heatmap_legend).unwrap_or_default();
+        let rows = 1
+            + self.lines.len()
+            + if modes.is_empty() { 0 } else { 1 + modes.len() }
+            + if heatmap.is_empty() {
+                0
+            } else {
+                1 + heatmap.len()
+            };
+        draw_rectangle(
+            10.0,
+            top - 4.0,
+            Self::LEGEND_WIDTH,
+            rows as f32 * row + 8.0,
+            Color::new(1.0, 1.0, 1.0, 0.85),
+        );
+        let text = |text: &str, index: usize, color: Color| {
+            draw_text(text, 38.0, top + (index as f32 + 0.75) * row, 20.0, color);
+        };
+        let swatch = |index: usize, fill: Color, outline: Color| {
+            let y = top + (index as f32 + 0.5) * row;
+            draw_circle(24.0, y, 6.0, fill);
+            draw_circle_lines(24.0, y, 6.0, 1.5, outline);
+        };
+
+        draw_text("Lines (click, A: all)", 16.0, top + 0.75 * row, 20.0, BLACK);
+        for (index, (line, style)) in self.lines.iter().enumerate() {
+            if self.hidden.contains(line) {
+                let gray = Color::new(0.6, 0.6, 0.6, 1.0);
+                swatch(index + 1, WHITE, gray);
+                text(&format!("{line} (hidden)"), index + 1, gray);
+            } else {
+                swatch(index + 1, style.color, style.stroke);
+                text(line, index + 1, BLACK);
+            }
+        }
+        if !modes.is_empty() {
+            let header = 1 + self.lines.len();
+            draw_text(
+                &format!("{} (C: switch)", self.color_mode),
+                16.0,
+                top + (header as f32 + 0.75) * row,
+                20.0,
+                BLACK,
+            );
+            for (index, (label, color)) in modes.into_iter().enumerate() {
+                swatch(header + 1 + index, color, Color::from_hex(0x0020_2020));
+                text(label, header + 1 + index, BLACK);
+            }
+        }
+        if let Some(metric) = self.layer {
+            let header = rows - 1 - heatmap.len();
+            draw_text(
+                &format!("Mean {metric} (H: switch)"),
+                16.0,
+                top + (header as f32 + 0.75) * row,
+                20.0,
+                BLACK,
+            );
+            for (index, (label, color)) in heatmap.iter().enumerate() {
+                swatch(header + 1 + index, *color, *color);
+                text(label, header + 1 + index, BLACK);
+            }
+        }
+    }
+
+    /// Draws the trajectory of the service day fainter and the recent track on top.
+    fn draw_trajectory(&self, records: &[Record]) {
+        let day = self.service_day(self.time);
+        let max_gap = self.config.max_age * 1000.0;
+        let track_start = self.time - self.config.track * 1000.0;
+        for pair in records.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            if b.timestamp - a.timestamp > max_gap || self.service_day(a.timestamp) != day {
+                continue;
+            }
+            let (x1, y1) = self.projection.project(&a.position);
+            let (x2, y2) = self.projection.project(&b.position);
+            let recent = a.timestamp >= track_start && b.timestamp <= self.time;
+            if recent {
+                draw_line(x1, y1, x2, y2, 4.0, a.line_color);
+            } else {
+                let mut color = a.line_color;
+                color.a = 0.4;
+                draw_line(x1, y1, x2, y2, 2.0, color);
+            }
+        }
+    }
+
+    fn draw_panel(&self, record: &Record) {
+        let mut lines = vec![
+            format!("{} train {}", record.line, record.train_number),
+            format!("vehicle {}", record.vehicle_number),
+            match record.delay {
+                Some(delay) => format!("delay {:+.0} min", delay / 60.0),
+                None => "delay unknown".to_string(),
+            },
+            format!("state {}", record.state),
+            format!(
+                "last update {:.0} s ago",
+                (self.time - record.timestamp) / 1000.0
+            ),
+        ];
+        if let Some(rake) = &record.rake {
+            lines.push(format!("rake {rake}"));
+        }
+        let (width, line_height) = (260.0, 24.0);
+        let x = screen_width() - width - 10.0;
+        draw_rectangle(
+            x,
+            10.0,
+            width,
+            lines.len() as f32 * line_height + 12.0,
+            Color::new(1.0, 1.0, 1.0, 0.85),
+        );
+        draw_rectangle(
+            x,
+            10.0,
+            6.0,
+            lines.len() as f32 * line_height + 12.0,
+            record.line_color,
+        );
+        for (index, line) in lines.iter().enumerate() {
+            draw_text(
+                line,
+                x + 16.0,
+                10.0 + (index + 1) as f32 * line_height,
+                22.0,
+                BLACK,
+            );
+        }
+    }
+
+    /// Runs the viewer until the window is closed.
+    pub async fn run(mut self) {
+        loop {
+            self.update();
+            self.draw();
+            next_frame().await;
+        }
+    }
+}
+
+/// Fits the map into the window, to the stations or to the trains if there are none.
+/// Font size of the name of the line in a badge.
+const BADGE_FONT_SIZE: u16 = 18;
+
+/// Places the badges of the `markers` in their order, each on its vehicle or, if that overlaps
+/// an earlier badge, on the first free spot around it.
+fn place_badges(markers: &mut [Marker]) {
+    let mut placed: Vec<Rect> = Vec::with_capacity(markers.len());
+    for marker in markers {
+        let size = measure_text(&marker.record.line, None, BADGE_FONT_SIZE, 1.0);
+        let (w, h) = (size.width + Viewer::BADGE_HEIGHT, Viewer::BADGE_HEIGHT);
+        let (x, y) = marker.position;
+        let (dx, dy) = (w / 2.0 + h, h * 1.5);
+        marker.badge = [
+            (0.0, 0.0),
+            (dx, 0.0),
+            (-dx, 0.0),
+            (0.0, -dy),
+            (0.0, dy),
+            (dx, -dy),
+            (-dx, -dy),
+            (dx, dy),
+            (-dx, dy),
+        ]
+        .into_iter()
+        .map(|(offset_x, offset_y)| Rect::new(x + offset_x - w / 2.0, y + offset_y - h / 2.0, w, h))
+        .find(|badge| !placed.iter().any(|other| other.overlaps(badge)));
+        placed.extend(marker.badge);
+    }
+}
+
+/// Draws a triangle pointing in the `direction`, starting `distance` pixels from the `center`.
+fn draw_arrow(center: Vec2, direction: Vec2, distance: f32, color: Color) {
+    let base = center + direction * distance;
+    let side = direction.perp() * 4.0;
+    draw_triangle(base + direction * 7.0, base + side, base - side, color);
+}
+
+/// The bounds and the middle of the color scale of the `metric`.
+fn heatmap_legend(metric: Metric) -> Vec<(String, Color)> {
+    let (good, bad) = metric.range();
+    [good, (good + bad) / 2.0, bad]
+        .into_iter()
+        .map(|value| (format!("{value:.0} {}", metric.unit()), metric.color(value)))
+        .collect()
+}
+
+fn fit(trains: &Trains, network: &Network, width: u32, height: u32) -> Projection {
+    if network.stations().is_empty() {
+        Projection::fit(
+            trains
+                .values()
+                .flat_map(|vehicle| &vehicle.records)
+                .map(|record| &record.position),
+            width,
+            height,
+        )
+    } else {
+        Projection::fit(
+            network.stations().iter().map(|station| &station.position),
+            width,
+            height,
+        )
+    }
+}